@@ -3,7 +3,7 @@
 
 from dataclasses import dataclass
 from enum import Enum
-from typing import TYPE_CHECKING, Iterable, Optional, Tuple, Union
+from typing import TYPE_CHECKING, Iterable, Mapping, Optional, Tuple, Union
 
 # Re-export GlobMatchErrorBehavior here as part of the public Plugin API.
 from pants.base.glob_match_error_behavior import GlobMatchErrorBehavior as GlobMatchErrorBehavior
@@ -28,6 +28,9 @@ Digest = PyDigest
 names/content.
 
 You can lift a `Digest` to a `Snapshot` with `await Get(Snapshot, Digest, my_digest)`.
+
+Symlinks which were captured in a `Digest` (for example, in the outputs of a remote process) are
+preserved, and are listed in `files`.
 """
 Snapshot = PySnapshot
 
@@ -84,6 +87,18 @@ class FileEntry:
     is_executable: bool = False
 
 
+@dataclass(frozen=True)
+class SymlinkEntry:
+    """A symlink in a Digest, and the (unresolved) path that it points to.
+
+    You can get back a list of `SymlinkEntry` objects, along with the other entries of a Digest, by
+    using `Get(DigestEntries, Digest)`.
+    """
+
+    path: str
+    target: str
+
+
 @dataclass(frozen=True)
 class Directory:
     """The path to a directory.
@@ -101,11 +116,11 @@ class DigestContents(Collection[FileContent]):
     """The file contents of a Digest."""
 
 
-class DigestEntries(Collection[Union[FileEntry, Directory]]):
+class DigestEntries(Collection[Union[FileEntry, SymlinkEntry, Directory]]):
     """The indirect file contents of a Digest.
 
-    DigestEntries is a collection of FileContent and Directory instances representing, respecively,
-    actual files and empty directories present in the Digest.
+    DigestEntries is a collection of FileEntry, SymlinkEntry and Directory instances representing,
+    respectively, actual files, symlinks and empty directories present in the Digest.
     """
 
 
@@ -139,6 +154,8 @@ class PathGlobs:
     glob_match_error_behavior: GlobMatchErrorBehavior
     conjunction: GlobExpansionConjunction
     description_of_origin: str
+    case_insensitive: bool
+    unicode_normalization: bool
 
     def __init__(
         self,
@@ -146,6 +163,8 @@ class PathGlobs:
         glob_match_error_behavior: GlobMatchErrorBehavior = GlobMatchErrorBehavior.ignore,
         conjunction: GlobExpansionConjunction = GlobExpansionConjunction.any_match,
         description_of_origin: Optional[str] = None,
+        case_insensitive: bool = False,
+        unicode_normalization: bool = False,
     ) -> None:
         """A request to find files given a set of globs.
 
@@ -159,6 +178,13 @@ class PathGlobs:
         :param description_of_origin: a human-friendly description of where this PathGlobs request
             is coming from, used to improve the error message for unmatched globs. For example,
             this might be the text string "the option `--isort-config`".
+        :param case_insensitive: whether to match paths regardless of their case, as on
+            case-insensitive filesystems. If `glob_match_error_behavior` is `warn` or `error`, a
+            Snapshot of the globs will also warn or error if it contains paths which differ only by
+            case.
+        :param unicode_normalization: whether to compare paths and globs after Unicode NFC
+            normalization, as on normalizing filesystems. Paths which are equal after normalization
+            are treated like paths which differ only by case.
         """
 
         # NB: this object is interpreted from within Snapshot::lift_path_globs() -- that method
@@ -167,6 +193,8 @@ class PathGlobs:
         self.glob_match_error_behavior = glob_match_error_behavior
         self.conjunction = conjunction
         self.description_of_origin = description_of_origin or ""
+        self.case_insensitive = case_insensitive
+        self.unicode_normalization = unicode_normalization
         self.__post_init__()
 
     def __post_init__(self) -> None:
@@ -263,6 +291,113 @@ class AddPrefix:
     prefix: str
 
 
+@frozen_after_init
+@dataclass(unsafe_hash=True)
+class RenamePaths:
+    digest: Digest
+    mapping: Tuple[Tuple[str, str], ...]
+    regex: Optional[str]
+    replacement: str
+
+    def __init__(
+        self,
+        digest: Digest,
+        mapping: Optional[Mapping[str, str]] = None,
+        *,
+        regex: Optional[str] = None,
+        replacement: str = "",
+    ) -> None:
+        """A request to move files and directories within a digest, without running a process.
+
+        Exactly one of `mapping` or `regex` must be set. This will fail if two different files
+        would be moved to the same path.
+
+        Example:
+
+            result = await Get(Digest, RenamePaths(digest, {"old.txt": "new.txt", "src": "lib"}))
+            result = await Get(Digest, RenamePaths(digest, regex=r"^(.*)\.tmpl$", replacement="$1"))
+
+        :param mapping: moves each path which is a key to the corresponding value. When a key is a
+            directory, all of its contents are moved. If multiple keys match a path, the longest
+            one is used.
+        :param regex: a regex to search for in each path: the first match is replaced with
+            `replacement`, which may refer to capture groups as `$1` or `${name}`.
+        """
+        if (mapping is None) == (regex is None):
+            raise ValueError("Exactly one of `mapping` or `regex` must be set for RenamePaths.")
+        self.digest = digest
+        self.mapping = tuple(sorted((mapping or {}).items()))
+        self.regex = regex
+        self.replacement = replacement
+
+
+@frozen_after_init
+@dataclass(unsafe_hash=True)
+class SubstituteContents:
+    digest: Digest
+    globs: PathGlobs
+    replacements: Tuple[Tuple[str, str], ...]
+    header: str
+
+    def __init__(
+        self,
+        digest: Digest,
+        globs: PathGlobs,
+        replacements: Iterable[Tuple[str, str]] = (),
+        *,
+        header: str = "",
+    ) -> None:
+        """A request to edit the content of the files in a digest which match the globs, without
+        running a process.
+
+        Example:
+
+            result = await Get(
+                Digest,
+                SubstituteContents(
+                    digest, PathGlobs(["**/*.py"]), [("VERSION", "1.0")], header="# Generated.\n"
+                ),
+            )
+
+        :param replacements: pairs of literal text to replace, and its replacement. Each pair is
+            applied in order to the result of the previous one, and replaces all occurrences.
+        :param header: text to prepend to each file, after the replacements have been applied.
+        """
+        self.digest = digest
+        self.globs = globs
+        self.replacements = tuple(replacements)
+        self.header = header
+
+
+@dataclass(frozen=True)
+class DiffDigests:
+    """A request to find the paths which differ between two digests.
+
+    Example:
+
+        diff = await Get(DigestDiff, DiffDigests(before_digest, after_digest))
+    """
+
+    before: Digest
+    after: Digest
+
+
+@dataclass(frozen=True)
+class DigestDiff:
+    """The sorted paths which were added, removed or modified between two digests.
+
+    When a directory is added or removed, both it and all of its contents are listed. Only files
+    are listed as modified: a file is modified if its content or executable bit changed.
+
+    NB: this object is interpreted from within `Snapshot::store_digest_diff()` in Rust -- that
+    method will need to be aware of any changes to this object's definition.
+    """
+
+    added: Tuple[str, ...]
+    removed: Tuple[str, ...]
+    modified: Tuple[str, ...]
+
+
 @dataclass(frozen=True)
 class DownloadFile:
     """Retrieve the contents of a file via an HTTP GET request or directly for local file:// URLs.
@@ -270,10 +405,72 @@ class DownloadFile:
     To compute the `expected_digest`, manually download the file, then run `shasum -a 256` to
     compute the fingerprint and `wc -c` to compute the expected length of the downloaded file in
     bytes.
+
+    Failed downloads are retried with exponential backoff, and interrupted HTTP downloads are
+    resumed if the server supports range requests. If the file cannot be downloaded from `url`,
+    each of the `mirror_urls` is tried in order. The name of the file is taken from `url`.
+
+    If a remote store is configured, the file is fetched from it (by `expected_digest`) before
+    falling back to the URLs, and a downloaded file is uploaded to it when remote cache writes (or
+    remote execution) are enabled.
     """
 
     url: str
     expected_digest: FileDigest
+    mirror_urls: Tuple[str, ...] = ()
+
+
+class ArchiveFormat(Enum):
+    """The formats of archive which may be extracted by `ExtractArchive`, or created by
+    `ArchiveDigest`.
+
+    NB: this object is interpreted from within `ArchiveFormat::create()` in Rust -- that method will
+    need to be aware of any changes to this object's definition.
+    """
+
+    tar = "tar"
+    tar_gz = "tar.gz"
+    tar_zst = "tar.zst"
+    zip = "zip"
+
+
+@dataclass(frozen=True)
+class ExtractArchive:
+    """Extract the archive file with the given digest into a Digest.
+
+    Executable bits and symlinks are preserved. Extraction fails if any entry of the archive has an
+    absolute path or would be extracted outside of the root of the archive, or if any symlink
+    would resolve outside of the root of the archive.
+
+    To extract a downloaded archive, use the `file_digest` of the `FileEntry` in the `DigestEntries`
+    of the `DownloadFile`'s result.
+    """
+
+    file_digest: FileDigest
+    format: ArchiveFormat
+
+
+@dataclass(frozen=True)
+class ArchiveDigest:
+    """Create an archive of the given Digest, and return a Digest containing only the archive file
+    at `output_path`.
+
+    The archive is reproducible: entries are sorted, and every entry has the same `mtime`, `uid`
+    and `gid`, so the same Digest and options always produce a byte-identical archive.
+
+    `compression_level` defaults to the default level of the format, and is ignored for `tar`. For
+    `zip`, a level of 0 stores entries uncompressed, and any other explicit level is an error:
+    entries are otherwise compressed at the default level. `uid` and `gid` are ignored for `zip`,
+    which also may not contain symlinks.
+    """
+
+    digest: Digest
+    output_path: str
+    format: ArchiveFormat
+    compression_level: Optional[int] = None
+    mtime: int = 0
+    uid: int = 0
+    gid: int = 0
 
 
 @side_effecting
@@ -317,8 +514,13 @@ def rules():
         QueryRule(Digest, (AddPrefix,)),
         QueryRule(Digest, (RemovePrefix,)),
         QueryRule(Digest, (DownloadFile,)),
+        QueryRule(Digest, (ExtractArchive,)),
+        QueryRule(Digest, (ArchiveDigest,)),
         QueryRule(Digest, (MergeDigests,)),
         QueryRule(Digest, (DigestSubset,)),
+        QueryRule(Digest, (RenamePaths,)),
+        QueryRule(Digest, (SubstituteContents,)),
+        QueryRule(DigestDiff, (DiffDigests,)),
         QueryRule(DigestContents, (Digest,)),
         QueryRule(Snapshot, (Digest,)),
         QueryRule(Paths, (PathGlobs,)),
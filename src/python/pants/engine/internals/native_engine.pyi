@@ -99,12 +99,18 @@ def scheduler_create(
     local_execution_root_dir: str,
     named_caches_dir: str,
     ca_certs_path: str | None,
+    download_options: PyDownloadOptions,
     ignore_patterns: Sequence[str],
     use_gitignore: bool,
     watch_filesystem: bool,
+    watch_quiet_window_millis: int,
     remoting_options: PyRemotingOptions,
     local_store_options: PyLocalStoreOptions,
     exec_strategy_opts: PyExecutionStrategyOptions,
+    graph_persistence_path: str | None,
+    use_watchman: bool,
+    watchman_socket_path: str | None,
+    watchman_clock_path: str | None,
 ) -> PyScheduler: ...
 def scheduler_execute(
     scheduler: PyScheduler, session: PySession, execution_request: PyExecutionRequest
@@ -159,6 +165,15 @@ class PySnapshot:
     @property
     def files(self) -> tuple[str, ...]: ...
 
+class PyDownloadOptions:
+    def __init__(
+        self,
+        *,
+        headers_by_host: tuple[tuple[str, tuple[tuple[str, str], ...]], ...],
+        netrc_path: str | None,
+        url_rewrites: tuple[tuple[str, str], ...],
+    ) -> None: ...
+
 class PyExecutionRequest:
     def __init__(
         self, *, poll: bool, poll_delay_in_ms: int | None, timeout_in_ms: int | None
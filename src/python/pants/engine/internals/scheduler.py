@@ -17,13 +17,17 @@ from pants.engine.collection import Collection
 from pants.engine.engine_aware import EngineAwareParameter, EngineAwareReturnType
 from pants.engine.fs import (
     AddPrefix,
+    ArchiveDigest,
     CreateDigest,
+    DiffDigests,
     Digest,
     DigestContents,
+    DigestDiff,
     DigestEntries,
     DigestSubset,
     Directory,
     DownloadFile,
+    ExtractArchive,
     FileContent,
     FileDigest,
     FileEntry,
@@ -32,11 +36,15 @@ from pants.engine.fs import (
     PathGlobsAndRoot,
     Paths,
     RemovePrefix,
+    RenamePaths,
     Snapshot,
+    SubstituteContents,
+    SymlinkEntry,
 )
 from pants.engine.goal import Goal
 from pants.engine.internals import native_engine
 from pants.engine.internals.native_engine import (
+    PyDownloadOptions,
     PyExecutionRequest,
     PyExecutionStrategyOptions,
     PyExecutor,
@@ -62,6 +70,7 @@ from pants.engine.rules import Rule, RuleIndex, TaskRule
 from pants.engine.unions import UnionMembership, is_union
 from pants.option.global_options import (
     LOCAL_STORE_LEASE_TIME_SECS,
+    DownloadOptions,
     ExecutionOptions,
     LocalStoreOptions,
 )
@@ -116,10 +125,16 @@ class Scheduler:
         execution_options: ExecutionOptions,
         local_store_options: LocalStoreOptions,
         executor: PyExecutor,
+        download_options: DownloadOptions = DownloadOptions(),
         include_trace_on_error: bool = True,
         visualize_to_dir: Optional[str] = None,
         validate_reachability: bool = True,
         watch_filesystem: bool = True,
+        watch_quiet_window: float = 0.05,
+        graph_persistence_path: Optional[str] = None,
+        use_watchman: bool = False,
+        watchman_socket_path: Optional[str] = None,
+        watchman_clock_path: Optional[str] = None,
     ) -> None:
         """
         :param ignore_patterns: A list of gitignore-style file patterns for pants to ignore.
@@ -132,11 +147,21 @@ class Scheduler:
         :param union_membership: All the registered and normalized union rules.
         :param execution_options: Execution options for (remote) processes.
         :param local_store_options: Options for the engine's LMDB store(s).
+        :param download_options: Options for authenticating and rewriting the URLs of downloads.
         :param include_trace_on_error: Include the trace through the graph upon encountering errors.
         :param validate_reachability: True to assert that all rules in an otherwise successfully
           constructed rule graph are reachable: if a graph cannot be successfully constructed, it
           is always a fatal error.
         :param watch_filesystem: False if filesystem watching should be disabled.
+        :param watch_quiet_window: The number of seconds to wait for filesystem events to stop
+          arriving before invalidating changed files.
+        :param graph_persistence_path: If set, the path to persist filesystem nodes of the graph to
+          on shutdown, and to restore them from on startup.
+        :param use_watchman: True to watch the filesystem using Watchman rather than inotify/FSEvents.
+        :param watchman_socket_path: The socket of the Watchman daemon, if it should not be discovered
+          via `watchman get-sockname`.
+        :param watchman_clock_path: If set, a file to record the latest Watchman clock in, which is used
+          to compute changes since the previous run on startup.
         """
         self.include_trace_on_error = include_trace_on_error
         self._visualize_to_dir = visualize_to_dir
@@ -152,6 +177,7 @@ class Scheduler:
             paths=Paths,
             file_content=FileContent,
             file_entry=FileEntry,
+            symlink_entry=SymlinkEntry,
             directory=Directory,
             digest_contents=DigestContents,
             digest_entries=DigestEntries,
@@ -162,6 +188,12 @@ class Scheduler:
             create_digest=CreateDigest,
             digest_subset=DigestSubset,
             download_file=DownloadFile,
+            extract_archive=ExtractArchive,
+            archive_digest=ArchiveDigest,
+            diff_digests=DiffDigests,
+            digest_diff=DigestDiff,
+            rename_paths=RenamePaths,
+            substitute_contents=SubstituteContents,
             platform=Platform,
             multi_platform_process=MultiPlatformProcess,
             process_result=FallibleProcessResultWithPlatform,
@@ -186,13 +218,23 @@ class Scheduler:
             cache_warnings_behavior=execution_options.remote_cache_warnings.value,
             cache_eager_fetch=execution_options.remote_cache_eager_fetch,
             cache_rpc_concurrency=execution_options.remote_cache_rpc_concurrency,
+            cache_circuit_breaker_failures=execution_options.remote_cache_circuit_breaker_failures,
+            cache_circuit_breaker_window_secs=execution_options.remote_cache_circuit_breaker_window_secs,
+            cache_circuit_breaker_probe_secs=execution_options.remote_cache_circuit_breaker_probe_secs,
             execution_extra_platform_properties=tuple(
                 tuple(pair.split("=", 1))
                 for pair in execution_options.remote_execution_extra_platform_properties
             ),
+            execution_output_node_properties=tuple(
+                execution_options.remote_execution_output_node_properties
+            ),
+            execution_output_paths=execution_options.remote_execution_output_paths,
             execution_headers=tuple(execution_options.remote_execution_headers.items()),
             execution_overall_deadline_secs=execution_options.remote_execution_overall_deadline_secs,
             execution_rpc_concurrency=execution_options.remote_execution_rpc_concurrency,
+            execution_local_fallback=execution_options.remote_execution_local_fallback,
+            rpc_requests_per_second=execution_options.remote_rpc_requests_per_second,
+            rpc_bytes_per_second=execution_options.remote_rpc_bytes_per_second,
         )
         py_local_store_options = PyLocalStoreOptions(
             store_dir=local_store_options.store_dir,
@@ -201,6 +243,16 @@ class Scheduler:
             directories_max_size_bytes=local_store_options.directories_max_size_bytes,
             lease_time_millis=LOCAL_STORE_LEASE_TIME_SECS * 1000,
             shard_count=local_store_options.shard_count,
+            materialize_strategy=local_store_options.materialize_strategy.value,
+            capture_node_properties=list(local_store_options.capture_node_properties),
+        )
+        py_download_options = PyDownloadOptions(
+            headers_by_host=tuple(
+                (host, tuple(headers.items()))
+                for host, headers in download_options.auth_headers.items()
+            ),
+            netrc_path=download_options.netrc_path,
+            url_rewrites=tuple(download_options.url_rewrites.items()),
         )
         exec_stategy_opts = PyExecutionStrategyOptions(
             local_cache=execution_options.process_execution_local_cache,
@@ -210,6 +262,7 @@ class Scheduler:
             local_parallelism=execution_options.process_execution_local_parallelism,
             local_enable_nailgun=execution_options.process_execution_local_enable_nailgun,
             remote_parallelism=execution_options.process_execution_remote_parallelism,
+            cache_verification_sample_rate=execution_options.process_execution_cache_verification_rate,
         )
 
         self._py_scheduler = native_engine.scheduler_create(
@@ -220,12 +273,18 @@ class Scheduler:
             local_execution_root_dir,
             named_caches_dir,
             ca_certs_path,
+            py_download_options,
             ignore_patterns,
             use_gitignore,
             watch_filesystem,
+            int(watch_quiet_window * 1000),
             remoting_options,
             py_local_store_options,
             exec_stategy_opts,
+            graph_persistence_path,
+            use_watchman,
+            watchman_socket_path,
+            watchman_clock_path,
         )
 
         # If configured, visualize the rule graph before asserting that it is valid.
@@ -4,6 +4,7 @@
 from __future__ import annotations
 
 import logging
+import os
 from dataclasses import dataclass
 from pathlib import Path
 from typing import Any, ClassVar, Iterable, List, Optional, Tuple, Type, cast
@@ -31,6 +32,7 @@ from pants.engine.unions import UnionMembership
 from pants.init import specs_calculator
 from pants.option.global_options import (
     DEFAULT_EXECUTION_OPTIONS,
+    DownloadOptions,
     DynamicRemoteOptions,
     ExecutionOptions,
     GlobalOptions,
@@ -180,6 +182,7 @@ class EngineInitializer:
         executor = executor or GlobalOptions.create_py_executor(bootstrap_options)
         execution_options = ExecutionOptions.from_options(bootstrap_options, dynamic_remote_options)
         local_store_options = LocalStoreOptions.from_options(bootstrap_options)
+        download_options = DownloadOptions.from_options(bootstrap_options)
         return EngineInitializer.setup_graph_extended(
             build_configuration,
             execution_options,
@@ -190,10 +193,20 @@ class EngineInitializer:
             local_execution_root_dir=bootstrap_options.local_execution_root_dir,
             named_caches_dir=bootstrap_options.named_caches_dir,
             ca_certs_path=bootstrap_options.ca_certs_path,
+            download_options=download_options,
             build_root=build_root,
             include_trace_on_error=bootstrap_options.print_stacktrace,
             engine_visualize_to=bootstrap_options.engine_visualize_to,
             watch_filesystem=bootstrap_options.watch_filesystem,
+            watch_quiet_window=bootstrap_options.watch_filesystem_quiet_window,
+            graph_persistence_path=(
+                os.path.join(bootstrap_options.pants_workdir, "graph_persistence.json")
+                if bootstrap_options.persist_graph
+                else None
+            ),
+            use_watchman=bootstrap_options.watchman,
+            watchman_socket_path=bootstrap_options.watchman_socket_path,
+            watchman_clock_path=os.path.join(bootstrap_options.pants_workdir, "watchman.clock"),
         )
 
     @staticmethod
@@ -208,10 +221,16 @@ class EngineInitializer:
         local_execution_root_dir: str,
         named_caches_dir: str,
         ca_certs_path: Optional[str] = None,
+        download_options: DownloadOptions = DownloadOptions(),
         build_root: Optional[str] = None,
         include_trace_on_error: bool = True,
         engine_visualize_to: Optional[str] = None,
         watch_filesystem: bool = True,
+        watch_quiet_window: float = 0.05,
+        graph_persistence_path: Optional[str] = None,
+        use_watchman: bool = False,
+        watchman_socket_path: Optional[str] = None,
+        watchman_clock_path: Optional[str] = None,
     ) -> GraphScheduler:
         build_root_path = build_root or get_buildroot()
 
@@ -291,6 +310,7 @@ class EngineInitializer:
             local_execution_root_dir=ensure_absolute_path(local_execution_root_dir),
             named_caches_dir=ensure_absolute_path(named_caches_dir),
             ca_certs_path=ensure_optional_absolute_path(ca_certs_path),
+            download_options=download_options,
             rules=rules,
             union_membership=union_membership,
             executor=executor,
@@ -299,6 +319,11 @@ class EngineInitializer:
             include_trace_on_error=include_trace_on_error,
             visualize_to_dir=engine_visualize_to,
             watch_filesystem=watch_filesystem,
+            watch_quiet_window=watch_quiet_window,
+            graph_persistence_path=ensure_optional_absolute_path(graph_persistence_path),
+            use_watchman=use_watchman,
+            watchman_socket_path=ensure_optional_absolute_path(watchman_socket_path),
+            watchman_clock_path=ensure_optional_absolute_path(watchman_clock_path),
         )
 
         return GraphScheduler(scheduler, goal_map)
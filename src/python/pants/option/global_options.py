@@ -35,6 +35,7 @@ from pants.option.scope import GLOBAL_SCOPE
 from pants.option.subsystem import Subsystem
 from pants.util.dirutil import fast_relpath_optional
 from pants.util.docutil import doc_url
+from pants.util.frozendict import FrozenDict
 from pants.util.logging import LogLevel
 from pants.util.ordered_set import OrderedSet
 from pants.util.osutil import CPU_COUNT
@@ -80,6 +81,13 @@ class RemoteCacheWarningsBehavior(Enum):
     backoff = "backoff"
 
 
+@enum.unique
+class LocalStoreMaterializeStrategy(Enum):
+    copy = "copy"
+    hardlink = "hardlink"
+    reflink = "reflink"
+
+
 @enum.unique
 class AuthPluginState(Enum):
     OK = "ok"
@@ -320,6 +328,7 @@ class ExecutionOptions:
     process_execution_local_enable_nailgun: bool
     process_execution_remote_parallelism: int
     process_execution_cache_namespace: str | None
+    process_execution_cache_verification_rate: float
 
     remote_store_address: str | None
     remote_store_headers: dict[str, str]
@@ -332,12 +341,21 @@ class ExecutionOptions:
     remote_cache_eager_fetch: bool
     remote_cache_warnings: RemoteCacheWarningsBehavior
     remote_cache_rpc_concurrency: int
+    remote_cache_circuit_breaker_failures: int
+    remote_cache_circuit_breaker_window_secs: int
+    remote_cache_circuit_breaker_probe_secs: int
 
     remote_execution_address: str | None
     remote_execution_extra_platform_properties: List[str]
+    remote_execution_output_node_properties: List[str]
+    remote_execution_output_paths: bool
     remote_execution_headers: Dict[str, str]
     remote_execution_overall_deadline_secs: int
     remote_execution_rpc_concurrency: int
+    remote_execution_local_fallback: bool
+
+    remote_rpc_requests_per_second: int | None
+    remote_rpc_bytes_per_second: int | None
 
     @classmethod
     def from_options(
@@ -359,6 +377,7 @@ class ExecutionOptions:
             process_execution_remote_parallelism=dynamic_remote_options.parallelism,
             process_execution_local_cleanup=bootstrap_options.process_execution_local_cleanup,
             process_execution_cache_namespace=bootstrap_options.process_execution_cache_namespace,
+            process_execution_cache_verification_rate=bootstrap_options.process_execution_cache_verification_rate,
             process_execution_local_enable_nailgun=bootstrap_options.process_execution_local_enable_nailgun,
             # Remote store setup.
             remote_store_address=dynamic_remote_options.store_address,
@@ -372,12 +391,21 @@ class ExecutionOptions:
             remote_cache_eager_fetch=bootstrap_options.remote_cache_eager_fetch,
             remote_cache_warnings=bootstrap_options.remote_cache_warnings,
             remote_cache_rpc_concurrency=dynamic_remote_options.cache_rpc_concurrency,
+            remote_cache_circuit_breaker_failures=bootstrap_options.remote_cache_circuit_breaker_failures,
+            remote_cache_circuit_breaker_window_secs=bootstrap_options.remote_cache_circuit_breaker_window_secs,
+            remote_cache_circuit_breaker_probe_secs=bootstrap_options.remote_cache_circuit_breaker_probe_secs,
             # Remote execution setup.
             remote_execution_address=dynamic_remote_options.execution_address,
             remote_execution_extra_platform_properties=bootstrap_options.remote_execution_extra_platform_properties,
+            remote_execution_output_node_properties=bootstrap_options.remote_execution_output_node_properties,
+            remote_execution_output_paths=bootstrap_options.remote_execution_output_paths,
             remote_execution_headers=dynamic_remote_options.execution_headers,
             remote_execution_overall_deadline_secs=bootstrap_options.remote_execution_overall_deadline_secs,
             remote_execution_rpc_concurrency=dynamic_remote_options.execution_rpc_concurrency,
+            remote_execution_local_fallback=bootstrap_options.remote_execution_local_fallback,
+            # Remote RPC rate limiting.
+            remote_rpc_requests_per_second=bootstrap_options.remote_rpc_requests_per_second,
+            remote_rpc_bytes_per_second=bootstrap_options.remote_rpc_bytes_per_second,
         )
 
 
@@ -394,6 +422,8 @@ class LocalStoreOptions:
     files_max_size_bytes: int = 256 * GIGABYTES
     directories_max_size_bytes: int = 16 * GIGABYTES
     shard_count: int = 16
+    materialize_strategy: LocalStoreMaterializeStrategy = LocalStoreMaterializeStrategy.copy
+    capture_node_properties: Tuple[str, ...] = ()
 
     def target_total_size_bytes(self) -> int:
         """Returns the target total size of all of the stores.
@@ -419,6 +449,37 @@ class LocalStoreOptions:
             files_max_size_bytes=options.local_store_files_max_size_bytes,
             directories_max_size_bytes=options.local_store_directories_max_size_bytes,
             shard_count=options.local_store_shard_count,
+            materialize_strategy=options.local_store_materialize_strategy,
+            capture_node_properties=tuple(options.local_store_capture_node_properties),
+        )
+
+
+@dataclass(frozen=True)
+class DownloadOptions:
+    """A collection of all options related to downloading files.
+
+    TODO: These options should move to a Subsystem once we add support for "bootstrap" Subsystems (ie,
+    allowing Subsystems to be consumed before the Scheduler has been created).
+    """
+
+    auth_headers: FrozenDict[str, FrozenDict[str, str]] = FrozenDict()
+    netrc_path: str | None = None
+    url_rewrites: FrozenDict[str, str] = FrozenDict()
+
+    @classmethod
+    def from_options(cls, options: OptionValueContainer) -> DownloadOptions:
+        netrc_path = None
+        if options.download_netrc:
+            netrc_path = os.environ.get("NETRC") or os.path.expanduser("~/.netrc")
+            if not os.path.isfile(netrc_path):
+                netrc_path = None
+        return cls(
+            auth_headers=FrozenDict(
+                (host, FrozenDict(headers))
+                for host, headers in options.download_auth_headers.items()
+            ),
+            netrc_path=netrc_path,
+            url_rewrites=FrozenDict(options.download_url_rewrites),
         )
 
 
@@ -434,6 +495,7 @@ DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     process_execution_local_parallelism=CPU_COUNT,
     process_execution_remote_parallelism=128,
     process_execution_cache_namespace=None,
+    process_execution_cache_verification_rate=0.0,
     process_execution_local_cleanup=True,
     process_execution_local_cache=True,
     process_execution_local_enable_nailgun=False,
@@ -451,14 +513,23 @@ DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     remote_cache_eager_fetch=True,
     remote_cache_warnings=RemoteCacheWarningsBehavior.first_only,
     remote_cache_rpc_concurrency=128,
+    remote_cache_circuit_breaker_failures=10,
+    remote_cache_circuit_breaker_window_secs=60,
+    remote_cache_circuit_breaker_probe_secs=5 * 60,
     # Remote execution setup.
     remote_execution_address=None,
     remote_execution_extra_platform_properties=[],
+    remote_execution_output_node_properties=[],
+    remote_execution_output_paths=False,
     remote_execution_headers={
         "user-agent": f"pants/{VERSION}",
     },
     remote_execution_overall_deadline_secs=60 * 60,  # one hour
     remote_execution_rpc_concurrency=128,
+    remote_execution_local_fallback=False,
+    # Remote RPC rate limiting.
+    remote_rpc_requests_per_second=None,
+    remote_rpc_bytes_per_second=None,
 )
 
 DEFAULT_LOCAL_STORE_OPTIONS = LocalStoreOptions()
@@ -928,6 +999,38 @@ class GlobalOptions(Subsystem):
             ),
             default=DEFAULT_LOCAL_STORE_OPTIONS.shard_count,
         )
+        register(
+            "--local-store-materialize-strategy",
+            type=LocalStoreMaterializeStrategy,
+            advanced=True,
+            help=(
+                "How the input files of local processes are materialized into their sandboxes."
+                "\n\n"
+                "With `copy`, the content of each file is copied out of the local store. With "
+                "`hardlink` or `reflink`, files are instead hardlinked or cloned (on filesystems "
+                "which support it, such as btrfs or XFS) from a cache of immutable files stored "
+                f"below `{local_store_dir_flag}`, which is much faster for large inputs. Files are "
+                "copied if they cannot be linked, e.g. because the sandbox is on a different "
+                "filesystem."
+                "\n\n"
+                "NB: Hardlinked input files are read-only, and so processes which modify their "
+                "inputs in place will fail."
+            ),
+            default=DEFAULT_LOCAL_STORE_OPTIONS.materialize_strategy,
+        )
+        register(
+            "--local-store-capture-node-properties",
+            type=list,
+            advanced=True,
+            help=(
+                "Node properties of workspace files to record in the digests of captured "
+                "snapshots, such as `unix_mode` or `mtime`."
+                "\n\n"
+                "NB: Recording `mtime` means that a digest changes whenever a file is touched, "
+                "which defeats caching of any process which consumes that file."
+            ),
+            default=list(DEFAULT_LOCAL_STORE_OPTIONS.capture_node_properties),
+        )
         register(
             "--local-store-processes-max-size-bytes",
             type=int,
@@ -1017,6 +1120,43 @@ class GlobalOptions(Subsystem):
             help="Path to a file containing PEM-format CA certificates used for verifying secure "
             "connections when downloading files required by a build.",
         )
+        register(
+            "--download-auth-headers",
+            advanced=True,
+            type=dict,
+            default={},
+            help=(
+                "Headers to set on requests when downloading files from each host, which are "
+                "usually used for authentication.\n\nFormat: a dict of host (or `host:port`) to a "
+                'dict of header=value, e.g. `{"artifacts.example.com": {"Authorization": '
+                '"Bearer <token>"}}`.\n\nHosts which have headers set here do not use credentials '
+                "from `--download-netrc`."
+            ),
+        )
+        register(
+            "--download-netrc",
+            advanced=True,
+            type=bool,
+            default=False,
+            help=(
+                "If true, use basic authentication with the credentials in the netrc file at "
+                "`$NETRC` (or `~/.netrc`) when downloading files from hosts which it lists."
+            ),
+        )
+        register(
+            "--download-url-rewrites",
+            advanced=True,
+            type=dict,
+            default={},
+            help=(
+                "Rules to rewrite the URLs of files before they are downloaded, e.g. to download "
+                "from an internal mirror.\n\nFormat: a dict of regex to replacement, e.g. "
+                '`{"^https://github.com/(.*)$": "https://mirror.example.com/github/$1"}`. The '
+                "replacement may refer to capture groups as `$1` or `${name}`. A URL is rewritten "
+                "by the first regex which matches it.\n\nThe name of a downloaded file is always "
+                "taken from its original URL."
+            ),
+        )
 
         register(
             process_execution_local_parallelism,
@@ -1048,6 +1188,20 @@ class GlobalOptions(Subsystem):
                 "process cache entries from being (re)used for different usecases or users."
             ),
         )
+        register(
+            "--process-execution-cache-verification-rate",
+            advanced=True,
+            type=float,
+            default=DEFAULT_EXECUTION_OPTIONS.process_execution_cache_verification_rate,
+            help=(
+                "The fraction of process cache hits (between 0.0 and 1.0) which should be "
+                "re-executed to verify that the process is deterministic.\n\n"
+                "The cached result is always used, but if re-executing the process produces a "
+                "different result a warning is logged describing the differing outputs. This is "
+                "useful to find processes which poison a shared cache, but is expensive: it "
+                "should generally only be enabled temporarily."
+            ),
+        )
         register(
             "--process-execution-local-enable-nailgun",
             type=bool,
@@ -1236,6 +1390,40 @@ class GlobalOptions(Subsystem):
             default=DEFAULT_EXECUTION_OPTIONS.remote_cache_rpc_concurrency,
             help="The number of concurrent requests allowed to the remote cache service.",
         )
+        register(
+            "--remote-cache-circuit-breaker-failures",
+            type=int,
+            advanced=True,
+            default=DEFAULT_EXECUTION_OPTIONS.remote_cache_circuit_breaker_failures,
+            help=(
+                "The number of consecutive remote cache failures after which Pants will stop "
+                "using the remote cache, rather than continuing to try it for every process.\n\n"
+                "Failures only count if they occur within "
+                "`--remote-cache-circuit-breaker-window-secs` of the first failure. Set to 0 to "
+                "always use the remote cache, regardless of failures."
+            ),
+        )
+        register(
+            "--remote-cache-circuit-breaker-window-secs",
+            type=int,
+            advanced=True,
+            default=DEFAULT_EXECUTION_OPTIONS.remote_cache_circuit_breaker_window_secs,
+            help=(
+                "The window in seconds within which consecutive remote cache failures count "
+                "towards `--remote-cache-circuit-breaker-failures`."
+            ),
+        )
+        register(
+            "--remote-cache-circuit-breaker-probe-secs",
+            type=int,
+            advanced=True,
+            default=DEFAULT_EXECUTION_OPTIONS.remote_cache_circuit_breaker_probe_secs,
+            help=(
+                "After the remote cache has been disabled due to failures, how often in seconds "
+                "Pants should try a single request to check whether it has recovered.\n\n"
+                "Set to 0 to disable the remote cache for the rest of the run instead."
+            ),
+        )
 
         register(
             "--remote-execution-address",
@@ -1258,6 +1446,30 @@ class GlobalOptions(Subsystem):
             type=list,
             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_extra_platform_properties,
         )
+        register(
+            "--remote-execution-output-node-properties",
+            advanced=True,
+            type=list,
+            default=DEFAULT_EXECUTION_OPTIONS.remote_execution_output_node_properties,
+            help=(
+                "Node properties of output files to request from the remote execution server, "
+                "such as `unix_mode` or `mtime`. These are preserved in the resulting digests "
+                "and applied when the outputs are materialized.\n\nProperties which the server "
+                "does not report as supported are not requested."
+            ),
+        )
+        register(
+            "--remote-execution-output-paths",
+            advanced=True,
+            type=bool,
+            default=DEFAULT_EXECUTION_OPTIONS.remote_execution_output_paths,
+            help=(
+                "Request the outputs of remotely executed processes using the `output_paths` "
+                "field of REAPI v2.1, which allows the server to report output symlinks.\n\n"
+                "Only enable this if your remote execution server supports REAPI v2.1: older "
+                "servers may reject or ignore the field."
+            ),
+        )
         register(
             "--remote-execution-headers",
             advanced=True,
@@ -1282,6 +1494,41 @@ class GlobalOptions(Subsystem):
             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_rpc_concurrency,
             help="The number of concurrent requests allowed to the remote execution service.",
         )
+        register(
+            "--remote-execution-local-fallback",
+            type=bool,
+            advanced=True,
+            default=DEFAULT_EXECUTION_OPTIONS.remote_execution_local_fallback,
+            help=(
+                "If remote execution of a process fails for reasons unrelated to the process "
+                "itself (such as RPC failures, or the overall deadline being exceeded), run the "
+                "process locally instead of failing.\n\nProcesses which run remotely and exit "
+                "with a non-zero exit code are not rerun locally."
+            ),
+        )
+        register(
+            "--remote-rpc-requests-per-second",
+            type=int,
+            advanced=True,
+            default=DEFAULT_EXECUTION_OPTIONS.remote_rpc_requests_per_second,
+            help=(
+                "The maximum number of requests per second sent to each remote service (the "
+                "store, cache and execution services are limited independently).\n\nIf unset, "
+                "the request rate is not limited. Regardless of this option, Pants will back off "
+                "when a server responds that it is overloaded (`RESOURCE_EXHAUSTED`)."
+            ),
+        )
+        register(
+            "--remote-rpc-bytes-per-second",
+            type=int,
+            advanced=True,
+            default=DEFAULT_EXECUTION_OPTIONS.remote_rpc_bytes_per_second,
+            help=(
+                "The maximum number of bytes per second transferred to and from each remote "
+                "service (the store, cache and execution services are limited independently)."
+                "\n\nIf unset, the transfer rate is not limited."
+            ),
+        )
 
         register(
             "--watch-filesystem",
@@ -1291,6 +1538,54 @@ class GlobalOptions(Subsystem):
             help="Set to False if Pants should not watch the filesystem for changes. `pantsd` or `loop` "
             "may not be enabled.",
         )
+        register(
+            "--watch-filesystem-quiet-window",
+            type=float,
+            default=0.05,
+            advanced=True,
+            help=(
+                "The number of seconds to wait for filesystem events to stop arriving before "
+                "invalidating the changed files. Events which arrive within this window (such as "
+                "while an IDE saves many files or code is being generated) are coalesced into a "
+                "single invalidation."
+            ),
+        )
+        register(
+            "--persist-graph",
+            type=bool,
+            default=False,
+            advanced=True,
+            help=(
+                "If True, memoized filesystem nodes (file digests, directory listings, and symlink "
+                "destinations) are persisted to the `pants_workdir` when `pantsd` shuts down, and "
+                "restored when it next starts. Restored nodes are validated against the filesystem, "
+                "so only unchanged files will avoid being re-read. Requires `--watch-filesystem`.\n\n"
+                "Process results are not persisted by this option, since they are already cached "
+                "across restarts by `--process-execution-local-cache`."
+            ),
+        )
+        register(
+            "--watchman",
+            type=bool,
+            default=False,
+            advanced=True,
+            help=(
+                "If True, watch the filesystem using a Watchman daemon (which must be installed) "
+                "rather than inotify (on Linux) or FSEvents (on macOS). Watchman is recommended for "
+                "very large repositories, which may exceed inotify's limits. Changes which occur "
+                "while `pantsd` is not running are detected when it next starts."
+            ),
+        )
+        register(
+            "--watchman-socket-path",
+            type=str,
+            default=None,
+            advanced=True,
+            help=(
+                "The path of the socket of the Watchman daemon to use with `--watchman`. If unset, "
+                "it is discovered using `watchman get-sockname`."
+            ),
+        )
 
     @classmethod
     def register_options(cls, register):
@@ -1469,6 +1764,14 @@ class GlobalOptions(Subsystem):
                 "`--remote-store-address` or to work properly."
             )
 
+        for opt_name in ("remote_rpc_requests_per_second", "remote_rpc_bytes_per_second"):
+            value = getattr(opts, opt_name)
+            if value is not None and value <= 0:
+                raise OptionsError(
+                    f"The `--{opt_name.replace('_', '-')}` option must be positive if set, but "
+                    f"was {value}. Leave it unset to not limit the rate."
+                )
+
         if not opts.watch_filesystem and (opts.pantsd or opts.loop):
             raise OptionsError(
                 "The `--no-watch-filesystem` option may not be set if "
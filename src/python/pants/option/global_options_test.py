@@ -13,6 +13,7 @@ from pants.base.build_environment import get_buildroot
 from pants.engine.environment import CompleteEnvironment
 from pants.engine.internals.scheduler import ExecutionError
 from pants.init.options_initializer import OptionsInitializer
+from pants.option.errors import OptionsError
 from pants.option.global_options import DynamicRemoteOptions, GlobalOptions
 from pants.option.options_bootstrapper import OptionsBootstrapper
 from pants.testutil.option_util import create_options_bootstrapper
@@ -134,6 +135,18 @@ def test_execution_options_remote_addresses() -> None:
         create_dynamic_remote_options(address=f"https:://{host}")
 
 
+def test_remote_rpc_rate_limits_must_be_positive() -> None:
+    def validate(*args: str) -> None:
+        ob = OptionsBootstrapper.create(env={}, args=list(args), allow_pantsrc=False)
+        GlobalOptions.validate_instance(ob.bootstrap_options.for_global_scope())
+
+    validate("--remote-rpc-requests-per-second=10", "--remote-rpc-bytes-per-second=1024")
+    with pytest.raises(OptionsError):
+        validate("--remote-rpc-requests-per-second=0")
+    with pytest.raises(OptionsError):
+        validate("--remote-rpc-bytes-per-second=-1")
+
+
 def test_invalidation_globs() -> None:
     # Confirm that an un-normalized relative path in the pythonpath is filtered out.
     suffix = "something-ridiculous"
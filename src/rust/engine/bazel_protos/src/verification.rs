@@ -12,6 +12,8 @@ pub fn verify_directory_canonical(
     .map_err(|e| format!("Invalid file in {:?}: {}", digest, e))?;
   verify_nodes(&directory.directories, |n| &n.name, |n| n.digest.as_ref())
     .map_err(|e| format!("Invalid directory in {:?}: {}", digest, e))?;
+  verify_nodes(&directory.symlinks, |n| &n.name, |_| None)
+    .map_err(|e| format!("Invalid symlink in {:?}: {}", digest, e))?;
   let child_names: HashSet<&str> = directory
     .files
     .iter()
@@ -22,10 +24,18 @@ pub fn verify_directory_canonical(
         .iter()
         .map(|dir_node| dir_node.name.as_str()),
     )
+    .chain(
+      directory
+        .symlinks
+        .iter()
+        .map(|symlink_node| symlink_node.name.as_str()),
+    )
     .collect();
-  if child_names.len() != directory.files.len() + directory.directories.len() {
+  if child_names.len()
+    != directory.files.len() + directory.directories.len() + directory.symlinks.len()
+  {
     return Err(format!(
-      "Child paths must be unique, but a child path of {:?} was both a file and a directory: {:?}",
+      "Child paths must be unique, but a child path of {:?} was more than one of a file, directory or symlink: {:?}",
       digest, directory
     ));
   }
@@ -1,6 +1,8 @@
 use hashing::EMPTY_DIGEST;
 
-use crate::gen::build::bazel::remote::execution::v2::{Digest, Directory, DirectoryNode, FileNode};
+use crate::gen::build::bazel::remote::execution::v2::{
+  Digest, Directory, DirectoryNode, FileNode, SymlinkNode,
+};
 use crate::verify_directory_canonical;
 
 const HASH: &str = "693d8db7b05e99c6b7a7c0616456039d89c555029026936248085193559a0b5d";
@@ -199,3 +201,51 @@ fn duplicate_path_in_file_and_directory() {
 
   verify_directory_canonical(EMPTY_DIGEST, &directory).expect_err("Want error");
 }
+
+#[test]
+fn unsorted_symlinks() {
+  let directory = Directory {
+    symlinks: vec![
+      SymlinkNode {
+        name: "roland".to_owned(),
+        target: "cats/roland".to_owned(),
+        ..SymlinkNode::default()
+      },
+      SymlinkNode {
+        name: "robin".to_owned(),
+        target: "birds/robin".to_owned(),
+        ..SymlinkNode::default()
+      },
+    ],
+    ..Directory::default()
+  };
+
+  let error = verify_directory_canonical(EMPTY_DIGEST, &directory).expect_err("Want error");
+  assert!(
+    error.contains("Invalid symlink"),
+    "Bad error message: {}",
+    error
+  );
+}
+
+#[test]
+fn duplicate_path_in_file_and_symlink() {
+  let directory = Directory {
+    files: vec![FileNode {
+      name: "roland".to_owned(),
+      digest: Some(Digest {
+        hash: HASH.to_owned(),
+        size_bytes: FILE_SIZE,
+      }),
+      ..FileNode::default()
+    }],
+    symlinks: vec![SymlinkNode {
+      name: "roland".to_owned(),
+      target: "cats/roland".to_owned(),
+      ..SymlinkNode::default()
+    }],
+    ..Directory::default()
+  };
+
+  verify_directory_canonical(EMPTY_DIGEST, &directory).expect_err("Want error");
+}
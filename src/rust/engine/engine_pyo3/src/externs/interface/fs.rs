@@ -11,7 +11,9 @@ use pyo3::types::{PyString, PyTuple, PyType};
 
 use either::Either;
 use fs::PathStat;
-use fs::{GlobExpansionConjunction, PathGlobs, PreparedPathGlobs, StrictGlobMatching};
+use fs::{
+  GlobExpansionConjunction, PathGlobs, PathMatchOptions, PreparedPathGlobs, StrictGlobMatching,
+};
 use hashing::{Digest, Fingerprint};
 use itertools::Itertools;
 use store::Snapshot;
@@ -63,11 +65,14 @@ impl<'source> FromPyObject<'source> for PyPathGlobs {
     let conjunction =
       GlobExpansionConjunction::create(conjunction_str).map_err(PyValueError::new_err)?;
 
-    Ok(PyPathGlobs(PathGlobs::new(
-      globs,
-      match_behavior,
-      conjunction,
-    )))
+    let match_options = PathMatchOptions::new(
+      obj.getattr("case_insensitive")?.extract()?,
+      obj.getattr("unicode_normalization")?.extract()?,
+    );
+
+    Ok(PyPathGlobs(
+      PathGlobs::new(globs, match_behavior, conjunction).with_match_options(match_options),
+    ))
   }
 }
 
@@ -168,12 +173,13 @@ impl PySnapshot {
 
   #[getter]
   fn files(&self, py: Python) -> Py<PyTuple> {
+    // NB: Symlinks are reported as files, since (like files) they are leaves of the tree.
     let files = self
       .0
       .path_stats
       .iter()
       .filter_map(|ps| match ps {
-        PathStat::File { path, .. } => path.to_str(),
+        PathStat::File { path, .. } | PathStat::Link { path, .. } => path.to_str(),
         _ => None,
       })
       .map(|ps| PyString::new(py, ps))
@@ -215,7 +221,9 @@ impl PyObjectProtocol for PySnapshot {
   fn __repr__(&self) -> PyResult<String> {
     let (dirs, files): (Vec<_>, Vec<_>) = self.0.path_stats.iter().partition_map(|ps| match ps {
       PathStat::Dir { path, .. } => Either::Left(path.to_string_lossy()),
-      PathStat::File { path, .. } => Either::Right(path.to_string_lossy()),
+      PathStat::File { path, .. } | PathStat::Link { path, .. } => {
+        Either::Right(path.to_string_lossy())
+      }
     });
 
     Ok(format!(
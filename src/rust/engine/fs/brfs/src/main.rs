@@ -39,6 +39,7 @@ use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
 use bazel_protos::require_digest;
 use clap::{value_t, App, Arg};
 use futures::future::FutureExt;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::tls;
 use hashing::{Digest, Fingerprint};
 use log::{debug, error, warn};
@@ -764,6 +765,7 @@ async fn main() {
         1,
         value_t!(args.value_of("rpc-concurrency-limit"), usize)
           .expect("Bad rpc-concurrency-limit flag"),
+        RateLimitConfig::default(),
         None,
         value_t!(args.value_of("batch-api-size-limit"), usize)
           .expect("Bad batch-api-size-limit flag"),
@@ -29,6 +29,7 @@
 #![type_length_limit = "1881109"]
 
 use std::io::{self, Write};
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::Arc;
@@ -43,6 +44,7 @@ use fs::{
 use futures::future::{self, BoxFuture};
 use futures::FutureExt;
 use grpc_util::prost::MessageExt;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::tls::{CertificateCheck, MtlsConfig};
 use hashing::{Digest, Fingerprint};
 use parking_lot::Mutex;
@@ -146,6 +148,26 @@ to this directory.",
                   "Set to manipulate the way a report is displayed."
                 )),
           )
+          .subcommand(
+            SubCommand::with_name("diff")
+              .about(
+                "Output the paths which differ between two directories addressed by fingerprint, \
+one per line, sorted by path. Each path is prefixed with A if it was added, D if it was deleted, or \
+M if it was modified in the second directory.",
+              )
+              .arg(Arg::with_name("before_fingerprint").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("before_size_bytes").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("after_fingerprint").required(true).takes_value(
+                true,
+              ))
+              .arg(Arg::with_name("after_size_bytes").required(true).takes_value(
+                true,
+              )),
+          )
           .subcommand(
             SubCommand::with_name("cat-proto")
               .about(
@@ -273,6 +295,20 @@ to this directory.",
               .required(false)
               .default_value("128")
         )
+        .arg(
+          Arg::with_name("rpc-requests-per-second")
+              .help("Maximum RPCs per second to the service (which must be positive). Unlimited if not set.")
+              .takes_value(true)
+              .long("rpc-requests-per-second")
+              .required(false)
+        )
+        .arg(
+          Arg::with_name("rpc-bytes-per-second")
+              .help("Maximum bytes per second transferred to/from the service (which must be positive). Unlimited if not set.")
+              .takes_value(true)
+              .long("rpc-bytes-per-second")
+              .required(false)
+        )
         .arg(
           Arg::with_name("batch-api-size-limit")
                .help("Maximum total size of blobs allowed to be sent in a single batch API call to the remote store.")
@@ -391,6 +427,18 @@ async fn execute(top_match: &clap::ArgMatches<'_>) -> Result<(), ExitError> {
             value_t!(top_match.value_of("rpc-attempts"), usize).expect("Bad rpc-attempts flag"),
             value_t!(top_match.value_of("rpc-concurrency-limit"), usize)
               .expect("Bad rpc-concurrency-limit flag"),
+            RateLimitConfig {
+              requests_per_second: top_match.value_of("rpc-requests-per-second").map(|_| {
+                value_t!(top_match.value_of("rpc-requests-per-second"), NonZeroUsize)
+                  .expect("Bad rpc-requests-per-second flag")
+                  .get()
+              }),
+              bytes_per_second: top_match.value_of("rpc-bytes-per-second").map(|_| {
+                value_t!(top_match.value_of("rpc-bytes-per-second"), NonZeroUsize)
+                  .expect("Bad rpc-bytes-per-second flag")
+                  .get()
+              }),
+            },
             None,
             value_t!(top_match.value_of("batch-api-size-limit"), usize)
               .expect("Bad batch-api-size-limit flag"),
@@ -525,6 +573,45 @@ async fn execute(top_match: &clap::ArgMatches<'_>) -> Result<(), ExitError> {
 
         Ok(())
       }
+      ("diff", Some(args)) => {
+        let digest_arg = |prefix: &str| -> Result<Digest, String> {
+          let fingerprint = Fingerprint::from_hex_string(
+            args.value_of(format!("{}_fingerprint", prefix)).unwrap(),
+          )?;
+          let size_bytes = args
+            .value_of(format!("{}_size_bytes", prefix))
+            .unwrap()
+            .parse::<usize>()
+            .expect("size_bytes must be a non-negative number");
+          Ok(Digest::new(fingerprint, size_bytes))
+        };
+        let diff = store
+          .diff(digest_arg("before")?, digest_arg("after")?)
+          .await
+          .map_err(|err| match err {
+            SnapshotOpsError::String(string)
+            | SnapshotOpsError::DigestMergeFailure(string)
+            | SnapshotOpsError::GlobMatchError(string) => {
+              if string.contains("was not known") {
+                ExitError(string, ExitCode::NotFound)
+              } else {
+                string.into()
+              }
+            }
+          })?;
+        let mut lines = diff
+          .added
+          .iter()
+          .map(|path| (path, "A"))
+          .chain(diff.removed.iter().map(|path| (path, "D")))
+          .chain(diff.modified.iter().map(|path| (path, "M")))
+          .collect::<Vec<_>>();
+        lines.sort();
+        for (path, status) in lines {
+          println!("{} {}", status, path.display());
+        }
+        Ok(())
+      }
       ("cat-proto", Some(args)) => {
         let fingerprint = Fingerprint::from_hex_string(args.value_of("fingerprint").unwrap())?;
         let size_bytes = args
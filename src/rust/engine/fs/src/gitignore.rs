@@ -0,0 +1,124 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ::ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ::ignore::Match;
+use lazy_static::lazy_static;
+use log::warn;
+use parking_lot::Mutex;
+
+const GITIGNORE: &str = ".gitignore";
+
+lazy_static! {
+  static ref GIT_INFO_EXCLUDE: PathBuf = PathBuf::from(".git/info/exclude");
+}
+
+///
+/// The `.gitignore` files of every directory under a root, along with its `.git/info/exclude`
+/// file, which are loaded lazily as directories are matched against, and cached per directory.
+///
+/// As in git, the patterns of a `.gitignore` file apply to paths beneath the directory containing
+/// it, and take precedence over those of `.gitignore` files in parent directories, which in turn
+/// take precedence over `.git/info/exclude`.
+///
+#[derive(Debug)]
+pub(crate) struct NestedGitignores {
+  root: PathBuf,
+  // The Gitignore for each directory (relative to the root) which has been matched against, or
+  // None if the directory does not contain a `.gitignore` file.
+  directories: Mutex<HashMap<PathBuf, Option<Arc<Gitignore>>>>,
+  info_exclude: Mutex<Option<Option<Arc<Gitignore>>>>,
+}
+
+impl NestedGitignores {
+  pub(crate) fn new(root: PathBuf) -> NestedGitignores {
+    NestedGitignores {
+      root,
+      directories: Mutex::default(),
+      info_exclude: Mutex::default(),
+    }
+  }
+
+  ///
+  /// Matches the given path (relative to the root) against the ignore files of its parent
+  /// directories.
+  ///
+  pub(crate) fn matched(&self, path: &Path, is_dir: bool) -> Match<()> {
+    for directory in path.ancestors().skip(1) {
+      if let Some(gitignore) = self.gitignore_for(directory) {
+        match gitignore.matched(path, is_dir) {
+          Match::None => (),
+          m => return m.map(|_| ()),
+        }
+      }
+    }
+    match self.info_exclude() {
+      Some(gitignore) => gitignore.matched(path, is_dir).map(|_| ()),
+      None => Match::None,
+    }
+  }
+
+  ///
+  /// If the given path (relative to the root) is an ignore file, drops any cached copy of it, and
+  /// returns the directory beneath which the set of ignored paths may have changed.
+  ///
+  pub(crate) fn invalidate_ignore_file(&self, path: &Path) -> Option<PathBuf> {
+    if path == GIT_INFO_EXCLUDE.as_path() {
+      *self.info_exclude.lock() = None;
+      return Some(PathBuf::new());
+    }
+    if path.file_name()? != GITIGNORE {
+      return None;
+    }
+    let directory = path.parent()?;
+    self.directories.lock().remove(directory);
+    Some(directory.to_owned())
+  }
+
+  fn gitignore_for(&self, directory: &Path) -> Option<Arc<Gitignore>> {
+    if let Some(gitignore) = self.directories.lock().get(directory) {
+      return gitignore.clone();
+    }
+    let gitignore = self.load(directory, &self.root.join(directory).join(GITIGNORE));
+    self
+      .directories
+      .lock()
+      .insert(directory.to_owned(), gitignore.clone());
+    gitignore
+  }
+
+  fn info_exclude(&self) -> Option<Arc<Gitignore>> {
+    let mut info_exclude = self.info_exclude.lock();
+    if let Some(gitignore) = &*info_exclude {
+      return gitignore.clone();
+    }
+    let gitignore = self.load(Path::new(""), &self.root.join(&*GIT_INFO_EXCLUDE));
+    *info_exclude = Some(gitignore.clone());
+    gitignore
+  }
+
+  ///
+  /// Loads the given ignore file, whose patterns are relative to the given directory. Returns None
+  /// if the file does not exist.
+  ///
+  fn load(&self, directory: &Path, ignore_file: &Path) -> Option<Arc<Gitignore>> {
+    if !ignore_file.is_file() {
+      return None;
+    }
+    let mut builder = GitignoreBuilder::new(directory);
+    if let Some(e) = builder.add(ignore_file) {
+      warn!("Failed to parse {}: {}", ignore_file.display(), e);
+    }
+    match builder.build() {
+      Ok(gitignore) => Some(Arc::new(gitignore)),
+      Err(e) => {
+        warn!("Failed to parse {}: {}", ignore_file.display(), e);
+        None
+      }
+    }
+  }
+}
@@ -0,0 +1,147 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::{Dir, DirectoryListing, PathGlob, PathMatchOptions, PathStat};
+
+///
+/// The default maximum number of entries in a GlobMatchCache. A cache which grows beyond this size
+/// is cleared.
+///
+const DEFAULT_CAPACITY: usize = 100_000;
+
+#[derive(Eq, Hash, PartialEq)]
+struct GlobMatchKey {
+  path_glob: PathGlob,
+  exclude: Vec<String>,
+  match_options: PathMatchOptions,
+}
+
+///
+/// The PathStats matched by a PathGlob (i.e., by a pattern suffix below one directory), along with
+/// the listings of the directories that they were matched against.
+///
+pub(crate) struct SubtreeMatches {
+  pub(crate) canonical_dir: Dir,
+  pub(crate) listing: Arc<DirectoryListing>,
+  pub(crate) path_stats: Vec<PathStat>,
+  pub(crate) children: Vec<Arc<SubtreeMatches>>,
+  pub(crate) matched: bool,
+  ///
+  /// False if these matches depend on more than the directory listings: for example, because they
+  /// include the destinations of Links.
+  ///
+  pub(crate) cacheable: bool,
+}
+
+impl SubtreeMatches {
+  ///
+  /// The directories and listings that these matches were computed from.
+  ///
+  pub(crate) fn listings(&self) -> Vec<(Dir, Arc<DirectoryListing>)> {
+    let mut listings = Vec::new();
+    let mut stack = vec![self];
+    while let Some(subtree) = stack.pop() {
+      listings.push((subtree.canonical_dir.clone(), subtree.listing.clone()));
+      stack.extend(subtree.children.iter().map(|child| child.as_ref()));
+    }
+    listings
+  }
+
+  pub(crate) fn extend_into(&self, result: &mut Vec<PathStat>) {
+    let mut stack = vec![self];
+    while let Some(subtree) = stack.pop() {
+      result.extend(subtree.path_stats.iter().cloned());
+      stack.extend(subtree.children.iter().map(|child| child.as_ref()));
+    }
+  }
+}
+
+///
+/// A cache of the PathStats matched by each PathGlob during glob expansion, which is shared between
+/// expansions of overlapping PathGlobs, such that many overlapping recursive globs cost roughly one
+/// traversal.
+///
+/// An entry covers the whole subtree below the directory of its PathGlob, and records the listings
+/// of all of the directories in that subtree. It is only used while each of those directories still
+/// lists identically: since they are listed via `Vfs::scandir` to check that, callers which track
+/// their dependencies on directory listings (such as the engine's `Scandir` nodes) continue to
+/// observe them, and an entry is invalidated along with any of them.
+///
+#[derive(Clone)]
+pub struct GlobMatchCache {
+  entries: Arc<Mutex<HashMap<GlobMatchKey, Arc<SubtreeMatches>>>>,
+  capacity: usize,
+  hits: Arc<AtomicUsize>,
+}
+
+impl GlobMatchCache {
+  pub fn new() -> GlobMatchCache {
+    Self::new_with_capacity(DEFAULT_CAPACITY)
+  }
+
+  pub fn new_with_capacity(capacity: usize) -> GlobMatchCache {
+    GlobMatchCache {
+      entries: Arc::default(),
+      capacity,
+      hits: Arc::default(),
+    }
+  }
+
+  ///
+  /// Returns the matches previously recorded for the given PathGlob, which the caller must check
+  /// against the current directory listings before using.
+  ///
+  pub(crate) fn get(
+    &self,
+    path_glob: &PathGlob,
+    exclude: &[String],
+    match_options: PathMatchOptions,
+  ) -> Option<Arc<SubtreeMatches>> {
+    let key = GlobMatchKey {
+      path_glob: path_glob.clone(),
+      exclude: exclude.to_vec(),
+      match_options,
+    };
+    self.entries.lock().get(&key).cloned()
+  }
+
+  pub(crate) fn insert(
+    &self,
+    path_glob: PathGlob,
+    exclude: &[String],
+    match_options: PathMatchOptions,
+    subtree: Arc<SubtreeMatches>,
+  ) {
+    let key = GlobMatchKey {
+      path_glob,
+      exclude: exclude.to_vec(),
+      match_options,
+    };
+    let mut entries = self.entries.lock();
+    if entries.len() >= self.capacity {
+      entries.clear();
+    }
+    entries.insert(key, subtree);
+  }
+
+  pub(crate) fn record_hit(&self) {
+    self.hits.fetch_add(1, Ordering::Relaxed);
+  }
+
+  #[cfg(test)]
+  pub(crate) fn hits(&self) -> usize {
+    self.hits.load(Ordering::Relaxed)
+  }
+}
+
+impl Default for GlobMatchCache {
+  fn default() -> GlobMatchCache {
+    GlobMatchCache::new()
+  }
+}
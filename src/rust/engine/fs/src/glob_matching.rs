@@ -15,9 +15,10 @@ use lazy_static::lazy_static;
 use log::warn;
 use parking_lot::Mutex;
 
+use crate::glob_match_cache::SubtreeMatches;
 use crate::{
-  Dir, GitignoreStyleExcludes, GlobExpansionConjunction, Link, PathStat, Stat, StrictGlobMatching,
-  Vfs,
+  Dir, DirectoryListing, GitignoreStyleExcludes, GlobExpansionConjunction, Link, PathMatchOptions,
+  PathStat, Stat, StrictGlobMatching, Vfs,
 };
 
 lazy_static! {
@@ -32,6 +33,11 @@ lazy_static! {
   };
 }
 
+///
+/// The maximum number of globs that a single filespec may expand to via brace alternation.
+///
+const MAX_BRACE_EXPANSIONS: usize = 1024;
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum PathGlob {
   Wildcard {
@@ -96,9 +102,16 @@ impl PathGlob {
   ) -> Result<Vec<PathGlobIncludeEntry>, String> {
     let mut spec_globs_map = Vec::new();
     for filespec in filespecs {
-      let canonical_dir = Dir(PathBuf::new());
-      let symbolic_path = PathBuf::new();
-      let globs = PathGlob::parse(canonical_dir, symbolic_path, &filespec)?;
+      let mut globs = Vec::new();
+      for expanded_filespec in expand_filespec(&filespec)? {
+        let canonical_dir = Dir(PathBuf::new());
+        let symbolic_path = PathBuf::new();
+        globs.extend(PathGlob::parse(
+          canonical_dir,
+          symbolic_path,
+          &expanded_filespec,
+        )?);
+      }
       spec_globs_map.push(PathGlobIncludeEntry {
         input: GlobParsedSource(filespec),
         globs,
@@ -107,6 +120,15 @@ impl PathGlob {
     Ok(spec_globs_map)
   }
 
+  ///
+  /// Escapes the given path so that it is matched literally when parsed as a filespec.
+  ///
+  pub fn escape(path: &str) -> String {
+    Pattern::escape(path)
+      .replace('{', "[{]")
+      .replace('}', "[}]")
+  }
+
   ///
   /// Normalize the given glob pattern string by splitting it into path components, and dropping
   /// references to the current directory, and consecutive '**'s.
@@ -249,6 +271,7 @@ impl PathGlob {
 pub struct ExpandablePathGlobs {
   pub include: Vec<PathGlob>,
   pub exclude: Arc<GitignoreStyleExcludes>,
+  pub match_options: PathMatchOptions,
 }
 
 #[derive(Debug, Clone)]
@@ -257,6 +280,7 @@ pub struct PreparedPathGlobs {
   pub(crate) exclude: Arc<GitignoreStyleExcludes>,
   strict_match_behavior: StrictGlobMatching,
   conjunction: GlobExpansionConjunction,
+  match_options: PathMatchOptions,
   patterns: Vec<glob::Pattern>,
 }
 
@@ -265,41 +289,68 @@ impl PreparedPathGlobs {
     ExpandablePathGlobs {
       include: Iterator::flatten(self.include.iter().map(|pgie| pgie.globs.clone())).collect(),
       exclude: self.exclude.clone(),
+      match_options: self.match_options,
     }
   }
 
+  pub fn strict_match_behavior(&self) -> &StrictGlobMatching {
+    &self.strict_match_behavior
+  }
+
+  pub fn match_options(&self) -> PathMatchOptions {
+    self.match_options
+  }
+
   fn parse_patterns_from_include(
     include: &[PathGlobIncludeEntry],
   ) -> Result<Vec<glob::Pattern>, String> {
-    include
-      .iter()
-      .map(|pattern| {
-        PathGlob::normalize_pattern(&pattern.input.0).and_then(|components| {
-          let normalized_pattern: PathBuf = components.into_iter().collect();
+    let mut patterns = Vec::new();
+    for pattern in include {
+      for expanded_pattern in expand_filespec(&pattern.input.0)? {
+        let components = PathGlob::normalize_pattern(&expanded_pattern)?;
+        let normalized_pattern: PathBuf = components.into_iter().collect();
+        patterns.push(
           Pattern::new(normalized_pattern.to_str().unwrap())
-            .map_err(|e| format!("Could not parse {:?} as a glob: {:?}", pattern.input.0, e))
-        })
-      })
-      .collect::<Result<Vec<_>, String>>()
+            .map_err(|e| format!("Could not parse {:?} as a glob: {:?}", pattern.input.0, e))?,
+        );
+      }
+    }
+    Ok(patterns)
   }
 
   pub fn create(
     globs: Vec<String>,
     strict_match_behavior: StrictGlobMatching,
     conjunction: GlobExpansionConjunction,
+  ) -> Result<PreparedPathGlobs, String> {
+    Self::create_with_match_options(
+      globs,
+      strict_match_behavior,
+      conjunction,
+      PathMatchOptions::default(),
+    )
+  }
+
+  pub fn create_with_match_options(
+    globs: Vec<String>,
+    strict_match_behavior: StrictGlobMatching,
+    conjunction: GlobExpansionConjunction,
+    match_options: PathMatchOptions,
   ) -> Result<PreparedPathGlobs, String> {
     let mut include_globs = Vec::new();
     let mut exclude_globs = Vec::new();
     for glob in globs {
+      let glob = match_options.normalize(&glob).into_owned();
       if glob.starts_with('!') {
         let normalized_exclude: String = glob.chars().skip(1).collect();
-        exclude_globs.push(normalized_exclude);
+        exclude_globs.extend(expand_filespec(&normalized_exclude)?);
       } else {
         include_globs.push(glob);
       }
     }
     let include = PathGlob::spread_filespecs(include_globs)?;
-    let exclude = GitignoreStyleExcludes::create(exclude_globs)?;
+    let exclude =
+      GitignoreStyleExcludes::create_with_match_options(exclude_globs, None, match_options)?;
     let patterns = PreparedPathGlobs::parse_patterns_from_include(&include)?;
 
     Ok(PreparedPathGlobs {
@@ -307,6 +358,7 @@ impl PreparedPathGlobs {
       exclude,
       strict_match_behavior,
       conjunction,
+      match_options,
       patterns,
     })
   }
@@ -327,6 +379,7 @@ impl PreparedPathGlobs {
       exclude: GitignoreStyleExcludes::create(vec![])?,
       strict_match_behavior: StrictGlobMatching::Ignore,
       conjunction: GlobExpansionConjunction::AllMatch,
+      match_options: PathMatchOptions::default(),
       patterns,
     })
   }
@@ -341,14 +394,172 @@ impl PreparedPathGlobs {
   /// via MemFS).
   ///
   pub fn matches(&self, path: &Path) -> bool {
+    let path_str = match path.to_str() {
+      Some(path_str) => self.match_options.normalize(path_str),
+      None => return false,
+    };
+    let options = MatchOptions {
+      case_sensitive: !self.match_options.case_insensitive,
+      ..*PATTERN_MATCH_OPTIONS
+    };
     self
       .patterns
       .iter()
-      .any(|pattern| pattern.matches_path_with(path, &PATTERN_MATCH_OPTIONS))
+      .any(|pattern| pattern.matches_with(&path_str, &options))
       && !self.exclude.is_ignored_path(path, false)
   }
 }
 
+///
+/// Expands brace alternations (`{a,b}`, which may be nested) in the given filespec, and translates
+/// the extensions which the `glob` crate does not support into equivalent patterns:
+///   * A negated character class may be written `[^...]` as well as `[!...]`.
+///   * `**` matches recursively only when it is an entire path component: elsewhere it is
+///     equivalent to `*` (as in gitignore files).
+///
+/// Braces and brackets may be matched literally by escaping them with a backslash, or by placing
+/// them in a character class.
+///
+pub(crate) fn expand_filespec(filespec: &str) -> Result<Vec<String>, String> {
+  let mut expansions = Vec::new();
+  expand_braces(filespec.chars().collect(), &mut expansions)
+    .map_err(|e| format!("Could not parse {:?} as a glob: {}", filespec, e))?;
+  Ok(
+    expansions
+      .into_iter()
+      .map(|expansion| translate_extensions(&expansion))
+      .collect(),
+  )
+}
+
+fn expand_braces(chars: Vec<char>, expansions: &mut Vec<String>) -> Result<(), String> {
+  let mut i = 0;
+  while i < chars.len() {
+    match chars[i] {
+      '\\' => i += 2,
+      '[' => i = char_class_end(&chars, i).unwrap_or(i + 1),
+      '{' => {
+        if let Some((alternatives, end)) = brace_alternatives(&chars, i) {
+          for (start, stop) in alternatives {
+            let mut expansion = chars[..i].to_vec();
+            expansion.extend_from_slice(&chars[start..stop]);
+            expansion.extend_from_slice(&chars[end..]);
+            expand_braces(expansion, expansions)?;
+          }
+          return Ok(());
+        }
+        i += 1;
+      }
+      _ => i += 1,
+    }
+  }
+  if expansions.len() >= MAX_BRACE_EXPANSIONS {
+    return Err(format!(
+      "brace alternation expands to more than {} globs",
+      MAX_BRACE_EXPANSIONS
+    ));
+  }
+  expansions.push(chars.into_iter().collect());
+  Ok(())
+}
+
+///
+/// Given the index of an opening brace, returns the (start, end) indices of each of its
+/// alternatives, and the index following the closing brace. Returns None if the brace is
+/// unmatched, or does not contain a comma (in which case it is matched literally).
+///
+fn brace_alternatives(chars: &[char], open: usize) -> Option<(Vec<(usize, usize)>, usize)> {
+  let mut alternatives = Vec::new();
+  let mut start = open + 1;
+  let mut depth = 0;
+  let mut i = open + 1;
+  while i < chars.len() {
+    match chars[i] {
+      '\\' => i += 1,
+      '[' => {
+        i = char_class_end(chars, i).unwrap_or(i + 1);
+        continue;
+      }
+      '{' => depth += 1,
+      '}' if depth > 0 => depth -= 1,
+      '}' => {
+        if alternatives.is_empty() {
+          return None;
+        }
+        alternatives.push((start, i));
+        return Some((alternatives, i + 1));
+      }
+      ',' if depth == 0 => {
+        alternatives.push((start, i));
+        start = i + 1;
+      }
+      _ => (),
+    }
+    i += 1;
+  }
+  None
+}
+
+///
+/// Given the index of an opening bracket, returns the index following the closing bracket of the
+/// character class, or None if the class is not closed. As in the `glob` crate, the first character
+/// of a class (following any negation) is always a member of the class, even if it is a `]`.
+///
+fn char_class_end(chars: &[char], open: usize) -> Option<usize> {
+  let mut first = open + 1;
+  if matches!(chars.get(first), Some('!') | Some('^')) {
+    first += 1;
+  }
+  chars
+    .get(first + 1..)?
+    .iter()
+    .position(|c| *c == ']')
+    .map(|position| first + 1 + position + 1)
+}
+
+fn translate_extensions(pattern: &str) -> String {
+  let chars = pattern.chars().collect::<Vec<_>>();
+  let mut translated = String::with_capacity(pattern.len());
+  let mut i = 0;
+  while i < chars.len() {
+    match chars[i] {
+      '\\' if i + 1 < chars.len() && "?*[]{}".contains(chars[i + 1]) => {
+        // An escaped metacharacter is matched literally via a character class.
+        translated.push('[');
+        translated.push(chars[i + 1]);
+        translated.push(']');
+        i += 2;
+      }
+      '[' => {
+        let end = char_class_end(&chars, i).unwrap_or(i + 1);
+        translated.push('[');
+        for (offset, c) in chars[i + 1..end].iter().enumerate() {
+          translated.push(if offset == 0 && *c == '^' { '!' } else { *c });
+        }
+        i = end;
+      }
+      '*' => {
+        let start = i;
+        while i < chars.len() && chars[i] == '*' {
+          i += 1;
+        }
+        let is_component =
+          (start == 0 || chars[start - 1] == '/') && (i == chars.len() || chars[i] == '/');
+        translated.push_str(if is_component && i - start > 1 {
+          *DOUBLE_STAR
+        } else {
+          "*"
+        });
+      }
+      c => {
+        translated.push(c);
+        i += 1;
+      }
+    }
+  }
+  translated
+}
+
 #[async_trait]
 pub trait GlobMatching<E: Display + Send + Sync + 'static>: Vfs<E> {
   ///
@@ -388,16 +599,19 @@ impl<E: Display + Send + Sync + 'static, T: Vfs<E>> GlobMatching<E> for T {}
 // The methods of `GlobMatching` are forwarded to methods here.
 #[async_trait]
 trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
-  async fn directory_listing(
+  ///
+  /// Matches the given wildcard against a directory listing, returning the matched PathStats, and
+  /// whether they depend only on the listing (i.e., whether no Links were matched).
+  ///
+  async fn match_directory_listing(
     &self,
-    canonical_dir: Dir,
-    symbolic_path: PathBuf,
-    wildcard: Pattern,
+    dir_listing: &DirectoryListing,
+    symbolic_path: &Path,
+    wildcard: &Pattern,
     exclude: &Arc<GitignoreStyleExcludes>,
-  ) -> Result<Vec<PathStat>, E> {
-    // List the directory.
-    let dir_listing = self.scandir(canonical_dir).await?;
-
+    match_options: PathMatchOptions,
+  ) -> Result<(Vec<PathStat>, bool), E> {
+    let mut matches_link = false;
     // Match any relevant Stats, and join them into PathStats.
     let path_stats = future::try_join_all(
       dir_listing
@@ -408,7 +622,7 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
           stat
             .path()
             .file_name()
-            .map(|file_name| wildcard.matches_path(Path::new(file_name)))
+            .map(|file_name| match_options.matches_path(wildcard, Path::new(file_name)))
             .unwrap_or(false)
         })
         .filter_map(|stat| {
@@ -422,6 +636,7 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
         .map(|(stat_symbolic_path, stat)| {
           let context = self.clone();
           let exclude = exclude.clone();
+          matches_link |= matches!(stat, Stat::Link(_));
           async move {
             // Canonicalize matched PathStats, and filter paths that are ignored by local excludes.
             // Context ("global") ignore patterns are applied during `scandir`.
@@ -444,7 +659,7 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
     )
     .await?;
     // See the note above.
-    Ok(path_stats.into_iter().flatten().collect())
+    Ok((path_stats.into_iter().flatten().collect(), !matches_link))
   }
 
   async fn expand_globs(
@@ -457,6 +672,7 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
       exclude,
       strict_match_behavior,
       conjunction,
+      match_options,
       ..
     } = path_globs;
 
@@ -472,7 +688,7 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
       let source = Arc::new(pgie.input);
       for path_glob in pgie.globs {
         sources.push(source.clone());
-        roots.push(self.expand_single(result.clone(), exclude.clone(), path_glob));
+        roots.push(self.expand_single(result.clone(), exclude.clone(), match_options, path_glob));
       }
     }
 
@@ -567,17 +783,108 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
     &self,
     result: Arc<Mutex<Vec<PathStat>>>,
     exclude: Arc<GitignoreStyleExcludes>,
+    match_options: PathMatchOptions,
     path_glob: PathGlob,
   ) -> Result<bool, E> {
+    let subtree = self
+      .expand_subtree(exclude, match_options, path_glob)
+      .await?;
+    subtree.extend_into(&mut result.lock());
+    Ok(subtree.matched)
+  }
+
+  ///
+  /// Expands the given PathGlob, reusing the matches of a previous expansion (of any PathGlobs) if
+  /// the directory listings that they were computed from are unchanged.
+  ///
+  async fn expand_subtree(
+    &self,
+    exclude: Arc<GitignoreStyleExcludes>,
+    match_options: PathMatchOptions,
+    path_glob: PathGlob,
+  ) -> Result<Arc<SubtreeMatches>, E> {
+    let cache = match self.glob_match_cache() {
+      Some(cache) => cache,
+      None => {
+        return self
+          .match_subtree(exclude, match_options, path_glob)
+          .await
+          .map(Arc::new)
+      }
+    };
+
+    if let Some(subtree) = cache.get(&path_glob, exclude.exclude_patterns(), match_options) {
+      if self.listings_unchanged(&subtree).await {
+        cache.record_hit();
+        return Ok(subtree);
+      }
+    }
+
+    let subtree = Arc::new(
+      self
+        .match_subtree(exclude.clone(), match_options, path_glob.clone())
+        .await?,
+    );
+    if subtree.cacheable {
+      cache.insert(
+        path_glob,
+        exclude.exclude_patterns(),
+        match_options,
+        subtree.clone(),
+      );
+    }
+    Ok(subtree)
+  }
+
+  ///
+  /// Lists each of the directories that the given matches were computed from (which records them
+  /// as dependencies for Vfs implementations which track them), and returns true if they are all
+  /// unchanged.
+  ///
+  async fn listings_unchanged(&self, subtree: &SubtreeMatches) -> bool {
+    let listings = subtree.listings();
+    let current_listings = future::join_all(
+      listings
+        .iter()
+        .map(|(canonical_dir, _)| self.scandir(canonical_dir.clone())),
+    )
+    .await;
+    listings
+      .iter()
+      .zip(current_listings)
+      .all(|((_, listing), current_listing)| match current_listing {
+        Ok(current_listing) => {
+          Arc::ptr_eq(listing, &current_listing) || listing == &current_listing
+        }
+        Err(_) => false,
+      })
+  }
+
+  async fn match_subtree(
+    &self,
+    exclude: Arc<GitignoreStyleExcludes>,
+    match_options: PathMatchOptions,
+    path_glob: PathGlob,
+  ) -> Result<SubtreeMatches, E> {
     match path_glob {
       PathGlob::Wildcard {
         canonical_dir,
         symbolic_path,
         wildcard,
       } => {
-        self
-          .expand_wildcard(result, exclude, canonical_dir, symbolic_path, wildcard)
-          .await
+        // Filter directory listing to append PathStats, with no continuation.
+        let listing = self.scandir(canonical_dir.clone()).await?;
+        let (path_stats, cacheable) = self
+          .match_directory_listing(&listing, &symbolic_path, &wildcard, &exclude, match_options)
+          .await?;
+        Ok(SubtreeMatches {
+          canonical_dir,
+          listing,
+          matched: !path_stats.is_empty(),
+          path_stats,
+          children: vec![],
+          cacheable,
+        })
       }
       PathGlob::DirWildcard {
         canonical_dir,
@@ -585,74 +892,41 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
         wildcard,
         remainder,
       } => {
-        self
-          .expand_dir_wildcard(
-            result,
-            exclude,
-            canonical_dir,
-            symbolic_path,
-            wildcard,
-            remainder,
-          )
-          .await
+        // Filter directory listing and recurse for matched Dirs.
+        let listing = self.scandir(canonical_dir.clone()).await?;
+        let (path_stats, cacheable) = self
+          .match_directory_listing(&listing, &symbolic_path, &wildcard, &exclude, match_options)
+          .await?;
+
+        let path_globs = path_stats
+          .into_iter()
+          .filter_map(|ps| match ps {
+            PathStat::Dir { path, stat } => Some(
+              PathGlob::parse_globs(stat, path, &remainder).map_err(|e| Self::mk_error(e.as_str())),
+            ),
+            PathStat::File { .. } | PathStat::Link { .. } => None,
+          })
+          .collect::<Result<Vec<_>, E>>()?;
+
+        let child_globs = path_globs
+          .into_iter()
+          .flat_map(Vec::into_iter)
+          .map(|pg| self.expand_subtree(exclude.clone(), match_options, pg))
+          .collect::<Vec<_>>();
+
+        let children = future::try_join_all(child_globs).await?;
+        Ok(SubtreeMatches {
+          canonical_dir,
+          listing,
+          path_stats: vec![],
+          matched: children.iter().any(|child| child.matched),
+          cacheable: cacheable && children.iter().all(|child| child.cacheable),
+          children,
+        })
       }
     }
   }
 
-  async fn expand_wildcard(
-    &self,
-    result: Arc<Mutex<Vec<PathStat>>>,
-    exclude: Arc<GitignoreStyleExcludes>,
-    canonical_dir: Dir,
-    symbolic_path: PathBuf,
-    wildcard: Pattern,
-  ) -> Result<bool, E> {
-    // Filter directory listing to append PathStats, with no continuation.
-    let path_stats = self
-      .directory_listing(canonical_dir, symbolic_path, wildcard, &exclude)
-      .await?;
-
-    let mut result = result.lock();
-    let matched = !path_stats.is_empty();
-    result.extend(path_stats);
-    Ok(matched)
-  }
-
-  async fn expand_dir_wildcard(
-    &self,
-    result: Arc<Mutex<Vec<PathStat>>>,
-    exclude: Arc<GitignoreStyleExcludes>,
-    canonical_dir: Dir,
-    symbolic_path: PathBuf,
-    wildcard: Pattern,
-    remainder: Vec<Pattern>,
-  ) -> Result<bool, E> {
-    // Filter directory listing and recurse for matched Dirs.
-    let context = self.clone();
-    let path_stats = self
-      .directory_listing(canonical_dir, symbolic_path, wildcard, &exclude)
-      .await?;
-
-    let path_globs = path_stats
-      .into_iter()
-      .filter_map(|ps| match ps {
-        PathStat::Dir { path, stat } => Some(
-          PathGlob::parse_globs(stat, path, &remainder).map_err(|e| Self::mk_error(e.as_str())),
-        ),
-        PathStat::File { .. } => None,
-      })
-      .collect::<Result<Vec<_>, E>>()?;
-
-    let child_globs = path_globs
-      .into_iter()
-      .flat_map(Vec::into_iter)
-      .map(|pg| context.expand_single(result.clone(), exclude.clone(), pg))
-      .collect::<Vec<_>>();
-
-    let child_matches = future::try_join_all(child_globs).await?;
-    Ok(child_matches.into_iter().any(|m| m))
-  }
-
   async fn canonicalize_link(
     &self,
     symbolic_path: PathBuf,
@@ -667,7 +941,7 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
       .to_str()
       .and_then(|dest_str| {
         // Escape any globs in the parsed dest, which should guarantee one output PathGlob.
-        PathGlob::create(vec![Pattern::escape(dest_str)]).ok()
+        PathGlob::create(vec![PathGlob::escape(dest_str)]).ok()
       })
       .unwrap_or_else(Vec::new);
 
@@ -682,6 +956,7 @@ trait GlobMatchingImplementation<E: Display + Send + Sync + 'static>: Vfs<E> {
     Ok(path_stats.pop().map(|ps| match ps {
       PathStat::Dir { stat, .. } => PathStat::dir(symbolic_path, stat),
       PathStat::File { stat, .. } => PathStat::file(symbolic_path, stat),
+      PathStat::Link { stat, target, .. } => PathStat::link(symbolic_path, stat, target),
     }))
   }
 }
@@ -1,8 +1,12 @@
 // Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use crate::glob_matching::PathGlob;
-use crate::{GitignoreStyleExcludes, GlobExpansionConjunction, PathGlobs, StrictGlobMatching};
+use std::path::Path;
+
+use crate::glob_matching::{expand_filespec, PathGlob};
+use crate::{
+  GitignoreStyleExcludes, GlobExpansionConjunction, PathGlobs, PathMatchOptions, StrictGlobMatching,
+};
 
 #[test]
 fn path_globs_create_distinguishes_between_includes_and_excludes() {
@@ -32,3 +36,141 @@ fn path_globs_create_distinguishes_between_includes_and_excludes() {
       .patterns
   );
 }
+
+fn expand(filespec: &str) -> Vec<String> {
+  expand_filespec(filespec).unwrap()
+}
+
+#[test]
+fn expand_brace_alternation() {
+  assert_eq!(expand("src/*.py"), vec!["src/*.py"]);
+  assert_eq!(expand("src/{a,b}.py"), vec!["src/a.py", "src/b.py"]);
+  assert_eq!(
+    expand("{src,test}/{a,b}"),
+    vec!["src/a", "src/b", "test/a", "test/b"]
+  );
+  assert_eq!(expand("f{,.d}"), vec!["f", "f.d"]);
+}
+
+#[test]
+fn expand_nested_brace_alternation() {
+  assert_eq!(expand("{a,b{c,d}}/e"), vec!["a/e", "bc/e", "bd/e"]);
+  assert_eq!(expand("{a,{b,{c,d}}}"), vec!["a", "b", "c", "d"]);
+}
+
+#[test]
+fn expand_literal_braces() {
+  // Braces without a comma, unmatched braces, and braces within character classes or escaped by a
+  // backslash are literal.
+  assert_eq!(expand("{a}"), vec!["{a}"]);
+  assert_eq!(expand("{a,b"), vec!["{a,b"]);
+  assert_eq!(expand("[{]a,b}"), vec!["[{]a,b}"]);
+  assert_eq!(expand("\\{a,b}"), vec!["[{]a,b}"]);
+  assert_eq!(expand("{a,[}]}"), vec!["a", "[}]"]);
+}
+
+#[test]
+fn expand_too_many_alternatives() {
+  let filespec = "{a,b}".repeat(11);
+  assert!(expand_filespec(&filespec)
+    .unwrap_err()
+    .contains("brace alternation expands to more than"));
+}
+
+#[test]
+fn translate_extensions() {
+  assert_eq!(expand("[^a-c]*"), vec!["[!a-c]*"]);
+  assert_eq!(expand("[!a-c]*"), vec!["[!a-c]*"]);
+  assert_eq!(expand("[]^]"), vec!["[]^]"]);
+  assert_eq!(expand("src/**.py"), vec!["src/*.py"]);
+  assert_eq!(expand("src/a**"), vec!["src/a*"]);
+  assert_eq!(expand("src/**/b"), vec!["src/**/b"]);
+  assert_eq!(expand("**"), vec!["**"]);
+}
+
+#[test]
+fn escape() {
+  assert_eq!(PathGlob::escape("a{b,c}[d]*"), "a[{]b,c[}][[]d[]][*]");
+  assert_eq!(expand(&PathGlob::escape("a{b,c}")), vec!["a[{]b,c[}]"]);
+}
+
+#[test]
+fn matches_with_extensions() {
+  let pg = PathGlobs::new(
+    vec![
+      "src/{a,b/{c,d}}.py".to_string(),
+      "test/[^x]*.py".to_string(),
+      "gen/**.txt".to_string(),
+      "!src/b/d.py".to_string(),
+      "!test/{y,z}.py".to_string(),
+    ],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .parse()
+  .unwrap();
+
+  assert!(pg.matches(Path::new("src/a.py")));
+  assert!(pg.matches(Path::new("src/b/c.py")));
+  assert!(!pg.matches(Path::new("src/b/d.py")));
+  assert!(!pg.matches(Path::new("src/b.py")));
+  assert!(pg.matches(Path::new("test/w.py")));
+  assert!(!pg.matches(Path::new("test/x.py")));
+  assert!(!pg.matches(Path::new("test/y.py")));
+  assert!(pg.matches(Path::new("gen/a.txt")));
+  assert!(!pg.matches(Path::new("gen/sub/a.txt")));
+}
+
+#[test]
+fn matches_case_insensitive() {
+  let globs = vec!["src/*.PY".to_string(), "!src/Skip.py".to_string()];
+  let exact = PathGlobs::new(
+    globs.clone(),
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .parse()
+  .unwrap();
+  let pg = PathGlobs::new(
+    globs,
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .with_match_options(PathMatchOptions::new(true, false))
+  .parse()
+  .unwrap();
+
+  assert!(!exact.matches(Path::new("SRC/a.py")));
+  assert!(pg.matches(Path::new("SRC/a.py")));
+  assert!(pg.matches(Path::new("src/A.Py")));
+  assert!(!pg.matches(Path::new("src/skip.py")));
+  assert!(!pg.matches(Path::new("src/a.txt")));
+}
+
+#[test]
+fn matches_unicode_normalized() {
+  // "é" as a single precomposed codepoint (NFC), and as "e" followed by a combining accent (NFD).
+  let nfc = "caf\u{e9}";
+  let nfd = "cafe\u{301}";
+  let exact = PathGlobs::new(
+    vec![format!("{}/*.txt", nfc), format!("!{}/skip.txt", nfd)],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .parse()
+  .unwrap();
+  let pg = PathGlobs::new(
+    vec![format!("{}/*.txt", nfc), format!("!{}/skip.txt", nfd)],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .with_match_options(PathMatchOptions::new(false, true))
+  .parse()
+  .unwrap();
+
+  assert!(!exact.matches(Path::new(&format!("{}/a.txt", nfd))));
+  assert!(pg.matches(Path::new(&format!("{}/a.txt", nfd))));
+  assert!(pg.matches(Path::new(&format!("{}/a.txt", nfc))));
+  assert!(!pg.matches(Path::new(&format!("{}/skip.txt", nfc))));
+  assert!(!pg.matches(Path::new(&format!("{}/A.txt", nfc).to_uppercase())));
+}
@@ -27,17 +27,21 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
+mod gitignore;
+mod glob_match_cache;
 mod glob_matching;
 #[cfg(test)]
 mod glob_matching_tests;
 #[cfg(test)]
 mod posixfs_tests;
 
+pub use crate::glob_match_cache::GlobMatchCache;
 pub use crate::glob_matching::{
   ExpandablePathGlobs, GlobMatching, PathGlob, PreparedPathGlobs, DOUBLE_STAR_GLOB,
   SINGLE_STAR_GLOB,
 };
 
+use std::borrow::Cow;
 use std::cmp::min;
 use std::io;
 use std::ops::Deref;
@@ -50,13 +54,19 @@ use ::ignore::gitignore::{Gitignore, GitignoreBuilder};
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::future::{self, TryFutureExt};
+use glob::{MatchOptions, Pattern};
 use lazy_static::lazy_static;
 use serde::Serialize;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+use crate::gitignore::NestedGitignores;
 
 lazy_static! {
   static ref EMPTY_IGNORE: Arc<GitignoreStyleExcludes> = Arc::new(GitignoreStyleExcludes {
     patterns: vec![],
     gitignore: Gitignore::empty(),
+    nested: None,
+    match_options: PathMatchOptions::default(),
   });
 }
 
@@ -192,6 +202,15 @@ pub enum PathStat {
     // The canonical Stat that underlies the Path.
     stat: File,
   },
+  Link {
+    // The symbolic name of some filesystem Path, which is context specific.
+    path: PathBuf,
+    // The Stat of the link itself: links are only preserved (rather than expanded to the Stat that
+    // they point to) when they are captured in, or read from, a Digest.
+    stat: Link,
+    // The target of the link, relative to the directory containing it.
+    target: PathBuf,
+  },
 }
 
 impl PathStat {
@@ -203,10 +222,15 @@ impl PathStat {
     PathStat::File { path, stat }
   }
 
+  pub fn link(path: PathBuf, stat: Link, target: PathBuf) -> PathStat {
+    PathStat::Link { path, stat, target }
+  }
+
   pub fn path(&self) -> &Path {
     match self {
       &PathStat::Dir { ref path, .. } => path.as_path(),
       &PathStat::File { ref path, .. } => path.as_path(),
+      &PathStat::Link { ref path, .. } => path.as_path(),
     }
   }
 
@@ -243,6 +267,10 @@ pub struct DirectoryListing(pub Vec<Stat>);
 pub struct GitignoreStyleExcludes {
   patterns: Vec<String>,
   gitignore: Gitignore,
+  // If enabled, the `.gitignore` files of each directory (and `.git/info/exclude`), which are
+  // consulted for paths that are not matched by `gitignore`.
+  nested: Option<NestedGitignores>,
+  match_options: PathMatchOptions,
 }
 
 impl GitignoreStyleExcludes {
@@ -257,12 +285,26 @@ impl GitignoreStyleExcludes {
   pub fn create_with_gitignore_file(
     patterns: Vec<String>,
     gitignore_path: Option<PathBuf>,
+  ) -> Result<Arc<Self>, String> {
+    Self::create_with_match_options(patterns, gitignore_path, PathMatchOptions::default())
+  }
+
+  ///
+  /// Creates an ignorer which compares paths to its patterns according to the given options.
+  ///
+  pub fn create_with_match_options(
+    patterns: Vec<String>,
+    gitignore_path: Option<PathBuf>,
+    match_options: PathMatchOptions,
   ) -> Result<Arc<Self>, String> {
     if patterns.is_empty() && gitignore_path.is_none() {
       return Ok(EMPTY_IGNORE.clone());
     }
 
     let mut ignore_builder = GitignoreBuilder::new("");
+    ignore_builder
+      .case_insensitive(match_options.case_insensitive)
+      .map_err(|e| format!("Could not build ignore patterns: {:?}", e))?;
 
     if let Some(path) = gitignore_path {
       if let Some(err) = ignore_builder.add(path) {
@@ -270,12 +312,14 @@ impl GitignoreStyleExcludes {
       }
     }
     for pattern in &patterns {
-      ignore_builder.add_line(None, pattern).map_err(|e| {
-        format!(
-          "Could not parse glob exclude pattern `{:?}`: {:?}",
-          pattern, e
-        )
-      })?;
+      ignore_builder
+        .add_line(None, &match_options.normalize(pattern))
+        .map_err(|e| {
+          format!(
+            "Could not parse glob exclude pattern `{:?}`: {:?}",
+            pattern, e
+          )
+        })?;
     }
 
     let gitignore = ignore_builder
@@ -285,9 +329,40 @@ impl GitignoreStyleExcludes {
     Ok(Arc::new(Self {
       patterns: patterns,
       gitignore,
+      nested: None,
+      match_options,
+    }))
+  }
+
+  ///
+  /// Creates an ignorer which respects the given patterns, as well as the `.gitignore` files in
+  /// each directory under the given root, and the root's `.git/info/exclude` file. The given
+  /// patterns take precedence over the ignore files.
+  ///
+  /// Ignore files are loaded lazily, and cached until `invalidate_ignore_file` is called for them.
+  ///
+  pub fn create_with_nested_gitignores(
+    patterns: Vec<String>,
+    root: PathBuf,
+  ) -> Result<Arc<Self>, String> {
+    let excludes = Self::create_with_gitignore_file(patterns, None)?;
+    Ok(Arc::new(Self {
+      patterns: excludes.patterns.clone(),
+      gitignore: excludes.gitignore.clone(),
+      nested: Some(NestedGitignores::new(root)),
+      match_options: PathMatchOptions::default(),
     }))
   }
 
+  ///
+  /// If the given path (relative to the root) is an ignore file which this ignorer respects, drops
+  /// any cached copy of it, and returns the directory beneath which the set of ignored paths may
+  /// have changed.
+  ///
+  pub fn invalidate_ignore_file(&self, path: &Path) -> Option<PathBuf> {
+    self.nested.as_ref()?.invalidate_ignore_file(path)
+  }
+
   fn exclude_patterns(&self) -> &[String] {
     self.patterns.as_slice()
   }
@@ -298,13 +373,38 @@ impl GitignoreStyleExcludes {
   }
 
   pub fn is_ignored_path(&self, path: &Path, is_dir: bool) -> bool {
-    match self.gitignore.matched(path, is_dir) {
+    if self.match_options.unicode_normalization {
+      if let Some(path_str) = path.to_str() {
+        let normalized = self.match_options.normalize(path_str);
+        if normalized != path_str {
+          return self.is_ignored_normalized_path(Path::new(normalized.as_ref()), is_dir);
+        }
+      }
+    }
+    self.is_ignored_normalized_path(path, is_dir)
+  }
+
+  fn is_ignored_normalized_path(&self, path: &Path, is_dir: bool) -> bool {
+    let m = match (self.gitignore.matched(path, is_dir), &self.nested) {
+      (::ignore::Match::None, Some(nested)) => nested.matched(path, is_dir),
+      (m, _) => m.map(|_| ()),
+    };
+    match m {
       ::ignore::Match::None | ::ignore::Match::Whitelist(_) => false,
       ::ignore::Match::Ignore(_) => true,
     }
   }
 
   pub fn is_ignored_or_child_of_ignored_path(&self, path: &Path, is_dir: bool) -> bool {
+    if self.nested.is_some() {
+      // Check the path itself, and then each of its parent directories.
+      return self.is_ignored_path(path, is_dir)
+        || path
+          .ancestors()
+          .skip(1)
+          .filter(|parent| !parent.as_os_str().is_empty())
+          .any(|parent| self.is_ignored_path(parent, true));
+    }
     match self.gitignore.matched_path_or_any_parents(path, is_dir) {
       ::ignore::Match::None | ::ignore::Match::Whitelist(_) => false,
       ::ignore::Match::Ignore(_) => true,
@@ -319,6 +419,10 @@ impl GitignoreStyleExcludes {
   /// or need to check for excluded files/directories.
   ///
   pub fn maybe_is_parent_of_ignored_path(&self, path: &Path) -> bool {
+    if self.match_options.is_inexact() {
+      // Patterns cannot be compared to paths as strings.
+      return true;
+    }
     match path.to_str() {
       None => true,
       Some(s) => {
@@ -389,6 +493,76 @@ impl GlobExpansionConjunction {
   }
 }
 
+///
+/// Controls how paths are compared to globs, in order to emulate the behavior of filesystems which
+/// are case-insensitive or which normalize Unicode paths.
+///
+#[derive(Debug, Clone, Copy, Default, Eq, Hash, PartialEq)]
+pub struct PathMatchOptions {
+  /// If true, paths are matched regardless of their case.
+  pub case_insensitive: bool,
+  /// If true, paths and globs are compared after NFC normalization.
+  pub unicode_normalization: bool,
+}
+
+impl PathMatchOptions {
+  pub fn new(case_insensitive: bool, unicode_normalization: bool) -> PathMatchOptions {
+    PathMatchOptions {
+      case_insensitive,
+      unicode_normalization,
+    }
+  }
+
+  ///
+  /// True if paths are compared in any way other than exactly.
+  ///
+  pub fn is_inexact(&self) -> bool {
+    self.case_insensitive || self.unicode_normalization
+  }
+
+  ///
+  /// Normalizes the given path or glob according to these options.
+  ///
+  pub fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+    if self.unicode_normalization && !is_nfc(path) {
+      Cow::Owned(path.nfc().collect())
+    } else {
+      Cow::Borrowed(path)
+    }
+  }
+
+  ///
+  /// Matches a single path component against a glob Pattern according to these options.
+  ///
+  pub fn matches_path(&self, pattern: &Pattern, path: &Path) -> bool {
+    let path = match path.to_str() {
+      Some(path) => path,
+      None => return false,
+    };
+    pattern.matches_with(
+      &self.normalize(path),
+      &MatchOptions {
+        case_sensitive: !self.case_insensitive,
+        ..MatchOptions::default()
+      },
+    )
+  }
+
+  ///
+  /// Returns a key for the given path which is equal for any two paths that would refer to the same
+  /// file on a filesystem which matches paths according to these options.
+  ///
+  pub fn collision_key(&self, path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let path = self.normalize(&path);
+    if self.case_insensitive {
+      path.to_lowercase()
+    } else {
+      path.into_owned()
+    }
+  }
+}
+
 #[derive(Clone)]
 pub enum SymlinkBehavior {
   Aware,
@@ -400,6 +574,7 @@ pub struct PathGlobs {
   globs: Vec<String>,
   strict_match_behavior: StrictGlobMatching,
   conjunction: GlobExpansionConjunction,
+  match_options: PathMatchOptions,
 }
 
 impl PathGlobs {
@@ -412,14 +587,21 @@ impl PathGlobs {
       globs,
       strict_match_behavior,
       conjunction,
+      match_options: PathMatchOptions::default(),
     }
   }
 
+  pub fn with_match_options(mut self, match_options: PathMatchOptions) -> PathGlobs {
+    self.match_options = match_options;
+    self
+  }
+
   pub fn parse(self) -> Result<glob_matching::PreparedPathGlobs, String> {
-    glob_matching::PreparedPathGlobs::create(
+    glob_matching::PreparedPathGlobs::create_with_match_options(
       self.globs,
       self.strict_match_behavior,
       self.conjunction,
+      self.match_options,
     )
   }
 }
@@ -444,6 +626,7 @@ pub struct PosixFS {
   ignore: Arc<GitignoreStyleExcludes>,
   executor: task_executor::Executor,
   symlink_behavior: SymlinkBehavior,
+  glob_match_cache: GlobMatchCache,
 }
 
 impl PosixFS {
@@ -483,6 +666,7 @@ impl PosixFS {
       ignore: ignorer,
       executor: executor,
       symlink_behavior: symlink_behavior,
+      glob_match_cache: GlobMatchCache::new(),
     })
   }
 
@@ -545,6 +729,10 @@ impl PosixFS {
     self.ignore.is_ignored(stat)
   }
 
+  pub fn glob_match_cache(&self) -> &GlobMatchCache {
+    &self.glob_match_cache
+  }
+
   pub fn file_path(&self, file: &File) -> PathBuf {
     self.root.0.join(&file.path)
   }
@@ -676,6 +864,10 @@ impl Vfs<io::Error> for Arc<PosixFS> {
     PosixFS::is_ignored(self, stat)
   }
 
+  fn glob_match_cache(&self) -> Option<&GlobMatchCache> {
+    Some(PosixFS::glob_match_cache(self))
+  }
+
   fn mk_error(msg: &str) -> io::Error {
     io::Error::new(io::ErrorKind::Other, msg)
   }
@@ -724,6 +916,12 @@ pub trait Vfs<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
   async fn read_link(&self, link: &Link) -> Result<PathBuf, E>;
   async fn scandir(&self, dir: Dir) -> Result<Arc<DirectoryListing>, E>;
   fn is_ignored(&self, stat: &Stat) -> bool;
+  ///
+  /// A cache of glob matches to share between glob expansions, if any.
+  ///
+  fn glob_match_cache(&self) -> Option<&GlobMatchCache> {
+    None
+  }
   fn mk_error(msg: &str) -> E;
 }
 
@@ -759,9 +957,16 @@ pub struct FileEntry {
   pub is_executable: bool,
 }
 
+#[derive(Debug, Eq, PartialEq)]
+pub struct SymlinkEntry {
+  pub path: PathBuf,
+  pub target: PathBuf,
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub enum DigestEntry {
   File(FileEntry),
+  Symlink(SymlinkEntry),
   EmptyDirectory(PathBuf),
 }
 
@@ -769,6 +974,7 @@ impl DigestEntry {
   pub fn path(&self) -> &Path {
     match self {
       DigestEntry::File(file_entry) => &file_entry.path,
+      DigestEntry::Symlink(symlink_entry) => &symlink_entry.path,
       DigestEntry::EmptyDirectory(path) => path,
     }
   }
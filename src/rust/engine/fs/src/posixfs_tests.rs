@@ -3,8 +3,8 @@ use testutil;
 
 use crate::{
   Dir, DirectoryListing, File, GitignoreStyleExcludes, GlobExpansionConjunction, GlobMatching,
-  Link, PathGlobs, PathStat, PathStatGetter, PosixFS, Stat, StrictGlobMatching, SymlinkBehavior,
-  Vfs,
+  Link, PathGlobs, PathMatchOptions, PathStat, PathStatGetter, PosixFS, Stat, StrictGlobMatching,
+  SymlinkBehavior, Vfs,
 };
 
 use async_trait::async_trait;
@@ -349,6 +349,130 @@ async fn memfs_expand_basic() {
   );
 }
 
+#[tokio::test]
+async fn memfs_expand_brace_alternation() {
+  let fs = Arc::new(MemFS::new(vec![
+    PathBuf::from("a/x.py"),
+    PathBuf::from("a/y.py"),
+    PathBuf::from("b/c/x.py"),
+    PathBuf::from("b/d/x.py"),
+  ]));
+  let globs = PathGlobs::new(
+    vec!["{a/[^y]*,b/{c,e}/*}.py".into()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .parse()
+  .unwrap();
+
+  let file = |path: &str| {
+    PathStat::file(
+      PathBuf::from(path),
+      File {
+        path: PathBuf::from(path),
+        is_executable: false,
+      },
+    )
+  };
+  assert_eq!(
+    fs.expand_globs(globs, None).await.unwrap(),
+    vec![file("a/x.py"), file("b/c/x.py")],
+  );
+}
+
+#[tokio::test]
+async fn expand_overlapping_globs_reuses_matches() {
+  let dir = tempfile::TempDir::new().unwrap();
+  std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+  make_file(&dir.path().join("a/x.py"), &[], 0o600);
+  make_file(&dir.path().join("a/b/y.py"), &[], 0o600);
+  let posix_fs = Arc::new(new_posixfs(dir.path()));
+  let expand = |globs: &[&str]| {
+    let posix_fs = posix_fs.clone();
+    let globs = PathGlobs::new(
+      globs.iter().map(|glob| glob.to_string()).collect(),
+      StrictGlobMatching::Ignore,
+      GlobExpansionConjunction::AnyMatch,
+    )
+    .parse()
+    .unwrap();
+    async move {
+      posix_fs
+        .expand_globs(globs, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|path_stat| path_stat.path().to_owned())
+        .collect::<Vec<_>>()
+    }
+  };
+
+  assert_eq!(
+    expand(&["**/*.py"]).await,
+    vec![PathBuf::from("a/b/y.py"), PathBuf::from("a/x.py")]
+  );
+  let hits = posix_fs.glob_match_cache.hits();
+  assert_eq!(
+    expand(&["**/*.py", "a/*.py"]).await,
+    vec![PathBuf::from("a/b/y.py"), PathBuf::from("a/x.py")]
+  );
+  assert!(posix_fs.glob_match_cache.hits() > hits);
+
+  // A change to a directory listing is observed by the next expansion.
+  make_file(&dir.path().join("a/b/z.py"), &[], 0o600);
+  assert_eq!(
+    expand(&["**/*.py"]).await,
+    vec![
+      PathBuf::from("a/b/y.py"),
+      PathBuf::from("a/b/z.py"),
+      PathBuf::from("a/x.py")
+    ]
+  );
+
+  // Matches which include the destinations of Links are not cached.
+  std::os::unix::fs::symlink("b/y.py", dir.path().join("a/w.py")).unwrap();
+  assert_eq!(
+    expand(&["a/*.py"]).await,
+    vec![PathBuf::from("a/w.py"), PathBuf::from("a/x.py")]
+  );
+  let hits = posix_fs.glob_match_cache.hits();
+  std::fs::remove_file(dir.path().join("a/b/y.py")).unwrap();
+  assert_eq!(expand(&["a/*.py"]).await, vec![PathBuf::from("a/x.py")]);
+  assert_eq!(posix_fs.glob_match_cache.hits(), hits);
+}
+
+#[tokio::test]
+async fn memfs_expand_case_insensitive() {
+  let fs = Arc::new(MemFS::new(vec![
+    PathBuf::from("Src/A.py"),
+    PathBuf::from("Src/b.PY"),
+    PathBuf::from("Src/c.txt"),
+  ]));
+  let globs = PathGlobs::new(
+    vec!["src/*.py".into()],
+    StrictGlobMatching::Ignore,
+    GlobExpansionConjunction::AnyMatch,
+  )
+  .with_match_options(PathMatchOptions::new(true, false))
+  .parse()
+  .unwrap();
+
+  // Matched paths are reported as they exist on disk.
+  let file = |path: &str| {
+    PathStat::file(
+      PathBuf::from(path),
+      File {
+        path: PathBuf::from(path),
+        is_executable: false,
+      },
+    )
+  };
+  assert_eq!(
+    fs.expand_globs(globs, None).await.unwrap(),
+    vec![file("Src/A.py"), file("Src/b.PY")],
+  );
+}
+
 async fn assert_only_file_is_executable(path: &Path, want_is_executable: bool) {
   let fs = new_posixfs(path);
   let stats = fs.scandir(Dir(PathBuf::from("."))).await.unwrap();
@@ -389,6 +513,7 @@ async fn read_mock_files(input: Vec<PathBuf>, posix_fs: &Arc<PosixFS>) -> Vec<St
       match path_stat {
         PathStat::Dir { stat, .. } => Stat::Dir(stat),
         PathStat::File { stat, .. } => Stat::File(stat),
+        PathStat::Link { stat, .. } => Stat::Link(stat),
       }
     })
     .collect()
@@ -445,6 +570,74 @@ async fn test_basic_gitignore_functionality() {
   assert!(posix_fs_2.is_ignored(&stats[3]));
 }
 
+#[tokio::test]
+async fn test_nested_gitignore_functionality() {
+  let root = tempfile::TempDir::new().unwrap();
+  let root_path = root.path();
+
+  std::fs::create_dir_all(root_path.join("sub/deeper")).unwrap();
+  std::fs::create_dir_all(root_path.join(".git/info")).unwrap();
+  let bytes = "content".as_bytes();
+  make_file(&root_path.join("a.tmp"), bytes, 0o700);
+  make_file(&root_path.join("a.log"), bytes, 0o700);
+  make_file(&root_path.join("sub/a.tmp"), bytes, 0o700);
+  make_file(&root_path.join("sub/keep.tmp"), bytes, 0o700);
+  make_file(&root_path.join("sub/b.txt"), bytes, 0o700);
+  make_file(&root_path.join("sub/deeper/b.txt"), bytes, 0o700);
+  make_file(&root_path.join(".gitignore"), "*.tmp".as_bytes(), 0o700);
+  make_file(
+    &root_path.join("sub/.gitignore"),
+    "!keep.tmp\nb.txt".as_bytes(),
+    0o700,
+  );
+  make_file(
+    &root_path.join(".git/info/exclude"),
+    "*.log".as_bytes(),
+    0o700,
+  );
+
+  let executor = task_executor::Executor::new();
+  let ignorer =
+    GitignoreStyleExcludes::create_with_nested_gitignores(vec![], root_path.to_owned()).unwrap();
+  let posix_fs = Arc::new(PosixFS::new(root.as_ref(), ignorer.clone(), executor).unwrap());
+
+  let stats = read_mock_files(
+    vec![
+      PathBuf::from("a.tmp"),
+      PathBuf::from("a.log"),
+      PathBuf::from("sub/a.tmp"),
+      PathBuf::from("sub/keep.tmp"),
+      PathBuf::from("sub/b.txt"),
+      PathBuf::from("sub/deeper/b.txt"),
+    ],
+    &posix_fs,
+  )
+  .await;
+
+  assert!(posix_fs.is_ignored(&stats[0]));
+  assert!(posix_fs.is_ignored(&stats[1]));
+  assert!(posix_fs.is_ignored(&stats[2]));
+  assert!(!posix_fs.is_ignored(&stats[3]));
+  assert!(posix_fs.is_ignored(&stats[4]));
+  assert!(posix_fs.is_ignored(&stats[5]));
+
+  // Changes to an ignore file are only observed once it has been invalidated.
+  make_file(&root_path.join("sub/.gitignore"), &[], 0o700);
+  assert!(posix_fs.is_ignored(&stats[4]));
+  assert_eq!(
+    ignorer.invalidate_ignore_file(Path::new("sub/.gitignore")),
+    Some(PathBuf::from("sub"))
+  );
+  assert!(posix_fs.is_ignored(&stats[3]));
+  assert!(!posix_fs.is_ignored(&stats[4]));
+
+  assert_eq!(
+    ignorer.invalidate_ignore_file(Path::new(".git/info/exclude")),
+    Some(PathBuf::new())
+  );
+  assert_eq!(ignorer.invalidate_ignore_file(Path::new("sub/b.txt")), None);
+}
+
 ///
 /// An in-memory implementation of Vfs, useful for precisely reproducing glob matching behavior for
 /// a set of file paths.
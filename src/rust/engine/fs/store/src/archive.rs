@@ -0,0 +1,729 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use bazel_protos::require_digest;
+use bytes::Bytes;
+use futures::future::{self, FutureExt};
+use grpc_util::prost::MessageExt;
+use hashing::Digest;
+use log::debug;
+
+use crate::snapshot::{check_symlinks, symlink_target};
+use crate::Store;
+
+///
+/// The number of entries which may be read from an archive ahead of being stored.
+///
+const ENTRY_BUFFER_SIZE: usize = 16;
+
+///
+/// The formats of archive which may be extracted into, or created from, a Directory.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ArchiveFormat {
+  Tar,
+  TarGz,
+  TarZst,
+  Zip,
+}
+
+impl ArchiveFormat {
+  pub fn create(spec: &str) -> Result<Self, String> {
+    match spec {
+      "tar" => Ok(ArchiveFormat::Tar),
+      "tar.gz" => Ok(ArchiveFormat::TarGz),
+      "tar.zst" => Ok(ArchiveFormat::TarZst),
+      "zip" => Ok(ArchiveFormat::Zip),
+      _ => Err(format!("Unrecognized archive format: {}.", spec)),
+    }
+  }
+}
+
+///
+/// Options controlling the creation of an archive. Entries are always written in sorted order, with
+/// fixed modes, so that the same Directory and options always produce a byte-identical archive.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ArchiveOptions {
+  pub format: ArchiveFormat,
+  /// The compression level to use, or None for the default level of the format. Ignored for
+  /// `tar`. For `zip`, a level of 0 stores entries uncompressed, and no other level is supported:
+  /// entries are otherwise deflated at the default level.
+  pub compression_level: Option<u32>,
+  /// The modification time of every entry, in seconds since the epoch.
+  pub mtime: u64,
+  /// The owning user of every entry. Ignored for `zip`.
+  pub uid: u64,
+  /// The owning group of every entry. Ignored for `zip`.
+  pub gid: u64,
+}
+
+impl ArchiveOptions {
+  pub fn new(format: ArchiveFormat) -> ArchiveOptions {
+    ArchiveOptions {
+      format,
+      compression_level: None,
+      mtime: 0,
+      uid: 0,
+      gid: 0,
+    }
+  }
+}
+
+///
+/// An entry read from or written to an archive, with its path relative to the root of the archive.
+///
+enum ArchiveEntry {
+  File {
+    path: PathBuf,
+    content: Bytes,
+    is_executable: bool,
+  },
+  Directory {
+    path: PathBuf,
+  },
+  Symlink {
+    path: PathBuf,
+    target: PathBuf,
+  },
+  // A hardlink to a file which occurs earlier in the archive.
+  Hardlink {
+    path: PathBuf,
+    target: PathBuf,
+  },
+}
+
+///
+/// Extracts the archive with the given file Digest into a Directory in the Store, and returns the
+/// Digest of the Directory.
+///
+/// Executable bits and symlinks are preserved. Entries whose paths are absolute or which would be
+/// extracted outside of the root of the archive are rejected, as are symlinks whose targets would
+/// resolve outside of the root of the archive, or which traverse other symlinks in the archive.
+///
+/// Entries are decompressed on a blocking thread and stored as they are read, so only a bounded
+/// number of (decompressed) entries are held in memory at once.
+///
+pub(crate) async fn extract_archive(
+  store: &Store,
+  archive_digest: Digest,
+  format: ArchiveFormat,
+) -> Result<Digest, String> {
+  let archive = store
+    .load_file_bytes_with(archive_digest, Bytes::copy_from_slice)
+    .await?
+    .ok_or_else(|| format!("Archive {:?} was not present in the store.", archive_digest))?;
+  let (sender, mut receiver) = tokio::sync::mpsc::channel(ENTRY_BUFFER_SIZE);
+  let reader = store.local.executor().spawn_blocking(move || {
+    read_archive(format, archive, |entry| {
+      // If the receiver has gone away, extraction has already failed.
+      sender
+        .blocking_send(entry)
+        .map_err(|_| "Archive extraction was cancelled.".to_owned())
+    })
+  });
+
+  let mut tree = DirectoryTree::default();
+  while let Some(entry) = receiver.recv().await {
+    match entry {
+      ArchiveEntry::File {
+        path,
+        content,
+        is_executable,
+      } => {
+        let path = relative_entry_path(&path)?;
+        let digest = store.store_file_bytes(content, true).await?;
+        tree.insert(
+          &path,
+          TreeEntry::File {
+            digest,
+            is_executable,
+          },
+        )?;
+      }
+      ArchiveEntry::Directory { path } => {
+        let path = relative_entry_path(&path)?;
+        tree.insert(&path, TreeEntry::Directory(DirectoryTree::default()))?;
+      }
+      ArchiveEntry::Symlink { path, target } => {
+        let path = relative_entry_path(&path)?;
+        let target = symlink_target(&path, &target, "archive root")?;
+        tree.insert(&path, TreeEntry::Symlink(target))?;
+      }
+      ArchiveEntry::Hardlink { path, target } => {
+        let path = relative_entry_path(&path)?;
+        let target = relative_entry_path(&target)?;
+        let file = match tree.get(&target) {
+          Some(file @ TreeEntry::File { .. }) => file.clone(),
+          _ => {
+            return Err(format!(
+              "Hardlink {} refers to {}, which is not a file earlier in the archive.",
+              path.display(),
+              target.display()
+            ))
+          }
+        };
+        tree.insert(&path, file)?;
+      }
+    }
+  }
+  // The reader has finished (successfully or not) once it has dropped its sender.
+  reader.await?;
+
+  // Now that the final set of symlinks is known, reject any which traverse one another, and so
+  // could escape the root although they do not lexically.
+  let mut symlinks = Vec::new();
+  tree.symlinks(Path::new(""), &mut symlinks);
+  check_symlinks(
+    &symlinks
+      .iter()
+      .map(|(path, target)| (path.as_path(), Path::new(target)))
+      .collect::<Vec<_>>(),
+    "archive root",
+  )?;
+
+  let mut directories = Vec::new();
+  let digest = tree.into_directories(&mut directories);
+  future::try_join_all(
+    directories
+      .iter()
+      .map(|directory| store.record_directory(directory, true)),
+  )
+  .await?;
+  Ok(digest)
+}
+
+///
+/// Reads the entries of the given archive, passing each of them to `emit` as it is read.
+///
+fn read_archive(
+  format: ArchiveFormat,
+  archive: Bytes,
+  emit: impl FnMut(ArchiveEntry) -> Result<(), String>,
+) -> Result<(), String> {
+  let reader = Cursor::new(archive);
+  match format {
+    ArchiveFormat::Tar => read_tar(reader, emit),
+    ArchiveFormat::TarGz => read_tar(flate2::read::GzDecoder::new(reader), emit),
+    ArchiveFormat::TarZst => read_tar(
+      zstd::Decoder::new(reader).map_err(|e| format!("Failed to decompress archive: {}", e))?,
+      emit,
+    ),
+    ArchiveFormat::Zip => read_zip(reader, emit),
+  }
+}
+
+fn read_tar<R: Read>(
+  reader: R,
+  mut emit: impl FnMut(ArchiveEntry) -> Result<(), String>,
+) -> Result<(), String> {
+  let mut archive = tar::Archive::new(reader);
+  for entry in archive
+    .entries()
+    .map_err(|e| format!("Failed to read archive: {}", e))?
+  {
+    let mut entry = entry.map_err(|e| format!("Failed to read archive entry: {}", e))?;
+    let path = entry
+      .path()
+      .map_err(|e| format!("Failed to read archive entry path: {}", e))?
+      .into_owned();
+    let link_target = || -> Result<PathBuf, String> {
+      entry
+        .link_name()
+        .map_err(|e| format!("Failed to read link target of {}: {}", path.display(), e))?
+        .map(|target| target.into_owned())
+        .ok_or_else(|| format!("Link {} had no target.", path.display()))
+    };
+    let entry_type = entry.header().entry_type();
+    if entry_type.is_file() {
+      let is_executable = entry
+        .header()
+        .mode()
+        .map_err(|e| format!("Failed to read mode of {}: {}", path.display(), e))?
+        & 0o111
+        != 0;
+      // NB: The size in the header is not trusted to preallocate the content.
+      let mut content = Vec::new();
+      entry
+        .read_to_end(&mut content)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+      emit(ArchiveEntry::File {
+        path,
+        content: content.into(),
+        is_executable,
+      })?;
+    } else if entry_type.is_dir() {
+      emit(ArchiveEntry::Directory { path })?;
+    } else if entry_type.is_symlink() {
+      let target = link_target()?;
+      emit(ArchiveEntry::Symlink { path, target })?;
+    } else if entry_type.is_hard_link() {
+      let target = link_target()?;
+      emit(ArchiveEntry::Hardlink { path, target })?;
+    } else {
+      // Metadata entries (such as pax headers) are consumed by the reader: any other entries are
+      // device files or fifos, which cannot be represented in a Directory.
+      debug!(
+        "Skipping archive entry {} of type {:?}",
+        path.display(),
+        entry_type
+      );
+    }
+  }
+  Ok(())
+}
+
+fn read_zip(
+  reader: Cursor<Bytes>,
+  mut emit: impl FnMut(ArchiveEntry) -> Result<(), String>,
+) -> Result<(), String> {
+  const S_IFMT: u32 = 0o170_000;
+  const S_IFLNK: u32 = 0o120_000;
+
+  let mut archive =
+    zip::ZipArchive::new(reader).map_err(|e| format!("Failed to read archive: {}", e))?;
+  for i in 0..archive.len() {
+    let mut file = archive
+      .by_index(i)
+      .map_err(|e| format!("Failed to read archive entry: {}", e))?;
+    // NB: The path is validated when the entry is inserted into the Directory.
+    let path = PathBuf::from(file.name());
+    if file.is_dir() {
+      emit(ArchiveEntry::Directory { path })?;
+      continue;
+    }
+
+    let mode = file.unix_mode().unwrap_or(0);
+    let mut content = Vec::new();
+    file
+      .read_to_end(&mut content)
+      .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    if mode & S_IFMT == S_IFLNK {
+      let target = String::from_utf8(content)
+        .map_err(|e| format!("Link {} had a non-UTF8 target: {}", path.display(), e))?;
+      emit(ArchiveEntry::Symlink {
+        path,
+        target: PathBuf::from(target),
+      })?;
+    } else {
+      emit(ArchiveEntry::File {
+        path,
+        content: content.into(),
+        is_executable: mode & 0o111 != 0,
+      })?;
+    }
+  }
+  Ok(())
+}
+
+///
+/// Normalizes the path of an archive entry, failing if it is absolute or would escape the root of
+/// the archive.
+///
+fn relative_entry_path(path: &Path) -> Result<PathBuf, String> {
+  let mut relative = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::Normal(name) => relative.push(name),
+      Component::CurDir => (),
+      Component::ParentDir => {
+        if !relative.pop() {
+          return Err(format!(
+            "Archive entry {} would be extracted outside of the archive root.",
+            path.display()
+          ));
+        }
+      }
+      Component::RootDir | Component::Prefix(_) => {
+        return Err(format!(
+          "Archive entry {} has an absolute path.",
+          path.display()
+        ))
+      }
+    }
+  }
+  Ok(relative)
+}
+
+#[derive(Clone)]
+enum TreeEntry {
+  File { digest: Digest, is_executable: bool },
+  Directory(DirectoryTree),
+  Symlink(String),
+}
+
+///
+/// An in-memory tree of the entries of an archive, which is converted into Directory protos once
+/// all entries have been inserted.
+///
+#[derive(Clone, Default)]
+struct DirectoryTree {
+  entries: BTreeMap<String, TreeEntry>,
+}
+
+impl DirectoryTree {
+  ///
+  /// Inserts an entry at the given path, creating any missing parent directories. As when
+  /// extracting an archive to disk, an entry replaces any earlier file or symlink at the same path.
+  ///
+  fn insert(&mut self, path: &Path, entry: TreeEntry) -> Result<(), String> {
+    let mut names = Vec::new();
+    for component in path.components() {
+      names.push(
+        component
+          .as_os_str()
+          .to_str()
+          .ok_or_else(|| format!("Archive entry {} had a non-UTF8 path.", path.display()))?,
+      );
+    }
+    let (name, parents) = match names.split_last() {
+      Some(split) => split,
+      // The root directory.
+      None => return Ok(()),
+    };
+
+    let mut directory = self;
+    for parent in parents {
+      directory = match directory
+        .entries
+        .entry((*parent).to_owned())
+        .or_insert_with(|| TreeEntry::Directory(DirectoryTree::default()))
+      {
+        TreeEntry::Directory(d) => d,
+        _ => {
+          return Err(format!(
+            "Archive entry {} is beneath a path which is not a directory.",
+            path.display()
+          ))
+        }
+      };
+    }
+
+    match (directory.entries.get(*name), entry) {
+      (Some(TreeEntry::Directory(_)), TreeEntry::Directory(_)) => (),
+      (Some(TreeEntry::Directory(_)), _) => {
+        return Err(format!(
+          "Archive entry {} would replace a directory.",
+          path.display()
+        ))
+      }
+      (_, entry) => {
+        directory.entries.insert((*name).to_owned(), entry);
+      }
+    }
+    Ok(())
+  }
+
+  fn get(&self, path: &Path) -> Option<&TreeEntry> {
+    let mut directory = self;
+    let mut entry = None;
+    for component in path.components() {
+      let child = directory.entries.get(component.as_os_str().to_str()?)?;
+      if let TreeEntry::Directory(d) = child {
+        directory = d;
+      }
+      entry = Some(child);
+    }
+    entry
+  }
+
+  ///
+  /// Collects the paths and targets of all symlinks in this tree, which is at the given path.
+  ///
+  fn symlinks<'a>(&'a self, path: &Path, symlinks: &mut Vec<(PathBuf, &'a str)>) {
+    for (name, entry) in &self.entries {
+      match entry {
+        TreeEntry::Directory(tree) => tree.symlinks(&path.join(name), symlinks),
+        TreeEntry::Symlink(target) => symlinks.push((path.join(name), target)),
+        TreeEntry::File { .. } => (),
+      }
+    }
+  }
+
+  ///
+  /// Converts this tree into Directory protos (children before their parents), returning the
+  /// Digest of the root Directory.
+  ///
+  fn into_directories(self, directories: &mut Vec<remexec::Directory>) -> Digest {
+    let mut directory = remexec::Directory::default();
+    // NB: BTreeMap iterates in sorted order, which means that each type of node is sorted by name,
+    // as required for a canonical Directory.
+    for (name, entry) in self.entries {
+      match entry {
+        TreeEntry::File {
+          digest,
+          is_executable,
+        } => directory.files.push(remexec::FileNode {
+          name,
+          digest: Some((&digest).into()),
+          is_executable,
+          ..remexec::FileNode::default()
+        }),
+        TreeEntry::Directory(tree) => {
+          let digest = tree.into_directories(directories);
+          directory.directories.push(remexec::DirectoryNode {
+            name,
+            digest: Some((&digest).into()),
+          })
+        }
+        TreeEntry::Symlink(target) => directory.symlinks.push(remexec::SymlinkNode {
+          name,
+          target,
+          ..remexec::SymlinkNode::default()
+        }),
+      }
+    }
+    let digest = Digest::of_bytes(&directory.to_bytes());
+    directories.push(directory);
+    digest
+  }
+}
+
+///
+/// Creates an archive of the Directory with the given Digest, stores it as a file in the Store, and
+/// returns the Digest of the file.
+///
+pub(crate) async fn create_archive(
+  store: &Store,
+  digest: Digest,
+  options: ArchiveOptions,
+) -> Result<Digest, String> {
+  if let (ArchiveFormat::Zip, Some(level @ 1..=u32::MAX)) =
+    (options.format, options.compression_level)
+  {
+    return Err(format!(
+      "Unsupported compression level {} for a zip archive: only 0 (to store entries \
+      uncompressed) or the default level are supported.",
+      level
+    ));
+  }
+  let entries = store
+    .walk(digest, |store, path_so_far, _, directory| {
+      let mut entries = directory
+        .symlinks
+        .iter()
+        .map(|symlink_node| ArchiveEntry::Symlink {
+          path: path_so_far.join(&symlink_node.name),
+          target: PathBuf::from(&symlink_node.target),
+        })
+        .collect::<Vec<_>>();
+      if !path_so_far.as_os_str().is_empty() {
+        entries.push(ArchiveEntry::Directory {
+          path: path_so_far.clone(),
+        });
+      }
+      let files = directory
+        .files
+        .iter()
+        .map(|file_node| {
+          let store = store.clone();
+          let path = path_so_far.join(&file_node.name);
+          let is_executable = file_node.is_executable;
+          let digest = require_digest(file_node.digest.as_ref());
+          async move {
+            let content = store
+              .load_file_bytes_with(digest?, Bytes::copy_from_slice)
+              .await?
+              .ok_or_else(|| format!("Couldn't find file contents for {:?}", path))?;
+            let res: Result<_, String> = Ok(ArchiveEntry::File {
+              path,
+              content,
+              is_executable,
+            });
+            res
+          }
+        })
+        .collect::<Vec<_>>();
+      async move {
+        entries.extend(future::try_join_all(files).await?);
+        Ok(entries)
+      }
+      .boxed()
+    })
+    .await?;
+  let mut entries = entries.into_iter().flatten().collect::<Vec<_>>();
+  // NB: Paths are ordered by component, so directories sort before their contents.
+  entries.sort_by(|l, r| l.path().cmp(r.path()));
+
+  let archive = store
+    .local
+    .executor()
+    .spawn_blocking(move || write_archive(&options, entries))
+    .await?;
+  store.store_file_bytes(Bytes::from(archive), true).await
+}
+
+impl ArchiveEntry {
+  fn path(&self) -> &Path {
+    match self {
+      ArchiveEntry::File { path, .. }
+      | ArchiveEntry::Directory { path }
+      | ArchiveEntry::Symlink { path, .. }
+      | ArchiveEntry::Hardlink { path, .. } => path,
+    }
+  }
+}
+
+fn write_archive(options: &ArchiveOptions, entries: Vec<ArchiveEntry>) -> Result<Vec<u8>, String> {
+  let compression_err = |e: std::io::Error| format!("Failed to compress archive: {}", e);
+  match options.format {
+    ArchiveFormat::Tar => write_tar(Vec::new(), options, entries),
+    ArchiveFormat::TarGz => {
+      let level = options
+        .compression_level
+        .map(flate2::Compression::new)
+        .unwrap_or_default();
+      write_tar(
+        flate2::write::GzEncoder::new(Vec::new(), level),
+        options,
+        entries,
+      )?
+      .finish()
+      .map_err(compression_err)
+    }
+    ArchiveFormat::TarZst => {
+      // NB: A level of 0 selects zstd's default level.
+      let level = options.compression_level.unwrap_or(0) as i32;
+      let encoder = zstd::Encoder::new(Vec::new(), level).map_err(compression_err)?;
+      write_tar(encoder, options, entries)?
+        .finish()
+        .map_err(compression_err)
+    }
+    ArchiveFormat::Zip => write_zip(options, entries),
+  }
+}
+
+fn write_tar<W: Write>(
+  writer: W,
+  options: &ArchiveOptions,
+  entries: Vec<ArchiveEntry>,
+) -> Result<W, String> {
+  let mut builder = tar::Builder::new(writer);
+  for entry in entries {
+    let mut header = tar::Header::new_gnu();
+    header.set_mtime(options.mtime);
+    header.set_uid(options.uid);
+    header.set_gid(options.gid);
+    header.set_size(0);
+    let res = match &entry {
+      ArchiveEntry::File {
+        path,
+        content,
+        is_executable,
+      } => {
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(if *is_executable { 0o755 } else { 0o644 });
+        header.set_size(content.len() as u64);
+        builder.append_data(&mut header, path, content.as_ref())
+      }
+      ArchiveEntry::Directory { path } => {
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        builder.append_data(&mut header, path, std::io::empty())
+      }
+      ArchiveEntry::Symlink { path, target } => {
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, path, target)
+      }
+      ArchiveEntry::Hardlink { path, target } => {
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_mode(0o644);
+        builder.append_link(&mut header, path, target)
+      }
+    };
+    res.map_err(|e| format!("Failed to add {} to archive: {}", entry.path().display(), e))?;
+  }
+  builder
+    .into_inner()
+    .map_err(|e| format!("Failed to write archive: {}", e))
+}
+
+fn write_zip(options: &ArchiveOptions, entries: Vec<ArchiveEntry>) -> Result<Vec<u8>, String> {
+  // NB: Other levels are rejected by `create_archive`.
+  let compression_method = match options.compression_level {
+    Some(0) => zip::CompressionMethod::Stored,
+    _ => zip::CompressionMethod::Deflated,
+  };
+  let file_options = zip::write::FileOptions::default()
+    .compression_method(compression_method)
+    .last_modified_time(zip_date_time(options.mtime));
+
+  let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+  for entry in entries {
+    let name = entry
+      .path()
+      .to_str()
+      .ok_or_else(|| {
+        format!(
+          "Archive entry {} had a non-UTF8 path.",
+          entry.path().display()
+        )
+      })?
+      .to_owned();
+    let err = |e: &dyn std::fmt::Display| format!("Failed to add {} to archive: {}", name, e);
+    match &entry {
+      ArchiveEntry::File {
+        content,
+        is_executable,
+        ..
+      } => {
+        let mode = if *is_executable { 0o755 } else { 0o644 };
+        writer
+          .start_file(name.as_str(), file_options.unix_permissions(mode))
+          .map_err(|e| err(&e))?;
+        writer.write_all(content).map_err(|e| err(&e))?;
+      }
+      ArchiveEntry::Directory { .. } => writer
+        .add_directory(name.as_str(), file_options.unix_permissions(0o755))
+        .map_err(|e| err(&e))?,
+      ArchiveEntry::Symlink { .. } | ArchiveEntry::Hardlink { .. } => {
+        return Err(err(&"links may not be stored in zip archives."))
+      }
+    }
+  }
+  writer
+    .finish()
+    .map(Cursor::into_inner)
+    .map_err(|e| format!("Failed to write archive: {}", e))
+}
+
+///
+/// Converts seconds since the epoch into a zip (MS-DOS) timestamp, clamped to the range that it
+/// can represent.
+///
+fn zip_date_time(mtime: u64) -> zip::DateTime {
+  let days = (mtime / 86_400) as i64;
+  let seconds = mtime % 86_400;
+
+  // Converts days since the epoch into a civil date: see
+  // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let day_of_era = z.rem_euclid(146_097);
+  let year_of_era =
+    (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let mp = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+  if year < 1980 {
+    return zip::DateTime::default();
+  }
+  zip::DateTime::from_date_and_time(
+    year as u16,
+    month as u8,
+    day as u8,
+    (seconds / 3600) as u8,
+    (seconds % 3600 / 60) as u8,
+    (seconds % 60) as u8,
+  )
+  .unwrap_or_else(|()| zip::DateTime::from_date_and_time(2107, 12, 31, 23, 59, 58).unwrap())
+}
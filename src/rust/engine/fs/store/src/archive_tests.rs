@@ -0,0 +1,528 @@
+use std::convert::TryInto;
+use std::io::Write;
+
+use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use bytes::Bytes;
+use hashing::Digest;
+use tempfile::TempDir;
+
+use crate::{ArchiveFormat, ArchiveOptions, Store};
+
+enum Entry {
+  File(&'static str, &'static str, u32),
+  Directory(&'static str),
+  Symlink(&'static str, &'static str),
+  Hardlink(&'static str, &'static str),
+}
+
+fn tar_bytes(entries: &[Entry]) -> Vec<u8> {
+  let mut builder = tar::Builder::new(Vec::new());
+  for entry in entries {
+    let mut header = tar::Header::new_gnu();
+    let (path, content): (&str, &[u8]) = match entry {
+      Entry::File(path, content, mode) => {
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(*mode);
+        header.set_size(content.len() as u64);
+        (path, content.as_bytes())
+      }
+      Entry::Directory(path) => {
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        (path, &[])
+      }
+      Entry::Symlink(path, target) | Entry::Hardlink(path, target) => {
+        let entry_type = match entry {
+          Entry::Symlink(..) => tar::EntryType::Symlink,
+          _ => tar::EntryType::Link,
+        };
+        header.set_entry_type(entry_type);
+        header.set_mode(0o777);
+        header.set_size(0);
+        header.set_link_name(target).unwrap();
+        (path, &[])
+      }
+    };
+    // NB: `Header::set_path` rejects paths containing `..`, so the name is written directly in
+    // order to construct malicious archives.
+    let name = &mut header.as_old_mut().name;
+    name[..path.len()].copy_from_slice(path.as_bytes());
+    header.set_cksum();
+    builder.append(&header, content).unwrap();
+  }
+  builder.into_inner().unwrap()
+}
+
+fn zip_bytes(entries: &[Entry]) -> Vec<u8> {
+  let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+  for entry in entries {
+    match entry {
+      Entry::File(path, content, mode) => {
+        let options = zip::write::FileOptions::default().unix_permissions(*mode);
+        writer.start_file(*path, options).unwrap();
+        writer.write_all(content.as_bytes()).unwrap();
+      }
+      Entry::Directory(path) => {
+        writer
+          .add_directory(*path, zip::write::FileOptions::default())
+          .unwrap();
+      }
+      Entry::Symlink(path, target) => {
+        // NB: The writer always marks entries as regular files, so the mode of symlinks is patched
+        // below.
+        writer
+          .start_file(*path, zip::write::FileOptions::default())
+          .unwrap();
+        writer.write_all(target.as_bytes()).unwrap();
+      }
+      Entry::Hardlink(..) => panic!("Zip archives do not support hardlinks."),
+    }
+  }
+  let mut archive = writer.finish().unwrap().into_inner();
+
+  let symlinks = entries
+    .iter()
+    .filter_map(|entry| match entry {
+      Entry::Symlink(path, _) => Some(path.as_bytes()),
+      _ => None,
+    })
+    .collect::<Vec<_>>();
+  const CENTRAL_DIRECTORY_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x01, 0x02];
+  for offset in 0..archive.len() - 46 {
+    if archive[offset..offset + 4] != CENTRAL_DIRECTORY_SIGNATURE {
+      continue;
+    }
+    let name_len = u16::from_le_bytes([archive[offset + 28], archive[offset + 29]]) as usize;
+    let name = &archive[offset + 46..offset + 46 + name_len];
+    if symlinks.contains(&name) {
+      let external_attributes = 0o120_777_u32 << 16;
+      archive[offset + 38..offset + 42].copy_from_slice(&external_attributes.to_le_bytes());
+    }
+  }
+  archive
+}
+
+fn new_store() -> (Store, TempDir) {
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  (store, dir)
+}
+
+async fn extract(store: &Store, archive: Vec<u8>, format: ArchiveFormat) -> Result<Digest, String> {
+  let archive_digest = store
+    .store_file_bytes(Bytes::from(archive), false)
+    .await
+    .unwrap();
+  store.extract_archive(archive_digest, format).await
+}
+
+async fn load_directory(store: &Store, digest: Digest) -> remexec::Directory {
+  store.load_directory(digest).await.unwrap().unwrap()
+}
+
+async fn load_subdirectory(
+  store: &Store,
+  directory: &remexec::Directory,
+  name: &str,
+) -> remexec::Directory {
+  let node = directory
+    .directories
+    .iter()
+    .find(|node| node.name == name)
+    .unwrap();
+  let digest = node.digest.as_ref().unwrap().try_into().unwrap();
+  load_directory(store, digest).await
+}
+
+async fn file_content(store: &Store, file: &remexec::FileNode) -> String {
+  let digest: Digest = file.digest.as_ref().unwrap().try_into().unwrap();
+  store
+    .load_file_bytes_with(digest, |bytes| String::from_utf8(bytes.to_vec()).unwrap())
+    .await
+    .unwrap()
+    .unwrap()
+}
+
+fn example_entries() -> Vec<Entry> {
+  vec![
+    Entry::Directory("bin/"),
+    Entry::File("bin/run", "#!/bin/sh", 0o755),
+    Entry::File("lib/data.txt", "data", 0o644),
+    Entry::Symlink("lib/run", "../bin/run"),
+  ]
+}
+
+async fn assert_example_extracted(store: &Store, digest: Digest) {
+  let root = load_directory(store, digest).await;
+  assert_eq!(
+    root
+      .directories
+      .iter()
+      .map(|node| node.name.as_str())
+      .collect::<Vec<_>>(),
+    vec!["bin", "lib"]
+  );
+
+  let bin = load_subdirectory(store, &root, "bin").await;
+  assert_eq!(bin.files.len(), 1);
+  assert_eq!(bin.files[0].name, "run");
+  assert!(bin.files[0].is_executable);
+  assert_eq!(file_content(store, &bin.files[0]).await, "#!/bin/sh");
+
+  let lib = load_subdirectory(store, &root, "lib").await;
+  assert_eq!(lib.files.len(), 1);
+  assert_eq!(lib.files[0].name, "data.txt");
+  assert!(!lib.files[0].is_executable);
+  assert_eq!(file_content(store, &lib.files[0]).await, "data");
+  assert_eq!(lib.symlinks.len(), 1);
+  assert_eq!(lib.symlinks[0].name, "run");
+  assert_eq!(lib.symlinks[0].target, "../bin/run");
+}
+
+#[tokio::test]
+async fn extract_tar() {
+  let (store, _dir) = new_store();
+  let digest = extract(&store, tar_bytes(&example_entries()), ArchiveFormat::Tar)
+    .await
+    .unwrap();
+  assert_example_extracted(&store, digest).await;
+}
+
+#[tokio::test]
+async fn extract_tar_gz() {
+  let (store, _dir) = new_store();
+  let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  encoder.write_all(&tar_bytes(&example_entries())).unwrap();
+  let digest = extract(&store, encoder.finish().unwrap(), ArchiveFormat::TarGz)
+    .await
+    .unwrap();
+  assert_example_extracted(&store, digest).await;
+}
+
+#[tokio::test]
+async fn extract_tar_zst() {
+  let (store, _dir) = new_store();
+  let archive = zstd::encode_all(&tar_bytes(&example_entries())[..], 0).unwrap();
+  let digest = extract(&store, archive, ArchiveFormat::TarZst)
+    .await
+    .unwrap();
+  assert_example_extracted(&store, digest).await;
+}
+
+#[tokio::test]
+async fn extract_zip() {
+  let (store, _dir) = new_store();
+  let digest = extract(&store, zip_bytes(&example_entries()), ArchiveFormat::Zip)
+    .await
+    .unwrap();
+  assert_example_extracted(&store, digest).await;
+}
+
+#[tokio::test]
+async fn extract_tar_hardlink() {
+  let (store, _dir) = new_store();
+  let archive = tar_bytes(&[
+    Entry::File("original", "content", 0o755),
+    Entry::Hardlink("link", "original"),
+  ]);
+  let digest = extract(&store, archive, ArchiveFormat::Tar).await.unwrap();
+
+  let root = load_directory(&store, digest).await;
+  assert_eq!(
+    root
+      .files
+      .iter()
+      .map(|file| (file.name.as_str(), file.is_executable))
+      .collect::<Vec<_>>(),
+    vec![("link", true), ("original", true)]
+  );
+  assert_eq!(root.files[0].digest, root.files[1].digest);
+}
+
+#[tokio::test]
+async fn extract_same_archive_is_deterministic() {
+  let (store, _dir) = new_store();
+  let first = extract(&store, tar_bytes(&example_entries()), ArchiveFormat::Tar)
+    .await
+    .unwrap();
+  let second = extract(&store, zip_bytes(&example_entries()), ArchiveFormat::Zip)
+    .await
+    .unwrap();
+  assert_eq!(first, second);
+}
+
+#[tokio::test]
+async fn extract_rejects_path_traversal() {
+  let (store, _dir) = new_store();
+  for format in &[ArchiveFormat::Tar, ArchiveFormat::Zip] {
+    let entries = [Entry::File("a/../../escaped", "content", 0o644)];
+    let archive = match format {
+      ArchiveFormat::Tar => tar_bytes(&entries),
+      _ => zip_bytes(&entries),
+    };
+    let err = extract(&store, archive, *format).await.unwrap_err();
+    assert!(err.contains("outside of the archive root"), "{}", err);
+  }
+}
+
+#[tokio::test]
+async fn extract_rejects_absolute_paths() {
+  let (store, _dir) = new_store();
+  let archive = tar_bytes(&[Entry::File("/etc/passwd", "content", 0o644)]);
+  let err = extract(&store, archive, ArchiveFormat::Tar)
+    .await
+    .unwrap_err();
+  assert!(err.contains("has an absolute path"), "{}", err);
+}
+
+#[tokio::test]
+async fn extract_rejects_escaping_symlinks() {
+  let (store, _dir) = new_store();
+  for target in &["../../etc/passwd", "/etc/passwd"] {
+    let archive = tar_bytes(&[Entry::Symlink("a/link", target)]);
+    let err = extract(&store, archive, ArchiveFormat::Tar)
+      .await
+      .unwrap_err();
+    assert!(err.contains("outside of the archive root"), "{}", err);
+  }
+}
+
+#[tokio::test]
+async fn extract_rejects_symlinks_traversing_symlinks() {
+  let (store, _dir) = new_store();
+  // Each target resolves within the root lexically, but `d/x` escapes via `l`.
+  let archive = tar_bytes(&[Entry::Symlink("l", "."), Entry::Symlink("d/x", "../l/..")]);
+  let err = extract(&store, archive, ArchiveFormat::Tar)
+    .await
+    .unwrap_err();
+  assert!(err.contains("traverses the symlink l"), "{}", err);
+}
+
+#[tokio::test]
+async fn extract_rejects_hardlink_to_missing_file() {
+  let (store, _dir) = new_store();
+  let archive = tar_bytes(&[Entry::Hardlink("link", "missing")]);
+  let err = extract(&store, archive, ArchiveFormat::Tar)
+    .await
+    .unwrap_err();
+  assert!(err.contains("not a file earlier in the archive"), "{}", err);
+}
+
+#[tokio::test]
+async fn extract_streams_many_entries() {
+  let (store, _dir) = new_store();
+  // More entries than are buffered between reading and storing them.
+  let names = (0..100)
+    .map(|i| &*Box::leak(format!("f{:03}", i).into_boxed_str()))
+    .collect::<Vec<_>>();
+  let mut entries = names
+    .iter()
+    .map(|name| Entry::File(name, name, 0o644))
+    .collect::<Vec<_>>();
+  let digest = extract(&store, tar_bytes(&entries), ArchiveFormat::Tar)
+    .await
+    .unwrap();
+  let directory = load_directory(&store, digest).await;
+  assert_eq!(directory.files.len(), 100);
+  assert_eq!(file_content(&store, &directory.files[99]).await, "f099");
+
+  // An invalid entry after them still fails the extraction.
+  entries.push(Entry::File("a/../../escaped", "content", 0o644));
+  let err = extract(&store, tar_bytes(&entries), ArchiveFormat::Tar)
+    .await
+    .unwrap_err();
+  assert!(err.contains("outside of the archive root"), "{}", err);
+}
+
+async fn create(store: &Store, digest: Digest, options: ArchiveOptions) -> Vec<u8> {
+  let archive_digest = store.create_archive(digest, options).await.unwrap();
+  store
+    .load_file_bytes_with(archive_digest, |bytes| bytes.to_vec())
+    .await
+    .unwrap()
+    .unwrap()
+}
+
+#[tokio::test]
+async fn create_roundtrips() {
+  let (store, _dir) = new_store();
+  let digest = extract(&store, tar_bytes(&example_entries()), ArchiveFormat::Tar)
+    .await
+    .unwrap();
+  for format in &[
+    ArchiveFormat::Tar,
+    ArchiveFormat::TarGz,
+    ArchiveFormat::TarZst,
+  ] {
+    let archive = create(&store, digest, ArchiveOptions::new(*format)).await;
+    let roundtripped = extract(&store, archive, *format).await.unwrap();
+    assert_eq!(digest, roundtripped, "{:?}", format);
+  }
+}
+
+#[tokio::test]
+async fn create_zip_roundtrips() {
+  let (store, _dir) = new_store();
+  let entries = [
+    Entry::File("bin/run", "#!/bin/sh", 0o755),
+    Entry::File("lib/data.txt", "data", 0o644),
+    Entry::Directory("empty/"),
+  ];
+  let digest = extract(&store, tar_bytes(&entries), ArchiveFormat::Tar)
+    .await
+    .unwrap();
+  let archive = create(&store, digest, ArchiveOptions::new(ArchiveFormat::Zip)).await;
+  let roundtripped = extract(&store, archive, ArchiveFormat::Zip).await.unwrap();
+  assert_eq!(digest, roundtripped);
+}
+
+#[tokio::test]
+async fn create_is_reproducible() {
+  let (store, _dir) = new_store();
+  let digest = extract(&store, tar_bytes(&example_entries()), ArchiveFormat::Tar)
+    .await
+    .unwrap();
+  let options = ArchiveOptions {
+    compression_level: Some(9),
+    mtime: 1_600_000_000,
+    uid: 1000,
+    gid: 1000,
+    ..ArchiveOptions::new(ArchiveFormat::TarGz)
+  };
+  let first = store.create_archive(digest, options).await.unwrap();
+  let second = store.create_archive(digest, options).await.unwrap();
+  assert_eq!(first, second);
+
+  let different_mtime = store
+    .create_archive(
+      digest,
+      ArchiveOptions {
+        mtime: 0,
+        ..options
+      },
+    )
+    .await
+    .unwrap();
+  assert_ne!(first, different_mtime);
+}
+
+#[tokio::test]
+async fn create_zip_rejects_compression_levels() {
+  let (store, _dir) = new_store();
+  let entries = [Entry::File("lib/data.txt", "data data data data", 0o644)];
+  let digest = extract(&store, tar_bytes(&entries), ArchiveFormat::Tar)
+    .await
+    .unwrap();
+  let options = |compression_level| ArchiveOptions {
+    compression_level,
+    ..ArchiveOptions::new(ArchiveFormat::Zip)
+  };
+  let err = store
+    .create_archive(digest, options(Some(9)))
+    .await
+    .unwrap_err();
+  assert!(
+    err.contains("Unsupported compression level 9 for a zip archive"),
+    "{}",
+    err
+  );
+
+  let stored = store
+    .create_archive(digest, options(Some(0)))
+    .await
+    .unwrap();
+  let deflated = store.create_archive(digest, options(None)).await.unwrap();
+  assert_ne!(stored, deflated);
+}
+
+#[tokio::test]
+async fn create_tar_uses_fixed_metadata() {
+  let (store, _dir) = new_store();
+  let digest = extract(&store, tar_bytes(&example_entries()), ArchiveFormat::Tar)
+    .await
+    .unwrap();
+  let options = ArchiveOptions {
+    mtime: 1_600_000_000,
+    uid: 1000,
+    gid: 1001,
+    ..ArchiveOptions::new(ArchiveFormat::Tar)
+  };
+  let archive = create(&store, digest, options).await;
+
+  let mut archive = tar::Archive::new(&archive[..]);
+  let entries = archive
+    .entries()
+    .unwrap()
+    .map(|entry| {
+      let entry = entry.unwrap();
+      let header = entry.header();
+      assert_eq!(header.mtime().unwrap(), 1_600_000_000);
+      assert_eq!(header.uid().unwrap(), 1000);
+      assert_eq!(header.gid().unwrap(), 1001);
+      (
+        entry.path().unwrap().display().to_string(),
+        header.mode().unwrap(),
+      )
+    })
+    .collect::<Vec<_>>();
+  assert_eq!(
+    entries,
+    vec![
+      ("bin".to_owned(), 0o755),
+      ("bin/run".to_owned(), 0o755),
+      ("lib".to_owned(), 0o755),
+      ("lib/data.txt".to_owned(), 0o644),
+      ("lib/run".to_owned(), 0o777),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn create_zip_uses_fixed_mtime() {
+  let (store, _dir) = new_store();
+  let digest = extract(
+    &store,
+    tar_bytes(&[Entry::File("file.txt", "content", 0o644)]),
+    ArchiveFormat::Tar,
+  )
+  .await
+  .unwrap();
+  let options = ArchiveOptions {
+    // 2020-09-13T12:26:40Z
+    mtime: 1_600_000_000,
+    ..ArchiveOptions::new(ArchiveFormat::Zip)
+  };
+  let archive = create(&store, digest, options).await;
+
+  let mut archive = zip::ZipArchive::new(std::io::Cursor::new(archive)).unwrap();
+  let modified = archive.by_index(0).unwrap().last_modified();
+  assert_eq!(
+    (
+      modified.year(),
+      modified.month(),
+      modified.day(),
+      modified.hour(),
+      modified.minute(),
+      modified.second()
+    ),
+    (2020, 9, 13, 12, 26, 40)
+  );
+}
+
+#[tokio::test]
+async fn create_zip_rejects_symlinks() {
+  let (store, _dir) = new_store();
+  let digest = extract(&store, tar_bytes(&example_entries()), ArchiveFormat::Tar)
+    .await
+    .unwrap();
+  let err = store
+    .create_archive(digest, ArchiveOptions::new(ArchiveFormat::Zip))
+    .await
+    .unwrap_err();
+  assert!(
+    err.contains("links may not be stored in zip archives"),
+    "{}",
+    err
+  );
+}
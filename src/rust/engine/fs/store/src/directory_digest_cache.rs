@@ -0,0 +1,243 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use bytes::{BufMut, BytesMut};
+use fs::PathStat;
+use futures::future;
+use grpc_util::prost::MessageExt;
+use hashing::{Digest, Fingerprint};
+use log::debug;
+use parking_lot::Mutex;
+
+use crate::{EntryType, Store, StoreFileByDigest};
+
+///
+/// The default maximum number of entries in a DirectoryDigestCache. A cache which grows beyond
+/// this size is cleared.
+///
+const DEFAULT_CAPACITY: usize = 200_000;
+
+///
+/// An in-memory cache of the Digests of Directories recorded while capturing Snapshots, keyed by
+/// the path of the directory and a fingerprint of its subtree.
+///
+/// A subtree fingerprint covers the names of the PathStats below a directory, the targets of
+/// symlinks, and the Digests, executable bits and NodeProperties of files, as reported by the
+/// `StoreFileByDigest` implementation of the capture. The file Digests are computed for a whole
+/// capture before any directory is recorded: when the implementation memoizes them (as the engine
+/// does, in its Graph), a directory whose subtree has not changed since a previous capture is
+/// reused without being rebuilt or re-recorded.
+///
+#[derive(Clone)]
+pub struct DirectoryDigestCache {
+  entries: Arc<Mutex<HashMap<(PathBuf, Fingerprint), Digest>>>,
+  capacity: usize,
+  hits: Arc<AtomicUsize>,
+}
+
+impl DirectoryDigestCache {
+  pub fn new() -> DirectoryDigestCache {
+    DirectoryDigestCache {
+      entries: Arc::default(),
+      capacity: DEFAULT_CAPACITY,
+      hits: Arc::default(),
+    }
+  }
+
+  ///
+  /// Computes the keys of all directories of a capture of the given (normalized) PathStats, using
+  /// the given StoreFileByDigest to digest all of their files.
+  ///
+  pub(crate) async fn keys<S, Error>(
+    &self,
+    file_digester: &S,
+    path_stats: &[PathStat],
+  ) -> Result<DirectoryKeys, String>
+  where
+    S: StoreFileByDigest<Error> + Sized + Clone + Send + 'static,
+    Error: fmt::Debug + 'static + Send,
+  {
+    let files = future::try_join_all(path_stats.iter().filter_map(|path_stat| match path_stat {
+      PathStat::File { stat, .. } => {
+        let digest = file_digester.store_by_digest(stat.clone());
+        let node_properties = file_digester.node_properties(stat);
+        let path = stat.path.clone();
+        Some(async move {
+          future::try_join(digest, node_properties)
+            .await
+            .map(|file| (path, file))
+            .map_err(|e| format!("{:?}", e))
+        })
+      }
+      _ => None,
+    }))
+    .await?
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+
+    let keys = subtree_keys(path_stats, &files);
+    Ok(DirectoryKeys {
+      cache: self.clone(),
+      keys: Arc::new(keys),
+      files: Arc::new(files),
+    })
+  }
+
+  #[cfg(test)]
+  pub(crate) fn hits(&self) -> usize {
+    self.hits.load(Ordering::Relaxed)
+  }
+}
+
+impl Default for DirectoryDigestCache {
+  fn default() -> DirectoryDigestCache {
+    DirectoryDigestCache::new()
+  }
+}
+
+///
+/// The keys of the directories of one capture via a DirectoryDigestCache, along with the Digests
+/// and NodeProperties of its files.
+///
+#[derive(Clone)]
+pub(crate) struct DirectoryKeys {
+  cache: DirectoryDigestCache,
+  keys: Arc<HashMap<PathBuf, Fingerprint>>,
+  files: Arc<HashMap<PathBuf, (Digest, Option<remexec::NodeProperties>)>>,
+}
+
+impl DirectoryKeys {
+  ///
+  /// Returns the Digest recorded for the unchanged subtree at the given path, if any, and if it is
+  /// still present in the local Store (in which case everything below it is leased, as storing it
+  /// would have done).
+  ///
+  pub(crate) async fn cached_digest(&self, store: &Store, path: &Path) -> Option<Digest> {
+    let key = (path.to_owned(), *self.keys.get(path)?);
+    let digest = self.cache.entries.lock().get(&key).cloned()?;
+    match store.local.entry_type(digest.hash).await {
+      Ok(Some(EntryType::Directory)) => (),
+      Ok(_) => return None,
+      Err(e) => {
+        debug!("Failed to check for cached directory {:?}: {}", digest, e);
+        return None;
+      }
+    }
+    if let Err(e) = store.lease_all_recursively(std::iter::once(&digest)).await {
+      debug!("Failed to lease cached directory {:?}: {}", digest, e);
+      return None;
+    }
+    self.cache.hits.fetch_add(1, Ordering::Relaxed);
+    Some(digest)
+  }
+
+  ///
+  /// Returns the Digest and NodeProperties of the file at the given path, which were computed
+  /// along with the keys.
+  ///
+  pub(crate) fn file(&self, path: &Path) -> Option<(Digest, Option<remexec::NodeProperties>)> {
+    self.files.get(path).cloned()
+  }
+
+  ///
+  /// Records the Digest of the directory at the given path.
+  ///
+  pub(crate) fn insert(&self, path: &Path, digest: Digest) {
+    if let Some(fingerprint) = self.keys.get(path) {
+      let mut entries = self.cache.entries.lock();
+      if entries.len() >= self.cache.capacity {
+        entries.clear();
+      }
+      entries.insert((path.to_owned(), *fingerprint), digest);
+    }
+  }
+}
+
+enum Child<'a> {
+  Dir,
+  Stat(&'a PathStat),
+}
+
+///
+/// Computes the fingerprints of the subtrees of each directory of the given PathStats, from the
+/// deepest directories upward.
+///
+fn subtree_keys(
+  path_stats: &[PathStat],
+  files: &HashMap<PathBuf, (Digest, Option<remexec::NodeProperties>)>,
+) -> HashMap<PathBuf, Fingerprint> {
+  let mut listings: HashMap<&Path, BTreeMap<&OsStr, Child>> = HashMap::new();
+  listings.entry(Path::new("")).or_default();
+  for path_stat in path_stats {
+    let mut path = path_stat.path();
+    let mut child = match path_stat {
+      PathStat::Dir { .. } => {
+        listings.entry(path).or_default();
+        Child::Dir
+      }
+      _ => Child::Stat(path_stat),
+    };
+    while let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+      let listing = listings.entry(parent).or_default();
+      if listing.contains_key(name) {
+        break;
+      }
+      listing.insert(name, child);
+      path = parent;
+      child = Child::Dir;
+    }
+  }
+
+  let mut directories = listings.keys().cloned().collect::<Vec<_>>();
+  directories.sort_by_key(|directory| Reverse(directory.components().count()));
+  let mut keys: HashMap<PathBuf, Fingerprint> = HashMap::with_capacity(directories.len());
+  for directory in directories {
+    let mut buf = BytesMut::new();
+    for (name, child) in &listings[directory] {
+      put_bytes(&mut buf, name.as_bytes());
+      match child {
+        Child::Dir => {
+          buf.put_u8(b'd');
+          buf.put_slice(keys[&directory.join(name)].as_bytes());
+        }
+        Child::Stat(PathStat::File { stat, .. }) => {
+          buf.put_u8(if stat.is_executable { b'x' } else { b'f' });
+          let (digest, node_properties) = &files[&stat.path];
+          buf.put_slice(digest.hash.as_bytes());
+          buf.put_u64(digest.size_bytes as u64);
+          match node_properties {
+            Some(node_properties) => {
+              buf.put_u8(1);
+              put_bytes(&mut buf, &node_properties.to_bytes());
+            }
+            None => buf.put_u8(0),
+          }
+        }
+        Child::Stat(PathStat::Link { target, .. }) => {
+          buf.put_u8(b'l');
+          put_bytes(&mut buf, target.as_os_str().as_bytes());
+        }
+        Child::Stat(PathStat::Dir { .. }) => {
+          unreachable!("Directories are recorded as Child::Dir.")
+        }
+      }
+    }
+    keys.insert(directory.to_owned(), Digest::of_bytes(&buf).hash);
+  }
+  keys
+}
+
+fn put_bytes(buf: &mut BytesMut, bytes: &[u8]) {
+  buf.put_u64(bytes.len() as u64);
+  buf.put_slice(bytes);
+}
@@ -0,0 +1,296 @@
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hashing::{Digest, Fingerprint, FINGERPRINT_SIZE};
+use lmdb::{self, Cursor, Transaction};
+use log::{debug, warn};
+use sharded_lmdb::{ShardedLmdb, VersionedFingerprint, DEFAULT_LEASE_TIME};
+
+use crate::{EntryType, Store, GIGABYTES};
+
+///
+/// Files whose mtime or ctime are within this window of the current time should not be cached by
+/// their `stat`, because a further modification within the granularity of the filesystem's
+/// timestamps would not be detectable.
+///
+pub const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+///
+/// True if the given timestamp (seconds and nanoseconds since the epoch) is within `racy_window`
+/// of `now`, in which case `stat` information including it should not be cached.
+///
+pub fn is_racy(secs: i64, nsecs: i64, now: Duration, racy_window: Duration) -> bool {
+  let timestamp = Duration::from_secs(secs.max(0) as u64) + Duration::from_nanos(nsecs as u64);
+  now < timestamp + racy_window
+}
+
+///
+/// A persistent cache from the `stat` information of a file to the Digest of its content, which
+/// allows a freshly started process to skip re-hashing files which have not changed.
+///
+/// Keys are a hash of the absolute path of a file along with its device, inode, size, mode, mtime
+/// and ctime. An entry is thus implicitly invalidated when any of those change. Entries are leased
+/// when they are created or used, and entries whose leases have expired (generally those for stale
+/// stat information) are removed by `garbage_collect`. If the cache fills up regardless, it is
+/// cleared.
+///
+#[derive(Clone)]
+pub struct FileDigestCache {
+  lmdb: ShardedLmdb,
+  racy_window: Duration,
+}
+
+impl FileDigestCache {
+  pub fn new(
+    root: PathBuf,
+    executor: task_executor::Executor,
+    shard_count: u8,
+  ) -> Result<FileDigestCache, String> {
+    Self::new_with_racy_window(root, executor, shard_count, RACY_WINDOW)
+  }
+
+  pub(crate) fn new_with_racy_window(
+    root: PathBuf,
+    executor: task_executor::Executor,
+    shard_count: u8,
+    racy_window: Duration,
+  ) -> Result<FileDigestCache, String> {
+    let lmdb = ShardedLmdb::new(root, GIGABYTES, executor, DEFAULT_LEASE_TIME, shard_count)?;
+    Ok(FileDigestCache { lmdb, racy_window })
+  }
+
+  ///
+  /// Stores the file at the given absolute path in the Store, and returns its Digest.
+  ///
+  /// If the file has not changed (according to `stat`) since a previous call, and its content is
+  /// still present in the local Store, the file is not re-hashed.
+  ///
+  pub async fn store_file(
+    &self,
+    store: &Store,
+    path: PathBuf,
+    data_is_immutable: bool,
+  ) -> Result<Digest, String> {
+    let key = self.stat_key(store, path.clone()).await;
+    if let Some(key) = key {
+      if let Some(digest) = self.cached_digest(store, key).await {
+        return Ok(digest);
+      }
+    }
+
+    let file_path = path.clone();
+    let digest = store
+      .store_file(true, data_is_immutable, move || {
+        std::fs::File::open(&file_path)
+      })
+      .await?;
+
+    // Only record the digest if the file did not change while we were hashing it.
+    if let Some(key) = key {
+      if self.stat_key(store, path.clone()).await == Some(key) {
+        if let Err(e) = self
+          .lmdb
+          .store_bytes(key, encode_digest(digest), true)
+          .await
+        {
+          if e.contains(&lmdb::Error::MapFull.to_string()) {
+            warn!("The file digest cache is full: clearing it.");
+            if let Err(e) = self.clear() {
+              warn!("Failed to clear the file digest cache: {}", e);
+            }
+          } else {
+            debug!("Failed to cache the digest of {}: {}", path.display(), e);
+          }
+        }
+      }
+    }
+    Ok(digest)
+  }
+
+  ///
+  /// Returns the Digest recorded for the given key, if any, and if its content is still present in
+  /// the local Store (in which case both the entry and the content are leased, as storing them
+  /// would have done).
+  ///
+  async fn cached_digest(&self, store: &Store, key: Fingerprint) -> Option<Digest> {
+    let digest = match self.lmdb.load_bytes_with(key, decode_digest).await {
+      Ok(Some(digest)) => digest,
+      Ok(None) => return None,
+      Err(e) => {
+        debug!("Failed to load cached file digest: {}", e);
+        return None;
+      }
+    };
+    match store.local.entry_type(digest.hash).await {
+      Ok(Some(_)) => (),
+      Ok(None) => return None,
+      Err(e) => {
+        debug!("Failed to check for cached file digest {:?}: {}", digest, e);
+        return None;
+      }
+    }
+    if let Err(e) = store
+      .local
+      .lease_all(std::iter::once((digest, EntryType::File)))
+      .await
+    {
+      debug!("{}", e);
+    }
+    if let Err(e) = self.lmdb.lease(key).await {
+      debug!("Failed to lease cached file digest {:?}: {}", digest, e);
+    }
+    Some(digest)
+  }
+
+  ///
+  /// Removes entries whose leases have expired.
+  ///
+  pub fn garbage_collect(&self) -> Result<(), String> {
+    self.remove_expired(SystemTime::now())
+  }
+
+  pub(crate) fn remove_expired(&self, now: SystemTime) -> Result<(), String> {
+    let now_secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut removed = 0;
+    for (env, database, lease_database) in self.lmdb.all_lmdbs() {
+      let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| format!("Failed to begin file digest cache transaction: {}", e))?;
+      let expired_keys = {
+        let mut cursor = txn
+          .open_ro_cursor(database)
+          .map_err(|e| format!("Failed to open file digest cache cursor: {}", e))?;
+        cursor
+          .iter()
+          .map(|(key, _)| key)
+          .filter(|key| {
+            let leased_until = match txn.get(lease_database, key) {
+              Ok(bytes) if bytes.len() == 8 => {
+                let mut array = [0_u8; 8];
+                array.copy_from_slice(bytes);
+                u64::from_le_bytes(array)
+              }
+              _ => 0,
+            };
+            leased_until < now_secs
+          })
+          .map(VersionedFingerprint::from_bytes_unsafe)
+          .collect::<Vec<_>>()
+      };
+      for key in &expired_keys {
+        for db in &[database, lease_database] {
+          match txn.del(*db, key, None) {
+            Ok(()) | Err(lmdb::Error::NotFound) => (),
+            Err(e) => return Err(format!("Failed to remove cached file digest: {}", e)),
+          }
+        }
+      }
+      txn
+        .commit()
+        .map_err(|e| format!("Failed to commit file digest cache removals: {}", e))?;
+      removed += expired_keys.len();
+    }
+    debug!(
+      "Removed {} expired entries from the file digest cache.",
+      removed
+    );
+    Ok(())
+  }
+
+  ///
+  /// Removes all entries.
+  ///
+  pub(crate) fn clear(&self) -> Result<(), String> {
+    for (env, database, lease_database) in self.lmdb.all_lmdbs() {
+      env
+        .begin_rw_txn()
+        .and_then(|mut txn| {
+          txn.clear_db(database)?;
+          txn.clear_db(lease_database)?;
+          txn.commit()
+        })
+        .map_err(|e| format!("Failed to clear the file digest cache: {}", e))?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Computes the cache key for the given path, or None if the file cannot be stat'd or was
+  /// modified too recently to be safely cached.
+  ///
+  async fn stat_key(&self, store: &Store, path: PathBuf) -> Option<Fingerprint> {
+    let racy_window = self.racy_window;
+    store
+      .local
+      .executor()
+      .spawn_blocking(move || match stat_key(&path, racy_window) {
+        Ok(key) => key,
+        Err(e) => {
+          debug!("Failed to stat {}: {}", path.display(), e);
+          None
+        }
+      })
+      .await
+  }
+
+  #[cfg(test)]
+  pub(crate) async fn lookup(&self, store: &Store, path: PathBuf) -> Option<Digest> {
+    let key = self.stat_key(store, path).await?;
+    self.cached_digest(store, key).await
+  }
+}
+
+fn stat_key(path: &Path, racy_window: Duration) -> Result<Option<Fingerprint>, io::Error> {
+  let metadata = std::fs::metadata(path)?;
+  if !metadata.is_file() {
+    return Ok(None);
+  }
+
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default();
+  if is_racy(metadata.mtime(), metadata.mtime_nsec(), now, racy_window)
+    || is_racy(metadata.ctime(), metadata.ctime_nsec(), now, racy_window)
+  {
+    return Ok(None);
+  }
+
+  let path_bytes = path.as_os_str().as_bytes();
+  let mut buf = BytesMut::with_capacity(path_bytes.len() + 9 * 8);
+  buf.put_slice(path_bytes);
+  buf.put_u64(metadata.dev());
+  buf.put_u64(metadata.ino());
+  buf.put_u64(metadata.size());
+  buf.put_u32(metadata.mode());
+  buf.put_i64(metadata.mtime());
+  buf.put_i64(metadata.mtime_nsec());
+  buf.put_i64(metadata.ctime());
+  buf.put_i64(metadata.ctime_nsec());
+  Ok(Some(Digest::of_bytes(&buf).hash))
+}
+
+fn encode_digest(digest: Digest) -> Bytes {
+  let mut buf = BytesMut::with_capacity(FINGERPRINT_SIZE + 8);
+  buf.put_slice(digest.hash.as_bytes());
+  buf.put_u64(digest.size_bytes as u64);
+  buf.freeze()
+}
+
+fn decode_digest(bytes: &[u8]) -> Result<Digest, String> {
+  if bytes.len() != FINGERPRINT_SIZE + 8 {
+    return Err(format!(
+      "Invalid cached file digest of length {}",
+      bytes.len()
+    ));
+  }
+  let mut size_bytes = [0; 8];
+  size_bytes.copy_from_slice(&bytes[FINGERPRINT_SIZE..]);
+  Ok(Digest::new(
+    Fingerprint::from_bytes_unsafe(&bytes[..FINGERPRINT_SIZE]),
+    u64::from_be_bytes(size_bytes) as usize,
+  ))
+}
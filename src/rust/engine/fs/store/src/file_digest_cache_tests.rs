@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use sharded_lmdb::DEFAULT_LEASE_TIME;
+use tempfile::TempDir;
+use testutil::data::TestData;
+
+use crate::{FileDigestCache, Store};
+
+fn setup(racy_window: Duration) -> (Store, FileDigestCache, TempDir) {
+  let dir = TempDir::new().unwrap();
+  let executor = task_executor::Executor::new();
+  let store = Store::local_only(executor.clone(), dir.path().join("store")).unwrap();
+  let cache = FileDigestCache::new_with_racy_window(
+    dir.path().join("file_digests"),
+    executor,
+    1,
+    racy_window,
+  )
+  .unwrap();
+  (store, cache, dir)
+}
+
+fn write_file(dir: &TempDir, data: &TestData) -> PathBuf {
+  let path = dir.path().join("file.txt");
+  std::fs::write(&path, data.bytes()).unwrap();
+  path
+}
+
+#[tokio::test]
+async fn stores_file() {
+  let (store, cache, dir) = setup(Duration::from_secs(0));
+  let path = write_file(&dir, &TestData::roland());
+
+  let digest = cache.store_file(&store, path, false).await.unwrap();
+  assert_eq!(digest, TestData::roland().digest());
+  assert_eq!(
+    store.load_file_bytes_with(digest, |b| b.to_vec()).await,
+    Ok(Some(TestData::roland().bytes().to_vec()))
+  );
+}
+
+#[tokio::test]
+async fn unchanged_file_is_cached() {
+  let (store, cache, dir) = setup(Duration::from_secs(0));
+  let path = write_file(&dir, &TestData::roland());
+
+  assert_eq!(cache.lookup(&store, path.clone()).await, None);
+  cache.store_file(&store, path.clone(), false).await.unwrap();
+  assert_eq!(
+    cache.lookup(&store, path).await,
+    Some(TestData::roland().digest())
+  );
+}
+
+#[tokio::test]
+async fn modified_file_is_rehashed() {
+  let (store, cache, dir) = setup(Duration::from_secs(0));
+  let path = write_file(&dir, &TestData::roland());
+  cache.store_file(&store, path.clone(), false).await.unwrap();
+
+  write_file(&dir, &TestData::catnip());
+  assert_eq!(cache.lookup(&store, path.clone()).await, None);
+  assert_eq!(
+    cache.store_file(&store, path, false).await.unwrap(),
+    TestData::catnip().digest()
+  );
+}
+
+#[tokio::test]
+async fn recently_modified_file_is_not_cached() {
+  let (store, cache, dir) = setup(Duration::from_secs(60 * 60));
+  let path = write_file(&dir, &TestData::roland());
+
+  assert_eq!(
+    cache.store_file(&store, path.clone(), false).await.unwrap(),
+    TestData::roland().digest()
+  );
+  assert_eq!(cache.lookup(&store, path).await, None);
+}
+
+#[tokio::test]
+async fn missing_content_is_restored() {
+  let (store, cache, dir) = setup(Duration::from_secs(0));
+  let path = write_file(&dir, &TestData::roland());
+  let digest = cache.store_file(&store, path.clone(), false).await.unwrap();
+
+  assert!(store.remove_file(digest).await.unwrap());
+  assert_eq!(cache.lookup(&store, path.clone()).await, None);
+  assert_eq!(cache.store_file(&store, path, false).await.unwrap(), digest);
+  assert_eq!(
+    store.load_file_bytes_with(digest, |b| b.to_vec()).await,
+    Ok(Some(TestData::roland().bytes().to_vec()))
+  );
+}
+
+#[tokio::test]
+async fn expired_entries_are_garbage_collected() {
+  let (store, cache, dir) = setup(Duration::from_secs(0));
+  let path = write_file(&dir, &TestData::roland());
+  cache.store_file(&store, path.clone(), false).await.unwrap();
+
+  // Entries which are still leased are retained.
+  cache.garbage_collect().unwrap();
+  assert_eq!(
+    cache.lookup(&store, path.clone()).await,
+    Some(TestData::roland().digest())
+  );
+
+  cache
+    .remove_expired(SystemTime::now() + DEFAULT_LEASE_TIME * 2)
+    .unwrap();
+  assert_eq!(cache.lookup(&store, path).await, None);
+}
+
+#[tokio::test]
+async fn cleared() {
+  let (store, cache, dir) = setup(Duration::from_secs(0));
+  let path = write_file(&dir, &TestData::roland());
+  cache.store_file(&store, path.clone(), false).await.unwrap();
+
+  cache.clear().unwrap();
+  assert_eq!(cache.lookup(&store, path.clone()).await, None);
+  // The cache is usable after having been cleared.
+  cache.store_file(&store, path.clone(), false).await.unwrap();
+  assert_eq!(
+    cache.lookup(&store, path).await,
+    Some(TestData::roland().digest())
+  );
+}
@@ -0,0 +1,324 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{Metadata, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use hashing::{Digest, Fingerprint, WriterHasher};
+use log::{debug, warn};
+use parking_lot::Mutex;
+
+///
+/// How the files of input Directories are materialized into sandboxes.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq, strum_macros::EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum MaterializeStrategy {
+  /// Copy the content of each file out of the local store.
+  Copy,
+  /// Hardlink each file from the immutable file cache. Hardlinked files share the read-only
+  /// cache entry, and so cannot be modified in place by a process without first making it
+  /// writable. Because a process could nonetheless do so, an entry whose `stat` has changed since
+  /// it was last verified is verified against its Digest again before it is next linked.
+  Hardlink,
+  /// Clone each file from the immutable file cache with a reflink (`FICLONE`), which creates an
+  /// independent copy-on-write file on filesystems which support it (such as btrfs or XFS).
+  Reflink,
+}
+
+///
+/// An on-disk cache of read-only files keyed by Digest, from which the files of input Directories
+/// are linked into sandboxes rather than being copied out of the local store. Files which cannot
+/// be linked (e.g. because the sandbox is on a different filesystem) are copied instead.
+///
+/// Entries are only created from content which has been verified against its Digest. The `stat`
+/// of each entry is recorded when it is verified, and an entry whose `stat` differs from the
+/// recorded one (including an entry created by a previous process) is verified again before it is
+/// used, and replaced if it was modified. Entries are removed when their content is garbage
+/// collected from the local store.
+///
+#[derive(Clone, Debug)]
+pub(crate) struct ImmutableFiles {
+  root: PathBuf,
+  strategy: MaterializeStrategy,
+  verified: Arc<Mutex<HashMap<PathBuf, StatKey>>>,
+}
+
+///
+/// The inode, size, mode, mtime and ctime of an entry.
+///
+type StatKey = (u64, u64, u32, i64, i64, i64, i64);
+
+fn stat_key(metadata: &Metadata) -> StatKey {
+  (
+    metadata.ino(),
+    metadata.size(),
+    metadata.mode(),
+    metadata.mtime(),
+    metadata.mtime_nsec(),
+    metadata.ctime(),
+    metadata.ctime_nsec(),
+  )
+}
+
+impl ImmutableFiles {
+  pub(crate) fn new(
+    root: PathBuf,
+    strategy: MaterializeStrategy,
+  ) -> Result<ImmutableFiles, String> {
+    std::fs::create_dir_all(&root).map_err(|e| {
+      format!(
+        "Failed to create immutable file cache at {}: {}",
+        root.display(),
+        e
+      )
+    })?;
+    Ok(ImmutableFiles {
+      root,
+      strategy,
+      verified: Arc::default(),
+    })
+  }
+
+  fn mode(is_executable: bool) -> u32 {
+    if is_executable {
+      0o555
+    } else {
+      0o444
+    }
+  }
+
+  ///
+  /// Executable and non-executable files have separate entries, because hardlinks share a mode.
+  ///
+  fn entry_path(&self, digest: Digest, is_executable: bool) -> PathBuf {
+    let hex = digest.hash.to_hex();
+    let suffix = if is_executable { ".x" } else { "" };
+    self
+      .root
+      .join(&hex[0..2])
+      .join(format!("{}-{}{}", hex, digest.size_bytes, suffix))
+  }
+
+  ///
+  /// Returns the path of the entry for the given Digest, if a valid one exists. An entry which has
+  /// been modified is removed.
+  ///
+  /// NB: Since this might re-hash the entry, it should be called on a blocking thread.
+  ///
+  pub(crate) fn existing_entry(
+    &self,
+    digest: Digest,
+    is_executable: bool,
+  ) -> Result<Option<PathBuf>, String> {
+    let path = self.entry_path(digest, is_executable);
+    let metadata = match std::fs::symlink_metadata(&path) {
+      Ok(metadata) => metadata,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(format!("Failed to stat {}: {}", path.display(), e)),
+    };
+    if metadata.is_file()
+      && metadata.len() == digest.size_bytes as u64
+      && metadata.permissions().mode() & 0o7777 == Self::mode(is_executable)
+    {
+      let stat = stat_key(&metadata);
+      if self.verified.lock().get(&path) == Some(&stat) {
+        return Ok(Some(path));
+      }
+      match hash_file(&path) {
+        Ok(actual_digest) if actual_digest == digest => {
+          self.verified.lock().insert(path.clone(), stat);
+          return Ok(Some(path));
+        }
+        Ok(_) => (),
+        Err(e) => debug!("Failed to verify {}: {}", path.display(), e),
+      }
+    }
+
+    warn!(
+      "Replacing modified entry {} in the immutable file cache.",
+      path.display()
+    );
+    self.verified.lock().remove(&path);
+    std::fs::remove_file(&path)
+      .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+    Ok(None)
+  }
+
+  ///
+  /// Creates the entry for the given Digest from the given bytes, after verifying them against
+  /// the Digest.
+  ///
+  pub(crate) fn create_entry(
+    &self,
+    digest: Digest,
+    is_executable: bool,
+    bytes: &[u8],
+  ) -> Result<PathBuf, String> {
+    let actual_digest = Digest::of_bytes(bytes);
+    if actual_digest != digest {
+      return Err(format!(
+        "Content for {:?} had digest {:?}, and so was not added to the immutable file cache.",
+        digest, actual_digest
+      ));
+    }
+
+    let path = self.entry_path(digest, is_executable);
+    let parent = path.parent().unwrap();
+    std::fs::create_dir_all(parent)
+      .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    // The entry is written to a temporary file which is then renamed into place, so that a
+    // partially written entry is never visible.
+    let mut tempfile = tempfile::NamedTempFile::new_in(parent)
+      .map_err(|e| format!("Failed to create a file in {}: {}", parent.display(), e))?;
+    tempfile
+      .write_all(bytes)
+      .and_then(|()| {
+        tempfile
+          .as_file()
+          .set_permissions(Permissions::from_mode(Self::mode(is_executable)))
+      })
+      .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    let stat = tempfile
+      .as_file()
+      .metadata()
+      .map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+    tempfile
+      .persist(&path)
+      .map_err(|e| format!("Failed to write {}: {}", path.display(), e.error))?;
+    self.verified.lock().insert(path.clone(), stat_key(&stat));
+    Ok(path)
+  }
+
+  ///
+  /// Returns true if entries can be linked into the given (existing) directory, by linking a probe
+  /// file into it. Neither hardlinks nor reflinks can cross filesystems, and not all filesystems
+  /// support reflinks: in those cases, files should be copied without being added to the cache.
+  ///
+  pub(crate) fn can_link_into(&self, directory: &Path) -> bool {
+    if self.strategy == MaterializeStrategy::Copy {
+      return false;
+    }
+    let probe_destination = directory.join(".immutable-file-link-probe");
+    let result = tempfile::NamedTempFile::new_in(&self.root)
+      .and_then(|mut probe| {
+        probe.write_all(b"probe")?;
+        Ok(probe)
+      })
+      .and_then(|probe| self.link(probe.path(), &probe_destination, false));
+    match result {
+      Ok(()) => {
+        let _ = std::fs::remove_file(&probe_destination);
+        true
+      }
+      Err(e) => {
+        debug!(
+          "Files cannot be linked from the immutable file cache into {}, and so will be copied: {}",
+          directory.display(),
+          e
+        );
+        false
+      }
+    }
+  }
+
+  ///
+  /// Links the given entry to the destination (which must not exist) according to the strategy of
+  /// this cache.
+  ///
+  pub(crate) fn link(
+    &self,
+    entry: &Path,
+    destination: &Path,
+    is_executable: bool,
+  ) -> io::Result<()> {
+    match self.strategy {
+      MaterializeStrategy::Copy => Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Files are not linked when using the copy strategy.",
+      )),
+      MaterializeStrategy::Hardlink => std::fs::hard_link(entry, destination),
+      MaterializeStrategy::Reflink => {
+        let source = std::fs::File::open(entry)?;
+        let destination_file = OpenOptions::new()
+          .create_new(true)
+          .write(true)
+          .mode(if is_executable { 0o755 } else { 0o644 })
+          .open(destination)?;
+        let result = reflink(&source, &destination_file);
+        if result.is_err() {
+          let _ = std::fs::remove_file(destination);
+        }
+        result
+      }
+    }
+  }
+
+  ///
+  /// Removes all entries whose content is not among the given fingerprints, i.e. whose content has
+  /// been garbage collected from the local store.
+  ///
+  pub(crate) fn retain(&self, fingerprints: &HashSet<Fingerprint>) -> Result<(), String> {
+    let read_dir = |dir: &Path| {
+      std::fs::read_dir(dir).map_err(|e| format!("Failed to list {}: {}", dir.display(), e))
+    };
+    for shard in read_dir(&self.root)? {
+      let shard = shard.map_err(|e| format!("Failed to list {}: {}", self.root.display(), e))?;
+      if !shard.path().is_dir() {
+        continue;
+      }
+      for entry in read_dir(&shard.path())? {
+        let entry = entry.map_err(|e| format!("Failed to list {}: {}", self.root.display(), e))?;
+        let file_name = entry.file_name();
+        // NB: Temporary files of entries which are being created are not parseable, and so are
+        // skipped.
+        let fingerprint = file_name
+          .to_str()
+          .and_then(|name| name.split('-').next())
+          .and_then(|hex| Fingerprint::from_hex_string(hex).ok());
+        match fingerprint {
+          Some(fingerprint) if !fingerprints.contains(&fingerprint) => {
+            self.verified.lock().remove(&entry.path());
+            std::fs::remove_file(entry.path())
+              .map_err(|e| format!("Failed to remove {}: {}", entry.path().display(), e))?;
+          }
+          _ => (),
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+fn hash_file(path: &Path) -> io::Result<Digest> {
+  let mut file = std::fs::File::open(path)?;
+  let mut hasher = WriterHasher::new(io::sink());
+  io::copy(&mut file, &mut hasher)?;
+  Ok(hasher.finish().0)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &std::fs::File, destination: &std::fs::File) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+
+  // `FICLONE` from linux/fs.h: `_IOW(0x94, 9, int)`.
+  const FICLONE: u64 = 0x4004_9409;
+  let res = unsafe { libc::ioctl(destination.as_raw_fd(), FICLONE as _, source.as_raw_fd()) };
+  if res == -1 {
+    Err(io::Error::last_os_error())
+  } else {
+    Ok(())
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &std::fs::File, _destination: &std::fs::File) -> io::Result<()> {
+  Err(io::Error::new(
+    io::ErrorKind::Other,
+    "Reflinks are only supported on Linux.",
+  ))
+}
@@ -29,32 +29,52 @@
 #![type_length_limit = "95595489"]
 #![recursion_limit = "256"]
 
+mod archive;
+#[cfg(test)]
+mod archive_tests;
+pub use crate::archive::{ArchiveFormat, ArchiveOptions};
+mod directory_digest_cache;
+pub use crate::directory_digest_cache::DirectoryDigestCache;
+mod file_digest_cache;
+#[cfg(test)]
+mod file_digest_cache_tests;
+pub use crate::file_digest_cache::{is_racy, FileDigestCache, RACY_WINDOW};
+mod immutable_files;
+pub use crate::immutable_files::MaterializeStrategy;
 mod snapshot;
-pub use crate::snapshot::{OneOffStoreFileByDigest, Snapshot, StoreFileByDigest};
+pub use crate::snapshot::{
+  NodePropertyOptions, OneOffStoreFileByDigest, Snapshot, StoreFileByDigest,
+};
 mod snapshot_ops;
 #[cfg(test)]
 mod snapshot_ops_tests;
 #[cfg(test)]
 mod snapshot_tests;
-pub use crate::snapshot_ops::{SnapshotOps, SnapshotOpsError, StoreWrapper, SubsetParams};
+pub use crate::snapshot_ops::{
+  DirectoryDiff, RenameParams, SnapshotOps, SnapshotOpsError, StoreWrapper, SubsetParams,
+  SubstituteParams,
+};
 
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::fs::OpenOptions;
 use std::io::{self, Read, Write};
-use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use async_trait::async_trait;
 use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
 use bazel_protos::require_digest;
 use bytes::Bytes;
 use double_checked_cell_async::DoubleCheckedCell;
-use fs::{default_cache_path, DigestEntry, FileContent, FileEntry, RelativePath};
+use filetime::FileTime;
+use fs::{default_cache_path, DigestEntry, FileContent, FileEntry, RelativePath, SymlinkEntry};
 use futures::future::{self, BoxFuture, Either, FutureExt, TryFutureExt};
 use grpc_util::prost::MessageExt;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::retry::{retry_call, status_is_retryable};
 use grpc_util::status_to_str;
 use hashing::Digest;
@@ -66,6 +86,7 @@ use sharded_lmdb::DEFAULT_LEASE_TIME;
 use tryfuture::try_future;
 use workunit_store::{get_workunit_store_handle, in_workunit, Level, Metric, WorkunitMetadata};
 
+use crate::immutable_files::ImmutableFiles;
 use crate::remote::ByteStoreError;
 
 const MEGABYTES: usize = 1024 * 1024;
@@ -162,6 +183,7 @@ impl RemoteStore {
 pub struct Store {
   local: local::ByteStore,
   remote: Option<RemoteStore>,
+  immutable_files: Option<ImmutableFiles>,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -197,6 +219,7 @@ impl Store {
     Ok(Store {
       local: local::ByteStore::new(executor, path)?,
       remote: None,
+      immutable_files: None,
     })
   }
 
@@ -208,6 +231,7 @@ impl Store {
     Ok(Store {
       local: local::ByteStore::new_with_options(executor, path, options)?,
       remote: None,
+      immutable_files: None,
     })
   }
 
@@ -221,9 +245,31 @@ impl Store {
     Store {
       local: self.local,
       remote: None,
+      immutable_files: self.immutable_files,
     }
   }
 
+  ///
+  /// Add an immutable file cache at the given path to a Store, from which the files of input
+  /// Directories are linked (according to the given strategy) by `materialize_input_directory`.
+  ///
+  pub fn into_with_immutable_files(
+    self,
+    path: PathBuf,
+    strategy: MaterializeStrategy,
+  ) -> Result<Store, String> {
+    let immutable_files = if strategy == MaterializeStrategy::Copy {
+      None
+    } else {
+      Some(ImmutableFiles::new(path, strategy)?)
+    };
+    Ok(Store {
+      local: self.local,
+      remote: self.remote,
+      immutable_files,
+    })
+  }
+
   ///
   /// Add remote storage to a Store. If it is missing a value which it tries to load, it will
   /// attempt to back-fill its local storage from the remote storage.
@@ -238,6 +284,7 @@ impl Store {
     upload_timeout: Duration,
     rpc_retries: usize,
     rpc_concurrency_limit: usize,
+    rpc_rate_limit: RateLimitConfig,
     capabilities_cell_opt: Option<Arc<DoubleCheckedCell<ServerCapabilities>>>,
     batch_api_size_limit: usize,
   ) -> Result<Store, String> {
@@ -252,9 +299,11 @@ impl Store {
         upload_timeout,
         rpc_retries,
         rpc_concurrency_limit,
+        rpc_rate_limit,
         capabilities_cell_opt,
         batch_api_size_limit,
       )?)),
+      immutable_files: self.immutable_files,
     })
   }
 
@@ -263,6 +312,13 @@ impl Store {
     default_cache_path().join("lmdb_store")
   }
 
+  ///
+  /// Returns true if the given file is present in the local store.
+  ///
+  pub async fn local_has_file(&self, digest: Digest) -> Result<bool, String> {
+    Ok(self.local.entry_type(digest.hash).await?.is_some())
+  }
+
   ///
   /// Remove a file locally, returning true if it existed, or false otherwise.
   ///
@@ -346,6 +402,29 @@ impl Store {
     .await
   }
 
+  ///
+  /// Extracts the archive with the given file Digest into a Directory, and returns its Digest.
+  ///
+  pub async fn extract_archive(
+    &self,
+    archive_digest: Digest,
+    format: ArchiveFormat,
+  ) -> Result<Digest, String> {
+    archive::extract_archive(self, archive_digest, format).await
+  }
+
+  ///
+  /// Creates an archive of the Directory with the given Digest, and returns the Digest of the
+  /// archive file.
+  ///
+  pub async fn create_archive(
+    &self,
+    digest: Digest,
+    options: ArchiveOptions,
+  ) -> Result<Digest, String> {
+    archive::create_archive(self, digest, options).await
+  }
+
   ///
   /// Loads the bytes of the file with the passed fingerprint from the local store and back-fill
   /// from remote when necessary and possible (i.e. when remote is configured), and returns the
@@ -811,10 +890,23 @@ impl Store {
             size
           )
         }
-        Ok(())
       }
-      Err(err) => Err(format!("Garbage collection failed: {:?}", err)),
+      Err(err) => return Err(format!("Garbage collection failed: {:?}", err)),
     }
+
+    // Remove the entries of the immutable file cache whose content was garbage collected.
+    if let Some(immutable_files) = &self.immutable_files {
+      let fingerprints = self
+        .local
+        .all_digests(EntryType::File)?
+        .into_iter()
+        .map(|digest| digest.hash)
+        .collect::<HashSet<_>>();
+      immutable_files
+        .retain(&fingerprints)
+        .map_err(|err| format!("Garbage collection failed: {}", err))?;
+    }
+    Ok(())
   }
 
   ///
@@ -922,19 +1014,85 @@ impl Store {
   }
 
   ///
-  /// Lays out the directory and all of its contents (files and directories) on disk so that a
-  /// process which uses the directory structure can run.
+  /// Lays out the directory and all of its contents (files, directories and symlinks) on disk so
+  /// that a process which uses the directory structure can run.
   ///
   /// Although `Directory` has internally unique paths, `materialize_directory` can be used with
   /// an existing destination directory, meaning that directory and file creation must be
   /// idempotent.
   ///
+  /// Fails before anything is materialized if the target of any symlink would resolve outside of
+  /// the destination. Symlinks are validated here rather than when a Directory is stored, because
+  /// whether a target escapes depends on where the Directory is nested (which `strip_prefix` and
+  /// `add_prefix` change).
+  ///
   pub fn materialize_directory(
     &self,
     destination: PathBuf,
     digest: Digest,
   ) -> BoxFuture<'static, Result<(), String>> {
-    self.materialize_directory_helper(destination, true, digest)
+    let store = self.clone();
+    async move {
+      store.check_symlinks(digest).await?;
+      store
+        .materialize_directory_helper(destination, true, digest, false)
+        .await
+    }
+    .boxed()
+  }
+
+  ///
+  /// Like `materialize_directory`, but for the inputs of a sandbox: if this Store has an immutable
+  /// file cache, files are linked from it rather than being copied, and so might be read-only.
+  ///
+  /// Whether files can be linked into the destination at all is checked once up front, so that
+  /// files are not added to the cache only to be copied (e.g. across filesystems).
+  ///
+  pub fn materialize_input_directory(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+  ) -> BoxFuture<'static, Result<(), String>> {
+    let store = self.clone();
+    async move {
+      store.check_symlinks(digest).await?;
+      let link_immutable_files = match store.immutable_files.clone() {
+        Some(immutable_files) => {
+          let destination = destination.clone();
+          store
+            .local
+            .executor()
+            .spawn_blocking(move || {
+              fs::safe_create_dir_all(&destination)?;
+              Ok::<_, String>(immutable_files.can_link_into(&destination))
+            })
+            .await?
+        }
+        None => false,
+      };
+      store
+        .materialize_directory_helper(destination, true, digest, link_immutable_files)
+        .await
+    }
+    .boxed()
+  }
+
+  ///
+  /// Validates the targets of the symlinks in the given Directory, which (unlike symlinks captured
+  /// from disk) might have been created remotely, or moved relative to one another.
+  ///
+  async fn check_symlinks(&self, digest: Digest) -> Result<(), String> {
+    let entries = self.entries_for_directory(digest).await?;
+    let symlinks = entries
+      .iter()
+      .filter_map(|entry| match entry {
+        DigestEntry::Symlink(symlink_entry) => {
+          Some((symlink_entry.path.as_path(), symlink_entry.target.as_path()))
+        }
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    snapshot::check_symlinks(&symlinks, "materialized directory")
   }
 
   fn materialize_directory_helper(
@@ -942,6 +1100,7 @@ impl Store {
     destination: PathBuf,
     is_root: bool,
     digest: Digest,
+    link_immutable_files: bool,
   ) -> BoxFuture<'static, Result<(), String>> {
     let store = self.clone();
     async move {
@@ -975,9 +1134,28 @@ impl Store {
           let store = store.clone();
           let path = destination.join(file_node.name.clone());
           let digest = try_future!(require_digest(file_node.digest.as_ref()));
-          store
-            .materialize_file(path, digest, file_node.is_executable)
-            .boxed()
+          match &store.immutable_files {
+            // NB: Files with NodeProperties are always copied, since their properties would
+            // otherwise be shared with other links to the same file.
+            Some(immutable_files)
+              if link_immutable_files && file_node.node_properties.is_none() =>
+            {
+              store.materialize_linked_file(
+                immutable_files.clone(),
+                path,
+                digest,
+                file_node.is_executable,
+              )
+            }
+            _ => store
+              .materialize_file(
+                path,
+                digest,
+                file_node.is_executable,
+                file_node.node_properties.clone(),
+              )
+              .boxed(),
+          }
         })
         .collect::<Vec<_>>();
       let directory_futures = directory
@@ -988,12 +1166,21 @@ impl Store {
           let path = destination.join(directory_node.name.clone());
           let digest = try_future!(require_digest(directory_node.digest.as_ref()));
 
-          store.materialize_directory_helper(path, false, digest)
+          store.materialize_directory_helper(path, false, digest, link_immutable_files)
         })
         .collect::<Vec<_>>();
-      let _ = future::try_join(
+      let symlink_futures = directory
+        .symlinks
+        .iter()
+        .map(|symlink_node| {
+          let path = destination.join(symlink_node.name.clone());
+          store.materialize_symlink(path, symlink_node.target.clone())
+        })
+        .collect::<Vec<_>>();
+      let _ = future::try_join3(
         future::try_join_all(file_futures),
         future::try_join_all(directory_futures),
+        future::try_join_all(symlink_futures),
       )
       .map(|r| r.map(|_| ()))
       .await?;
@@ -1002,11 +1189,16 @@ impl Store {
     .boxed()
   }
 
+  ///
+  /// Writes the file with the given Digest to the destination. If NodeProperties are given, the
+  /// file's mode (which takes precedence over `is_executable`) and mtime are set from them.
+  ///
   fn materialize_file(
     &self,
     destination: PathBuf,
     digest: Digest,
     is_executable: bool,
+    node_properties: Option<remexec::NodeProperties>,
   ) -> BoxFuture<'static, Result<(), String>> {
     let store = self.clone();
     let res = async move {
@@ -1030,6 +1222,9 @@ impl Store {
             })?;
           f.write_all(bytes)
             .map_err(|e| format!("Error writing file {}: {:?}", destination.display(), e))?;
+          if let Some(node_properties) = &node_properties {
+            Self::apply_node_properties(&destination, &f, node_properties)?;
+          }
           Ok(())
         })
         .await?;
@@ -1042,6 +1237,122 @@ impl Store {
     res.boxed()
   }
 
+  ///
+  /// Links the file with the given Digest to the destination from the given immutable file cache,
+  /// first creating its entry if need be. Falls back to copying the file if it cannot be linked.
+  ///
+  fn materialize_linked_file(
+    &self,
+    immutable_files: ImmutableFiles,
+    destination: PathBuf,
+    digest: Digest,
+    is_executable: bool,
+  ) -> BoxFuture<'static, Result<(), String>> {
+    let store = self.clone();
+    async move {
+      let executor = store.local.executor().clone();
+      let link_result: Result<(), String> = async {
+        let immutable_files2 = immutable_files.clone();
+        let existing_entry = executor
+          .spawn_blocking(move || immutable_files2.existing_entry(digest, is_executable))
+          .await?;
+        let entry = match existing_entry {
+          Some(entry) => entry,
+          None => {
+            let immutable_files2 = immutable_files.clone();
+            store
+              .load_file_bytes_with(digest, move |bytes| {
+                immutable_files2.create_entry(digest, is_executable, bytes)
+              })
+              .await?
+              .ok_or_else(|| format!("File with digest {:?} not found", digest))??
+          }
+        };
+        let destination2 = destination.clone();
+        executor
+          .spawn_blocking(move || {
+            if destination2.symlink_metadata().is_ok() {
+              std::fs::remove_file(&destination2)?;
+            }
+            immutable_files.link(&entry, &destination2, is_executable)
+          })
+          .await
+          .map_err(|e| e.to_string())
+      }
+      .await;
+
+      if let Err(e) = link_result {
+        log::debug!(
+          "Failed to link {} from the immutable file cache, and so copying it instead: {}",
+          destination.display(),
+          e
+        );
+        store
+          .materialize_file(destination, digest, is_executable, None)
+          .await?;
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+
+  fn apply_node_properties(
+    destination: &Path,
+    f: &std::fs::File,
+    node_properties: &remexec::NodeProperties,
+  ) -> Result<(), String> {
+    if let Some(mtime) = node_properties.mtime.clone() {
+      let mtime = SystemTime::try_from(mtime)
+        .map_err(|e| format!("Invalid mtime for file {}: {:?}", destination.display(), e))?;
+      filetime::set_file_handle_times(f, None, Some(FileTime::from_system_time(mtime))).map_err(
+        |e| {
+          format!(
+            "Error setting mtime of file {}: {:?}",
+            destination.display(),
+            e
+          )
+        },
+      )?;
+    }
+    if let Some(unix_mode) = node_properties.unix_mode {
+      // NB: Unlike the mode given when the file is created, this is not subject to the umask.
+      f.set_permissions(std::fs::Permissions::from_mode(unix_mode & 0o7777))
+        .map_err(|e| {
+          format!(
+            "Error setting mode of file {}: {:?}",
+            destination.display(),
+            e
+          )
+        })?;
+    }
+    Ok(())
+  }
+
+  fn materialize_symlink(
+    &self,
+    destination: PathBuf,
+    target: String,
+  ) -> BoxFuture<'static, Result<(), String>> {
+    self
+      .local
+      .executor()
+      .spawn_blocking(move || {
+        if destination.symlink_metadata().is_ok() {
+          std::fs::remove_file(&destination)
+            .map_err(|e| format!("Failed to overwrite {}: {:?}", destination.display(), e))?;
+        }
+        std::os::unix::fs::symlink(&target, &destination).map_err(|e| {
+          format!(
+            "Failed to create symlink {} -> {}: {:?}",
+            destination.display(),
+            target,
+            e
+          )
+        })
+      })
+      .boxed()
+  }
+
   ///
   /// Returns files sorted by their path.
   ///
@@ -1092,7 +1403,8 @@ impl Store {
   }
 
   ///
-  /// Returns indirect references to files in a Digest sorted by their path.
+  /// Returns indirect references to files, the targets of symlinks, and empty directories in a
+  /// Digest sorted by their path.
   ///
   pub fn entries_for_directory(
     &self,
@@ -1100,7 +1412,7 @@ impl Store {
   ) -> BoxFuture<'static, Result<Vec<DigestEntry>, String>> {
     self
       .walk(digest, move |_, path_so_far, _, directory| {
-        if directory.files.is_empty() {
+        if directory.files.is_empty() && directory.symlinks.is_empty() {
           // Only report an empty directory if the directory is a leaf node. (The caller is
           // expected to create parent directories for both files and empty leaf
           // directories.)
@@ -1110,23 +1422,23 @@ impl Store {
             future::ready(Ok(vec![])).boxed()
           }
         } else {
-          future::ready(
-            directory
-              .files
-              .iter()
-              .map(|file_node| {
-                let path = path_so_far.join(file_node.name.clone());
-                let is_executable = file_node.is_executable;
-                let digest = require_digest(file_node.digest.as_ref())?;
-                Ok(DigestEntry::File(FileEntry {
-                  path,
-                  digest,
-                  is_executable,
-                }))
-              })
-              .collect::<Result<Vec<_>, String>>(),
-          )
-          .boxed()
+          let files = directory.files.iter().map(|file_node| {
+            let path = path_so_far.join(file_node.name.clone());
+            let is_executable = file_node.is_executable;
+            let digest = require_digest(file_node.digest.as_ref())?;
+            Ok(DigestEntry::File(FileEntry {
+              path,
+              digest,
+              is_executable,
+            }))
+          });
+          let symlinks = directory.symlinks.iter().map(|symlink_node| {
+            Ok(DigestEntry::Symlink(SymlinkEntry {
+              path: path_so_far.join(symlink_node.name.clone()),
+              target: PathBuf::from(symlink_node.target.clone()),
+            }))
+          });
+          future::ready(files.chain(symlinks).collect::<Result<Vec<_>, String>>()).boxed()
         }
       })
       .map(|file_contents_per_directory| {
@@ -1264,6 +1576,10 @@ impl StoreWrapper for Store {
   async fn record_directory(&self, directory: &remexec::Directory) -> Result<Digest, String> {
     Store::record_directory(self, directory, true).await
   }
+
+  async fn store_file_bytes(&self, bytes: Bytes) -> Result<Digest, String> {
+    Store::store_file_bytes(self, bytes, true).await
+  }
 }
 
 // Only public for testing.
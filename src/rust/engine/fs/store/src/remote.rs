@@ -12,6 +12,7 @@ use bytes::{Bytes, BytesMut};
 use double_checked_cell_async::DoubleCheckedCell;
 use futures::Future;
 use futures::StreamExt;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::retry::{retry_call, status_is_retryable};
 use grpc_util::{headers_to_http_header_map, layered_service, status_to_str, LayeredService};
 use hashing::Digest;
@@ -76,6 +77,7 @@ impl ByteStore {
     upload_timeout: Duration,
     rpc_retries: usize,
     rpc_concurrency_limit: usize,
+    rpc_rate_limit: RateLimitConfig,
     capabilities_cell_opt: Option<Arc<DoubleCheckedCell<ServerCapabilities>>>,
     batch_api_size_limit: usize,
   ) -> Result<ByteStore, String> {
@@ -91,6 +93,7 @@ impl ByteStore {
     let channel = layered_service(
       tonic::transport::Channel::balance_list(vec![endpoint].into_iter()),
       rpc_concurrency_limit,
+      rpc_rate_limit,
       http_headers,
     );
 
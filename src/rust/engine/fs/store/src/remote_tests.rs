@@ -2,6 +2,7 @@ use std::collections::{BTreeMap, HashSet};
 use std::time::Duration;
 
 use bytes::Bytes;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::tls;
 use hashing::Digest;
 use mock::StubCAS;
@@ -159,6 +160,7 @@ async fn write_file_multiple_chunks() {
     Duration::from_secs(5),
     1,
     256,
+    RateLimitConfig::default(),
     None,
     0, // disable batch API, force streaming API
   )
@@ -231,6 +233,7 @@ async fn write_connection_error() {
     Duration::from_secs(1),
     1,
     256,
+    RateLimitConfig::default(),
     None,
     super::tests::STORE_BATCH_API_SIZE_LIMIT,
   )
@@ -309,6 +312,7 @@ fn new_byte_store(cas: &StubCAS) -> ByteStore {
     Duration::from_secs(1),
     1,
     256,
+    RateLimitConfig::default(),
     None,
     super::tests::STORE_BATCH_API_SIZE_LIMIT,
   )
@@ -1,23 +1,27 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsString;
 use std::fmt;
 use std::iter::Iterator;
-use std::path::{Path, PathBuf};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
 use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
 use fs::{
-  Dir, File, GitignoreStyleExcludes, GlobMatching, PathStat, PosixFS, PreparedPathGlobs,
-  SymlinkBehavior,
+  Dir, File, GitignoreStyleExcludes, GlobMatching, Link, PathMatchOptions, PathStat, PosixFS,
+  PreparedPathGlobs, StrictGlobMatching, SymlinkBehavior,
 };
 use futures::future;
 use futures::FutureExt;
 use hashing::{Digest, EMPTY_DIGEST};
 use itertools::Itertools;
+use log::warn;
 
-use crate::Store;
+use crate::directory_digest_cache::DirectoryKeys;
+use crate::{DirectoryDigestCache, Store};
 
 #[derive(Eq, Hash, PartialEq)]
 pub struct Snapshot {
@@ -42,11 +46,147 @@ impl Snapshot {
     path_stats: Vec<PathStat>,
   ) -> Result<Snapshot, String> {
     let path_stats = PathStat::normalize_path_stats(path_stats)?;
-    let digest =
-      Snapshot::ingest_directory_from_sorted_path_stats(store, file_digester, &path_stats).await?;
+    check_path_stat_symlinks(&path_stats)?;
+    let digest = Snapshot::ingest_directory_from_sorted_path_stats(
+      store,
+      file_digester,
+      None,
+      PathBuf::new(),
+      &path_stats,
+    )
+    .await?;
+    Ok(Snapshot { digest, path_stats })
+  }
+
+  ///
+  /// Like `from_path_stats`, but reuses the Digests of directories whose subtrees are unchanged
+  /// since they were previously recorded via the given DirectoryDigestCache (see its docs).
+  ///
+  pub async fn from_path_stats_with_cache<
+    S: StoreFileByDigest<Error> + Sized + Clone + Send + 'static,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    store: Store,
+    file_digester: S,
+    directory_digest_cache: DirectoryDigestCache,
+    path_stats: Vec<PathStat>,
+  ) -> Result<Snapshot, String> {
+    let path_stats = PathStat::normalize_path_stats(path_stats)?;
+    check_path_stat_symlinks(&path_stats)?;
+    let directory_keys = directory_digest_cache
+      .keys(&file_digester, &path_stats)
+      .await?;
+    let digest = match directory_keys.cached_digest(&store, Path::new("")).await {
+      Some(digest) => digest,
+      None => {
+        Snapshot::ingest_directory_from_sorted_path_stats(
+          store,
+          file_digester,
+          Some(directory_keys),
+          PathBuf::new(),
+          &path_stats,
+        )
+        .await?
+      }
+    };
     Ok(Snapshot { digest, path_stats })
   }
 
+  ///
+  /// Checks for PathStats whose paths would refer to the same file on a filesystem which compares
+  /// paths according to the given options (such as a case-insensitive filesystem), and fails or
+  /// warns about them according to the given behavior.
+  ///
+  pub fn check_path_collisions(
+    path_stats: &[PathStat],
+    match_options: PathMatchOptions,
+    behavior: &StrictGlobMatching,
+  ) -> Result<(), String> {
+    if !match_options.is_inexact() || !behavior.should_check_glob_matches() {
+      return Ok(());
+    }
+
+    // Paths collide if any of their ancestors do, so every ancestor of each path is checked too.
+    let mut paths_by_key: BTreeMap<String, Vec<&Path>> = BTreeMap::new();
+    for path_stat in path_stats {
+      for path in path_stat.path().ancestors() {
+        if path.as_os_str().is_empty() {
+          continue;
+        }
+        let paths = paths_by_key
+          .entry(match_options.collision_key(path))
+          .or_default();
+        if !paths.contains(&path) {
+          paths.push(path);
+        }
+      }
+    }
+    // A collision between paths with different parents is implied by a collision between their
+    // parents, and so only the latter is reported.
+    let collisions = paths_by_key
+      .into_iter()
+      .filter(|(_, paths)| {
+        paths.len() > 1 && paths.iter().all(|path| path.parent() == paths[0].parent())
+      })
+      .map(|(_, mut paths)| {
+        paths.sort();
+        paths
+      })
+      .collect::<Vec<_>>();
+    if collisions.is_empty() {
+      return Ok(());
+    }
+
+    let origin = match behavior {
+      StrictGlobMatching::Warn(description) | StrictGlobMatching::Error(description) => {
+        format!(" from {}", description)
+      }
+      StrictGlobMatching::Ignore => "".to_owned(),
+    };
+    let collisions = collisions
+      .iter()
+      .map(|paths| {
+        format!(
+          "{:?} differ only by {}",
+          paths,
+          Self::collision_difference(paths, match_options)
+        )
+      })
+      .collect::<Vec<_>>();
+    let msg = format!(
+      "Paths{} would collide on a filesystem which does not distinguish them: {}",
+      origin,
+      collisions.join("; ")
+    );
+    if behavior.should_throw_on_error() {
+      Err(msg)
+    } else {
+      warn!("{}", msg);
+      Ok(())
+    }
+  }
+
+  ///
+  /// Describes how the given colliding paths differ, given the options that they collide under.
+  ///
+  fn collision_difference(paths: &[&Path], match_options: PathMatchOptions) -> &'static str {
+    let collide_under = |match_options: PathMatchOptions| {
+      let key = match_options.collision_key(paths[0]);
+      paths
+        .iter()
+        .all(|path| match_options.collision_key(path) == key)
+    };
+    if !match_options.case_insensitive || collide_under(PathMatchOptions::new(false, true)) {
+      "Unicode normalization"
+    } else if !match_options.unicode_normalization
+      || collide_under(PathMatchOptions::new(true, false))
+    {
+      "case"
+    } else {
+      "case and Unicode normalization"
+    }
+  }
+
   pub async fn from_digest(store: Store, digest: Digest) -> Result<Snapshot, String> {
     let path_stats_per_directory = store
       .walk(digest, |_, path_so_far, _, directory| {
@@ -65,6 +205,14 @@ impl Snapshot {
             },
           )
         }));
+        path_stats.extend(directory.symlinks.iter().map(move |symlink_node| {
+          let path = path_so_far.join(symlink_node.name.clone());
+          PathStat::link(
+            path.clone(),
+            Link(path),
+            PathBuf::from(symlink_node.target.clone()),
+          )
+        }));
         future::ok(path_stats).boxed()
       })
       .await?;
@@ -85,7 +233,15 @@ impl Snapshot {
     path_stats: Vec<PathStat>,
   ) -> Result<Digest, String> {
     let path_stats = PathStat::normalize_path_stats(path_stats)?;
-    Snapshot::ingest_directory_from_sorted_path_stats(store, file_digester, &path_stats).await
+    check_path_stat_symlinks(&path_stats)?;
+    Snapshot::ingest_directory_from_sorted_path_stats(
+      store,
+      file_digester,
+      None,
+      PathBuf::new(),
+      &path_stats,
+    )
+    .await
   }
 
   // NB: This function is recursive, and so cannot be directly marked async:
@@ -96,9 +252,12 @@ impl Snapshot {
   >(
     store: Store,
     file_digester: S,
+    directory_keys: Option<DirectoryKeys>,
+    directory_path: PathBuf,
     path_stats: &[PathStat],
   ) -> future::BoxFuture<'static, Result<Digest, String>> {
     let mut file_futures = Vec::new();
+    let mut symlinks = Vec::new();
     let mut dir_futures: Vec<future::BoxFuture<'static, Result<remexec::DirectoryNode, String>>> =
       Vec::new();
 
@@ -120,15 +279,26 @@ impl Snapshot {
             let is_executable = stat.is_executable;
             let stat = stat.clone();
             let file_digester = file_digester.clone();
+            let digested_file = directory_keys
+              .as_ref()
+              .and_then(|directory_keys| directory_keys.file(&stat.path));
             file_futures.push(async move {
-              let digest_future = file_digester.store_by_digest(stat);
-              let digest = digest_future.await.map_err(|e| format!("{:?}", e))?;
+              let (digest, node_properties) = match digested_file {
+                Some(digested_file) => digested_file,
+                None => {
+                  let node_properties_future = file_digester.node_properties(&stat);
+                  let digest_future = file_digester.store_by_digest(stat);
+                  future::try_join(digest_future, node_properties_future)
+                    .await
+                    .map_err(|e| format!("{:?}", e))?
+                }
+              };
 
               let file_node = remexec::FileNode {
                 name: osstring_as_utf8(first_component)?,
                 digest: Some((&digest).into()),
                 is_executable,
-                ..remexec::FileNode::default()
+                node_properties,
               };
               Ok(file_node)
             });
@@ -147,18 +317,54 @@ impl Snapshot {
               Ok(directory_node)
             }));
           }
+          PathStat::Link { ref target, .. } => {
+            let target = match symlink_target(
+              &directory_path.join(&first_component),
+              target,
+              "snapshot root",
+            ) {
+              Ok(target) => target,
+              Err(e) => return future::err(e).boxed(),
+            };
+            let name = match osstring_as_utf8(first_component) {
+              Ok(name) => name,
+              Err(e) => return future::err(e).boxed(),
+            };
+            symlinks.push(remexec::SymlinkNode {
+              name,
+              target,
+              ..remexec::SymlinkNode::default()
+            });
+          }
         }
       } else {
         let store = store.clone();
         let file_digester = file_digester.clone();
+        let directory_keys = directory_keys.clone();
+        let child_directory_path = directory_path.join(&first_component);
         dir_futures.push(Box::pin(async move {
+          let cached_digest = match directory_keys {
+            Some(ref directory_keys) => {
+              directory_keys
+                .cached_digest(&store, &child_directory_path)
+                .await
+            }
+            None => None,
+          };
           // TODO: Memoize this in the graph
-          let digest = Snapshot::ingest_directory_from_sorted_path_stats(
-            store,
-            file_digester,
-            &paths_of_child_dir(path_group),
-          )
-          .await?;
+          let digest = match cached_digest {
+            Some(digest) => digest,
+            None => {
+              Snapshot::ingest_directory_from_sorted_path_stats(
+                store,
+                file_digester,
+                directory_keys,
+                child_directory_path,
+                &paths_of_child_dir(path_group),
+              )
+              .await?
+            }
+          };
 
           let dir_node = remexec::DirectoryNode {
             name: osstring_as_utf8(first_component)?,
@@ -179,9 +385,14 @@ impl Snapshot {
       let directory = remexec::Directory {
         directories: dirs,
         files,
+        symlinks,
         ..remexec::Directory::default()
       };
-      store.record_directory(&directory, true).await
+      let digest = store.record_directory(&directory, true).await?;
+      if let Some(directory_keys) = directory_keys {
+        directory_keys.insert(&directory_path, digest);
+      }
+      Ok(digest)
     }
     .boxed()
   }
@@ -332,11 +543,101 @@ fn paths_of_child_dir(paths: Vec<PathStat>) -> Vec<PathStat> {
           path: path.iter().skip(1).collect(),
           stat,
         },
+        PathStat::Link { path, stat, target } => PathStat::Link {
+          path: path.iter().skip(1).collect(),
+          stat,
+          target,
+        },
       })
     })
     .collect()
 }
 
+///
+/// Validates that the target of a symlink at the given (normalized) path is relative, and resolves
+/// within the root that the path is relative to (which is described by `root` in errors).
+///
+/// NB: This only validates the target lexically: see `check_symlinks` for targets which traverse
+/// other symlinks.
+///
+pub(crate) fn symlink_target(path: &Path, target: &Path, root: &str) -> Result<String, String> {
+  resolve_symlink(path, target, root, |_| false)?;
+  target
+    .to_str()
+    .map(str::to_owned)
+    .ok_or_else(|| format!("Symlink {} had a non-UTF8 target.", path.display()))
+}
+
+///
+/// Validates the targets of the given symlinks (as `symlink_target` does), and additionally
+/// rejects targets which traverse any of the other symlinks. Because the filesystem resolves each
+/// traversed symlink, such a target could escape the root although it does not lexically: for
+/// example, `d/x -> ../l/..` escapes via `l -> .`.
+///
+pub(crate) fn check_symlinks(symlinks: &[(&Path, &Path)], root: &str) -> Result<(), String> {
+  let paths = symlinks
+    .iter()
+    .map(|(path, _)| *path)
+    .collect::<HashSet<_>>();
+  for (path, target) in symlinks {
+    resolve_symlink(path, target, root, |dir| paths.contains(dir))?;
+  }
+  Ok(())
+}
+
+fn check_path_stat_symlinks(path_stats: &[PathStat]) -> Result<(), String> {
+  let symlinks = path_stats
+    .iter()
+    .filter_map(|path_stat| match path_stat {
+      PathStat::Link { path, target, .. } => Some((path.as_path(), target.as_path())),
+      _ => None,
+    })
+    .collect::<Vec<_>>();
+  check_symlinks(&symlinks, "snapshot root")
+}
+
+///
+/// Lexically resolves the target of the symlink at the given path, failing if it escapes the root
+/// or if it traverses a directory for which `is_symlink` returns true.
+///
+fn resolve_symlink(
+  path: &Path,
+  target: &Path,
+  root: &str,
+  is_symlink: impl Fn(&Path) -> bool,
+) -> Result<PathBuf, String> {
+  let escapes = || {
+    format!(
+      "Symlink {} -> {} refers to a path outside of the {}.",
+      path.display(),
+      target.display(),
+      root
+    )
+  };
+  let mut resolved = path.parent().map(Path::to_owned).unwrap_or_default();
+  for component in target.components() {
+    if component != Component::CurDir && is_symlink(&resolved) {
+      return Err(format!(
+        "Symlink {} -> {} traverses the symlink {}, which is not supported.",
+        path.display(),
+        target.display(),
+        resolved.display()
+      ));
+    }
+    match component {
+      Component::Normal(name) => resolved.push(name),
+      Component::CurDir => (),
+      Component::ParentDir => {
+        if !resolved.pop() {
+          return Err(escapes());
+        }
+      }
+      Component::RootDir | Component::Prefix(_) => return Err(escapes()),
+    }
+  }
+  Ok(resolved)
+}
+
 pub fn osstring_as_utf8(path: OsString) -> Result<String, String> {
   path
     .into_string()
@@ -349,6 +650,82 @@ pub fn osstring_as_utf8(path: OsString) -> Result<String, String> {
 // to store the bytes) and Vfs (used to read the files off disk if needed).
 pub trait StoreFileByDigest<Error> {
   fn store_by_digest(&self, file: File) -> future::BoxFuture<'static, Result<Digest, Error>>;
+
+  ///
+  /// Returns the NodeProperties (beyond its executable bit) to record for the given File. By
+  /// default, none are recorded.
+  ///
+  fn node_properties(
+    &self,
+    _file: &File,
+  ) -> future::BoxFuture<'static, Result<Option<remexec::NodeProperties>, Error>>
+  where
+    Error: Send + 'static,
+  {
+    future::ok(None).boxed()
+  }
+}
+
+///
+/// Which optional NodeProperties of files to capture when constructing a Snapshot from the
+/// filesystem.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct NodePropertyOptions {
+  pub unix_mode: bool,
+  pub mtime: bool,
+}
+
+impl NodePropertyOptions {
+  ///
+  /// Parses options from the names of the NodeProperties to capture.
+  ///
+  pub fn from_names(names: &[String]) -> Result<NodePropertyOptions, String> {
+    let mut options = NodePropertyOptions::default();
+    for name in names {
+      match name.as_str() {
+        "unix_mode" => options.unix_mode = true,
+        "mtime" => options.mtime = true,
+        _ => {
+          return Err(format!(
+            "Unsupported node property `{}`: expected one of `unix_mode` or `mtime`.",
+            name
+          ))
+        }
+      }
+    }
+    Ok(options)
+  }
+
+  ///
+  /// The NodeProperties selected by these options for the file at the given path, if any. This
+  /// blocks to stat the file if any NodeProperties are selected.
+  ///
+  pub fn node_properties(&self, path: &Path) -> Result<Option<remexec::NodeProperties>, String> {
+    if *self == NodePropertyOptions::default() {
+      return Ok(None);
+    }
+    let metadata =
+      std::fs::metadata(path).map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+    let unix_mode = if self.unix_mode {
+      Some(metadata.permissions().mode() & 0o7777)
+    } else {
+      None
+    };
+    let mtime = if self.mtime {
+      let modified = metadata
+        .modified()
+        .map_err(|e| format!("Failed to read mtime of {}: {}", path.display(), e))?;
+      Some(modified.into())
+    } else {
+      None
+    };
+    Ok(Some(remexec::NodeProperties {
+      unix_mode,
+      mtime,
+      ..remexec::NodeProperties::default()
+    }))
+  }
 }
 
 ///
@@ -1,7 +1,7 @@
 // Copyright 2020 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::convert::From;
 use std::iter::Iterator;
 use std::path::{Path, PathBuf};
@@ -10,19 +10,23 @@ use std::sync::Arc;
 use async_trait::async_trait;
 use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
 use bazel_protos::require_digest;
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use fs::{
-  ExpandablePathGlobs, GitignoreStyleExcludes, PathGlob, PreparedPathGlobs, RelativePath,
-  DOUBLE_STAR_GLOB, SINGLE_STAR_GLOB,
+  ExpandablePathGlobs, GitignoreStyleExcludes, PathGlob, PathMatchOptions, PreparedPathGlobs,
+  RelativePath, DOUBLE_STAR_GLOB, SINGLE_STAR_GLOB,
 };
 use futures::future::{self, FutureExt, TryFutureExt};
 use glob::Pattern;
+use grpc_util::prost::MessageExt;
 use hashing::{Digest, EMPTY_DIGEST};
 use indexmap::{self, IndexMap};
-use itertools::{Either, Itertools};
+use itertools::{Either, EitherOrBoth, Itertools};
 use log::log_enabled;
+use memchr::memmem;
+use regex::Regex;
 
-use crate::{snapshot::osstring_as_utf8, Snapshot};
+use crate::snapshot::{check_symlinks, osstring_as_utf8};
+use crate::Snapshot;
 
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub enum SnapshotOpsError {
@@ -45,6 +49,104 @@ pub struct SubsetParams {
   pub globs: PreparedPathGlobs,
 }
 
+///
+/// How to move the files and directories within a snapshot: see `SnapshotOps::rename`.
+///
+#[derive(Debug, Clone)]
+pub enum RenameParams {
+  /// Moves each path which is a key of the map to the corresponding value. When a key is a
+  /// directory, all of its contents are moved. If multiple keys match a path, the longest wins.
+  Mapping(BTreeMap<RelativePath, RelativePath>),
+  /// Replaces the first match of the regex in each path with the replacement, which may refer to
+  /// capture groups as `$1` or `${name}`.
+  Regex { regex: Regex, replacement: String },
+}
+
+impl RenameParams {
+  fn rename(&self, path: &Path) -> Result<PathBuf, String> {
+    let renamed = match self {
+      RenameParams::Mapping(mapping) => {
+        let renamed = mapping
+          .iter()
+          .filter_map(|(from, to)| {
+            path
+              .strip_prefix(from)
+              .ok()
+              .map(|rest| (from.components().count(), to.as_path().join(rest)))
+          })
+          .max_by_key(|(len, _)| *len);
+        match renamed {
+          Some((_, renamed)) => renamed,
+          None => return Ok(path.to_owned()),
+        }
+      }
+      RenameParams::Regex { regex, replacement } => {
+        let path_str = path
+          .to_str()
+          .ok_or_else(|| format!("Could not decode path `{:?}` as UTF8.", path))?;
+        PathBuf::from(regex.replace(path_str, replacement.as_str()).into_owned())
+      }
+    };
+    let relative_path = RelativePath::new(&renamed).map_err(|e| {
+      format!(
+        "Cannot rename {} to {}: {}",
+        path.display(),
+        renamed.display(),
+        e
+      )
+    })?;
+    if relative_path.as_os_str().is_empty() {
+      return Err(format!(
+        "Cannot rename {} to the root directory.",
+        path.display()
+      ));
+    }
+    Ok(relative_path.into())
+  }
+}
+
+///
+/// Edits to apply to the content of the files within a snapshot: see `SnapshotOps::substitute`.
+///
+#[derive(Debug, Clone)]
+pub struct SubstituteParams {
+  /// The files to edit.
+  pub globs: PreparedPathGlobs,
+  /// Pairs of literal bytes to replace, and their replacement. Each pair is applied to the result
+  /// of the previous one, and replaces all non-overlapping occurrences.
+  pub replacements: Vec<(Bytes, Bytes)>,
+  /// Bytes to prepend to each file (after the replacements have been applied).
+  pub header: Bytes,
+}
+
+impl SubstituteParams {
+  fn substitute(&self, content: &[u8]) -> Bytes {
+    let mut content = content.to_vec();
+    for (from, to) in &self.replacements {
+      content = replace_all(&content, from, to);
+    }
+    let mut result = BytesMut::with_capacity(self.header.len() + content.len());
+    result.extend_from_slice(&self.header);
+    result.extend_from_slice(&content);
+    result.freeze()
+  }
+}
+
+fn replace_all(haystack: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+  if from.is_empty() {
+    return haystack.to_vec();
+  }
+  let mut result = Vec::with_capacity(haystack.len());
+  let mut remaining_start = 0;
+  for index in memmem::find_iter(haystack, from) {
+    result.extend_from_slice(&haystack[remaining_start..index]);
+    result.extend_from_slice(to);
+    remaining_start = index + from.len();
+  }
+  result.extend_from_slice(&haystack[remaining_start..]);
+  result
+}
+
 ///
 /// A trait that encapsulates some of the features of a Store, with nicer type signatures. This is
 /// used to implement the `SnapshotOps` trait.
@@ -61,6 +163,8 @@ pub trait StoreWrapper: Clone + Send + Sync {
   async fn load_directory_or_err(&self, digest: Digest) -> Result<remexec::Directory, String>;
 
   async fn record_directory(&self, directory: &remexec::Directory) -> Result<Digest, String>;
+
+  async fn store_file_bytes(&self, bytes: Bytes) -> Result<Digest, String>;
 }
 
 ///
@@ -117,6 +221,16 @@ fn merge_directories_recursive<T: StoreWrapper + 'static>(
 
     out_dir.files = file_nodes.into_iter().dedup().cloned().collect();
 
+    // Merge SymlinkNodes.
+    let symlink_nodes = Iterator::flatten(
+      directories
+        .iter()
+        .map(|directory| directory.symlinks.iter()),
+    )
+    .sorted_by(|a, b| a.name.cmp(&b.name));
+
+    out_dir.symlinks = symlink_nodes.into_iter().dedup().cloned().collect();
+
     // Group and recurse for DirectoryNodes.
     let child_directory_futures = {
       let store = store_wrapper.clone();
@@ -184,9 +298,10 @@ async fn error_for_collisions<T: StoreWrapper + 'static>(
     .iter()
     .map(|n| n.name.clone())
     .chain(dir.directories.iter().map(|n| n.name.clone()))
+    .chain(dir.symlinks.iter().map(|n| n.name.clone()))
     .collect::<HashSet<_>>()
     .len();
-  if unique_count == (dir.files.len() + dir.directories.len()) {
+  if unique_count == (dir.files.len() + dir.directories.len() + dir.symlinks.len()) {
     return Ok(());
   }
 
@@ -235,11 +350,21 @@ async fn error_for_collisions<T: StoreWrapper + 'static>(
       res
     })
     .map(|f| f.boxed());
+  let symlink_details_by_name = dir
+    .symlinks
+    .iter()
+    .map(|symlink_node| async move {
+      let detail = format!("symlink target={}", symlink_node.target);
+      let res: Result<_, String> = Ok((symlink_node.name.clone(), detail));
+      res
+    })
+    .map(|f| f.boxed());
 
   let duplicate_details = async move {
     let details_by_name = future::try_join_all(
       file_details_by_name
         .chain(dir_details_by_name)
+        .chain(symlink_details_by_name)
         .collect::<Vec<_>>(),
     )
     .await?
@@ -285,8 +410,10 @@ async fn error_for_collisions<T: StoreWrapper + 'static>(
 ///
 struct IntermediateGlobbedFilesAndDirectories {
   globbed_files: IndexMap<PathBuf, remexec::FileNode>,
+  globbed_symlinks: IndexMap<PathBuf, remexec::SymlinkNode>,
   globbed_directories: IndexMap<PathBuf, remexec::DirectoryNode>,
   cur_dir_files: IndexMap<PathBuf, remexec::FileNode>,
+  cur_dir_symlinks: IndexMap<PathBuf, remexec::SymlinkNode>,
   cur_dir_directories: IndexMap<PathBuf, remexec::DirectoryNode>,
   todo_directories: IndexMap<PathBuf, Vec<RestrictedPathGlob>>,
   prefix: PathBuf,
@@ -299,6 +426,8 @@ struct GlobbedFilesAndDirectories {
   cur_dir_directories: IndexMap<PathBuf, remexec::DirectoryNode>,
   // All of the files of the source Directory matching the current glob.
   globbed_files: IndexMap<PathBuf, remexec::FileNode>,
+  // All of the symlinks of the source Directory matching the current glob.
+  globbed_symlinks: IndexMap<PathBuf, remexec::SymlinkNode>,
   // All of the matching subdirectories of the source Directory *after* being subsetted to match the
   // current glob.
   globbed_directories: IndexMap<PathBuf, remexec::DirectoryNode>,
@@ -306,6 +435,7 @@ struct GlobbedFilesAndDirectories {
   // the current glob.
   todo_directories: IndexMap<PathBuf, Vec<RestrictedPathGlob>>,
   exclude: Arc<GitignoreStyleExcludes>,
+  match_options: PathMatchOptions,
 }
 
 impl IntermediateGlobbedFilesAndDirectories {
@@ -319,6 +449,11 @@ impl IntermediateGlobbedFilesAndDirectories {
       .into_iter()
       .map(|file_node| (PathBuf::from(file_node.name.clone()), file_node))
       .collect();
+    let cur_dir_symlinks: IndexMap<PathBuf, remexec::SymlinkNode> = cur_dir
+      .symlinks
+      .into_iter()
+      .map(|symlink_node| (PathBuf::from(symlink_node.name.clone()), symlink_node))
+      .collect();
     let cur_dir_directories: IndexMap<PathBuf, remexec::DirectoryNode> = cur_dir
       .directories
       .into_iter()
@@ -326,13 +461,16 @@ impl IntermediateGlobbedFilesAndDirectories {
       .collect();
 
     let globbed_files: IndexMap<PathBuf, remexec::FileNode> = IndexMap::new();
+    let globbed_symlinks: IndexMap<PathBuf, remexec::SymlinkNode> = IndexMap::new();
     let globbed_directories: IndexMap<PathBuf, remexec::DirectoryNode> = IndexMap::new();
     let todo_directories: IndexMap<PathBuf, Vec<RestrictedPathGlob>> = IndexMap::new();
 
     IntermediateGlobbedFilesAndDirectories {
       globbed_files,
+      globbed_symlinks,
       globbed_directories,
       cur_dir_files,
+      cur_dir_symlinks,
       cur_dir_directories,
       todo_directories,
       prefix,
@@ -345,6 +483,7 @@ impl IntermediateGlobbedFilesAndDirectories {
   ) -> Result<GlobbedFilesAndDirectories, SnapshotOpsError> {
     let IntermediateGlobbedFilesAndDirectories {
       mut globbed_files,
+      mut globbed_symlinks,
       mut globbed_directories,
       // NB: When iterating over files, we can remove them from `cur_dir_files` after they are
       // successfully matched once, hence the `mut` declaration. This is a small
@@ -353,10 +492,16 @@ impl IntermediateGlobbedFilesAndDirectories {
       // created after matching against a single directory node! So we do *not* mark
       // `cur_dir_directories` as `mut`.
       mut cur_dir_files,
+      mut cur_dir_symlinks,
       cur_dir_directories,
       mut todo_directories,
       prefix,
-      multiple_globs: MultipleGlobs { include, exclude },
+      multiple_globs:
+        MultipleGlobs {
+          include,
+          exclude,
+          match_options,
+        },
     } = self;
 
     // Populate globbed_{files,directories} by iterating over all the globs.
@@ -373,7 +518,8 @@ impl IntermediateGlobbedFilesAndDirectories {
         .filter(|path| {
           // NB: match just the current path component against the wildcard, but use the prefix
           // when checking against the `exclude` patterns!
-          wildcard.matches_path(path) && !exclude.is_ignored_path(&prefix.join(path), false)
+          match_options.matches_path(wildcard, path)
+            && !exclude.is_ignored_path(&prefix.join(path), false)
         })
         .cloned()
         .collect();
@@ -384,6 +530,22 @@ impl IntermediateGlobbedFilesAndDirectories {
         globbed_files.insert(file_path, file_node);
       }
 
+      // NB: Symlinks are leaves of the tree, and so are matched in the same way as files.
+      // TODO(#12462): Remove allow once upstream resolves https://github.com/rust-lang/rust-clippy/issues/6066.
+      #[allow(clippy::needless_collect)]
+      let matching_symlinks: Vec<PathBuf> = cur_dir_symlinks
+        .keys()
+        .filter(|path| {
+          match_options.matches_path(wildcard, path)
+            && !exclude.is_ignored_path(&prefix.join(path), false)
+        })
+        .cloned()
+        .collect();
+      for symlink_path in matching_symlinks.into_iter() {
+        let symlink_node = cur_dir_symlinks.remove(&symlink_path).unwrap();
+        globbed_symlinks.insert(symlink_path, symlink_node);
+      }
+
       // TODO(#12462): Remove allow once upstream resolves https://github.com/rust-lang/rust-clippy/issues/6066.
       #[allow(clippy::needless_collect)]
       let matching_directories: Vec<PathBuf> = cur_dir_directories
@@ -391,7 +553,8 @@ impl IntermediateGlobbedFilesAndDirectories {
         .filter(|path| {
           // NB: match just the current path component against the wildcard, but use the prefix
           // when checking against the `exclude` patterns!
-          wildcard.matches_path(path) && !exclude.is_ignored_path(&prefix.join(path), true)
+          match_options.matches_path(wildcard, path)
+            && !exclude.is_ignored_path(&prefix.join(path), true)
         })
         .cloned()
         .collect();
@@ -492,9 +655,11 @@ impl IntermediateGlobbedFilesAndDirectories {
     Ok(GlobbedFilesAndDirectories {
       cur_dir_directories,
       globbed_files,
+      globbed_symlinks,
       globbed_directories,
       todo_directories,
       exclude,
+      match_options,
     })
   }
 }
@@ -543,9 +708,11 @@ async fn snapshot_glob_match<T: StoreWrapper>(
     let GlobbedFilesAndDirectories {
       cur_dir_directories,
       globbed_files,
+      globbed_symlinks,
       globbed_directories,
       todo_directories,
       exclude,
+      match_options,
     } = intermediate_globbed
       .populate_globbed_files_and_directories()
       .await?;
@@ -579,6 +746,7 @@ async fn snapshot_glob_match<T: StoreWrapper>(
       let multiple_globs = MultipleGlobs {
         include: all_path_globs,
         exclude: exclude.clone(),
+        match_options,
       };
       unexpanded_stack.insert(
         full_name,
@@ -595,6 +763,7 @@ async fn snapshot_glob_match<T: StoreWrapper>(
     // stack, we will have already globbed all of its subdirectories.
     let partially_expanded_context = PartiallyExpandedDirectoryContext {
       files: globbed_files.into_iter().map(|(_, node)| node).collect(),
+      symlinks: globbed_symlinks.into_iter().map(|(_, node)| node).collect(),
       known_directories: globbed_directories
         .into_iter()
         .map(|(_, node)| node)
@@ -610,6 +779,7 @@ async fn snapshot_glob_match<T: StoreWrapper>(
     prefix,
     PartiallyExpandedDirectoryContext {
       files,
+      symlinks,
       known_directories,
       directory_promises,
     },
@@ -643,6 +813,7 @@ async fn snapshot_glob_match<T: StoreWrapper>(
       .collect();
     let final_directory = remexec::Directory {
       files,
+      symlinks,
       directories: all_directories,
       ..remexec::Directory::default()
     };
@@ -654,6 +825,433 @@ async fn snapshot_glob_match<T: StoreWrapper>(
   Ok(*final_digest)
 }
 
+///
+/// Lists the files, symlinks and leaf empty directories beneath the given Directory, with their
+/// paths relative to it.
+///
+// NB: This function is recursive, and so cannot be directly marked async:
+//   https://rust-lang.github.io/async-book/07_workarounds/05_recursion.html
+fn collect_entries<T: StoreWrapper + 'static>(
+  store_wrapper: T,
+  parent_path: PathBuf,
+  digest: Digest,
+) -> future::BoxFuture<'static, Result<Vec<(PathBuf, TreeEntry)>, String>> {
+  async move {
+    let directory = store_wrapper.load_directory_or_err(digest).await?;
+    if directory.files.is_empty()
+      && directory.directories.is_empty()
+      && directory.symlinks.is_empty()
+    {
+      return Ok(if parent_path.as_os_str().is_empty() {
+        vec![]
+      } else {
+        vec![(parent_path, TreeEntry::Directory(EntryTree::default()))]
+      });
+    }
+
+    let mut entries = directory
+      .files
+      .iter()
+      .map(|file_node| {
+        Ok((
+          parent_path.join(&file_node.name),
+          TreeEntry::File {
+            digest: require_digest(file_node.digest.as_ref())?,
+            is_executable: file_node.is_executable,
+            node_properties: file_node.node_properties.clone(),
+          },
+        ))
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+    entries.extend(directory.symlinks.iter().map(|symlink_node| {
+      (
+        parent_path.join(&symlink_node.name),
+        TreeEntry::Symlink(symlink_node.target.clone()),
+      )
+    }));
+    let child_entries = directory
+      .directories
+      .iter()
+      .map(|directory_node| {
+        Ok(collect_entries(
+          store_wrapper.clone(),
+          parent_path.join(&directory_node.name),
+          require_digest(directory_node.digest.as_ref())?,
+        ))
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+    for child in future::try_join_all(child_entries).await? {
+      entries.extend(child);
+    }
+    Ok(entries)
+  }
+  .boxed()
+}
+
+///
+/// Validates the targets of the symlinks among the given entries (see `check_symlinks`), which
+/// might have been invalidated by moving the symlinks relative to one another or to the root.
+///
+fn check_entry_symlinks(entries: &[(PathBuf, TreeEntry)]) -> Result<(), String> {
+  let symlinks = entries
+    .iter()
+    .filter_map(|(path, entry)| match entry {
+      TreeEntry::Symlink(target) => Some((path.as_path(), Path::new(target))),
+      _ => None,
+    })
+    .collect::<Vec<_>>();
+  check_symlinks(&symlinks, "digest root")
+}
+
+async fn check_directory_symlinks<T: StoreWrapper + 'static>(
+  store_wrapper: T,
+  digest: Digest,
+) -> Result<(), String> {
+  let entries = collect_entries(store_wrapper, PathBuf::new(), digest).await?;
+  check_entry_symlinks(&entries)
+}
+
+///
+/// Creates a Digest containing the given entries, failing if different entries have the same path.
+///
+async fn digest_of_entries<T: StoreWrapper + 'static>(
+  store_wrapper: T,
+  entries: Vec<(PathBuf, TreeEntry)>,
+) -> Result<Digest, SnapshotOpsError> {
+  let mut tree = EntryTree::default();
+  for (path, entry) in entries {
+    tree.insert(&path, entry)?;
+  }
+
+  let mut directories = Vec::new();
+  let digest = tree.into_directories(&mut directories);
+  future::try_join_all(
+    directories
+      .iter()
+      .map(|directory| store_wrapper.record_directory(directory)),
+  )
+  .await?;
+  Ok(digest)
+}
+
+///
+/// A file (with its NodeProperties), symlink or empty directory within an `EntryTree`.
+///
+#[derive(Debug, PartialEq)]
+enum TreeEntry {
+  File {
+    digest: Digest,
+    is_executable: bool,
+    node_properties: Option<remexec::NodeProperties>,
+  },
+  Directory(EntryTree),
+  Symlink(String),
+}
+
+///
+/// An in-memory tree of entries, which is converted into Directory protos once all entries have
+/// been inserted, so that each Directory is recorded once.
+///
+#[derive(Debug, Default, PartialEq)]
+struct EntryTree {
+  entries: BTreeMap<String, TreeEntry>,
+}
+
+impl EntryTree {
+  ///
+  /// Inserts an entry at the given path, creating any missing parent directories. Inserting an
+  /// identical entry (or an empty directory where a directory exists) has no effect.
+  ///
+  fn insert(&mut self, path: &Path, entry: TreeEntry) -> Result<(), String> {
+    let collision = || {
+      format!(
+        "Cannot create a Directory with different entries at the same path: {}",
+        path.display()
+      )
+    };
+    let mut names = path
+      .components()
+      .map(|component| osstring_as_utf8(component.as_os_str().to_os_string()))
+      .collect::<Result<Vec<_>, _>>()?;
+    let name = match names.pop() {
+      Some(name) => name,
+      // The root directory.
+      None => return Ok(()),
+    };
+
+    let mut directory = self;
+    for parent in names {
+      directory = match directory
+        .entries
+        .entry(parent)
+        .or_insert_with(|| TreeEntry::Directory(EntryTree::default()))
+      {
+        TreeEntry::Directory(d) => d,
+        _ => return Err(collision()),
+      };
+    }
+
+    match directory.entries.get(&name) {
+      None => {
+        directory.entries.insert(name, entry);
+      }
+      Some(TreeEntry::Directory(_)) if entry == TreeEntry::Directory(EntryTree::default()) => (),
+      Some(existing) if *existing == entry => (),
+      Some(_) => return Err(collision()),
+    }
+    Ok(())
+  }
+
+  ///
+  /// Converts this tree into Directory protos (children before their parents), returning the
+  /// Digest of the root Directory.
+  ///
+  fn into_directories(self, directories: &mut Vec<remexec::Directory>) -> Digest {
+    let mut directory = remexec::Directory::default();
+    // NB: BTreeMap iterates in sorted order, which means that each type of node is sorted by name,
+    // as required for a canonical Directory.
+    for (name, entry) in self.entries {
+      match entry {
+        TreeEntry::File {
+          digest,
+          is_executable,
+          node_properties,
+        } => directory.files.push(remexec::FileNode {
+          name,
+          digest: Some((&digest).into()),
+          is_executable,
+          node_properties,
+        }),
+        TreeEntry::Directory(tree) => {
+          let digest = tree.into_directories(directories);
+          directory.directories.push(remexec::DirectoryNode {
+            name,
+            digest: Some((&digest).into()),
+          })
+        }
+        TreeEntry::Symlink(target) => directory.symlinks.push(remexec::SymlinkNode {
+          name,
+          target,
+          ..remexec::SymlinkNode::default()
+        }),
+      }
+    }
+    let digest = Digest::of_bytes(&directory.to_bytes());
+    directories.push(directory);
+    digest
+  }
+}
+
+///
+/// Rebuilds the given Directory with the matched files substituted. Only the directories which
+/// contain matched files are loaded and rebuilt: all others keep their existing digests.
+///
+// NB: This function is recursive, and so cannot be directly marked async:
+//   https://rust-lang.github.io/async-book/07_workarounds/05_recursion.html
+fn substitute_recursive<T: StoreWrapper + 'static>(
+  store_wrapper: T,
+  parent_path: PathBuf,
+  digest: Digest,
+  matched_files: Arc<HashSet<PathBuf>>,
+  matched_directories: Arc<HashSet<PathBuf>>,
+  params: Arc<SubstituteParams>,
+) -> future::BoxFuture<'static, Result<Digest, String>> {
+  async move {
+    let mut directory = store_wrapper.load_directory_or_err(digest).await?;
+
+    let files = directory
+      .files
+      .iter()
+      .enumerate()
+      .filter(|(_, file_node)| matched_files.contains(&parent_path.join(&file_node.name)))
+      .map(|(i, file_node)| {
+        let store_wrapper = store_wrapper.clone();
+        let params = params.clone();
+        let path = parent_path.join(&file_node.name);
+        let digest = require_digest(file_node.digest.as_ref());
+        async move {
+          let digest = digest?;
+          let content = store_wrapper
+            .load_file_bytes_with(digest, move |bytes| params.substitute(bytes))
+            .await?
+            .ok_or_else(|| {
+              format!(
+                "File {} with digest {:?} was not found in the Store.",
+                path.display(),
+                digest
+              )
+            })?;
+          let res: Result<_, String> = Ok((i, store_wrapper.store_file_bytes(content).await?));
+          res
+        }
+      })
+      .collect::<Vec<_>>();
+    let child_directories = directory
+      .directories
+      .iter()
+      .enumerate()
+      .filter(|(_, directory_node)| {
+        matched_directories.contains(&parent_path.join(&directory_node.name))
+      })
+      .map(|(i, directory_node)| {
+        let child = require_digest(directory_node.digest.as_ref()).map(|digest| {
+          substitute_recursive(
+            store_wrapper.clone(),
+            parent_path.join(&directory_node.name),
+            digest,
+            matched_files.clone(),
+            matched_directories.clone(),
+            params.clone(),
+          )
+        });
+        async move {
+          let res: Result<_, String> = Ok((i, child?.await?));
+          res
+        }
+      })
+      .collect::<Vec<_>>();
+    let (files, child_directories) = future::try_join(
+      future::try_join_all(files),
+      future::try_join_all(child_directories),
+    )
+    .await?;
+
+    for (i, digest) in files {
+      directory.files[i].digest = Some((&digest).into());
+    }
+    for (i, digest) in child_directories {
+      directory.directories[i].digest = Some((&digest).into());
+    }
+    store_wrapper.record_directory(&directory).await
+  }
+  .boxed()
+}
+
+///
+/// The paths which differ between two Directories: see `SnapshotOps::diff`.
+///
+/// Paths are relative to the root of the Directories, and sorted.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DirectoryDiff {
+  /// Files and directories which are present only in the second Directory. When a directory is
+  /// added, all of its contents are also listed.
+  pub added: Vec<PathBuf>,
+  /// Files and directories which are present only in the first Directory. When a directory is
+  /// removed, all of its contents are also listed.
+  pub removed: Vec<PathBuf>,
+  /// Files which are present in both Directories, but with different content, executable bits or
+  /// node properties, and symlinks which are present in both, but with different targets.
+  pub modified: Vec<PathBuf>,
+}
+
+impl DirectoryDiff {
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+  }
+
+  fn extend(&mut self, other: DirectoryDiff) {
+    self.added.extend(other.added);
+    self.removed.extend(other.removed);
+    self.modified.extend(other.modified);
+  }
+}
+
+// NB: This function is recursive, and so cannot be directly marked async:
+//   https://rust-lang.github.io/async-book/07_workarounds/05_recursion.html
+fn diff_directories_recursive<T: StoreWrapper + 'static>(
+  store_wrapper: T,
+  parent_path: PathBuf,
+  before_digest: Digest,
+  after_digest: Digest,
+) -> future::BoxFuture<'static, Result<DirectoryDiff, String>> {
+  async move {
+    let mut diff = DirectoryDiff::default();
+    // Identical subtrees cannot contain any differences, so there is no need to load them.
+    if before_digest == after_digest {
+      return Ok(diff);
+    }
+
+    let (before, after) = future::try_join(
+      store_wrapper.load_directory_or_err(before_digest),
+      store_wrapper.load_directory_or_err(after_digest),
+    )
+    .await?;
+
+    // Directory protos are sorted by name, so their entries may be joined in order.
+    for file in before
+      .files
+      .iter()
+      .merge_join_by(after.files.iter(), |b, a| b.name.cmp(&a.name))
+    {
+      match file {
+        EitherOrBoth::Left(b) => diff.removed.push(parent_path.join(&b.name)),
+        EitherOrBoth::Right(a) => diff.added.push(parent_path.join(&a.name)),
+        EitherOrBoth::Both(b, a) => {
+          if b.digest != a.digest
+            || b.is_executable != a.is_executable
+            || b.node_properties != a.node_properties
+          {
+            diff.modified.push(parent_path.join(&b.name));
+          }
+        }
+      }
+    }
+    for symlink in before
+      .symlinks
+      .iter()
+      .merge_join_by(after.symlinks.iter(), |b, a| b.name.cmp(&a.name))
+    {
+      match symlink {
+        EitherOrBoth::Left(b) => diff.removed.push(parent_path.join(&b.name)),
+        EitherOrBoth::Right(a) => diff.added.push(parent_path.join(&a.name)),
+        EitherOrBoth::Both(b, a) => {
+          if b.target != a.target {
+            diff.modified.push(parent_path.join(&b.name));
+          }
+        }
+      }
+    }
+
+    // Recurse into each child directory, diffing a directory which is present on only one side
+    // against the empty directory.
+    let child_diffs = before
+      .directories
+      .iter()
+      .merge_join_by(after.directories.iter(), |b, a| b.name.cmp(&a.name))
+      .map(|directory| {
+        let (name, before_child, after_child) = match directory {
+          EitherOrBoth::Left(b) => {
+            diff.removed.push(parent_path.join(&b.name));
+            (&b.name, require_digest(b.digest.as_ref())?, EMPTY_DIGEST)
+          }
+          EitherOrBoth::Right(a) => {
+            diff.added.push(parent_path.join(&a.name));
+            (&a.name, EMPTY_DIGEST, require_digest(a.digest.as_ref())?)
+          }
+          EitherOrBoth::Both(b, a) => (
+            &b.name,
+            require_digest(b.digest.as_ref())?,
+            require_digest(a.digest.as_ref())?,
+          ),
+        };
+        Ok(diff_directories_recursive(
+          store_wrapper.clone(),
+          parent_path.join(name),
+          before_child,
+          after_child,
+        ))
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+
+    for child_diff in future::try_join_all(child_diffs).await? {
+      diff.extend(child_diff);
+    }
+    Ok(diff)
+  }
+  .boxed()
+}
+
 ///
 /// High-level operations to manipulate and merge `Digest`s.
 ///
@@ -666,9 +1264,9 @@ pub trait SnapshotOps: StoreWrapper + 'static {
   /// Given N Snapshots, returns a new Snapshot that merges them.
   ///
   async fn merge(&self, digests: Vec<Digest>) -> Result<Digest, SnapshotOpsError> {
-    merge_directories(self.clone(), digests)
-      .await
-      .map_err(|e| e.into())
+    let merged = merge_directories(self.clone(), digests).await?;
+    check_directory_symlinks(self.clone(), merged).await?;
+    Ok(merged)
   }
 
   async fn add_prefix(
@@ -725,7 +1323,13 @@ pub trait SnapshotOps: StoreWrapper + 'static {
             }
           })
           .collect();
-        let files: Vec<_> = dir.files.iter().map(|file| file.name.to_owned()).collect();
+        // NB: Symlinks are reported as files, since (like files) they are leaves of the tree.
+        let files: Vec<_> = dir
+          .files
+          .iter()
+          .map(|file| file.name.to_owned())
+          .chain(dir.symlinks.iter().map(|symlink| symlink.name.to_owned()))
+          .collect();
 
         match (saw_matching_dir, extra_directories.is_empty() && files.is_empty()) {
           (false, true) => {
@@ -783,12 +1387,86 @@ pub trait SnapshotOps: StoreWrapper + 'static {
   async fn create_empty_dir(&self, path: RelativePath) -> Result<Digest, SnapshotOpsError> {
     self.add_prefix(EMPTY_DIGEST, path).await
   }
+
+  ///
+  /// Returns the paths which were added, removed or modified between the `before` and `after`
+  /// Directories. Subdirectories with identical digests are not traversed.
+  ///
+  async fn diff(&self, before: Digest, after: Digest) -> Result<DirectoryDiff, SnapshotOpsError> {
+    let mut diff = diff_directories_recursive(self.clone(), PathBuf::new(), before, after).await?;
+    diff.added.sort();
+    diff.removed.sort();
+    diff.modified.sort();
+    Ok(diff)
+  }
+
+  ///
+  /// Moves the files and directories within a Digest according to the RenameParams, preserving
+  /// the NodeProperties of files. Fails if two different files would be moved to the same path, or
+  /// if a moved symlink would refer to a path outside of the Digest.
+  ///
+  async fn rename(&self, digest: Digest, params: RenameParams) -> Result<Digest, SnapshotOpsError> {
+    let entries = collect_entries(self.clone(), PathBuf::new(), digest)
+      .await?
+      .into_iter()
+      .map(|(path, entry)| Ok((params.rename(&path)?, entry)))
+      .collect::<Result<Vec<_>, String>>()?;
+    check_entry_symlinks(&entries)?;
+    digest_of_entries(self.clone(), entries).await
+  }
+
+  ///
+  /// Edits the content of the files within a Digest which match the globs of the SubstituteParams.
+  ///
+  async fn substitute(
+    &self,
+    digest: Digest,
+    params: SubstituteParams,
+  ) -> Result<Digest, SnapshotOpsError> {
+    let matched_digest = self
+      .subset(
+        digest,
+        SubsetParams {
+          globs: params.globs.clone(),
+        },
+      )
+      .await?;
+    let matched_paths = collect_entries(self.clone(), PathBuf::new(), matched_digest)
+      .await?
+      .into_iter()
+      .filter_map(|(path, entry)| match entry {
+        TreeEntry::File { .. } => Some(path),
+        _ => None,
+      })
+      .collect::<HashSet<_>>();
+
+    if matched_paths.is_empty() {
+      return Ok(digest);
+    }
+    let matched_directories = matched_paths
+      .iter()
+      .flat_map(|path| path.ancestors().skip(1))
+      .map(Path::to_owned)
+      .collect::<HashSet<_>>();
+    Ok(
+      substitute_recursive(
+        self.clone(),
+        PathBuf::new(),
+        digest,
+        Arc::new(matched_paths),
+        Arc::new(matched_directories),
+        Arc::new(params),
+      )
+      .await?,
+    )
+  }
 }
 
 impl<T: StoreWrapper + 'static> SnapshotOps for T {}
 
 struct PartiallyExpandedDirectoryContext {
   pub files: Vec<remexec::FileNode>,
+  pub symlinks: Vec<remexec::SymlinkNode>,
   pub known_directories: Vec<remexec::DirectoryNode>,
   pub directory_promises: Vec<PathBuf>,
 }
@@ -832,14 +1510,20 @@ fn to_bazel_digest(digest: Digest) -> remexec::Digest {
 struct MultipleGlobs {
   pub include: Vec<RestrictedPathGlob>,
   pub exclude: Arc<GitignoreStyleExcludes>,
+  pub match_options: PathMatchOptions,
 }
 
 impl From<ExpandablePathGlobs> for MultipleGlobs {
   fn from(globs: ExpandablePathGlobs) -> Self {
-    let ExpandablePathGlobs { include, exclude } = globs;
+    let ExpandablePathGlobs {
+      include,
+      exclude,
+      match_options,
+    } = globs;
     MultipleGlobs {
       include: include.into_iter().map(|x| x.into()).collect(),
       exclude,
+      match_options,
     }
   }
 }
@@ -1,19 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::create_dir_all;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use bytes::Bytes;
 use fs::{GlobExpansionConjunction, PosixFS, PreparedPathGlobs, StrictGlobMatching};
 use hashing::Digest;
 use parking_lot::Mutex;
+use regex::Regex;
+use testutil::data::TestDirectory;
 use testutil::make_file;
 
 use crate::{
   snapshot_ops::StoreWrapper,
   snapshot_tests::{expand_all_sorted, setup, STR, STR2},
-  OneOffStoreFileByDigest, RelativePath, Snapshot, SnapshotOps, Store, SubsetParams,
+  DirectoryDiff, OneOffStoreFileByDigest, RelativePath, RenameParams, Snapshot, SnapshotOps, Store,
+  SubsetParams, SubstituteParams,
 };
 
 async fn get_duplicate_rolands<T: StoreWrapper + 'static>(
@@ -140,6 +144,44 @@ async fn subset_recursive_wildcard() {
   assert_eq!(subset_roland4, snapshot1.digest);
 }
 
+#[tokio::test]
+async fn subset_brace_alternation() {
+  let (store, tempdir, posix_fs, digester) = setup();
+
+  let (merged_digest, snapshot1, snapshot2) = get_duplicate_rolands(
+    store.clone(),
+    store.clone(),
+    tempdir.path(),
+    posix_fs.clone(),
+    digester,
+  )
+  .await;
+
+  let subset_params1 = make_subset_params(&["subdir/{roland1,other}"]);
+  let subset_roland1 = store
+    .clone()
+    .subset(merged_digest, subset_params1)
+    .await
+    .unwrap();
+  assert_eq!(subset_roland1, snapshot1.digest);
+
+  let subset_params2 = make_subset_params(&["{subdir,other}/roland[^1]"]);
+  let subset_roland2 = store
+    .clone()
+    .subset(merged_digest, subset_params2)
+    .await
+    .unwrap();
+  assert_eq!(subset_roland2, snapshot2.digest);
+
+  let subset_params3 = make_subset_params(&["!subdir/roland{1,3}", "subdir/**"]);
+  let subset_roland3 = store
+    .clone()
+    .subset(merged_digest, subset_params3)
+    .await
+    .unwrap();
+  assert_eq!(subset_roland3, snapshot2.digest);
+}
+
 #[derive(Clone)]
 struct LoadTrackingStore {
   store: Store,
@@ -177,6 +219,10 @@ impl StoreWrapper for LoadTrackingStore {
   async fn record_directory(&self, directory: &remexec::Directory) -> Result<Digest, String> {
     Store::record_directory(&self.store, directory, true).await
   }
+
+  async fn store_file_bytes(&self, bytes: Bytes) -> Result<Digest, String> {
+    Store::store_file_bytes(&self.store, bytes, true).await
+  }
 }
 
 #[tokio::test]
@@ -196,6 +242,8 @@ async fn subset_tracking_load_counts() {
     digester,
   )
   .await;
+  // Merging validates the symlinks of the result, which loads every directory once.
+  load_tracking_store.load_counts.lock().clear();
 
   let prefix = RelativePath::new(PathBuf::from("subdir")).unwrap();
   let subdir_digest = load_tracking_store
@@ -230,3 +278,467 @@ async fn subset_tracking_load_counts() {
   let num_loads: HashMap<Digest, usize> = load_tracking_store.load_counts.lock().clone();
   assert_eq!(num_subdir_loads, *num_loads.get(&subdir_digest).unwrap());
 }
+
+///
+/// Creates a Digest containing the given files (as path, content and is_executable).
+///
+async fn digest_of_files(store: &Store, files: &[(&str, &str, bool)]) -> Digest {
+  let mut digests = Vec::new();
+  for (path, content, is_executable) in files {
+    let file_digest = store
+      .store_file_bytes(Bytes::from(content.to_string()), false)
+      .await
+      .unwrap();
+    let snapshot = store
+      .snapshot_of_one_file(
+        RelativePath::new(path).unwrap(),
+        file_digest,
+        *is_executable,
+      )
+      .await
+      .unwrap();
+    digests.push(snapshot.digest);
+  }
+  store.merge(digests).await.unwrap()
+}
+
+///
+/// Creates a Digest containing the given symlinks (as path and target).
+///
+async fn digest_of_symlinks(store: &Store, symlinks: &[(&str, &str)]) -> Digest {
+  let mut digests = Vec::new();
+  for (path, target) in symlinks {
+    let path = Path::new(path);
+    let directory = remexec::Directory {
+      symlinks: vec![remexec::SymlinkNode {
+        name: path.file_name().unwrap().to_str().unwrap().to_owned(),
+        target: target.to_string(),
+        ..remexec::SymlinkNode::default()
+      }],
+      ..remexec::Directory::default()
+    };
+    let digest = store.record_directory(&directory, false).await.unwrap();
+    let parent = RelativePath::new(path.parent().unwrap()).unwrap();
+    digests.push(store.add_prefix(digest, parent).await.unwrap());
+  }
+  store.merge(digests).await.unwrap()
+}
+
+#[tokio::test]
+async fn subset_symlinks() {
+  let (store, _, _, _) = setup();
+
+  let files = digest_of_files(&store, &[("a.txt", "a", false), ("dir/b.txt", "b", false)]).await;
+  let symlinks = digest_of_symlinks(&store, &[("link", "a.txt"), ("dir/link", "b.txt")]).await;
+  let digest = store.merge(vec![files, symlinks]).await.unwrap();
+
+  let expected = store
+    .merge(vec![
+      digest_of_files(&store, &[("dir/b.txt", "b", false)]).await,
+      digest_of_symlinks(&store, &[("dir/link", "b.txt")]).await,
+    ])
+    .await
+    .unwrap();
+  assert_eq!(
+    store.subset(digest, make_subset_params(&["dir/*"])).await,
+    Ok(expected)
+  );
+  assert_eq!(
+    store
+      .subset(digest, make_subset_params(&["link", "dir/link"]))
+      .await,
+    Ok(symlinks)
+  );
+  assert_eq!(
+    store
+      .subset(digest, make_subset_params(&["**", "!link", "!dir/link"]))
+      .await,
+    Ok(files)
+  );
+}
+
+fn paths(paths: &[&str]) -> Vec<PathBuf> {
+  paths.iter().map(PathBuf::from).collect()
+}
+
+#[tokio::test]
+async fn diff_added_removed_and_modified() {
+  let (store, _, _, _) = setup();
+
+  let before = digest_of_files(
+    &store,
+    &[
+      ("a.txt", "a", false),
+      ("b.txt", "b", false),
+      ("dir/c.txt", "c", false),
+      ("dir/d.txt", "d", false),
+      ("gone/nested/e.txt", "e", false),
+      ("same/f.txt", "f", false),
+    ],
+  )
+  .await;
+  let after = digest_of_files(
+    &store,
+    &[
+      ("a.txt", "a", false),
+      ("b.txt", "B", false),
+      ("dir/c.txt", "c", true),
+      ("new/g.txt", "g", false),
+      ("same/f.txt", "f", false),
+    ],
+  )
+  .await;
+
+  assert_eq!(
+    store.diff(before, after).await.unwrap(),
+    DirectoryDiff {
+      added: paths(&["new", "new/g.txt"]),
+      removed: paths(&["dir/d.txt", "gone", "gone/nested", "gone/nested/e.txt"]),
+      modified: paths(&["b.txt", "dir/c.txt"]),
+    }
+  );
+  assert_eq!(
+    store.diff(after, before).await.unwrap(),
+    DirectoryDiff {
+      added: paths(&["dir/d.txt", "gone", "gone/nested", "gone/nested/e.txt"]),
+      removed: paths(&["new", "new/g.txt"]),
+      modified: paths(&["b.txt", "dir/c.txt"]),
+    }
+  );
+}
+
+#[tokio::test]
+async fn diff_file_replaced_by_directory() {
+  let (store, _, _, _) = setup();
+
+  let before = digest_of_files(&store, &[("path", "a file", false)]).await;
+  let after = digest_of_files(&store, &[("path/file", "a nested file", false)]).await;
+
+  assert_eq!(
+    store.diff(before, after).await.unwrap(),
+    DirectoryDiff {
+      added: paths(&["path", "path/file"]),
+      removed: paths(&["path"]),
+      modified: vec![],
+    }
+  );
+}
+
+#[tokio::test]
+async fn diff_symlinks() {
+  let (store, _, _, _) = setup();
+
+  let before = digest_of_symlinks(&store, &[("gone", "a"), ("link", "a"), ("same", "a")]).await;
+  let after = digest_of_symlinks(&store, &[("link", "b"), ("new", "a"), ("same", "a")]).await;
+
+  assert_eq!(
+    store.diff(before, after).await.unwrap(),
+    DirectoryDiff {
+      added: paths(&["new"]),
+      removed: paths(&["gone"]),
+      modified: paths(&["link"]),
+    }
+  );
+}
+
+#[tokio::test]
+async fn diff_identical() {
+  let (store, _, _, _) = setup();
+
+  let digest = digest_of_files(&store, &[("dir/a.txt", "a", false)]).await;
+  let diff = store.diff(digest, digest).await.unwrap();
+  assert!(diff.is_empty(), "{:?}", diff);
+}
+
+#[tokio::test]
+async fn diff_skips_identical_subdirectories() {
+  let (store, _, _, _) = setup();
+  let load_tracking_store = LoadTrackingStore {
+    store: store.clone(),
+    load_counts: Arc::new(Mutex::new(HashMap::new())),
+  };
+
+  let before = digest_of_files(
+    &store,
+    &[("a.txt", "a", false), ("same/nested/b.txt", "b", false)],
+  )
+  .await;
+  let after = digest_of_files(
+    &store,
+    &[("a.txt", "A", false), ("same/nested/b.txt", "b", false)],
+  )
+  .await;
+  let same_digest = digest_of_files(&store, &[("nested/b.txt", "b", false)]).await;
+
+  assert_eq!(
+    load_tracking_store.diff(before, after).await.unwrap(),
+    DirectoryDiff {
+      added: vec![],
+      removed: vec![],
+      modified: paths(&["a.txt"]),
+    }
+  );
+  let load_counts = load_tracking_store.load_counts.lock().clone();
+  assert_eq!(load_counts.get(&before), Some(&1));
+  assert_eq!(load_counts.get(&same_digest), None);
+}
+
+fn rename_mapping(mapping: &[(&str, &str)]) -> RenameParams {
+  RenameParams::Mapping(
+    mapping
+      .iter()
+      .map(|(from, to)| {
+        (
+          RelativePath::new(from).unwrap(),
+          RelativePath::new(to).unwrap(),
+        )
+      })
+      .collect::<BTreeMap<_, _>>(),
+  )
+}
+
+fn rename_regex(regex: &str, replacement: &str) -> RenameParams {
+  RenameParams::Regex {
+    regex: Regex::new(regex).unwrap(),
+    replacement: replacement.to_owned(),
+  }
+}
+
+#[tokio::test]
+async fn rename_with_mapping() {
+  let (store, _, _, _) = setup();
+
+  let digest = digest_of_files(
+    &store,
+    &[
+      ("a.txt", "a", true),
+      ("dir/b.txt", "b", false),
+      ("dir/sub/c.txt", "c", false),
+      ("other/d.txt", "d", false),
+    ],
+  )
+  .await;
+  let renamed = store
+    .rename(
+      digest,
+      rename_mapping(&[
+        ("a.txt", "bin/a"),
+        ("dir", "moved"),
+        ("dir/sub", "elsewhere/sub"),
+      ]),
+    )
+    .await
+    .unwrap();
+
+  let expected = digest_of_files(
+    &store,
+    &[
+      ("bin/a", "a", true),
+      ("moved/b.txt", "b", false),
+      ("elsewhere/sub/c.txt", "c", false),
+      ("other/d.txt", "d", false),
+    ],
+  )
+  .await;
+  assert_eq!(renamed, expected);
+}
+
+#[tokio::test]
+async fn rename_with_regex() {
+  let (store, _, _, _) = setup();
+
+  let digest = digest_of_files(
+    &store,
+    &[("src/a.py.tmpl", "a", false), ("src/b.py", "b", false)],
+  )
+  .await;
+  let renamed = store
+    .rename(digest, rename_regex(r"^src/(.*)\.tmpl$", "gen/$1"))
+    .await
+    .unwrap();
+
+  let expected = digest_of_files(
+    &store,
+    &[("gen/a.py", "a", false), ("src/b.py", "b", false)],
+  )
+  .await;
+  assert_eq!(renamed, expected);
+}
+
+#[tokio::test]
+async fn rename_symlinks() {
+  let (store, _, _, _) = setup();
+
+  let digest = digest_of_symlinks(&store, &[("dir/link", "target"), ("link", "dir")]).await;
+  let renamed = store
+    .rename(digest, rename_mapping(&[("dir", "moved")]))
+    .await
+    .unwrap();
+
+  // Only the paths of symlinks are renamed: not their targets.
+  let expected = digest_of_symlinks(&store, &[("moved/link", "target"), ("link", "dir")]).await;
+  assert_eq!(renamed, expected);
+}
+
+#[tokio::test]
+async fn rename_rejects_escaping_symlinks() {
+  let (store, _, _, _) = setup();
+
+  let digest = digest_of_symlinks(&store, &[("dir/link", "../a.txt")]).await;
+  let err = store
+    .rename(digest, rename_mapping(&[("dir/link", "link")]))
+    .await
+    .unwrap_err();
+  assert!(
+    format!("{:?}", err).contains("outside of the digest root"),
+    "{:?}",
+    err
+  );
+}
+
+#[tokio::test]
+async fn merge_rejects_symlinks_traversing_symlinks() {
+  let (store, _, _, _) = setup();
+
+  // Neither Digest is invalid on its own, but once merged, `d/x` would escape via `l`.
+  let link = digest_of_symlinks(&store, &[("l", ".")]).await;
+  let traversing = digest_of_symlinks(&store, &[("d/x", "../l/..")]).await;
+  let err = store.merge(vec![link, traversing]).await.unwrap_err();
+  assert!(
+    format!("{:?}", err).contains("traverses the symlink l"),
+    "{:?}",
+    err
+  );
+}
+
+#[tokio::test]
+async fn rename_preserves_node_properties() {
+  let (store, _, _, _) = setup();
+
+  let directory_with_name = |name: &str| {
+    let mut directory = TestDirectory::containing_roland().directory();
+    directory.files[0].name = name.to_owned();
+    directory.files[0].node_properties = Some(remexec::NodeProperties {
+      unix_mode: Some(0o640),
+      ..remexec::NodeProperties::default()
+    });
+    directory
+  };
+  let digest = store
+    .record_directory(&directory_with_name("roland.ext"), false)
+    .await
+    .unwrap();
+  let renamed = store
+    .rename(digest, rename_mapping(&[("roland.ext", "renamed.ext")]))
+    .await
+    .unwrap();
+
+  let expected = store
+    .record_directory(&directory_with_name("renamed.ext"), false)
+    .await
+    .unwrap();
+  assert_eq!(renamed, expected);
+}
+
+#[tokio::test]
+async fn rename_preserves_empty_directories() {
+  let (store, _, _, _) = setup();
+
+  let file_digest = digest_of_files(&store, &[("a.txt", "a", false)]).await;
+  let empty_dir = store
+    .create_empty_dir(RelativePath::new("empty").unwrap())
+    .await
+    .unwrap();
+  let digest = store.merge(vec![file_digest, empty_dir]).await.unwrap();
+
+  let renamed = store
+    .rename(digest, rename_mapping(&[("empty", "still/empty")]))
+    .await
+    .unwrap();
+
+  let expected_empty_dir = store
+    .create_empty_dir(RelativePath::new("still/empty").unwrap())
+    .await
+    .unwrap();
+  let expected = store
+    .merge(vec![file_digest, expected_empty_dir])
+    .await
+    .unwrap();
+  assert_eq!(renamed, expected);
+}
+
+#[tokio::test]
+async fn rename_collision() {
+  let (store, _, _, _) = setup();
+
+  let digest = digest_of_files(&store, &[("a.txt", "a", false), ("b.txt", "b", false)]).await;
+  let err = store
+    .rename(digest, rename_mapping(&[("a.txt", "b.txt")]))
+    .await
+    .unwrap_err();
+  assert!(format!("{:?}", err).contains("b.txt"), "{:?}", err);
+}
+
+#[tokio::test]
+async fn rename_invalid_destination() {
+  let (store, _, _, _) = setup();
+
+  let digest = digest_of_files(&store, &[("a.txt", "a", false)]).await;
+  let err = store
+    .rename(digest, rename_regex("^", "../"))
+    .await
+    .unwrap_err();
+  assert!(
+    format!("{:?}", err).contains("escape the root"),
+    "{:?}",
+    err
+  );
+
+  let err = store
+    .rename(digest, rename_mapping(&[("a.txt", "")]))
+    .await
+    .unwrap_err();
+  assert!(
+    format!("{:?}", err).contains("to the root directory"),
+    "{:?}",
+    err
+  );
+}
+
+#[tokio::test]
+async fn substitute_contents() {
+  let (store, _, _, _) = setup();
+
+  let digest = digest_of_files(
+    &store,
+    &[
+      ("src/a.py", "import foo\nfoo.bar(foo)\n", true),
+      ("src/b.txt", "foo", false),
+    ],
+  )
+  .await;
+  let substituted = store
+    .substitute(
+      digest,
+      SubstituteParams {
+        globs: make_subset_params(&["src/*.py"]).globs,
+        replacements: vec![
+          (Bytes::from("foo"), Bytes::from("baz")),
+          (Bytes::from("baz.bar"), Bytes::from("qux")),
+        ],
+        header: Bytes::from("# Generated.\n"),
+      },
+    )
+    .await
+    .unwrap();
+
+  let expected = digest_of_files(
+    &store,
+    &[
+      ("src/a.py", "# Generated.\nimport baz\nqux(baz)\n", true),
+      ("src/b.txt", "foo", false),
+    ],
+  )
+  .await;
+  assert_eq!(substituted, expected);
+}
@@ -2,16 +2,21 @@ use std::convert::TryInto;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use futures::future::{self, FutureExt};
 use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
 use task_executor;
 use tempfile;
 use testutil::data::TestDirectory;
 use testutil::make_file;
 
-use crate::{OneOffStoreFileByDigest, RelativePath, Snapshot, SnapshotOps, Store};
+use crate::{
+  DirectoryDigestCache, NodePropertyOptions, OneOffStoreFileByDigest, RelativePath, Snapshot,
+  SnapshotOps, Store, StoreFileByDigest,
+};
 use fs::{
-  Dir, File, GitignoreStyleExcludes, GlobExpansionConjunction, GlobMatching, PathGlobs, PathStat,
-  PosixFS, StrictGlobMatching,
+  Dir, File, GitignoreStyleExcludes, GlobExpansionConjunction, GlobMatching, Link, PathGlobs,
+  PathMatchOptions, PathStat, PosixFS, StrictGlobMatching,
 };
 
 pub const STR: &str = "European Burmese";
@@ -494,6 +499,376 @@ async fn strip_subdir_not_in_dir() {
   assert_eq!(result, Err(format!("Cannot strip prefix cats/ugly from root directory (Digest with hash {:?}) - subdirectory cats didn't contain a directory named ugly but did contain file named: roland.ext", dir.digest().hash).into()));
 }
 
+#[tokio::test]
+async fn snapshot_with_cache_reuses_unchanged_directories() {
+  let (store, dir, posix_fs, digester) = setup();
+  let cache = DirectoryDigestCache::new();
+
+  std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+  std::fs::create_dir_all(dir.path().join("a/c")).unwrap();
+  std::fs::create_dir_all(dir.path().join("d")).unwrap();
+  make_file(&dir.path().join("a/b/1"), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("a/c/2"), STR.as_bytes(), 0o600);
+  make_file(&dir.path().join("d/3"), STR.as_bytes(), 0o600);
+
+  let path_stats = expand_all_sorted(posix_fs.clone()).await;
+  let snapshot1 = Snapshot::from_path_stats_with_cache(
+    store.clone(),
+    digester.clone(),
+    cache.clone(),
+    path_stats.clone(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    snapshot1,
+    Snapshot::from_path_stats(store.clone(), digester.clone(), path_stats)
+      .await
+      .unwrap()
+  );
+  assert_eq!(cache.hits(), 0);
+
+  // After modifying one file, only its ancestors should be re-recorded.
+  make_file(&dir.path().join("a/b/1"), STR2.as_bytes(), 0o600);
+  let path_stats = expand_all_sorted(posix_fs).await;
+  let snapshot2 = Snapshot::from_path_stats_with_cache(
+    store.clone(),
+    digester.clone(),
+    cache.clone(),
+    path_stats.clone(),
+  )
+  .await
+  .unwrap();
+  assert_ne!(snapshot1.digest, snapshot2.digest);
+  assert_eq!(
+    snapshot2,
+    Snapshot::from_path_stats(store.clone(), digester.clone(), path_stats.clone())
+      .await
+      .unwrap()
+  );
+  // The unchanged `a/c` and `d` directories are not descended into.
+  assert_eq!(cache.hits(), 2);
+
+  // Without changes, the whole Snapshot is reused.
+  let snapshot3 = Snapshot::from_path_stats_with_cache(store, digester, cache.clone(), path_stats)
+    .await
+    .unwrap();
+  assert_eq!(snapshot2, snapshot3);
+  assert_eq!(cache.hits(), 3);
+}
+
+#[tokio::test]
+async fn snapshot_with_cache_keys_node_properties() {
+  let (store, dir, posix_fs, digester) = setup();
+  let cache = DirectoryDigestCache::new();
+  let digester = NodePropertyDigester {
+    digester,
+    posix_fs: posix_fs.clone(),
+    options: NodePropertyOptions::from_names(&["unix_mode".to_owned()]).unwrap(),
+  };
+
+  std::fs::create_dir_all(dir.path().join("a")).unwrap();
+  make_file(&dir.path().join("a/1"), STR.as_bytes(), 0o600);
+  let path_stats = expand_all_sorted(posix_fs).await;
+  let snapshot1 = Snapshot::from_path_stats_with_cache(
+    store.clone(),
+    digester.clone(),
+    cache.clone(),
+    path_stats.clone(),
+  )
+  .await
+  .unwrap();
+
+  // Changing only the mode of a file changes its NodeProperties, and so must not hit the cache.
+  std::fs::set_permissions(
+    dir.path().join("a/1"),
+    <std::fs::Permissions as std::os::unix::fs::PermissionsExt>::from_mode(0o640),
+  )
+  .unwrap();
+  let snapshot2 = Snapshot::from_path_stats_with_cache(
+    store.clone(),
+    digester.clone(),
+    cache.clone(),
+    path_stats.clone(),
+  )
+  .await
+  .unwrap();
+  assert_ne!(snapshot1.digest, snapshot2.digest);
+  assert_eq!(
+    snapshot2,
+    Snapshot::from_path_stats(store, digester, path_stats)
+      .await
+      .unwrap()
+  );
+  assert_eq!(cache.hits(), 0);
+}
+
+#[tokio::test]
+async fn snapshot_symlinks() {
+  let (store, tempdir, _, digester) = setup();
+
+  let path_stats = PathStat::normalize_path_stats(vec![
+    make_dir_stat(tempdir.path(), &PathBuf::from("cats")),
+    make_file_stat(
+      tempdir.path(),
+      &PathBuf::from("cats/roland"),
+      STR.as_bytes(),
+      false,
+    ),
+    make_link_stat(&PathBuf::from("cats/link"), "roland"),
+    make_link_stat(&PathBuf::from("link"), "./cats/../cats/roland"),
+  ])
+  .unwrap();
+  let snapshot = Snapshot::from_path_stats(store.clone(), digester, path_stats.clone())
+    .await
+    .unwrap();
+  assert_eq!(
+    Snapshot::from_digest(store.clone(), snapshot.digest).await,
+    Ok(Snapshot {
+      digest: snapshot.digest,
+      path_stats,
+    })
+  );
+
+  let prefixed = store
+    .add_prefix(snapshot.digest, RelativePath::new("pets").unwrap())
+    .await
+    .unwrap();
+  assert_eq!(
+    store
+      .strip_prefix(prefixed, RelativePath::new("pets").unwrap())
+      .await,
+    Ok(snapshot.digest)
+  );
+  // A symlink is a non-matching entry when stripping a prefix.
+  let err = store
+    .strip_prefix(snapshot.digest, RelativePath::new("cats").unwrap())
+    .await
+    .unwrap_err();
+  assert!(
+    format!("{:?}", err).contains("contained non-matching file named: link"),
+    "{:?}",
+    err
+  );
+}
+
+#[tokio::test]
+async fn snapshot_rejects_escaping_symlinks() {
+  let (store, _, _, digester) = setup();
+
+  for target in &["../outside", "cats/../../outside", "/etc/passwd"] {
+    let err = Snapshot::from_path_stats(
+      store.clone(),
+      digester.clone(),
+      vec![make_link_stat(&PathBuf::from("link"), target)],
+    )
+    .await
+    .unwrap_err();
+    assert!(err.contains("outside of the snapshot root"), "{}", err);
+  }
+
+  // A target which only escapes via another symlink is rejected as well.
+  let err = Snapshot::from_path_stats(
+    store.clone(),
+    digester.clone(),
+    vec![
+      make_link_stat(&PathBuf::from("l"), "."),
+      make_link_stat(&PathBuf::from("d/x"), "../l/.."),
+    ],
+  )
+  .await
+  .unwrap_err();
+  assert!(err.contains("traverses the symlink l"), "{}", err);
+
+  // But a symlink to another symlink is supported.
+  Snapshot::from_path_stats(
+    store,
+    digester,
+    vec![
+      make_link_stat(&PathBuf::from("l"), "."),
+      make_link_stat(&PathBuf::from("d/x"), "../l"),
+    ],
+  )
+  .await
+  .unwrap();
+}
+
+#[tokio::test]
+async fn snapshot_merge_symlinks() {
+  let (store, _, _, digester) = setup();
+
+  let snapshot_of = |target: &'static str| {
+    Snapshot::from_path_stats(
+      store.clone(),
+      digester.clone(),
+      vec![make_link_stat(&PathBuf::from("link"), target)],
+    )
+  };
+  let snapshot1 = snapshot_of("roland").await.unwrap();
+  let snapshot2 = snapshot_of("robin").await.unwrap();
+
+  // Identical symlinks are merged.
+  assert_eq!(
+    store.merge(vec![snapshot1.digest, snapshot1.digest]).await,
+    Ok(snapshot1.digest)
+  );
+  // But symlinks with different targets collide.
+  let err = store
+    .merge(vec![snapshot1.digest, snapshot2.digest])
+    .await
+    .unwrap_err();
+  assert!(
+    format!("{:?}", err).contains("found 2 duplicate entries"),
+    "{:?}",
+    err
+  );
+}
+
+///
+/// A StoreFileByDigest which additionally captures NodeProperties, as the engine's does.
+///
+#[derive(Clone)]
+struct NodePropertyDigester {
+  digester: OneOffStoreFileByDigest,
+  posix_fs: Arc<PosixFS>,
+  options: NodePropertyOptions,
+}
+
+impl StoreFileByDigest<String> for NodePropertyDigester {
+  fn store_by_digest(&self, file: File) -> future::BoxFuture<'static, Result<Digest, String>> {
+    self.digester.store_by_digest(file)
+  }
+
+  fn node_properties(
+    &self,
+    file: &File,
+  ) -> future::BoxFuture<'static, Result<Option<remexec::NodeProperties>, String>> {
+    future::ready(self.options.node_properties(&self.posix_fs.file_path(file))).boxed()
+  }
+}
+
+#[tokio::test]
+async fn snapshot_node_properties() {
+  let (store, dir, posix_fs, digester) = setup();
+
+  make_file(&dir.path().join("roland"), STR.as_bytes(), 0o640);
+  let path_stats = expand_all_sorted(posix_fs.clone()).await;
+
+  let snapshot_with = |names: &[&str]| {
+    let names = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let digester = NodePropertyDigester {
+      digester: digester.clone(),
+      posix_fs: posix_fs.clone(),
+      options: NodePropertyOptions::from_names(&names).unwrap(),
+    };
+    Snapshot::from_path_stats(store.clone(), digester, path_stats.clone())
+  };
+  let snapshot = snapshot_with(&[]).await.unwrap();
+  let snapshot_with_mode = snapshot_with(&["unix_mode"]).await.unwrap();
+  assert_ne!(snapshot.digest, snapshot_with_mode.digest);
+  assert_eq!(snapshot.path_stats, snapshot_with_mode.path_stats);
+
+  let directory = store
+    .load_directory(snapshot_with_mode.digest)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(
+    directory.files[0].node_properties,
+    Some(remexec::NodeProperties {
+      unix_mode: Some(0o640),
+      ..remexec::NodeProperties::default()
+    })
+  );
+  let directory = store
+    .load_directory(snapshot.digest)
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(directory.files[0].node_properties, None);
+
+  let err = NodePropertyOptions::from_names(&["owner".to_owned()]).unwrap_err();
+  assert!(err.contains("Unsupported node property `owner`"), "{}", err);
+}
+
+#[test]
+fn check_path_collisions() {
+  let file = |path: &str| {
+    PathStat::file(
+      PathBuf::from(path),
+      File {
+        path: PathBuf::from(path),
+        is_executable: false,
+      },
+    )
+  };
+  let path_stats = vec![
+    file("a/README"),
+    file("a/readme"),
+    file("a/caf\u{e9}"),
+    file("a/cafe\u{301}"),
+    file("b/other"),
+    file("Foo/a.py"),
+    file("foo/b.py"),
+  ];
+  let error = StrictGlobMatching::Error("tests".to_owned());
+
+  // Exact matching never collides.
+  assert_eq!(
+    Ok(()),
+    Snapshot::check_path_collisions(&path_stats, PathMatchOptions::default(), &error)
+  );
+  // Nor are collisions checked when match failures are ignored.
+  assert_eq!(
+    Ok(()),
+    Snapshot::check_path_collisions(
+      &path_stats,
+      PathMatchOptions::new(true, true),
+      &StrictGlobMatching::Ignore
+    )
+  );
+  assert_eq!(
+    Ok(()),
+    Snapshot::check_path_collisions(
+      &path_stats,
+      PathMatchOptions::new(true, true),
+      &StrictGlobMatching::Warn("tests".to_owned())
+    )
+  );
+
+  let err =
+    Snapshot::check_path_collisions(&path_stats, PathMatchOptions::new(true, false), &error)
+      .unwrap_err();
+  assert!(err.contains("a/README"), "{}", err);
+  assert!(!err.contains("caf"), "{}", err);
+  // Paths whose parent directories collide are reported via the parent directories.
+  assert!(
+    err.contains(r#"["Foo", "foo"] differ only by case"#),
+    "{}",
+    err
+  );
+  assert!(!err.contains("a.py"), "{}", err);
+  let err =
+    Snapshot::check_path_collisions(&path_stats, PathMatchOptions::new(false, true), &error)
+      .unwrap_err();
+  assert!(!err.contains("a/README"), "{}", err);
+  assert!(!err.contains("Foo"), "{}", err);
+  assert!(
+    err.contains("differ only by Unicode normalization"),
+    "{}",
+    err
+  );
+  // With both options, each collision is described by how its paths actually differ.
+  let err = Snapshot::check_path_collisions(&path_stats, PathMatchOptions::new(true, true), &error)
+    .unwrap_err();
+  assert!(err.contains("a/readme\"] differ only by case;"), "{}", err);
+  assert!(
+    err.contains("differ only by Unicode normalization"),
+    "{}",
+    err
+  );
+}
+
 fn make_dir_stat(root: &Path, relpath: &Path) -> PathStat {
   std::fs::create_dir(root.join(relpath)).unwrap();
   PathStat::dir(relpath.to_owned(), Dir(relpath.to_owned()))
@@ -514,6 +889,14 @@ fn make_file_stat(root: &Path, relpath: &Path, contents: &[u8], is_executable: b
   )
 }
 
+fn make_link_stat(relpath: &Path, target: &str) -> PathStat {
+  PathStat::link(
+    relpath.to_owned(),
+    Link(relpath.to_owned()),
+    PathBuf::from(target),
+  )
+}
+
 pub async fn expand_all_sorted(posix_fs: Arc<PosixFS>) -> Vec<PathStat> {
   let path_globs = PathGlobs::new(
     vec!["**".to_owned()],
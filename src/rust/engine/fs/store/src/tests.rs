@@ -1,7 +1,7 @@
 use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::Read;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tempfile::TempDir;
@@ -9,13 +9,16 @@ use testutil::data::{TestData, TestDirectory};
 
 use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
 use bytes::{Bytes, BytesMut};
-use fs::{DigestEntry, FileEntry};
+use fs::{DigestEntry, FileEntry, SymlinkEntry};
 use grpc_util::prost::MessageExt;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::tls;
 use hashing::{Digest, Fingerprint};
 use mock::StubCAS;
 
-use crate::{EntryType, FileContent, Store, UploadSummary, MEGABYTES};
+use crate::{
+  EntryType, FileContent, MaterializeStrategy, ShrinkBehavior, Store, UploadSummary, MEGABYTES,
+};
 
 pub(crate) const STORE_BATCH_API_SIZE_LIMIT: usize = 4 * 1024 * 1024;
 
@@ -93,6 +96,7 @@ fn new_store<P: AsRef<Path>>(dir: P, cas_address: &str) -> Store {
       Duration::from_secs(1),
       1,
       256,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
@@ -832,6 +836,7 @@ async fn instance_name_upload() {
       Duration::from_secs(1),
       1,
       256,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
@@ -862,6 +867,7 @@ async fn instance_name_download() {
       Duration::from_secs(1),
       1,
       256,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
@@ -913,6 +919,7 @@ async fn auth_upload() {
       Duration::from_secs(1),
       1,
       256,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
@@ -945,6 +952,7 @@ async fn auth_download() {
       Duration::from_secs(1),
       1,
       256,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
@@ -968,7 +976,7 @@ async fn materialize_missing_file() {
   let store_dir = TempDir::new().unwrap();
   let store = new_local_store(store_dir.path());
   store
-    .materialize_file(file.clone(), TestData::roland().digest(), false)
+    .materialize_file(file.clone(), TestData::roland().digest(), false, None)
     .await
     .expect_err("Want unknown digest error");
 }
@@ -987,7 +995,7 @@ async fn materialize_file() {
     .await
     .expect("Error saving bytes");
   store
-    .materialize_file(file.clone(), testdata.digest(), false)
+    .materialize_file(file.clone(), testdata.digest(), false, None)
     .await
     .expect("Error materializing file");
   assert_eq!(file_contents(&file), testdata.bytes());
@@ -1008,7 +1016,7 @@ async fn materialize_file_executable() {
     .await
     .expect("Error saving bytes");
   store
-    .materialize_file(file.clone(), testdata.digest(), true)
+    .materialize_file(file.clone(), testdata.digest(), true, None)
     .await
     .expect("Error materializing file");
   assert_eq!(file_contents(&file), testdata.bytes());
@@ -1120,6 +1128,317 @@ async fn materialize_directory_executable() {
   assert!(!is_executable(&materialize_dir.path().join("food.ext")));
 }
 
+#[tokio::test]
+async fn materialize_directory_symlinks() {
+  let materialize_dir = TempDir::new().unwrap();
+
+  let roland = TestData::roland();
+  let mut directory = TestDirectory::containing_roland().directory();
+  directory.symlinks.push(remexec::SymlinkNode {
+    name: "z-link.ext".to_owned(),
+    target: "roland.ext".to_owned(),
+    ..remexec::SymlinkNode::default()
+  });
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  let digest = store
+    .record_directory(&directory, false)
+    .await
+    .expect("Error saving Directory");
+  store
+    .store_file_bytes(roland.bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+
+  // Materialize twice, to confirm that an existing symlink is replaced.
+  for _ in 0..2 {
+    store
+      .materialize_directory(materialize_dir.path().to_owned(), digest)
+      .await
+      .expect("Error materializing");
+  }
+
+  let link = materialize_dir.path().join("z-link.ext");
+  assert_eq!(
+    list_dir(materialize_dir.path()),
+    vec!["roland.ext", "z-link.ext"]
+  );
+  assert_eq!(
+    std::fs::read_link(&link).unwrap(),
+    PathBuf::from("roland.ext")
+  );
+  assert_eq!(file_contents(&link), roland.bytes());
+}
+
+#[tokio::test]
+async fn materialize_directory_rejects_escaping_symlinks() {
+  let materialize_dir = TempDir::new().unwrap();
+
+  let mut directory = TestDirectory::containing_roland().directory();
+  directory.symlinks.push(remexec::SymlinkNode {
+    name: "z-link.ext".to_owned(),
+    target: "../roland.ext".to_owned(),
+    ..remexec::SymlinkNode::default()
+  });
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  let digest = store
+    .record_directory(&directory, false)
+    .await
+    .expect("Error saving Directory");
+
+  let destination = materialize_dir.path().join("out");
+  let err = store
+    .materialize_directory(destination.clone(), digest)
+    .await
+    .unwrap_err();
+  assert!(
+    err.contains("outside of the materialized directory"),
+    "{}",
+    err
+  );
+  assert!(!destination.exists());
+}
+
+#[tokio::test]
+async fn materialize_directory_node_properties() {
+  let materialize_dir = TempDir::new().unwrap();
+
+  let roland = TestData::roland();
+  let mtime = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000);
+  let mut directory = TestDirectory::containing_roland().directory();
+  directory.files[0].node_properties = Some(remexec::NodeProperties {
+    unix_mode: Some(0o440),
+    mtime: Some(mtime.into()),
+    ..remexec::NodeProperties::default()
+  });
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  let digest = store
+    .record_directory(&directory, false)
+    .await
+    .expect("Error saving Directory");
+  store
+    .store_file_bytes(roland.bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+
+  // Materialize twice, to confirm that an existing read-only file is replaced.
+  for _ in 0..2 {
+    store
+      .materialize_directory(materialize_dir.path().to_owned(), digest)
+      .await
+      .expect("Error materializing");
+  }
+
+  let file = materialize_dir.path().join("roland.ext");
+  let metadata = std::fs::metadata(&file).unwrap();
+  assert_eq!(file_contents(&file), roland.bytes());
+  assert_eq!(metadata.permissions().mode() & 0o7777, 0o440);
+  assert_eq!(metadata.modified().unwrap(), mtime);
+}
+
+fn new_local_store_with_immutable_files(dir: &Path, strategy: MaterializeStrategy) -> Store {
+  new_local_store(dir)
+    .into_with_immutable_files(dir.join("immutable"), strategy)
+    .expect("Error creating immutable file cache")
+}
+
+fn immutable_file_entries(store_dir: &Path) -> Vec<String> {
+  let immutable_dir = store_dir.join("immutable");
+  let mut entries = list_dir(&immutable_dir)
+    .into_iter()
+    .flat_map(|shard| list_dir(&immutable_dir.join(shard)))
+    .collect::<Vec<_>>();
+  entries.sort();
+  entries
+}
+
+async fn store_mixed_executable_files(store: &Store) -> Digest {
+  store
+    .store_file_bytes(TestData::catnip().bytes(), false)
+    .await
+    .expect("Error saving file bytes");
+  store
+    .record_directory(
+      &TestDirectory::with_mixed_executable_files().directory(),
+      false,
+    )
+    .await
+    .expect("Error saving Directory")
+}
+
+#[tokio::test]
+async fn materialize_input_directory_hardlinks() {
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store_with_immutable_files(store_dir.path(), MaterializeStrategy::Hardlink);
+  let digest = store_mixed_executable_files(&store).await;
+
+  let materialize_dir1 = TempDir::new().unwrap();
+  let materialize_dir2 = TempDir::new().unwrap();
+  for materialize_dir in &[&materialize_dir1, &materialize_dir2] {
+    store
+      .materialize_input_directory(materialize_dir.path().to_owned(), digest)
+      .await
+      .expect("Error materializing");
+  }
+
+  let metadata = |dir: &TempDir, name: &str| std::fs::metadata(dir.path().join(name)).unwrap();
+  let feed = metadata(&materialize_dir1, "feed.ext");
+  let food = metadata(&materialize_dir1, "food.ext");
+  // Both sandboxes link the same read-only entries, which are separate for executable files.
+  assert_eq!(feed.ino(), metadata(&materialize_dir2, "feed.ext").ino());
+  assert_eq!(food.ino(), metadata(&materialize_dir2, "food.ext").ino());
+  assert_ne!(feed.ino(), food.ino());
+  assert_eq!(feed.permissions().mode() & 0o7777, 0o555);
+  assert_eq!(food.permissions().mode() & 0o7777, 0o444);
+  assert_eq!(
+    file_contents(&materialize_dir1.path().join("food.ext")),
+    TestData::catnip().bytes()
+  );
+  assert_eq!(immutable_file_entries(store_dir.path()).len(), 2);
+
+  // But `materialize_directory` copies files.
+  let copy_dir = TempDir::new().unwrap();
+  store
+    .materialize_directory(copy_dir.path().to_owned(), digest)
+    .await
+    .expect("Error materializing");
+  let copied_food = metadata(&copy_dir, "food.ext");
+  assert_ne!(food.ino(), copied_food.ino());
+  assert_eq!(copied_food.permissions().mode() & 0o7777, 0o644);
+}
+
+#[tokio::test]
+async fn materialize_input_directory_replaces_modified_entries() {
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store_with_immutable_files(store_dir.path(), MaterializeStrategy::Hardlink);
+  let digest = store_mixed_executable_files(&store).await;
+
+  let materialize_dir1 = TempDir::new().unwrap();
+  store
+    .materialize_input_directory(materialize_dir1.path().to_owned(), digest)
+    .await
+    .expect("Error materializing");
+  // Modify the linked entry via the sandbox.
+  let food = materialize_dir1.path().join("food.ext");
+  std::fs::set_permissions(&food, std::fs::Permissions::from_mode(0o644)).unwrap();
+  std::fs::write(&food, "not catnip").unwrap();
+
+  let materialize_dir2 = TempDir::new().unwrap();
+  store
+    .materialize_input_directory(materialize_dir2.path().to_owned(), digest)
+    .await
+    .expect("Error materializing");
+  let food = materialize_dir2.path().join("food.ext");
+  assert_eq!(file_contents(&food), TestData::catnip().bytes());
+  assert_eq!(
+    std::fs::metadata(&food).unwrap().permissions().mode() & 0o7777,
+    0o444
+  );
+}
+
+#[tokio::test]
+async fn materialize_input_directory_verifies_entries_with_changed_stats() {
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store_with_immutable_files(store_dir.path(), MaterializeStrategy::Hardlink);
+  let digest = store_mixed_executable_files(&store).await;
+
+  let materialize_dir1 = TempDir::new().unwrap();
+  store
+    .materialize_input_directory(materialize_dir1.path().to_owned(), digest)
+    .await
+    .expect("Error materializing");
+  // Modify the linked entry via the sandbox, preserving its size and mode.
+  let food = materialize_dir1.path().join("food.ext");
+  let modified = vec![b'x'; TestData::catnip().bytes().len()];
+  std::fs::set_permissions(&food, std::fs::Permissions::from_mode(0o644)).unwrap();
+  std::fs::write(&food, &modified).unwrap();
+  std::fs::set_permissions(&food, std::fs::Permissions::from_mode(0o444)).unwrap();
+
+  let materialize_dir2 = TempDir::new().unwrap();
+  store
+    .materialize_input_directory(materialize_dir2.path().to_owned(), digest)
+    .await
+    .expect("Error materializing");
+  assert_eq!(
+    file_contents(&materialize_dir2.path().join("food.ext")),
+    TestData::catnip().bytes()
+  );
+}
+
+#[tokio::test]
+async fn materialize_input_directory_reflinks() {
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store_with_immutable_files(store_dir.path(), MaterializeStrategy::Reflink);
+  let digest = store_mixed_executable_files(&store).await;
+
+  // Whether or not the filesystem supports reflinks (and so whether or not the files are copied),
+  // the materialized files should be independent of the immutable file cache, and writable.
+  let materialize_dir = TempDir::new().unwrap();
+  store
+    .materialize_input_directory(materialize_dir.path().to_owned(), digest)
+    .await
+    .expect("Error materializing");
+  let feed = materialize_dir.path().join("feed.ext");
+  let food = materialize_dir.path().join("food.ext");
+  assert_eq!(file_contents(&feed), TestData::catnip().bytes());
+  assert_eq!(file_contents(&food), TestData::catnip().bytes());
+  assert_eq!(
+    std::fs::metadata(&feed).unwrap().permissions().mode() & 0o7777,
+    0o755
+  );
+  assert_eq!(
+    std::fs::metadata(&food).unwrap().permissions().mode() & 0o7777,
+    0o644
+  );
+}
+
+#[tokio::test]
+async fn garbage_collect_removes_immutable_files() {
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store_with_immutable_files(store_dir.path(), MaterializeStrategy::Hardlink);
+  // Roland is leased, but catnip is not.
+  store
+    .store_file_bytes(TestData::roland().bytes(), true)
+    .await
+    .expect("Error saving file bytes");
+  let roland_digest = store
+    .record_directory(&TestDirectory::containing_roland().directory(), true)
+    .await
+    .expect("Error saving Directory");
+  let catnip_digest = store_mixed_executable_files(&store).await;
+
+  let materialize_dir = TempDir::new().unwrap();
+  for digest in &[roland_digest, catnip_digest] {
+    store
+      .materialize_input_directory(materialize_dir.path().to_owned(), *digest)
+      .await
+      .expect("Error materializing");
+  }
+  assert_eq!(immutable_file_entries(store_dir.path()).len(), 3);
+
+  store
+    .garbage_collect(0, ShrinkBehavior::Fast)
+    .expect("Error garbage collecting");
+  assert_eq!(
+    immutable_file_entries(store_dir.path()),
+    vec![format!(
+      "{}-{}",
+      TestData::roland().fingerprint().to_hex(),
+      TestData::roland().len()
+    )]
+  );
+  // Already materialized files are unaffected.
+  assert_eq!(
+    file_contents(&materialize_dir.path().join("food.ext")),
+    TestData::catnip().bytes()
+  );
+}
+
 #[tokio::test]
 async fn contents_for_directory_empty() {
   let store_dir = TempDir::new().unwrap();
@@ -1269,6 +1588,44 @@ async fn entries_for_directory() {
   );
 }
 
+#[tokio::test]
+async fn entries_for_directory_symlinks() {
+  let roland = TestData::roland();
+  let mut directory = TestDirectory::containing_roland().directory();
+  directory.symlinks.push(remexec::SymlinkNode {
+    name: "z-link.ext".to_owned(),
+    target: "roland.ext".to_owned(),
+    ..remexec::SymlinkNode::default()
+  });
+
+  let store_dir = TempDir::new().unwrap();
+  let store = new_local_store(store_dir.path());
+  let digest = store
+    .record_directory(&directory, false)
+    .await
+    .expect("Error saving Directory");
+
+  let digest_entries = store
+    .entries_for_directory(digest)
+    .await
+    .expect("Getting DigestEntries");
+
+  assert_same_digest_entries(
+    digest_entries,
+    vec![
+      DigestEntry::File(FileEntry {
+        path: PathBuf::from("roland.ext"),
+        digest: roland.digest(),
+        is_executable: false,
+      }),
+      DigestEntry::Symlink(SymlinkEntry {
+        path: PathBuf::from("z-link.ext"),
+        target: PathBuf::from("roland.ext"),
+      }),
+    ],
+  );
+}
+
 fn assert_same_digest_entries(left: Vec<DigestEntry>, right: Vec<DigestEntry>) {
   assert_eq!(
     left.len(),
@@ -1304,6 +1661,15 @@ fn assert_same_digest_entries(left: Vec<DigestEntry>, right: Vec<DigestEntry>) {
           );
         }
       }
+      (DigestEntry::Symlink(l), DigestEntry::Symlink(r)) => {
+        if l != r {
+          success = false;
+          eprintln!(
+            "Symlinks did not match for index {}: {:?}, {:?}",
+            index, l, r
+          );
+        }
+      }
       (DigestEntry::EmptyDirectory(path_left), DigestEntry::EmptyDirectory(path_right)) => {
         if path_left != path_right {
           success = false;
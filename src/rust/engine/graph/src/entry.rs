@@ -232,6 +232,23 @@ impl<N: Node> Entry<N> {
     }
   }
 
+  ///
+  /// Creates an Entry which has already completed with the given clean value, and which has no
+  /// dependencies.
+  ///
+  pub(crate) fn new_completed(node: N, item: N::Item) -> Entry<N> {
+    Entry {
+      node,
+      state: Arc::new(Mutex::new(EntryState::Completed {
+        run_token: RunToken::initial(),
+        generation: Generation::initial(),
+        pollers: Vec::new(),
+        result: EntryResult::Clean(item),
+        dep_generations: Vec::new(),
+      })),
+    }
+  }
+
   pub fn node(&self) -> &N {
     &self.node
   }
@@ -280,6 +297,20 @@ impl<N: Node> Entry<N> {
     let _ = recv.await;
   }
 
+  ///
+  /// If the value of this Entry is Clean (and thus valid independent of any particular Run),
+  /// returns it.
+  ///
+  pub(crate) fn peek_clean(&self) -> Option<N::Item> {
+    match *self.state.lock() {
+      EntryState::Completed {
+        result: EntryResult::Clean(ref item),
+        ..
+      } => Some(item.clone()),
+      _ => None,
+    }
+  }
+
   ///
   /// If the Future for this Node has already completed, returns a clone of its result.
   ///
@@ -895,6 +895,37 @@ impl<N: Node> Graph<N> {
     }
   }
 
+  ///
+  /// Visits each Node with a Clean value: i.e., a value which is valid independent of the Run
+  /// which computed it. Used to persist values which might be restored via `insert_clean`.
+  ///
+  pub fn visit_clean(&self, mut f: impl FnMut(&N, N::Item)) {
+    let inner = self.inner.lock();
+    for node in inner.pg.raw_nodes() {
+      if let Some(item) = node.weight.peek_clean() {
+        f(node.weight.node(), item);
+      }
+    }
+  }
+
+  ///
+  /// Inserts a Node which has already completed with the given Clean value, unless the Node is
+  /// already present in the Graph. Returns true if the Node was inserted.
+  ///
+  /// The Node must not have any dependencies: it will be invalidated (and re-run) in the same way
+  /// as any other Node, but the Graph will not know to re-check any dependencies before reusing its
+  /// value.
+  ///
+  pub fn insert_clean(&self, node: N, item: N::Item) -> bool {
+    let mut inner = self.inner.lock();
+    if inner.nodes.contains_key(&node) {
+      return false;
+    }
+    let id = inner.pg.add_node(Entry::new_completed(node.clone(), item));
+    inner.nodes.insert(node, id);
+    true
+  }
+
   ///
   /// Executes an operation while all access to the Graph is prevented (by acquiring the Graph's
   /// lock).
@@ -60,6 +60,46 @@ async fn invalidate_and_clean() {
   );
 }
 
+#[tokio::test]
+async fn insert_clean_and_invalidate() {
+  let graph = Arc::new(Graph::new());
+  let context = TContext::new(graph.clone());
+
+  // Insert a value for the leaf Node, which should be used rather than running it.
+  assert!(graph.insert_clean(TNode::new(0), vec![T(0, 1)]));
+  assert!(!graph.insert_clean(TNode::new(0), vec![T(0, 2)]));
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 1), T(1, 0)])
+  );
+  assert_eq!(context.runs(), vec![TNode::new(1)]);
+
+  // Clean values (including the inserted one) are visited.
+  let mut clean = HashMap::new();
+  graph.visit_clean(|node, item| {
+    clean.insert(node.0, item);
+  });
+  assert_eq!(clean.get(&0), Some(&vec![T(0, 1)]));
+  assert_eq!(clean.get(&1), Some(&vec![T(0, 1), T(1, 0)]));
+
+  // Invalidating the inserted Node causes it to actually run.
+  assert_eq!(
+    graph.invalidate_from_roots(|&TNode(n, _)| n == 0),
+    InvalidationResult {
+      cleared: 1,
+      dirtied: 1
+    }
+  );
+  assert_eq!(
+    graph.create(TNode::new(1), &context).await,
+    Ok(vec![T(0, 0), T(1, 0)])
+  );
+  assert_eq!(
+    context.runs(),
+    vec![TNode::new(1), TNode::new(0), TNode::new(1)]
+  );
+}
+
 #[tokio::test]
 async fn invalidate_and_rerun() {
   let graph = Arc::new(Graph::new());
@@ -34,6 +34,7 @@ use std::iter::FromIterator;
 use std::str::FromStr;
 
 use crate::headers::{SetRequestHeaders, SetRequestHeadersLayer};
+use crate::rate_limit::{RateLimit, RateLimitConfig, RateLimitLayer};
 use either::Either;
 use http::header::{HeaderName, USER_AGENT};
 use http::{HeaderMap, HeaderValue};
@@ -46,21 +47,24 @@ use tower::ServiceBuilder;
 pub mod headers;
 pub mod hyper;
 pub mod prost;
+pub mod rate_limit;
 pub mod retry;
 pub mod tls;
 
 // NB: Rather than boxing our tower/tonic services, we define a type alias that fully defines the
 // Service layers that we use universally. If this type becomes unwieldy, or our various Services
 // diverge in which layers they use, we should instead use a Box<dyn Service<..>>.
-pub type LayeredService = SetRequestHeaders<ConcurrencyLimit<Channel>>;
+pub type LayeredService = SetRequestHeaders<RateLimit<ConcurrencyLimit<Channel>>>;
 
 pub fn layered_service(
   channel: Channel,
   concurrency_limit: usize,
+  rate_limit: RateLimitConfig,
   http_headers: HeaderMap,
 ) -> LayeredService {
   ServiceBuilder::new()
     .layer(SetRequestHeadersLayer::new(http_headers))
+    .layer(RateLimitLayer::new(rate_limit))
     .concurrency_limit(concurrency_limit)
     .service(channel)
 }
@@ -0,0 +1,638 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Buf;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http::{HeaderMap, Request, Response, StatusCode};
+use http_body::{Body, SizeHint};
+use parking_lot::Mutex;
+use tokio::time::Sleep;
+use tower_layer::Layer;
+use tower_service::Service;
+use workunit_store::ObservationMetric;
+
+/// The initial delay applied after the server first signals that it is overloaded.
+const INITIAL_OVERLOAD_BACKOFF: Duration = Duration::from_millis(100);
+/// The maximum delay applied while the server continues to signal that it is overloaded.
+const MAX_OVERLOAD_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The gRPC status code for `OK`.
+const GRPC_STATUS_OK: &str = "0";
+/// The gRPC status code for `RESOURCE_EXHAUSTED`.
+const GRPC_STATUS_RESOURCE_EXHAUSTED: &str = "8";
+
+///
+/// Limits on the rate of requests (and bytes) that may be sent to a remote service.
+///
+/// A limit of `None` means that the corresponding rate is not limited: limits which are configured
+/// must be positive. Independent of the configured limits, requests are delayed with exponential
+/// backoff when the server responds with `RESOURCE_EXHAUSTED` (or HTTP 429).
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimitConfig {
+  pub requests_per_second: Option<usize>,
+  pub bytes_per_second: Option<usize>,
+}
+
+///
+/// A token bucket which is allowed to go into debt: a caller that takes more than the available
+/// balance (e.g. a large response body) delays subsequent callers until the debt is repaid.
+///
+#[derive(Debug)]
+struct TokenBucket {
+  rate_per_second: f64,
+  capacity: f64,
+  balance: f64,
+  last_refill: Instant,
+}
+
+impl TokenBucket {
+  fn new(rate_per_second: usize, now: Instant) -> TokenBucket {
+    // Allow a burst of up to one second worth of tokens.
+    let rate_per_second = rate_per_second as f64;
+    TokenBucket {
+      rate_per_second,
+      capacity: rate_per_second,
+      balance: rate_per_second,
+      last_refill: now,
+    }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.last_refill);
+    self.balance = (self.balance + elapsed.as_secs_f64() * self.rate_per_second).min(self.capacity);
+    self.last_refill = now;
+  }
+
+  ///
+  /// Returns the time until the balance will be at least `needed`, or `None` if it already is.
+  ///
+  fn delay_until_available(&mut self, now: Instant, needed: f64) -> Option<Duration> {
+    self.refill(now);
+    if self.balance >= needed {
+      None
+    } else {
+      Some(Duration::from_secs_f64(
+        (needed - self.balance) / self.rate_per_second,
+      ))
+    }
+  }
+
+  fn take(&mut self, now: Instant, amount: f64) {
+    self.refill(now);
+    self.balance -= amount;
+  }
+}
+
+#[derive(Debug, Default)]
+struct OverloadBackoff {
+  current: Duration,
+  blocked_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct State {
+  requests: Option<TokenBucket>,
+  bytes: Option<TokenBucket>,
+  backoff: OverloadBackoff,
+}
+
+impl State {
+  ///
+  /// Returns how long a new request must wait before being sent, or `None` if it may be sent now.
+  ///
+  fn delay(&mut self, now: Instant) -> Option<Duration> {
+    let backoff_delay = self
+      .backoff
+      .blocked_until
+      .and_then(|until| until.checked_duration_since(now))
+      .filter(|d| *d > Duration::from_secs(0));
+    let request_delay = self
+      .requests
+      .as_mut()
+      .and_then(|b| b.delay_until_available(now, 1.0));
+    // Bytes are not known until a request is made, so we only wait for any debt to be repaid.
+    let bytes_delay = self
+      .bytes
+      .as_mut()
+      .and_then(|b| b.delay_until_available(now, 0.0));
+    vec![backoff_delay, request_delay, bytes_delay]
+      .into_iter()
+      .flatten()
+      .max()
+  }
+
+  fn take_request(&mut self, now: Instant, bytes: u64) {
+    if let Some(ref mut requests) = self.requests {
+      requests.take(now, 1.0);
+    }
+    self.take_bytes(now, bytes);
+  }
+
+  fn take_bytes(&mut self, now: Instant, bytes: u64) {
+    if let Some(ref mut bytes_bucket) = self.bytes {
+      bytes_bucket.take(now, bytes as f64);
+    }
+  }
+
+  fn record_overloaded(&mut self, now: Instant) -> Duration {
+    let next = if self.backoff.current == Duration::from_secs(0) {
+      INITIAL_OVERLOAD_BACKOFF
+    } else {
+      std::cmp::min(self.backoff.current * 2, MAX_OVERLOAD_BACKOFF)
+    };
+    self.backoff.current = next;
+    self.backoff.blocked_until = Some(now + next);
+    next
+  }
+
+  fn record_success(&mut self) {
+    // Decay the backoff gradually, so that a server which is just barely keeping up is not
+    // immediately flooded again.
+    let next = self.backoff.current / 2;
+    self.backoff.current = if next < INITIAL_OVERLOAD_BACKOFF {
+      Duration::from_secs(0)
+    } else {
+      next
+    };
+  }
+}
+
+///
+/// A handle to rate limiting state which is shared between all clones of a `RateLimit` service.
+///
+#[derive(Clone)]
+struct Shared(Arc<Mutex<State>>);
+
+impl Shared {
+  fn new(config: RateLimitConfig) -> Shared {
+    let now = Instant::now();
+    Shared(Arc::new(Mutex::new(State {
+      requests: config
+        .requests_per_second
+        .filter(|r| *r > 0)
+        .map(|r| TokenBucket::new(r, now)),
+      bytes: config
+        .bytes_per_second
+        .filter(|r| *r > 0)
+        .map(|r| TokenBucket::new(r, now)),
+      backoff: OverloadBackoff::default(),
+    })))
+  }
+
+  fn observe_response_headers(&self, status: StatusCode, headers: &HeaderMap) {
+    // NB: A gRPC response generally carries its status in its trailers, which are observed
+    // separately. Only a "trailers-only" response carries its status in its headers.
+    if is_overloaded(status, headers) {
+      self.overloaded();
+    } else if status.is_success() && is_grpc_ok(headers) {
+      self.0.lock().record_success();
+    }
+  }
+
+  fn observe_trailers(&self, trailers: &HeaderMap) {
+    if is_overloaded(StatusCode::OK, trailers) {
+      self.overloaded();
+    } else if is_grpc_ok(trailers) {
+      self.0.lock().record_success();
+    }
+  }
+
+  fn overloaded(&self) {
+    let backoff = self.0.lock().record_overloaded(Instant::now());
+    log::debug!(
+      "Remote server signaled that it is overloaded: backing off for {:?}.",
+      backoff
+    );
+    if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+      workunit_store_handle.store.record_observation(
+        ObservationMetric::RemoteRpcOverloadBackoffMs,
+        backoff.as_millis() as u64,
+      );
+    }
+  }
+}
+
+fn is_grpc_ok(headers: &HeaderMap) -> bool {
+  headers
+    .get("grpc-status")
+    .map(|v| v.as_bytes() == GRPC_STATUS_OK.as_bytes())
+    .unwrap_or(false)
+}
+
+fn is_overloaded(status: StatusCode, headers: &HeaderMap) -> bool {
+  status == StatusCode::TOO_MANY_REQUESTS
+    || headers
+      .get("grpc-status")
+      .map(|v| v.as_bytes() == GRPC_STATUS_RESOURCE_EXHAUSTED.as_bytes())
+      .unwrap_or(false)
+}
+
+#[derive(Clone)]
+pub struct RateLimitLayer {
+  shared: Shared,
+}
+
+impl RateLimitLayer {
+  ///
+  /// Creates a layer whose services (and all of their clones) share a single set of limits.
+  ///
+  pub fn new(config: RateLimitConfig) -> Self {
+    RateLimitLayer {
+      shared: Shared::new(config),
+    }
+  }
+}
+
+impl fmt::Debug for RateLimitLayer {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("RateLimitLayer").finish()
+  }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+  type Service = RateLimit<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    RateLimit {
+      inner,
+      shared: self.shared.clone(),
+      sleep: None,
+      throttled_since: None,
+    }
+  }
+}
+
+///
+/// A Service which delays requests according to a `RateLimitConfig`, and which backs off when the
+/// server signals that it is overloaded.
+///
+/// Requests are delayed in `poll_ready` (rather than in the response future) so that callers
+/// which are waiting for the rate limit do not hold permits for any inner `ConcurrencyLimit`.
+///
+pub struct RateLimit<S> {
+  inner: S,
+  shared: Shared,
+  sleep: Option<Pin<Box<Sleep>>>,
+  throttled_since: Option<Instant>,
+}
+
+impl<S: Clone> Clone for RateLimit<S> {
+  fn clone(&self) -> Self {
+    RateLimit {
+      inner: self.inner.clone(),
+      shared: self.shared.clone(),
+      sleep: None,
+      throttled_since: None,
+    }
+  }
+}
+
+impl<S> fmt::Debug for RateLimit<S>
+where
+  S: fmt::Debug,
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("RateLimit")
+      .field("inner", &self.inner)
+      .finish()
+  }
+}
+
+impl<ReqBody, ResBody, S> Service<Request<ReqBody>> for RateLimit<S>
+where
+  ReqBody: Body,
+  S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+  S::Future: Send + 'static,
+{
+  type Response = Response<RateLimitedBody<ResBody>>;
+  type Error = S::Error;
+  type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    loop {
+      if let Some(sleep) = self.sleep.as_mut() {
+        if sleep.as_mut().poll(cx).is_pending() {
+          return Poll::Pending;
+        }
+        self.sleep = None;
+      }
+
+      let now = Instant::now();
+      match self.shared.0.lock().delay(now) {
+        Some(delay) => {
+          self.throttled_since.get_or_insert(now);
+          self.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+        None => break,
+      }
+    }
+
+    if let Some(throttled_since) = self.throttled_since.take() {
+      if let Some(workunit_store_handle) = workunit_store::get_workunit_store_handle() {
+        workunit_store_handle.store.record_observation(
+          ObservationMetric::RemoteRpcThrottledTimeMicros,
+          throttled_since.elapsed().as_micros() as u64,
+        );
+      }
+    }
+
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+    // NB: Streaming request bodies will generally only report a lower bound (possibly zero) for
+    // their size.
+    let request_bytes = req.body().size_hint().lower();
+    self
+      .shared
+      .0
+      .lock()
+      .take_request(Instant::now(), request_bytes);
+
+    let shared = self.shared.clone();
+    self
+      .inner
+      .call(req)
+      .map(move |res| {
+        res.map(|response| {
+          shared.observe_response_headers(response.status(), response.headers());
+          response.map(|inner| RateLimitedBody { inner, shared })
+        })
+      })
+      .boxed()
+  }
+}
+
+///
+/// A response body which charges the bytes that it receives against the byte rate limit, and which
+/// inspects trailers for overload signals.
+///
+pub struct RateLimitedBody<B> {
+  inner: B,
+  shared: Shared,
+}
+
+impl<B> fmt::Debug for RateLimitedBody<B>
+where
+  B: fmt::Debug,
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("RateLimitedBody")
+      .field("inner", &self.inner)
+      .finish()
+  }
+}
+
+impl<B> Body for RateLimitedBody<B>
+where
+  B: Body + Unpin,
+{
+  type Data = B::Data;
+  type Error = B::Error;
+
+  fn poll_data(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+    let res = Pin::new(&mut self.inner).poll_data(cx);
+    if let Poll::Ready(Some(Ok(ref data))) = res {
+      self
+        .shared
+        .0
+        .lock()
+        .take_bytes(Instant::now(), data.remaining() as u64);
+    }
+    res
+  }
+
+  fn poll_trailers(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+    let res = Pin::new(&mut self.inner).poll_trailers(cx);
+    if let Poll::Ready(Ok(Some(ref trailers))) = res {
+      self.shared.observe_trailers(trailers);
+    }
+    res
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.inner.is_end_stream()
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    self.inner.size_hint()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::Infallible;
+  use std::pin::Pin;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+  use std::task::{Context, Poll};
+  use std::time::{Duration, Instant};
+
+  use bytes::Bytes;
+  use http::{HeaderMap, Request, Response};
+  use hyper::Body;
+  use tower::{Service, ServiceBuilder, ServiceExt};
+
+  use super::{RateLimitConfig, RateLimitLayer, State, TokenBucket, INITIAL_OVERLOAD_BACKOFF};
+
+  #[test]
+  fn token_bucket_delays_when_in_debt() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(10, now);
+    assert_eq!(bucket.delay_until_available(now, 1.0), None);
+
+    // Spend the entire burst, plus 5 tokens of debt.
+    bucket.take(now, 15.0);
+    let delay = bucket.delay_until_available(now, 1.0).unwrap();
+    assert_eq!(delay, Duration::from_millis(600));
+
+    // After the debt has been repaid, tokens are available again.
+    let later = now + Duration::from_millis(600);
+    assert_eq!(bucket.delay_until_available(later, 1.0), None);
+  }
+
+  #[test]
+  fn token_bucket_does_not_exceed_capacity() {
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(10, now);
+    bucket.take(now, 10.0);
+    // Waiting for a long time should not allow for a burst larger than the capacity.
+    let later = now + Duration::from_secs(60);
+    bucket.take(later, 11.0);
+    assert!(bucket.delay_until_available(later, 0.0).is_some());
+  }
+
+  #[test]
+  fn overload_backoff_grows_and_decays() {
+    let now = Instant::now();
+    let mut state = State {
+      requests: None,
+      bytes: None,
+      backoff: super::OverloadBackoff::default(),
+    };
+    assert_eq!(state.delay(now), None);
+
+    assert_eq!(state.record_overloaded(now), INITIAL_OVERLOAD_BACKOFF);
+    assert_eq!(state.record_overloaded(now), INITIAL_OVERLOAD_BACKOFF * 2);
+    assert_eq!(state.delay(now), Some(INITIAL_OVERLOAD_BACKOFF * 2));
+
+    state.record_success();
+    assert_eq!(state.backoff.current, INITIAL_OVERLOAD_BACKOFF);
+    state.record_success();
+    assert_eq!(state.backoff.current, Duration::from_secs(0));
+  }
+
+  #[tokio::test]
+  async fn requests_are_rate_limited() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+    let mut service = ServiceBuilder::new()
+      .layer(RateLimitLayer::new(RateLimitConfig {
+        requests_per_second: Some(20),
+        bytes_per_second: None,
+      }))
+      .service_fn(move |_req: Request<Body>| {
+        calls2.fetch_add(1, Ordering::SeqCst);
+        async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+      });
+
+    // The first 20 requests are admitted as a burst, and the next 2 must wait for a refill.
+    let start = Instant::now();
+    for _ in 0..22 {
+      service
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::new(Body::empty()))
+        .await
+        .unwrap();
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 22);
+    assert!(start.elapsed() >= Duration::from_millis(90));
+  }
+
+  #[tokio::test]
+  async fn zero_rates_are_unlimited() {
+    let mut service = ServiceBuilder::new()
+      .layer(RateLimitLayer::new(RateLimitConfig {
+        requests_per_second: Some(0),
+        bytes_per_second: Some(0),
+      }))
+      .service_fn(|_req: Request<Body>| async {
+        Ok::<_, Infallible>(Response::new(Body::from("content")))
+      });
+
+    for _ in 0..10 {
+      let response = service
+        .ready()
+        .await
+        .unwrap()
+        .call(Request::new(Body::from("content")))
+        .await
+        .unwrap();
+      hyper::body::to_bytes(response.into_body()).await.unwrap();
+    }
+  }
+
+  #[tokio::test]
+  async fn resource_exhausted_triggers_backoff() {
+    let mut service = ServiceBuilder::new()
+      .layer(RateLimitLayer::new(RateLimitConfig::default()))
+      .service_fn(|_req: Request<Body>| async {
+        Ok::<_, Infallible>(
+          Response::builder()
+            .header("grpc-status", "8")
+            .body(Body::empty())
+            .unwrap(),
+        )
+      });
+
+    service
+      .ready()
+      .await
+      .unwrap()
+      .call(Request::new(Body::empty()))
+      .await
+      .unwrap();
+
+    // The next request should be delayed by the overload backoff.
+    let start = Instant::now();
+    service.ready().await.unwrap();
+    assert!(start.elapsed() >= INITIAL_OVERLOAD_BACKOFF - Duration::from_millis(10));
+  }
+
+  ///
+  /// An empty response body which ends with the given trailers, as a gRPC response does.
+  ///
+  struct TrailersBody(Option<HeaderMap>);
+
+  impl http_body::Body for TrailersBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_data(
+      self: Pin<&mut Self>,
+      _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+      Poll::Ready(None)
+    }
+
+    fn poll_trailers(
+      mut self: Pin<&mut Self>,
+      _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+      Poll::Ready(Ok(self.0.take()))
+    }
+  }
+
+  #[tokio::test]
+  async fn resource_exhausted_in_trailers_escalates_backoff() {
+    let grpc_status = Arc::new(parking_lot::Mutex::new("8"));
+    let grpc_status2 = grpc_status.clone();
+    let layer = RateLimitLayer::new(RateLimitConfig::default());
+    let mut service =
+      ServiceBuilder::new()
+        .layer(layer.clone())
+        .service_fn(move |_req: Request<Body>| {
+          let mut trailers = HeaderMap::new();
+          trailers.insert("grpc-status", grpc_status2.lock().parse().unwrap());
+          async move { Ok::<_, Infallible>(Response::new(TrailersBody(Some(trailers)))) }
+        });
+    // NB: The service is called without waiting for it to be ready, which would wait out the
+    // backoff.
+    let mut call = || {
+      let response = service.call(Request::new(Body::empty()));
+      async move {
+        let mut body = response.await.unwrap().into_body();
+        http_body::Body::trailers(&mut body).await.unwrap();
+      }
+    };
+    let backoff = || layer.shared.0.lock().backoff.current;
+
+    // The headers of each response carry no status, so only the trailers are observed.
+    for expected in &[1, 2, 4] {
+      call().await;
+      assert_eq!(backoff(), INITIAL_OVERLOAD_BACKOFF * *expected);
+    }
+
+    // And a successful status in the trailers decays the backoff.
+    *grpc_status.lock() = "0";
+    call().await;
+    assert_eq!(backoff(), INITIAL_OVERLOAD_BACKOFF * 2);
+  }
+}
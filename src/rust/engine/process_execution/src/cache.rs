@@ -15,6 +15,7 @@ use workunit_store::{
   in_workunit, Level, Metric, ObservationMetric, RunningWorkunit, WorkunitMetadata,
 };
 
+use crate::verification::CacheVerifier;
 use crate::{
   Context, FallibleProcessResultWithPlatform, MultiPlatformProcess, Platform, Process,
   ProcessCacheScope, ProcessMetadata, ProcessResultSource,
@@ -33,6 +34,7 @@ pub struct CommandRunner {
   process_execution_store: ShardedLmdb,
   file_store: Store,
   metadata: ProcessMetadata,
+  verifier: Option<CacheVerifier>,
 }
 
 impl CommandRunner {
@@ -41,12 +43,14 @@ impl CommandRunner {
     process_execution_store: ShardedLmdb,
     file_store: Store,
     metadata: ProcessMetadata,
+    verifier: Option<CacheVerifier>,
   ) -> CommandRunner {
     CommandRunner {
       underlying,
       process_execution_store,
       file_store,
       metadata,
+      verifier,
     }
   }
 }
@@ -125,6 +129,9 @@ impl crate::CommandRunner for CommandRunner {
     .await;
 
     if let Ok(result) = cache_read_result {
+      if let Some(verifier) = self.verifier.as_ref().filter(|v| v.should_verify()) {
+        verifier.verify_cache_hit(context, req, result.clone());
+      }
       return Ok(result);
     }
 
@@ -57,6 +57,7 @@ fn create_cached_runner(
     process_execution_store,
     store,
     ProcessMetadata::default(),
+    None,
   ));
 
   (runner, cache_dir)
@@ -0,0 +1,194 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::RemoteCacheWarningsBehavior;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CircuitBreakerOptions {
+  /// The number of consecutive failures which will open the circuit. Zero disables the breaker.
+  pub failure_threshold: usize,
+  /// Consecutive failures only count towards the threshold if they occur within this window of
+  /// the first failure.
+  pub failure_window: Duration,
+  /// How long to wait after opening the circuit before probing whether the service has recovered.
+  /// If `None`, the circuit stays open for the lifetime of the breaker.
+  pub probe_interval: Option<Duration>,
+}
+
+impl Default for CircuitBreakerOptions {
+  fn default() -> Self {
+    CircuitBreakerOptions {
+      failure_threshold: 0,
+      failure_window: Duration::from_secs(60),
+      probe_interval: Some(Duration::from_secs(5 * 60)),
+    }
+  }
+}
+
+#[derive(Debug)]
+enum State {
+  /// Requests are allowed. Tracks the current streak of consecutive failures.
+  Closed {
+    consecutive_failures: usize,
+    first_failure: Option<Instant>,
+  },
+  /// Requests are not allowed until the given time, at which point a single probe is allowed.
+  Open { probe_at: Option<Instant> },
+  /// A single probe request has been allowed, and we are waiting for its outcome.
+  Probing { started: Instant },
+}
+
+///
+/// A circuit breaker which stops requests to a remote service after repeated consecutive
+/// failures, and then periodically allows a single request through to probe for recovery.
+///
+/// Logging when the circuit opens is controlled by the given `RemoteCacheWarningsBehavior`, so
+/// that a failing service results in a single clear warning rather than one per request.
+///
+pub struct CircuitBreaker {
+  name: &'static str,
+  options: CircuitBreakerOptions,
+  warnings_behavior: RemoteCacheWarningsBehavior,
+  state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+  pub fn new(
+    name: &'static str,
+    options: CircuitBreakerOptions,
+    warnings_behavior: RemoteCacheWarningsBehavior,
+  ) -> CircuitBreaker {
+    CircuitBreaker {
+      name,
+      options,
+      warnings_behavior,
+      state: Mutex::new(State::Closed {
+        consecutive_failures: 0,
+        first_failure: None,
+      }),
+    }
+  }
+
+  ///
+  /// Returns true if a request should be attempted. While the circuit is open, this will return
+  /// true at most once per probe interval, and the caller must report the outcome of the request.
+  ///
+  pub fn allow_request(&self) -> bool {
+    self.allow_request_at(Instant::now())
+  }
+
+  pub(crate) fn allow_request_at(&self, now: Instant) -> bool {
+    let mut state = self.state.lock();
+    match *state {
+      State::Closed { .. } => true,
+      State::Open { probe_at } => match probe_at {
+        Some(probe_at) if now >= probe_at => {
+          log::debug!("Probing whether the {} has recovered.", self.name);
+          *state = State::Probing { started: now };
+          true
+        }
+        _ => false,
+      },
+      State::Probing { started } => {
+        // If a probe never reported an outcome (because it was cancelled, for example), allow
+        // another one after a probe interval has elapsed.
+        match self.options.probe_interval {
+          Some(interval) if now >= started + interval => {
+            *state = State::Probing { started: now };
+            true
+          }
+          _ => false,
+        }
+      }
+    }
+  }
+
+  ///
+  /// Returns true if the circuit is currently open (i.e. requests are being skipped).
+  ///
+  pub fn is_open(&self) -> bool {
+    !matches!(*self.state.lock(), State::Closed { .. })
+  }
+
+  pub fn record_success(&self) {
+    let mut state = self.state.lock();
+    if let State::Probing { .. } = *state {
+      log::info!(
+        "The {} has recovered: re-enabling it for this run.",
+        self.name
+      );
+    }
+    *state = State::Closed {
+      consecutive_failures: 0,
+      first_failure: None,
+    };
+  }
+
+  pub fn record_failure(&self) {
+    self.record_failure_at(Instant::now())
+  }
+
+  pub(crate) fn record_failure_at(&self, now: Instant) {
+    if self.options.failure_threshold == 0 {
+      return;
+    }
+
+    let mut state = self.state.lock();
+    let open = match *state {
+      State::Closed {
+        consecutive_failures,
+        first_failure,
+      } => {
+        let (consecutive_failures, first_failure) = match first_failure {
+          Some(first) if now.duration_since(first) <= self.options.failure_window => {
+            (consecutive_failures + 1, first)
+          }
+          // Either this is the first failure, or the streak is too old to count.
+          _ => (1, now),
+        };
+        if consecutive_failures >= self.options.failure_threshold {
+          self.log_opened(consecutive_failures);
+          true
+        } else {
+          *state = State::Closed {
+            consecutive_failures,
+            first_failure: Some(first_failure),
+          };
+          false
+        }
+      }
+      State::Probing { .. } => {
+        log::debug!("The {} has not yet recovered.", self.name);
+        true
+      }
+      State::Open { .. } => false,
+    };
+
+    if open {
+      *state = State::Open {
+        probe_at: self.options.probe_interval.map(|interval| now + interval),
+      };
+    }
+  }
+
+  fn log_opened(&self, consecutive_failures: usize) {
+    let retry_desc = match self.options.probe_interval {
+      Some(interval) => format!(
+        "Pants will check again whether it is available every {} seconds.",
+        interval.as_secs()
+      ),
+      None => "It will not be used for the rest of this run.".to_owned(),
+    };
+    let log_msg = format!(
+      "The {} failed {} consecutive times, and has been disabled. {}",
+      self.name, consecutive_failures, retry_desc
+    );
+    match self.warnings_behavior {
+      RemoteCacheWarningsBehavior::Ignore => log::debug!("{}", log_msg),
+      RemoteCacheWarningsBehavior::FirstOnly | RemoteCacheWarningsBehavior::Backoff => {
+        log::warn!("{}", log_msg)
+      }
+    }
+  }
+}
@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerOptions};
+use crate::RemoteCacheWarningsBehavior;
+
+fn breaker(failure_threshold: usize, probe_interval: Option<Duration>) -> CircuitBreaker {
+  CircuitBreaker::new(
+    "test service",
+    CircuitBreakerOptions {
+      failure_threshold,
+      failure_window: Duration::from_secs(10),
+      probe_interval,
+    },
+    RemoteCacheWarningsBehavior::FirstOnly,
+  )
+}
+
+#[test]
+fn opens_after_consecutive_failures() {
+  let now = Instant::now();
+  let breaker = breaker(3, None);
+
+  breaker.record_failure_at(now);
+  breaker.record_failure_at(now);
+  assert!(!breaker.is_open());
+  assert!(breaker.allow_request_at(now));
+
+  breaker.record_failure_at(now);
+  assert!(breaker.is_open());
+  assert!(!breaker.allow_request_at(now));
+  // Without a probe interval, the circuit stays open.
+  assert!(!breaker.allow_request_at(now + Duration::from_secs(3600)));
+}
+
+#[test]
+fn success_resets_failure_streak() {
+  let now = Instant::now();
+  let breaker = breaker(2, None);
+
+  breaker.record_failure_at(now);
+  breaker.record_success();
+  breaker.record_failure_at(now);
+  assert!(!breaker.is_open());
+}
+
+#[test]
+fn failures_outside_window_do_not_open() {
+  let now = Instant::now();
+  let breaker = breaker(2, None);
+
+  breaker.record_failure_at(now);
+  breaker.record_failure_at(now + Duration::from_secs(11));
+  assert!(!breaker.is_open());
+  breaker.record_failure_at(now + Duration::from_secs(12));
+  assert!(breaker.is_open());
+}
+
+#[test]
+fn zero_threshold_never_opens() {
+  let now = Instant::now();
+  let breaker = breaker(0, None);
+  for _ in 0..100 {
+    breaker.record_failure_at(now);
+  }
+  assert!(!breaker.is_open());
+}
+
+#[test]
+fn probes_after_interval() {
+  let now = Instant::now();
+  let interval = Duration::from_secs(30);
+  let breaker = breaker(1, Some(interval));
+
+  breaker.record_failure_at(now);
+  assert!(!breaker.allow_request_at(now + Duration::from_secs(1)));
+
+  // A single probe is allowed once the interval has elapsed.
+  let probe_time = now + interval;
+  assert!(breaker.allow_request_at(probe_time));
+  assert!(!breaker.allow_request_at(probe_time));
+
+  // A failed probe re-opens the circuit for another interval.
+  breaker.record_failure_at(probe_time);
+  assert!(!breaker.allow_request_at(probe_time + Duration::from_secs(1)));
+  assert!(breaker.allow_request_at(probe_time + interval));
+
+  // And a successful probe closes it.
+  breaker.record_success();
+  assert!(!breaker.is_open());
+  assert!(breaker.allow_request_at(probe_time + interval));
+}
@@ -0,0 +1,72 @@
+use async_trait::async_trait;
+use log::warn;
+use workunit_store::{Metric, RunningWorkunit};
+
+use crate::{Context, FallibleProcessResultWithPlatform, MultiPlatformProcess, Process};
+
+///
+/// A CommandRunner which runs processes with a primary runner (generally remote execution), and
+/// reruns them with a fallback runner (generally local execution) if the primary runner fails.
+///
+/// Only infrastructure failures (which the primary runner reports as an `Err`) trigger a fallback:
+/// a process which ran to completion with a non-zero exit code is a valid result, and is returned
+/// as is.
+///
+pub struct CommandRunner {
+  primary: Box<dyn crate::CommandRunner>,
+  fallback: Box<dyn crate::CommandRunner>,
+}
+
+impl CommandRunner {
+  pub fn new(
+    primary: Box<dyn crate::CommandRunner>,
+    fallback: Box<dyn crate::CommandRunner>,
+  ) -> CommandRunner {
+    CommandRunner { primary, fallback }
+  }
+}
+
+#[async_trait]
+impl crate::CommandRunner for CommandRunner {
+  async fn run(
+    &self,
+    context: Context,
+    workunit: &mut RunningWorkunit,
+    req: MultiPlatformProcess,
+  ) -> Result<FallibleProcessResultWithPlatform, String> {
+    let primary_err = match self
+      .primary
+      .run(context.clone(), workunit, req.clone())
+      .await
+    {
+      Ok(result) => return Ok(result),
+      Err(err) => err,
+    };
+
+    if self.fallback.extract_compatible_request(&req).is_none() {
+      return Err(primary_err);
+    }
+
+    warn!(
+      "Remote execution failed for {}, falling back to running it locally: {}",
+      req.user_facing_name(),
+      primary_err
+    );
+    workunit.increment_counter(Metric::RemoteExecutionFallbacks, 1);
+    self
+      .fallback
+      .run(context, workunit, req)
+      .await
+      .map_err(|fallback_err| {
+        workunit.increment_counter(Metric::RemoteExecutionFallbackErrors, 1);
+        format!(
+          "Failed to execute remotely ({}), and then failed to execute locally: {}",
+          primary_err, fallback_err
+        )
+      })
+  }
+
+  fn extract_compatible_request(&self, req: &MultiPlatformProcess) -> Option<Process> {
+    self.primary.extract_compatible_request(req)
+  }
+}
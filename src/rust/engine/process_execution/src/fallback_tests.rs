@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use hashing::EMPTY_DIGEST;
+use workunit_store::{RunningWorkunit, WorkunitStore};
+
+use crate::{
+  CommandRunner as CommandRunnerTrait, Context, FallibleProcessResultWithPlatform,
+  MultiPlatformProcess, Platform, Process, ProcessResultMetadata, ProcessResultSource,
+};
+
+#[derive(Clone)]
+struct MockCommandRunner {
+  result: Result<FallibleProcessResultWithPlatform, String>,
+  compatible: bool,
+  call_counter: Arc<AtomicUsize>,
+}
+
+impl MockCommandRunner {
+  fn new(
+    result: Result<i32, String>,
+    source: ProcessResultSource,
+  ) -> (Box<MockCommandRunner>, Arc<AtomicUsize>) {
+    let call_counter = Arc::new(AtomicUsize::new(0));
+    let runner = MockCommandRunner {
+      result: result.map(|exit_code| FallibleProcessResultWithPlatform {
+        stdout_digest: EMPTY_DIGEST,
+        stderr_digest: EMPTY_DIGEST,
+        exit_code,
+        output_directory: EMPTY_DIGEST,
+        platform: Platform::current().unwrap(),
+        metadata: ProcessResultMetadata::new(None, source),
+      }),
+      compatible: true,
+      call_counter: call_counter.clone(),
+    };
+    (Box::new(runner), call_counter)
+  }
+}
+
+#[async_trait]
+impl CommandRunnerTrait for MockCommandRunner {
+  async fn run(
+    &self,
+    _context: Context,
+    _workunit: &mut RunningWorkunit,
+    _req: MultiPlatformProcess,
+  ) -> Result<FallibleProcessResultWithPlatform, String> {
+    self.call_counter.fetch_add(1, Ordering::SeqCst);
+    self.result.clone()
+  }
+
+  fn extract_compatible_request(&self, req: &MultiPlatformProcess) -> Option<Process> {
+    if self.compatible {
+      req.0.values().next().cloned()
+    } else {
+      None
+    }
+  }
+}
+
+async fn run(
+  primary: Box<MockCommandRunner>,
+  fallback: Box<MockCommandRunner>,
+) -> Result<FallibleProcessResultWithPlatform, String> {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let runner = crate::fallback::CommandRunner::new(primary, fallback);
+  runner
+    .run(
+      Context::default(),
+      &mut workunit,
+      Process::new(vec!["/bin/true".to_owned()]).into(),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn primary_success() {
+  let (primary, primary_calls) = MockCommandRunner::new(Ok(0), ProcessResultSource::RanRemotely);
+  let (fallback, fallback_calls) = MockCommandRunner::new(Ok(0), ProcessResultSource::RanLocally);
+
+  let result = run(primary, fallback).await.unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanRemotely);
+  assert_eq!(primary_calls.load(Ordering::SeqCst), 1);
+  assert_eq!(fallback_calls.load(Ordering::SeqCst), 0);
+}
+
+/// A process which ran remotely and failed is a valid result, and should not be rerun locally.
+#[tokio::test]
+async fn primary_non_zero_exit_code_does_not_fall_back() {
+  let (primary, _) = MockCommandRunner::new(Ok(1), ProcessResultSource::RanRemotely);
+  let (fallback, fallback_calls) = MockCommandRunner::new(Ok(0), ProcessResultSource::RanLocally);
+
+  let result = run(primary, fallback).await.unwrap();
+  assert_eq!(result.exit_code, 1);
+  assert_eq!(result.metadata.source, ProcessResultSource::RanRemotely);
+  assert_eq!(fallback_calls.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn primary_error_falls_back() {
+  let (primary, _) = MockCommandRunner::new(
+    Err("Too many failures from server.".to_owned()),
+    ProcessResultSource::RanRemotely,
+  );
+  let (fallback, fallback_calls) = MockCommandRunner::new(Ok(0), ProcessResultSource::RanLocally);
+
+  let result = run(primary, fallback).await.unwrap();
+  assert_eq!(result.metadata.source, ProcessResultSource::RanLocally);
+  assert_eq!(fallback_calls.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn fallback_error_includes_both_errors() {
+  let (primary, _) = MockCommandRunner::new(
+    Err("remote error".to_owned()),
+    ProcessResultSource::RanRemotely,
+  );
+  let (fallback, _) = MockCommandRunner::new(
+    Err("local error".to_owned()),
+    ProcessResultSource::RanLocally,
+  );
+
+  let err = run(primary, fallback).await.unwrap_err();
+  assert!(err.contains("remote error"), "{}", err);
+  assert!(err.contains("local error"), "{}", err);
+}
+
+#[tokio::test]
+async fn no_fallback_for_incompatible_process() {
+  let (primary, _) = MockCommandRunner::new(
+    Err("remote error".to_owned()),
+    ProcessResultSource::RanRemotely,
+  );
+  let (mut fallback, fallback_calls) =
+    MockCommandRunner::new(Ok(0), ProcessResultSource::RanLocally);
+  fallback.compatible = false;
+
+  let err = run(primary, fallback).await.unwrap_err();
+  assert_eq!(err, "remote error");
+  assert_eq!(fallback_calls.load(Ordering::SeqCst), 0);
+}
@@ -49,6 +49,14 @@ pub mod cache;
 #[cfg(test)]
 mod cache_tests;
 
+pub mod circuit_breaker;
+#[cfg(test)]
+mod circuit_breaker_tests;
+
+pub mod fallback;
+#[cfg(test)]
+mod fallback_tests;
+
 pub mod local;
 #[cfg(test)]
 mod local_tests;
@@ -65,6 +73,10 @@ pub mod nailgun;
 
 pub mod named_caches;
 
+pub mod verification;
+#[cfg(test)]
+mod verification_tests;
+
 extern crate uname;
 
 pub use crate::named_caches::{CacheDest, CacheName, NamedCaches};
@@ -378,6 +390,13 @@ pub struct ProcessMetadata {
   pub instance_name: Option<String>,
   pub cache_key_gen_version: Option<String>,
   pub platform_properties: Vec<(String, String)>,
+  /// The names of the NodeProperties (e.g. `unix_mode` or `mtime`) to request for outputs when
+  /// the server supports them.
+  pub output_node_properties: Vec<String>,
+  /// Whether to request outputs via the REAPI v2.1 `output_paths` field (which servers that
+  /// support it report any output symlinks for) rather than `output_files` and
+  /// `output_directories`.
+  pub output_paths: bool,
 }
 
 ///
@@ -538,6 +557,25 @@ pub trait CommandRunner: Send + Sync {
   fn extract_compatible_request(&self, req: &MultiPlatformProcess) -> Option<Process>;
 }
 
+///
+/// Allows a CommandRunner to be shared between multiple wrapping CommandRunners.
+///
+#[async_trait]
+impl<T: CommandRunner + ?Sized> CommandRunner for Arc<T> {
+  async fn run(
+    &self,
+    context: Context,
+    workunit: &mut RunningWorkunit,
+    req: MultiPlatformProcess,
+  ) -> Result<FallibleProcessResultWithPlatform, String> {
+    (**self).run(context, workunit, req).await
+  }
+
+  fn extract_compatible_request(&self, req: &MultiPlatformProcess) -> Option<Process> {
+    (**self).extract_compatible_request(req)
+  }
+}
+
 // TODO(#8513) possibly move to the MEPR struct, or to the hashing crate?
 pub fn digest(req: MultiPlatformProcess, metadata: &ProcessMetadata) -> Digest {
   let mut hashes: Vec<String> = req
@@ -479,7 +479,7 @@ pub trait CapturedWorkdir {
       },
       |_workunit| async move {
         store2
-          .materialize_directory(workdir_path_2, input_files)
+          .materialize_input_directory(workdir_path_2, input_files)
           .await
       },
     )
@@ -683,7 +683,7 @@ pub trait CapturedWorkdir {
 }
 
 /// Create a file called __run.sh with the env, cwd and argv used by Pants to facilitate debugging.
-fn setup_run_sh_script(
+pub fn setup_run_sh_script(
   env: &BTreeMap<String, String>,
   working_directory: &Option<RelativePath>,
   argv: &[String],
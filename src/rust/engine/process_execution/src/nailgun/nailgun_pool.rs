@@ -54,7 +54,7 @@ impl NailgunPool {
     let workdir_for_server2 = workdir_for_server.clone();
 
     // TODO(#8481) This materializes the input files in the client req, which is a superset of the files we need (we only need the classpath, not the input files)
-    store.materialize_directory(workdir_for_server.clone(), input_files)
+    store.materialize_input_directory(workdir_for_server.clone(), input_files)
     .and_then(move |_metadata| async move {
       let jdk_home_in_workdir = &workdir_for_server.join(".jdk");
       let jdk_home_in_workdir2 = jdk_home_in_workdir.clone();
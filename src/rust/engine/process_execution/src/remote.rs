@@ -15,12 +15,13 @@ use bazel_protos::require_digest;
 use bytes::Bytes;
 use concrete_time::TimeSpan;
 use double_checked_cell_async::DoubleCheckedCell;
-use fs::{self, File, PathStat};
+use fs::{self, File, Link, PathStat};
 use futures::future::{self, BoxFuture, TryFutureExt};
 use futures::FutureExt;
 use futures::{Stream, StreamExt};
 use grpc_util::headers_to_http_header_map;
 use grpc_util::prost::MessageExt;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::retry::{retry_call, status_is_retryable};
 use grpc_util::{layered_service, status_to_str, LayeredService};
 use hashing::{Digest, Fingerprint};
@@ -127,6 +128,7 @@ impl CommandRunner {
     retry_interval_duration: Duration,
     execution_concurrency_limit: usize,
     cache_concurrency_limit: usize,
+    rate_limit: RateLimitConfig,
     capabilities_cell_opt: Option<Arc<DoubleCheckedCell<ServerCapabilities>>>,
   ) -> Result<Self, String> {
     let execution_use_tls = execution_address.starts_with("https://");
@@ -148,6 +150,7 @@ impl CommandRunner {
     let execution_channel = layered_service(
       tonic::transport::Channel::balance_list(vec![execution_endpoint].into_iter()),
       execution_concurrency_limit,
+      rate_limit,
       execution_http_headers,
     );
     let execution_client = Arc::new(ExecutionClient::new(execution_channel.clone()));
@@ -162,6 +165,7 @@ impl CommandRunner {
     let store_channel = layered_service(
       tonic::transport::Channel::balance_list(vec![store_endpoint].into_iter()),
       cache_concurrency_limit,
+      rate_limit,
       store_http_headers,
     );
 
@@ -190,6 +194,30 @@ impl CommandRunner {
     self.platform
   }
 
+  ///
+  /// Returns the metadata of this runner without any output node properties which the server does
+  /// not support (see `supported_metadata`). The capabilities of the server are fetched on first
+  /// use: if they cannot be, the metadata is returned unfiltered.
+  ///
+  pub(crate) async fn execution_metadata(&self) -> ProcessMetadata {
+    match self.get_capabilities().await {
+      Ok(capabilities) => {
+        trace!("RE capabilities: {:?}", &capabilities);
+        supported_metadata(self.metadata.clone(), capabilities)
+      }
+      Err(e) => {
+        if !self.metadata.output_node_properties.is_empty() {
+          warn!(
+            "Failed to get the capabilities of the remote execution server, so requesting all \
+             configured output node properties: {}",
+            e
+          );
+        }
+        self.metadata.clone()
+      }
+    }
+  }
+
   async fn get_capabilities(&self) -> Result<&remexec::ServerCapabilities, String> {
     let capabilities_fut = async {
       let mut request = remexec::GetCapabilitiesRequest::default();
@@ -734,13 +762,12 @@ impl crate::CommandRunner for CommandRunner {
     _workunit: &mut RunningWorkunit,
     request: MultiPlatformProcess,
   ) -> Result<FallibleProcessResultWithPlatform, String> {
-    // Retrieve capabilities for this server.
-    let capabilities = self.get_capabilities().await?;
-    trace!("RE capabilities: {:?}", &capabilities);
+    // Retrieve capabilities for this server, which determine the output node properties to request.
+    let metadata = self.execution_metadata().await;
 
     // Construct the REv2 ExecuteRequest and related data for this execution request.
     let request = self.extract_compatible_request(&request).unwrap();
-    let (action, command, execute_request) = make_execute_request(&request, self.metadata.clone())?;
+    let (action, command, execute_request) = make_execute_request(&request, metadata.clone())?;
     let build_id = context.build_id.clone();
 
     debug!("Remote execution: {}", request.description);
@@ -763,7 +790,7 @@ impl crate::CommandRunner for CommandRunner {
     let cached_response_opt = check_action_cache(
       action_digest,
       &command,
-      &self.metadata,
+      &metadata,
       self.platform,
       &context2,
       self.action_cache_client.clone(),
@@ -876,6 +903,32 @@ fn maybe_add_workunit(
   }
 }
 
+///
+/// Removes any output node properties which the server does not support from the given
+/// ProcessMetadata, since requesting them would cause the server to reject the request.
+///
+pub fn supported_metadata(
+  mut metadata: ProcessMetadata,
+  capabilities: &remexec::ServerCapabilities,
+) -> ProcessMetadata {
+  let supported_node_properties = capabilities
+    .execution_capabilities
+    .as_ref()
+    .map(|execution_capabilities| &execution_capabilities.supported_node_properties[..])
+    .unwrap_or_default();
+  metadata.output_node_properties.retain(|name| {
+    let supported = supported_node_properties.contains(name);
+    if !supported {
+      debug!(
+        "Not requesting output node property `{}`, which the server does not support.",
+        name
+      );
+    }
+    supported
+  });
+  metadata
+}
+
 pub fn make_execute_request(
   req: &Process,
   metadata: ProcessMetadata,
@@ -907,6 +960,8 @@ pub fn make_execute_request(
     instance_name,
     cache_key_gen_version,
     mut platform_properties,
+    mut output_node_properties,
+    output_paths,
   } = metadata;
 
   // TODO: Disabling append-only caches in remoting until server support exists due to
@@ -977,6 +1032,25 @@ pub fn make_execute_request(
   output_directories.sort();
   command.output_directories = output_directories;
 
+  // REAPI v2.1 supersedes `output_files` and `output_directories` with `output_paths`: servers
+  // which support it will ignore the former fields, and will report any output symlinks in
+  // `output_symlinks`. Older servers may reject requests with unknown fields, so this is opt-in.
+  if output_paths {
+    let mut output_paths = command
+      .output_files
+      .iter()
+      .chain(command.output_directories.iter())
+      .cloned()
+      .collect::<Vec<_>>();
+    output_paths.sort();
+    command.output_paths = output_paths;
+  }
+
+  // Like platform properties, node properties MUST be sorted for consistent hashing.
+  output_node_properties.sort();
+  output_node_properties.dedup();
+  command.output_node_properties = output_node_properties;
+
   if let Some(working_directory) = &req.working_directory {
     command.working_directory = working_directory
       .to_str()
@@ -1235,8 +1309,9 @@ pub fn extract_output_files(
     );
   }
 
-  // Make a directory for the files
+  // Make a directory for the files and symlinks
   let mut path_map = HashMap::new();
+  let mut node_properties_map = HashMap::new();
   let path_stats_result: Result<Vec<PathStat>, String> = action_result
     .output_files
     .iter()
@@ -1244,6 +1319,9 @@ pub fn extract_output_files(
       let output_file_path_buf = PathBuf::from(output_file.path.clone());
       let digest: Result<Digest, String> = require_digest(output_file.digest.as_ref());
       path_map.insert(output_file_path_buf.clone(), digest?);
+      if let Some(node_properties) = &output_file.node_properties {
+        node_properties_map.insert(output_file_path_buf.clone(), node_properties.clone());
+      }
       Ok(PathStat::file(
         output_file_path_buf.clone(),
         File {
@@ -1254,17 +1332,42 @@ pub fn extract_output_files(
     })
     .collect();
 
-  let path_stats = try_future!(path_stats_result);
+  // Servers which support REAPI v2.1 may report symlinks in both `output_symlinks` and the
+  // deprecated `output_file_symlinks` and `output_directory_symlinks` fields, so dedupe them.
+  let output_symlinks = action_result
+    .output_file_symlinks
+    .iter()
+    .chain(action_result.output_directory_symlinks.iter())
+    .chain(action_result.output_symlinks.iter())
+    .map(|output_symlink| {
+      (
+        PathBuf::from(&output_symlink.path),
+        PathBuf::from(&output_symlink.target),
+      )
+    })
+    .collect::<BTreeMap<_, _>>();
+
+  let mut path_stats = try_future!(path_stats_result);
+  path_stats.extend(
+    output_symlinks
+      .into_iter()
+      .map(|(path, target)| PathStat::link(path.clone(), Link(path), target)),
+  );
 
   #[derive(Clone)]
   struct StoreOneOffRemoteDigest {
     map_of_paths_to_digests: HashMap<PathBuf, Digest>,
+    map_of_paths_to_node_properties: HashMap<PathBuf, remexec::NodeProperties>,
   }
 
   impl StoreOneOffRemoteDigest {
-    fn new(map: HashMap<PathBuf, Digest>) -> StoreOneOffRemoteDigest {
+    fn new(
+      map: HashMap<PathBuf, Digest>,
+      node_properties: HashMap<PathBuf, remexec::NodeProperties>,
+    ) -> StoreOneOffRemoteDigest {
       StoreOneOffRemoteDigest {
         map_of_paths_to_digests: map,
+        map_of_paths_to_node_properties: node_properties,
       }
     }
   }
@@ -1280,12 +1383,25 @@ pub fn extract_output_files(
       }
       .boxed()
     }
+
+    fn node_properties(
+      &self,
+      file: &File,
+    ) -> future::BoxFuture<'static, Result<Option<remexec::NodeProperties>, String>> {
+      future::ok(
+        self
+          .map_of_paths_to_node_properties
+          .get(&file.path)
+          .cloned(),
+      )
+      .boxed()
+    }
   }
 
   async move {
     let files_digest = Snapshot::digest_from_path_stats(
       store.clone(),
-      StoreOneOffRemoteDigest::new(path_map),
+      StoreOneOffRemoteDigest::new(path_map, node_properties_map),
       path_stats,
     )
     .map_err(move |error| {
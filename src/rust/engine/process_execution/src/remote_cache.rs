@@ -11,6 +11,7 @@ use bazel_protos::require_digest;
 use fs::RelativePath;
 use futures::future::BoxFuture;
 use futures::FutureExt;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::retry::status_is_retryable;
 use grpc_util::{
   headers_to_http_header_map, layered_service, retry::retry_call, status_to_str, LayeredService,
@@ -24,7 +25,9 @@ use workunit_store::{
   in_workunit, Level, Metric, ObservationMetric, RunningWorkunit, WorkunitMetadata,
 };
 
+use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerOptions};
 use crate::remote::make_execute_request;
+use crate::verification::CacheVerifier;
 use crate::{
   Context, FallibleProcessResultWithPlatform, MultiPlatformProcess, Platform, Process,
   ProcessCacheScope, ProcessMetadata, RemoteCacheWarningsBehavior,
@@ -53,6 +56,8 @@ pub struct CommandRunner {
   warnings_behavior: RemoteCacheWarningsBehavior,
   read_errors_counter: Arc<Mutex<BTreeMap<String, usize>>>,
   write_errors_counter: Arc<Mutex<BTreeMap<String, usize>>>,
+  circuit_breaker: Arc<CircuitBreaker>,
+  verifier: Option<CacheVerifier>,
 }
 
 impl CommandRunner {
@@ -70,6 +75,9 @@ impl CommandRunner {
     warnings_behavior: RemoteCacheWarningsBehavior,
     eager_fetch: bool,
     concurrency_limit: usize,
+    rate_limit: RateLimitConfig,
+    circuit_breaker_options: CircuitBreakerOptions,
+    verifier: Option<CacheVerifier>,
   ) -> Result<Self, String> {
     let tls_client_config = if action_cache_address.starts_with("https://") {
       Some(grpc_util::tls::Config::new_without_mtls(root_ca_certs).try_into()?)
@@ -86,6 +94,7 @@ impl CommandRunner {
     let channel = layered_service(
       tonic::transport::Channel::balance_list(vec![endpoint].into_iter()),
       concurrency_limit,
+      rate_limit,
       http_headers,
     );
     let action_cache_client = Arc::new(ActionCacheClient::new(channel));
@@ -104,6 +113,12 @@ impl CommandRunner {
       warnings_behavior,
       read_errors_counter: Arc::new(Mutex::new(BTreeMap::new())),
       write_errors_counter: Arc::new(Mutex::new(BTreeMap::new())),
+      circuit_breaker: Arc::new(CircuitBreaker::new(
+        "remote cache",
+        circuit_breaker_options,
+        warnings_behavior,
+      )),
+      verifier,
     })
   }
 
@@ -369,6 +384,7 @@ impl CommandRunner {
       .await;
       match response {
         Ok(cached_response_opt) => {
+          self.circuit_breaker.record_success();
           log::debug!(
             "remote cache response: digest={:?}: {:?}",
             action_digest,
@@ -377,6 +393,7 @@ impl CommandRunner {
           cached_response_opt
         }
         Err(err) => {
+          self.circuit_breaker.record_failure();
           self.log_cache_error(err, CacheErrorType::ReadError);
           None
         }
@@ -497,6 +514,18 @@ impl CommandRunner {
     Ok(())
   }
 
+  ///
+  /// Returns true if the circuit breaker allows a request to the remote cache, and otherwise
+  /// records that a request was skipped.
+  ///
+  fn allow_cache_request(&self, workunit: &mut RunningWorkunit) -> bool {
+    let allowed = self.circuit_breaker.allow_request();
+    if !allowed {
+      workunit.increment_counter(Metric::RemoteCacheRequestsSkipped, 1);
+    }
+    allowed
+  }
+
   fn log_cache_error(&self, err: String, err_type: CacheErrorType) {
     let err_count = {
       let mut errors_counter = match err_type {
@@ -557,7 +586,16 @@ impl crate::CommandRunner for CommandRunner {
     let (command_digest, action_digest) =
       crate::remote::ensure_action_stored_locally(&self.store, &command, &action).await?;
 
-    let (result, hit_cache) = if self.cache_read {
+    // If the remote cache has been failing consistently, skip it entirely rather than adding
+    // latency to every process.
+    let cache_read = self.cache_read && self.allow_cache_request(workunit);
+    // Decide up front whether to verify a potential cache hit, since the request is consumed by
+    // the speculation.
+    let verification_req = match &self.verifier {
+      Some(verifier) if cache_read && verifier.should_verify() => Some((verifier, req.clone())),
+      _ => None,
+    };
+    let (result, hit_cache) = if cache_read {
       self
         .speculate_read_action_cache(
           context.clone(),
@@ -575,7 +613,15 @@ impl crate::CommandRunner for CommandRunner {
       )
     };
 
-    if !hit_cache && (result.exit_code == 0 || write_failures_to_cache) && self.cache_write {
+    if let (true, Some((verifier, verification_req))) = (hit_cache, verification_req) {
+      verifier.verify_cache_hit(context.clone(), verification_req, result.clone());
+    }
+
+    if !hit_cache
+      && (result.exit_code == 0 || write_failures_to_cache)
+      && self.cache_write
+      && self.allow_cache_request(workunit)
+    {
       // NB: We use a distinct workunit for the start of the cache write so that we guarantee the
       // counter is recorded, given that the cache write is async and may still be executing after
       // the Pants session has finished and workunits are no longer processed.
@@ -616,8 +662,12 @@ impl crate::CommandRunner for CommandRunner {
             )
             .await;
           match write_result {
-            Ok(_) => workunit.increment_counter(Metric::RemoteCacheWriteSuccesses, 1),
+            Ok(_) => {
+              command_runner.circuit_breaker.record_success();
+              workunit.increment_counter(Metric::RemoteCacheWriteSuccesses, 1)
+            }
             Err(err) => {
+              command_runner.circuit_breaker.record_failure();
               command_runner.log_cache_error(err, CacheErrorType::WriteError);
               workunit.increment_counter(Metric::RemoteCacheWriteErrors, 1);
             }
@@ -7,6 +7,7 @@ use std::time::Duration;
 use async_trait::async_trait;
 use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
 use fs::RelativePath;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::tls;
 use hashing::{Digest, EMPTY_DIGEST};
 use maplit::hashset;
@@ -16,9 +17,11 @@ use store::Store;
 use tempfile::TempDir;
 use testutil::data::{TestData, TestDirectory, TestTree};
 use tokio::time::sleep;
-use workunit_store::{RunningWorkunit, WorkunitStore};
+use workunit_store::{Metric, RunningWorkunit, WorkunitStore};
 
+use crate::circuit_breaker::CircuitBreakerOptions;
 use crate::remote::{ensure_action_stored_locally, make_execute_request};
+use crate::verification::CacheVerifier;
 use crate::{
   CommandRunner as CommandRunnerTrait, Context, FallibleProcessResultWithPlatform,
   MultiPlatformProcess, Platform, Process, ProcessMetadata, ProcessResultMetadata,
@@ -97,6 +100,7 @@ impl StoreSetup {
         Duration::from_secs(1),
         1,
         256,
+        RateLimitConfig::default(),
         None,
         4 * 1024 * 1024,
       )
@@ -129,6 +133,24 @@ fn create_cached_runner(
   read_delay_ms: u64,
   write_delay_ms: u64,
   eager_fetch: bool,
+) -> (Box<dyn CommandRunnerTrait>, StubActionCache) {
+  create_cached_runner_with_circuit_breaker(
+    local,
+    store_setup,
+    read_delay_ms,
+    write_delay_ms,
+    eager_fetch,
+    CircuitBreakerOptions::default(),
+  )
+}
+
+fn create_cached_runner_with_circuit_breaker(
+  local: Box<dyn CommandRunnerTrait>,
+  store_setup: &StoreSetup,
+  read_delay_ms: u64,
+  write_delay_ms: u64,
+  eager_fetch: bool,
+  circuit_breaker_options: CircuitBreakerOptions,
 ) -> (Box<dyn CommandRunnerTrait>, StubActionCache) {
   let action_cache = StubActionCache::new_with_delays(read_delay_ms, write_delay_ms).unwrap();
   let runner = Box::new(
@@ -146,6 +168,9 @@ fn create_cached_runner(
       RemoteCacheWarningsBehavior::FirstOnly,
       eager_fetch,
       256,
+      RateLimitConfig::default(),
+      circuit_breaker_options,
+      None,
     )
     .expect("caching command runner"),
   );
@@ -224,6 +249,119 @@ async fn cache_read_skipped_on_errors() {
   assert_eq!(local_runner_call_counter.load(Ordering::SeqCst), 1);
 }
 
+/// After repeated failures, the circuit breaker should disable the cache, even once it recovers.
+#[tokio::test]
+async fn cache_read_skipped_when_circuit_open() {
+  let (mut workunit_store, mut workunit) = WorkunitStore::setup_for_tests();
+  let store_setup = StoreSetup::new();
+  // NB: The local runner is slow enough that the failed cache read is always observed (opening
+  // the circuit) before the first run completes.
+  let (local_runner, local_runner_call_counter) = create_local_runner(1, 1000);
+  let (cache_runner, action_cache) = create_cached_runner_with_circuit_breaker(
+    local_runner,
+    &store_setup,
+    0,
+    0,
+    false,
+    CircuitBreakerOptions {
+      failure_threshold: 1,
+      probe_interval: None,
+      ..CircuitBreakerOptions::default()
+    },
+  );
+
+  let (process, action_digest) = create_process(&store_setup.store).await;
+  insert_into_action_cache(&action_cache, &action_digest, 0, EMPTY_DIGEST, EMPTY_DIGEST);
+  action_cache.always_errors.store(true, Ordering::SeqCst);
+
+  let remote_result = cache_runner
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(remote_result.exit_code, 1);
+  assert_eq!(local_runner_call_counter.load(Ordering::SeqCst), 1);
+
+  // The cache has recovered, but the circuit is open, so we run locally again.
+  action_cache.always_errors.store(false, Ordering::SeqCst);
+  let remote_result = cache_runner
+    .run(Context::default(), &mut workunit, process.clone().into())
+    .await
+    .unwrap();
+  assert_eq!(remote_result.exit_code, 1);
+  assert_eq!(local_runner_call_counter.load(Ordering::SeqCst), 2);
+
+  workunit.complete();
+  let skipped = workunit_store.with_latest_workunits(log::Level::Trace, |_, completed| {
+    completed
+      .iter()
+      .filter_map(|workunit| {
+        workunit
+          .counters
+          .get(&Metric::RemoteCacheRequestsSkipped)
+          .cloned()
+      })
+      .sum::<u64>()
+  });
+  assert_eq!(skipped, 1);
+}
+
+/// A sampled cache hit should be verified in the background with the verification runner, and the
+/// verification should neither consult nor write to the remote cache.
+#[tokio::test]
+async fn cache_hit_verified_locally() {
+  let (workunit_store, mut workunit) = WorkunitStore::setup_for_tests();
+  let store_setup = StoreSetup::new();
+  let (local_runner, local_runner_call_counter) = create_local_runner(1, 10);
+  let local_runner: Arc<dyn CommandRunnerTrait> = Arc::new(*local_runner);
+  let action_cache = StubActionCache::new().unwrap();
+  let cache_runner = crate::remote_cache::CommandRunner::new(
+    local_runner.clone(),
+    ProcessMetadata::default(),
+    store_setup.executor.clone(),
+    store_setup.store.clone(),
+    &action_cache.address(),
+    None,
+    BTreeMap::default(),
+    Platform::current().unwrap(),
+    true,
+    true,
+    RemoteCacheWarningsBehavior::FirstOnly,
+    false,
+    256,
+    RateLimitConfig::default(),
+    CircuitBreakerOptions::default(),
+    CacheVerifier::new(
+      local_runner,
+      store_setup.store.clone(),
+      store_setup.executor.clone(),
+      1.0,
+    ),
+  )
+  .expect("caching command runner");
+
+  let (process, action_digest) = create_process(&store_setup.store).await;
+  insert_into_action_cache(&action_cache, &action_digest, 0, EMPTY_DIGEST, EMPTY_DIGEST);
+
+  let context = Context::new(workunit_store.clone(), "verification".to_owned());
+  let remote_result = cache_runner
+    .run(context, &mut workunit, process.into())
+    .await
+    .unwrap();
+  assert_eq!(remote_result.exit_code, 0);
+
+  let verify_workunits = crate::verification_tests::verify_workunits(workunit_store).await;
+  assert_eq!(verify_workunits.len(), 1);
+  assert_eq!(
+    verify_workunits[0]
+      .counters
+      .get(&Metric::CacheVerificationMismatches),
+    Some(&1)
+  );
+  assert_eq!(local_runner_call_counter.load(Ordering::SeqCst), 1);
+  // The result of the verification was not written to the remote cache.
+  assert_eq!(action_cache.action_map.lock().len(), 1);
+}
+
 /// With eager_fetch enabled, we should skip the remote cache if any of the process result's
 /// digests are invalid. This will force rerunning the process locally. Otherwise, we should use
 /// the cached result with its non-existent digests.
@@ -599,6 +737,9 @@ async fn make_action_result_basic() {
     RemoteCacheWarningsBehavior::FirstOnly,
     false,
     256,
+    RateLimitConfig::default(),
+    CircuitBreakerOptions::default(),
+    None,
   )
   .expect("caching command runner");
 
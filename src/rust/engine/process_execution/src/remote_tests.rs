@@ -7,6 +7,7 @@ use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
 use bazel_protos::gen::google::longrunning::Operation;
 use bytes::Bytes;
 use grpc_util::prost::MessageExt;
+use grpc_util::rate_limit::RateLimitConfig;
 use grpc_util::tls;
 use hashing::{Digest, Fingerprint, EMPTY_DIGEST};
 use maplit::{btreemap, hashset};
@@ -228,6 +229,8 @@ async fn make_execute_request_with_instance_name() {
         instance_name: Some("dark-tower".to_owned()),
         cache_key_gen_version: None,
         platform_properties: vec![("target_platform".to_owned(), "apple-2e".to_owned())],
+        output_node_properties: vec![],
+        output_paths: false,
       }
     ),
     Ok((want_action, want_command, want_execute_request))
@@ -319,6 +322,8 @@ async fn make_execute_request_with_cache_key_gen_version() {
         instance_name: None,
         cache_key_gen_version: Some("meep".to_owned()),
         platform_properties: vec![],
+        output_node_properties: vec![],
+        output_paths: false,
       }
     ),
     Ok((want_action, want_command, want_execute_request))
@@ -464,7 +469,9 @@ async fn make_execute_request_with_jdk_and_extra_platform_properties() {
           ("Multi".to_owned(), "uno".to_owned()),
           ("last".to_owned(), "bar".to_owned()),
           ("Multi".to_owned(), "dos".to_owned()),
-        ]
+        ],
+        output_node_properties: vec![],
+        output_paths: false,
       },
     ),
     Ok((want_action, want_command, want_execute_request))
@@ -872,6 +879,7 @@ async fn sends_headers() {
       Duration::from_secs(1),
       1,
       STORE_CONCURRENCY_LIMIT,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
@@ -892,6 +900,7 @@ async fn sends_headers() {
     RETRY_INTERVAL,
     EXEC_CONCURRENCY_LIMIT,
     CACHE_CONCURRENCY_LIMIT,
+    RateLimitConfig::default(),
     None,
   )
   .unwrap();
@@ -1073,6 +1082,7 @@ async fn ensure_inline_stdio_is_stored() {
       Duration::from_secs(1),
       1,
       STORE_CONCURRENCY_LIMIT,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
@@ -1090,6 +1100,7 @@ async fn ensure_inline_stdio_is_stored() {
     RETRY_INTERVAL,
     EXEC_CONCURRENCY_LIMIT,
     CACHE_CONCURRENCY_LIMIT,
+    RateLimitConfig::default(),
     None,
   )
   .unwrap();
@@ -1453,6 +1464,7 @@ async fn execute_missing_file_uploads_if_known() {
       Duration::from_secs(1),
       1,
       STORE_CONCURRENCY_LIMIT,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
@@ -1477,6 +1489,7 @@ async fn execute_missing_file_uploads_if_known() {
     RETRY_INTERVAL,
     EXEC_CONCURRENCY_LIMIT,
     CACHE_CONCURRENCY_LIMIT,
+    RateLimitConfig::default(),
     None,
   )
   .unwrap();
@@ -1534,6 +1547,7 @@ async fn execute_missing_file_errors_if_unknown() {
       Duration::from_secs(1),
       1,
       STORE_CONCURRENCY_LIMIT,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
@@ -1551,6 +1565,7 @@ async fn execute_missing_file_errors_if_unknown() {
     RETRY_INTERVAL,
     EXEC_CONCURRENCY_LIMIT,
     CACHE_CONCURRENCY_LIMIT,
+    RateLimitConfig::default(),
     None,
   )
   .unwrap();
@@ -2002,6 +2017,207 @@ async fn extract_output_files_from_response_no_prefix() {
   )
 }
 
+#[tokio::test]
+async fn extract_output_files_from_response_symlinks() {
+  // Servers may report a symlink in both `output_symlinks` and a deprecated field.
+  let symlink = remexec::OutputSymlink {
+    path: "cats/link.ext".into(),
+    target: "../roland.ext".into(),
+    ..Default::default()
+  };
+  let execute_response = remexec::ExecuteResponse {
+    result: Some(remexec::ActionResult {
+      exit_code: 0,
+      output_files: vec![remexec::OutputFile {
+        path: "roland.ext".into(),
+        digest: Some((&TestData::roland().digest()).into()),
+        ..Default::default()
+      }],
+      output_file_symlinks: vec![symlink.clone()],
+      output_symlinks: vec![symlink],
+      ..Default::default()
+    }),
+    ..Default::default()
+  };
+
+  let cats_directory = remexec::Directory {
+    symlinks: vec![remexec::SymlinkNode {
+      name: "link.ext".into(),
+      target: "../roland.ext".into(),
+      ..Default::default()
+    }],
+    ..Default::default()
+  };
+  let mut directory = TestDirectory::containing_roland().directory();
+  directory.directories.push(remexec::DirectoryNode {
+    name: "cats".into(),
+    digest: Some((&digest(&cats_directory).unwrap()).into()),
+  });
+
+  assert_eq!(
+    extract_output_files_from_response(&execute_response).await,
+    Ok(digest(&directory).unwrap())
+  )
+}
+
+#[tokio::test]
+async fn extract_output_files_from_response_node_properties() {
+  let node_properties = remexec::NodeProperties {
+    unix_mode: Some(0o444),
+    ..Default::default()
+  };
+  let execute_response = remexec::ExecuteResponse {
+    result: Some(remexec::ActionResult {
+      exit_code: 0,
+      output_files: vec![remexec::OutputFile {
+        path: "roland.ext".into(),
+        digest: Some((&TestData::roland().digest()).into()),
+        node_properties: Some(node_properties.clone()),
+        ..Default::default()
+      }],
+      ..Default::default()
+    }),
+    ..Default::default()
+  };
+
+  let mut directory = TestDirectory::containing_roland().directory();
+  directory.files[0].node_properties = Some(node_properties);
+
+  assert_eq!(
+    extract_output_files_from_response(&execute_response).await,
+    Ok(digest(&directory).unwrap())
+  )
+}
+
+#[test]
+fn make_execute_request_with_supported_output_node_properties() {
+  let metadata = ProcessMetadata {
+    output_node_properties: owned_string_vec(&["unix_mode", "mtime", "unsupported"]),
+    ..ProcessMetadata::default()
+  };
+  let capabilities = remexec::ServerCapabilities {
+    execution_capabilities: Some(remexec::ExecutionCapabilities {
+      supported_node_properties: owned_string_vec(&["mtime", "unix_mode"]),
+      ..Default::default()
+    }),
+    ..Default::default()
+  };
+
+  let req = Process::new(owned_string_vec(&["/bin/echo", "yo"]));
+  let (_, command, _) = crate::remote::make_execute_request(
+    &req,
+    crate::remote::supported_metadata(metadata.clone(), &capabilities),
+  )
+  .unwrap();
+  assert_eq!(
+    command.output_node_properties,
+    owned_string_vec(&["mtime", "unix_mode"])
+  );
+
+  // Nothing is requested from a server which does not report its supported node properties.
+  let (_, command, _) = crate::remote::make_execute_request(
+    &req,
+    crate::remote::supported_metadata(metadata, &remexec::ServerCapabilities::default()),
+  )
+  .unwrap();
+  assert!(command.output_node_properties.is_empty());
+}
+
+#[test]
+fn make_execute_request_with_output_paths() {
+  let mut req = Process::new(owned_string_vec(&["/bin/echo", "yo"]));
+  req.output_files = relative_paths(&["path/to/file.ext", "other/file.ext"]).collect();
+  req.output_directories = relative_paths(&["directory/name"]).collect();
+
+  // Without the option, only the fields supported by REAPI v2.0 are set.
+  let (_, command, _) =
+    crate::remote::make_execute_request(&req, ProcessMetadata::default()).unwrap();
+  assert!(command.output_paths.is_empty());
+
+  let (_, command, _) = crate::remote::make_execute_request(
+    &req,
+    ProcessMetadata {
+      output_paths: true,
+      ..ProcessMetadata::default()
+    },
+  )
+  .unwrap();
+  assert_eq!(
+    command.output_paths,
+    owned_string_vec(&["directory/name", "other/file.ext", "path/to/file.ext"])
+  );
+  assert_eq!(
+    command.output_files,
+    owned_string_vec(&["other/file.ext", "path/to/file.ext"])
+  );
+  assert_eq!(
+    command.output_directories,
+    owned_string_vec(&["directory/name"])
+  );
+}
+
+fn command_runner_requesting_unix_mode(address: &str, store: Store) -> CommandRunner {
+  CommandRunner::new(
+    address,
+    address,
+    ProcessMetadata {
+      output_node_properties: owned_string_vec(&["unix_mode"]),
+      ..ProcessMetadata::default()
+    },
+    None,
+    BTreeMap::new(),
+    store,
+    Platform::Linux_x86_64,
+    OVERALL_DEADLINE_SECS,
+    RETRY_INTERVAL,
+    EXEC_CONCURRENCY_LIMIT,
+    CACHE_CONCURRENCY_LIMIT,
+    RateLimitConfig::default(),
+    None,
+  )
+  .unwrap()
+}
+
+#[tokio::test]
+async fn execution_metadata_filters_unsupported_node_properties() {
+  WorkunitStore::setup_for_tests();
+  let mock_server = mock::execution_server::TestServer::new(
+    mock::execution_server::MockExecution::new(vec![]),
+    None,
+  );
+  let cas = mock::StubCAS::empty();
+  let store_dir = TempDir::new().unwrap();
+  let store = make_store(store_dir.path(), &cas, task_executor::Executor::new());
+  let command_runner = command_runner_requesting_unix_mode(&mock_server.address(), store);
+
+  // The mock server does not report any supported node properties.
+  let metadata = command_runner.execution_metadata().await;
+  assert!(metadata.output_node_properties.is_empty());
+}
+
+#[tokio::test]
+async fn execution_metadata_without_capabilities() {
+  WorkunitStore::setup_for_tests();
+  let address = {
+    let mock_server = mock::execution_server::TestServer::new(
+      mock::execution_server::MockExecution::new(vec![]),
+      None,
+    );
+    mock_server.address()
+  };
+  let cas = mock::StubCAS::empty();
+  let store_dir = TempDir::new().unwrap();
+  let store = make_store(store_dir.path(), &cas, task_executor::Executor::new());
+  let command_runner = command_runner_requesting_unix_mode(&address, store);
+
+  // The server has shut down, so its capabilities are unknown, and nothing is filtered.
+  let metadata = command_runner.execution_metadata().await;
+  assert_eq!(
+    metadata.output_node_properties,
+    owned_string_vec(&["unix_mode"])
+  );
+}
+
 pub fn echo_foo_request() -> MultiPlatformProcess {
   let mut req = Process::new(owned_string_vec(&["/bin/echo", "-n", "foo"]));
   req.timeout = Some(Duration::from_millis(5000));
@@ -2225,6 +2441,7 @@ fn create_command_runner(
     RETRY_INTERVAL,
     EXEC_CONCURRENCY_LIMIT,
     CACHE_CONCURRENCY_LIMIT,
+    RateLimitConfig::default(),
     None,
   )
   .expect("Failed to make command runner");
@@ -2277,6 +2494,7 @@ pub(crate) fn make_store(
       Duration::from_secs(1),
       1,
       STORE_CONCURRENCY_LIMIT,
+      RateLimitConfig::default(),
       None,
       STORE_BATCH_API_SIZE_LIMIT,
     )
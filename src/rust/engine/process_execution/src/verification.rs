@@ -0,0 +1,231 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::FutureExt;
+use log::{debug, warn};
+use rand::{thread_rng, Rng};
+use store::{SnapshotOps, Store};
+use task_executor::Executor;
+use workunit_store::{in_workunit, Level, Metric, UserMetadataItem, WorkunitMetadata};
+
+use crate::{Context, FallibleProcessResultWithPlatform, MultiPlatformProcess};
+
+///
+/// A difference between an expected (generally cached) process result and an actual (generally
+/// re-executed) process result.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResultDifference {
+  ExitCode { expected: i32, actual: i32 },
+  Stdout,
+  Stderr,
+  OnlyExpected(PathBuf),
+  OnlyActual(PathBuf),
+  Contents(PathBuf),
+}
+
+impl fmt::Display for ResultDifference {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ResultDifference::ExitCode { expected, actual } => {
+        write!(f, "exit code: expected {}, got {}", expected, actual)
+      }
+      ResultDifference::Stdout => write!(f, "stdout differs"),
+      ResultDifference::Stderr => write!(f, "stderr differs"),
+      ResultDifference::OnlyExpected(path) => {
+        write!(f, "{}: only in expected outputs", path.display())
+      }
+      ResultDifference::OnlyActual(path) => write!(f, "{}: only in actual outputs", path.display()),
+      ResultDifference::Contents(path) => write!(f, "{}: contents differ", path.display()),
+    }
+  }
+}
+
+///
+/// Compares two process results, returning their differences. Output differences are sorted by
+/// path, with paths which are only in the actual outputs last. Output files are compared by digest,
+/// executable bit and node properties, and symlinks by target, so this will fetch the output trees
+/// (but not file contents) from the remote Store if necessary.
+///
+pub async fn diff_results(
+  store: &Store,
+  expected: &FallibleProcessResultWithPlatform,
+  actual: &FallibleProcessResultWithPlatform,
+) -> Result<Vec<ResultDifference>, String> {
+  let mut differences = vec![];
+  if expected.exit_code != actual.exit_code {
+    differences.push(ResultDifference::ExitCode {
+      expected: expected.exit_code,
+      actual: actual.exit_code,
+    });
+  }
+  if expected.stdout_digest != actual.stdout_digest {
+    differences.push(ResultDifference::Stdout);
+  }
+  if expected.stderr_digest != actual.stderr_digest {
+    differences.push(ResultDifference::Stderr);
+  }
+
+  let diff = store
+    .diff(expected.output_directory, actual.output_directory)
+    .await
+    .map_err(|e| format!("Failed to diff output directories: {:?}", e))?;
+  let mut expected_differences = diff
+    .removed
+    .into_iter()
+    .map(|path| (path.clone(), ResultDifference::OnlyExpected(path)))
+    .chain(
+      diff
+        .modified
+        .into_iter()
+        .map(|path| (path.clone(), ResultDifference::Contents(path))),
+    )
+    .collect::<Vec<_>>();
+  expected_differences.sort_by(|(l, _), (r, _)| l.cmp(r));
+  differences.extend(expected_differences.into_iter().map(|(_, d)| d));
+  differences.extend(diff.added.into_iter().map(ResultDifference::OnlyActual));
+
+  Ok(differences)
+}
+
+///
+/// Re-executes a sample of the processes for which a caching CommandRunner hit its cache, in order
+/// to detect non-determinism.
+///
+/// The runner used for verification must run processes locally, without consulting or writing to
+/// any cache: it should generally be the local runner that the caching runners wrap.
+///
+#[derive(Clone)]
+pub struct CacheVerifier {
+  runner: Arc<dyn crate::CommandRunner>,
+  store: Store,
+  executor: Executor,
+  sample_rate: f64,
+}
+
+impl CacheVerifier {
+  ///
+  /// Creates a verifier for the given sample rate between 0.0 (never) and 1.0 (always), or None if
+  /// the sample rate is 0.0.
+  ///
+  pub fn new(
+    runner: Arc<dyn crate::CommandRunner>,
+    store: Store,
+    executor: Executor,
+    sample_rate: f64,
+  ) -> Option<CacheVerifier> {
+    if sample_rate > 0.0 {
+      Some(CacheVerifier {
+        runner,
+        store,
+        executor,
+        sample_rate: sample_rate.min(1.0),
+      })
+    } else {
+      None
+    }
+  }
+
+  ///
+  /// Returns true if a cache hit should be verified.
+  ///
+  pub(crate) fn should_verify(&self) -> bool {
+    thread_rng().gen_bool(self.sample_rate)
+  }
+
+  ///
+  /// Re-executes a process for which we hit the cache in the background, and compares the result
+  /// to the cached result, recording the outcome in metrics and in the metadata of a
+  /// `verify_cache_hit` workunit.
+  ///
+  /// The cached result is used regardless of the outcome: this only detects non-determinism.
+  ///
+  pub(crate) fn verify_cache_hit(
+    &self,
+    context: Context,
+    req: MultiPlatformProcess,
+    cached_result: FallibleProcessResultWithPlatform,
+  ) {
+    let verifier = self.clone();
+    // NB: We use `TaskExecutor::spawn` instead of `tokio::spawn` to ensure logging still works.
+    let _verify_join = self.executor.spawn(
+      async move {
+        verifier
+          .verify_cache_hit_inner(context, req, cached_result)
+          .await
+      }
+      .boxed(),
+    );
+  }
+
+  async fn verify_cache_hit_inner(
+    self,
+    context: Context,
+    req: MultiPlatformProcess,
+    cached_result: FallibleProcessResultWithPlatform,
+  ) {
+    let description = req.user_facing_name();
+    let runner = self.runner;
+    let store = self.store;
+    in_workunit!(
+      context.workunit_store.clone(),
+      "verify_cache_hit".to_owned(),
+      WorkunitMetadata {
+        level: Level::Debug,
+        desc: Some(format!("Verifying cache hit: {}", description)),
+        ..WorkunitMetadata::default()
+      },
+      |workunit| async move {
+        workunit.increment_counter(Metric::CacheVerificationRuns, 1);
+        let differences = match runner.run(context, workunit, req).await {
+          Ok(result) => diff_results(&store, &cached_result, &result).await,
+          Err(e) => Err(e),
+        };
+        let differences = match differences {
+          Ok(differences) => differences,
+          Err(e) => {
+            debug!("Failed to verify cache hit for {}: {}", description, e);
+            workunit.increment_counter(Metric::CacheVerificationErrors, 1);
+            return;
+          }
+        };
+
+        let matched = differences.is_empty();
+        if !matched {
+          workunit.increment_counter(Metric::CacheVerificationMismatches, 1);
+          warn!(
+            "Re-executing {} produced a different result than the cached result:\n  {}",
+            description,
+            differences
+              .iter()
+              .map(|d| d.to_string())
+              .collect::<Vec<_>>()
+              .join("\n  ")
+          );
+        }
+        workunit.update_metadata(|initial| WorkunitMetadata {
+          level: if matched { initial.level } else { Level::Warn },
+          user_metadata: vec![
+            (
+              "verification_matched".to_owned(),
+              UserMetadataItem::ImmediateInt(matched as i64),
+            ),
+            (
+              "verification_differences".to_owned(),
+              UserMetadataItem::ImmediateString(
+                differences
+                  .iter()
+                  .map(|d| d.to_string())
+                  .collect::<Vec<_>>()
+                  .join("\n"),
+              ),
+            ),
+          ],
+          ..initial
+        });
+      }
+    )
+    .await
+  }
+}
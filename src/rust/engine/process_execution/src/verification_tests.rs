@@ -0,0 +1,294 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use hashing::{Digest, EMPTY_DIGEST};
+use sharded_lmdb::{ShardedLmdb, DEFAULT_LEASE_TIME};
+use store::Store;
+use tempfile::TempDir;
+use testutil::data::{TestData, TestDirectory};
+use workunit_store::{Metric, RunningWorkunit, UserMetadataItem, Workunit, WorkunitStore};
+
+use crate::verification::{diff_results, CacheVerifier, ResultDifference};
+use crate::{
+  CommandRunner as CommandRunnerTrait, Context, FallibleProcessResultWithPlatform,
+  MultiPlatformProcess, Platform, Process, ProcessMetadata, ProcessResultMetadata,
+  ProcessResultSource,
+};
+
+fn result(
+  exit_code: i32,
+  stdout: Digest,
+  output_directory: Digest,
+) -> FallibleProcessResultWithPlatform {
+  FallibleProcessResultWithPlatform {
+    stdout_digest: stdout,
+    stderr_digest: EMPTY_DIGEST,
+    exit_code,
+    output_directory,
+    platform: Platform::current().unwrap(),
+    metadata: ProcessResultMetadata::new(None, ProcessResultSource::RanLocally),
+  }
+}
+
+async fn store_with_directories(directories: Vec<TestDirectory>) -> (Store, TempDir) {
+  let dir = TempDir::new().unwrap();
+  let store = Store::local_only(task_executor::Executor::new(), dir.path()).unwrap();
+  store
+    .store_file_bytes(TestData::roland().bytes(), false)
+    .await
+    .unwrap();
+  for directory in directories {
+    store
+      .record_directory(&directory.directory(), false)
+      .await
+      .unwrap();
+  }
+  (store, dir)
+}
+
+#[tokio::test]
+async fn diff_identical() {
+  let (store, _dir) = store_with_directories(vec![TestDirectory::containing_roland()]).await;
+  let digest = TestDirectory::containing_roland().digest();
+
+  let differences = diff_results(
+    &store,
+    &result(0, EMPTY_DIGEST, digest),
+    &result(0, EMPTY_DIGEST, digest),
+  )
+  .await
+  .unwrap();
+  assert_eq!(differences, vec![]);
+}
+
+#[tokio::test]
+async fn diff_exit_code_and_stdout() {
+  let (store, _dir) = store_with_directories(vec![]).await;
+
+  let differences = diff_results(
+    &store,
+    &result(0, EMPTY_DIGEST, EMPTY_DIGEST),
+    &result(1, TestData::roland().digest(), EMPTY_DIGEST),
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    differences,
+    vec![
+      ResultDifference::ExitCode {
+        expected: 0,
+        actual: 1
+      },
+      ResultDifference::Stdout,
+    ]
+  );
+}
+
+#[tokio::test]
+async fn diff_output_files() {
+  let (store, _dir) = store_with_directories(vec![
+    TestDirectory::containing_roland_and_treats(),
+    TestDirectory::containing_wrong_roland(),
+    TestDirectory::containing_robin(),
+  ])
+  .await;
+
+  let differences = diff_results(
+    &store,
+    &result(
+      0,
+      EMPTY_DIGEST,
+      TestDirectory::containing_roland_and_treats().digest(),
+    ),
+    &result(
+      0,
+      EMPTY_DIGEST,
+      TestDirectory::containing_wrong_roland().digest(),
+    ),
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    differences,
+    vec![
+      ResultDifference::Contents(PathBuf::from("roland.ext")),
+      ResultDifference::OnlyExpected(PathBuf::from("treats.ext")),
+    ]
+  );
+
+  let differences = diff_results(
+    &store,
+    &result(
+      0,
+      EMPTY_DIGEST,
+      TestDirectory::containing_wrong_roland().digest(),
+    ),
+    &result(0, EMPTY_DIGEST, TestDirectory::containing_robin().digest()),
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    differences,
+    vec![
+      ResultDifference::OnlyExpected(PathBuf::from("roland.ext")),
+      ResultDifference::OnlyActual(PathBuf::from("robin.ext")),
+    ]
+  );
+}
+
+#[tokio::test]
+async fn diff_output_symlinks_and_node_properties() {
+  let (store, _dir) = store_with_directories(vec![]).await;
+  let roland = TestDirectory::containing_roland().directory();
+  let record = |directory: remexec::Directory| {
+    let store = store.clone();
+    async move { store.record_directory(&directory, false).await.unwrap() }
+  };
+  let with_symlink = |target: &str| {
+    let mut directory = roland.clone();
+    directory.symlinks.push(remexec::SymlinkNode {
+      name: "link".to_owned(),
+      target: target.to_owned(),
+      ..remexec::SymlinkNode::default()
+    });
+    directory
+  };
+  let mut with_node_properties = roland.clone();
+  with_node_properties.files[0].node_properties = Some(remexec::NodeProperties {
+    unix_mode: Some(0o600),
+    ..remexec::NodeProperties::default()
+  });
+
+  let differences = diff_results(
+    &store,
+    &result(0, EMPTY_DIGEST, record(with_symlink("roland.ext")).await),
+    &result(0, EMPTY_DIGEST, record(with_symlink("other.ext")).await),
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    differences,
+    vec![ResultDifference::Contents(PathBuf::from("link"))]
+  );
+
+  let differences = diff_results(
+    &store,
+    &result(0, EMPTY_DIGEST, record(roland.clone()).await),
+    &result(0, EMPTY_DIGEST, record(with_node_properties).await),
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    differences,
+    vec![ResultDifference::Contents(PathBuf::from("roland.ext"))]
+  );
+}
+
+/// A runner which writes roland to stdout the first time it runs, and nothing thereafter.
+#[derive(Clone)]
+struct NonDeterministicCommandRunner {
+  call_counter: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl CommandRunnerTrait for NonDeterministicCommandRunner {
+  async fn run(
+    &self,
+    _context: Context,
+    _workunit: &mut RunningWorkunit,
+    _req: MultiPlatformProcess,
+  ) -> Result<FallibleProcessResultWithPlatform, String> {
+    let stdout = if self.call_counter.fetch_add(1, Ordering::SeqCst) == 0 {
+      TestData::roland().digest()
+    } else {
+      EMPTY_DIGEST
+    };
+    Ok(result(0, stdout, EMPTY_DIGEST))
+  }
+
+  fn extract_compatible_request(&self, req: &MultiPlatformProcess) -> Option<Process> {
+    req.0.values().next().cloned()
+  }
+}
+
+#[tokio::test]
+async fn local_cache_hit_verified() {
+  let (workunit_store, mut workunit) = WorkunitStore::setup_for_tests();
+  let (store, _store_dir) = store_with_directories(vec![]).await;
+  let cache_dir = TempDir::new().unwrap();
+  let process_execution_store = ShardedLmdb::new(
+    cache_dir.path().to_owned(),
+    50 * 1024 * 1024,
+    task_executor::Executor::new(),
+    DEFAULT_LEASE_TIME,
+    1,
+  )
+  .unwrap();
+
+  let call_counter = Arc::new(AtomicUsize::new(0));
+  let underlying = Arc::new(NonDeterministicCommandRunner {
+    call_counter: call_counter.clone(),
+  });
+  let runner = crate::cache::CommandRunner::new(
+    underlying.clone(),
+    process_execution_store,
+    store.clone(),
+    ProcessMetadata::default(),
+    CacheVerifier::new(underlying, store, task_executor::Executor::new(), 1.0),
+  );
+  let context = Context::new(workunit_store.clone(), "verification".to_owned());
+  let process = Process::new(vec!["/bin/echo".to_owned()]);
+
+  // The first run misses, and the second hits and is then verified in the background.
+  for _ in 0..2 {
+    let result = runner
+      .run(context.clone(), &mut workunit, process.clone().into())
+      .await
+      .unwrap();
+    assert_eq!(result.stdout_digest, TestData::roland().digest());
+  }
+
+  let verify_workunits = verify_workunits(workunit_store).await;
+  assert_eq!(call_counter.load(Ordering::SeqCst), 2);
+  assert_eq!(verify_workunits.len(), 1);
+  let verify_workunit = &verify_workunits[0];
+  assert_eq!(
+    verify_workunit
+      .counters
+      .get(&Metric::CacheVerificationMismatches),
+    Some(&1)
+  );
+  assert!(verify_workunit.metadata.user_metadata.contains(&(
+    "verification_matched".to_owned(),
+    UserMetadataItem::ImmediateInt(0)
+  )));
+}
+
+///
+/// Waits for (background) cache verification to complete, and returns its workunits.
+///
+pub(crate) async fn verify_workunits(mut workunit_store: WorkunitStore) -> Vec<Workunit> {
+  // Verification runs in the background, so poll for up to ten seconds for it to complete.
+  let mut verify_workunits = vec![];
+  for _ in 0..1000 {
+    verify_workunits.extend(workunit_store.with_latest_workunits(
+      log::Level::Trace,
+      |_, completed| {
+        completed
+          .iter()
+          .filter(|workunit| workunit.name == "verify_cache_hit")
+          .cloned()
+          .collect::<Vec<_>>()
+      },
+    ));
+    if !verify_workunits.is_empty() {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+  }
+  verify_workunits
+}
@@ -29,22 +29,34 @@
 #![type_length_limit = "1257309"]
 
 use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
 use std::iter::{FromIterator, Iterator};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::process::exit;
+use std::sync::Arc;
 use std::time::Duration;
 
+use bazel_protos::gen::build::bazel::remote::execution::v2::action_cache_client::ActionCacheClient;
 use bazel_protos::gen::build::bazel::remote::execution::v2::{Action, Command};
 use bazel_protos::gen::buildbarn::cas::UncachedActionResult;
 use bazel_protos::require_digest;
 use fs::RelativePath;
+use grpc_util::rate_limit::RateLimitConfig;
+use grpc_util::{headers_to_http_header_map, layered_service};
 use hashing::{Digest, Fingerprint};
-use process_execution::{Context, NamedCaches, Platform, ProcessCacheScope, ProcessMetadata};
+use process_execution::{
+  Context, FallibleProcessResultWithPlatform, NamedCaches, Platform, ProcessCacheScope,
+  ProcessMetadata,
+};
 use prost::Message;
 use store::{Store, StoreWrapper};
 use structopt::StructOpt;
 use workunit_store::{in_workunit, WorkunitMetadata, WorkunitStore};
 
+#[cfg(test)]
+mod tests;
+
 #[derive(StructOpt)]
 struct CommandSpec {
   #[structopt(last = true)]
@@ -62,6 +74,14 @@ struct CommandSpec {
   #[structopt(long)]
   extra_platform_property: Vec<String>,
 
+  /// Node properties (e.g. `unix_mode`) to request for outputs, if the server supports them.
+  #[structopt(long)]
+  output_node_property: Vec<String>,
+
+  /// Request outputs via the REAPI v2.1 `output_paths` field, which preserves output symlinks.
+  #[structopt(long)]
+  output_paths: bool,
+
   /// Environment variables with which the process should be run.
   #[structopt(long)]
   env: Vec<String>,
@@ -188,6 +208,15 @@ struct Opt {
   #[structopt(long, default_value = "128")]
   cache_rpc_concurrency: usize,
 
+  /// Maximum number of requests per second to each remote service. Unlimited if not set.
+  #[structopt(long)]
+  rpc_requests_per_second: Option<NonZeroUsize>,
+
+  /// Maximum number of bytes per second transferred to/from each remote service. Unlimited if
+  /// not set.
+  #[structopt(long)]
+  rpc_bytes_per_second: Option<NonZeroUsize>,
+
   /// Whether or not to enable running the process through a Nailgun server.
   /// This will likely start a new Nailgun server as a side effect.
   #[structopt(long)]
@@ -200,6 +229,17 @@ struct Opt {
   /// Extra header to pass on remote execution request.
   #[structopt(long)]
   header: Vec<String>,
+
+  /// Rather than running the process, materialize its input root into this directory (which may
+  /// or may not exist), along with a `__run.sh` script which reproduces its environment, working
+  /// directory and argv.
+  #[structopt(long)]
+  replay_to: Option<PathBuf>,
+
+  /// With --replay-to (which it requires), additionally run the process locally, and diff its
+  /// outputs against the result for its action in the remote action cache (at --cas-server).
+  #[structopt(long, requires = "replay-to")]
+  replay_and_diff: bool,
 }
 
 /// A binary which takes args of format:
@@ -221,6 +261,11 @@ async fn main() {
 
   let executor = task_executor::Executor::new();
 
+  let rate_limit = RateLimitConfig {
+    requests_per_second: args.rpc_requests_per_second.map(NonZeroUsize::get),
+    bytes_per_second: args.rpc_bytes_per_second.map(NonZeroUsize::get),
+  };
+
   let local_store_path = args
     .local_store_path
     .clone()
@@ -230,21 +275,7 @@ async fn main() {
     Store::local_only(executor.clone(), local_store_path).expect("Error making local store");
   let store = match (&args.server, &args.cas_server) {
     (_, Some(cas_server)) => {
-      let root_ca_certs = args
-        .cas_root_ca_cert_file
-        .as_ref()
-        .map(|path| std::fs::read(path).expect("Error reading root CA certs file"));
-
-      let mut headers = BTreeMap::new();
-      if let Some(ref oauth_path) = args.cas_oauth_bearer_token_path {
-        let token =
-          std::fs::read_to_string(oauth_path).expect("Error reading oauth bearer token file");
-        headers.insert(
-          "authorization".to_owned(),
-          format!("Bearer {}", token.trim()),
-        );
-      }
-
+      let (root_ca_certs, headers) = cas_root_ca_certs_and_headers(&args);
       local_only_store.into_with_remote(
         cas_server,
         args.remote_instance_name.clone(),
@@ -254,6 +285,7 @@ async fn main() {
         Duration::from_secs(30),
         args.store_rpc_retries,
         args.store_rpc_concurrency,
+        rate_limit,
         None,
         args.store_batch_api_size_limit,
       )
@@ -267,14 +299,38 @@ async fn main() {
     .await
     .expect("Failed to construct request");
 
-  if let Some(run_under) = args.run_under {
-    let run_under = shlex::split(&run_under).expect("Could not shlex --run-under arg");
+  if let Some(ref run_under) = args.run_under {
+    let run_under = shlex::split(run_under).expect("Could not shlex --run-under arg");
     request.argv = run_under
       .into_iter()
       .chain(request.argv.into_iter())
       .collect();
   }
 
+  if let Some(replay_dir) = args.replay_to.clone() {
+    let exit_code = in_workunit!(
+      workunit_store.clone(),
+      "process_executor_replay".to_owned(),
+      WorkunitMetadata::default(),
+      |workunit| async move {
+        replay(
+          &store,
+          executor,
+          &args,
+          replay_dir,
+          request,
+          process_metadata,
+          rate_limit,
+          workunit,
+        )
+        .await
+      }
+    )
+    .await
+    .expect("Error replaying process");
+    exit(exit_code);
+  }
+
   let runner: Box<dyn process_execution::CommandRunner> = match args.server {
     Some(address) => {
       let root_ca_certs = args
@@ -304,6 +360,7 @@ async fn main() {
             Duration::from_millis(100),
             args.execution_rpc_concurrency,
             args.cache_rpc_concurrency,
+            rate_limit,
             None,
           )
           .expect("Failed to make command runner"),
@@ -362,6 +419,150 @@ async fn main() {
   exit(result.exit_code);
 }
 
+fn cas_root_ca_certs_and_headers(args: &Opt) -> (Option<Vec<u8>>, BTreeMap<String, String>) {
+  let root_ca_certs = args
+    .cas_root_ca_cert_file
+    .as_ref()
+    .map(|path| std::fs::read(path).expect("Error reading root CA certs file"));
+
+  let mut headers = BTreeMap::new();
+  if let Some(ref oauth_path) = args.cas_oauth_bearer_token_path {
+    let token = std::fs::read_to_string(oauth_path).expect("Error reading oauth bearer token file");
+    headers.insert(
+      "authorization".to_owned(),
+      format!("Bearer {}", token.trim()),
+    );
+  }
+  (root_ca_certs, headers)
+}
+
+///
+/// Materializes the inputs of the given process into `replay_dir` along with a `__run.sh` script
+/// to reproduce it. If requested, also runs the process locally and diffs its outputs against the
+/// result in the remote action cache.
+///
+/// Returns the exit code for process_executor: non-zero if the outputs differed.
+///
+async fn replay(
+  store: &Store,
+  executor: task_executor::Executor,
+  args: &Opt,
+  replay_dir: PathBuf,
+  request: process_execution::Process,
+  metadata: ProcessMetadata,
+  rate_limit: RateLimitConfig,
+  workunit: &mut workunit_store::RunningWorkunit,
+) -> Result<i32, String> {
+  store
+    .materialize_directory(replay_dir.clone(), request.input_files)
+    .await?;
+  process_execution::local::setup_run_sh_script(
+    &request.env,
+    &request.working_directory,
+    &request.argv,
+    &replay_dir,
+  )?;
+  println!(
+    "Materialized the inputs of the process into {}: run {} to reproduce it.",
+    replay_dir.display(),
+    replay_dir.join("__run.sh").display()
+  );
+
+  if !args.replay_and_diff {
+    return Ok(0);
+  }
+
+  let cached_result = lookup_cached_result(store, args, &request, &metadata, rate_limit)
+    .await?
+    .ok_or_else(|| "The action was not found in the remote action cache.".to_owned())?;
+
+  let local_runner = process_execution::local::CommandRunner::new(
+    store.clone(),
+    executor,
+    args.work_dir.clone().unwrap_or_else(std::env::temp_dir),
+    NamedCaches::new(
+      args
+        .named_cache_path
+        .clone()
+        .unwrap_or_else(NamedCaches::default_path),
+    ),
+    true,
+  );
+  let local_result = process_execution::CommandRunner::run(
+    &local_runner,
+    Context::default(),
+    workunit,
+    request.into(),
+  )
+  .await?;
+
+  let differences =
+    process_execution::verification::diff_results(store, &cached_result, &local_result).await?;
+  if differences.is_empty() {
+    println!("The local result matched the cached result.");
+    Ok(0)
+  } else {
+    println!("The local result differed from the cached result:");
+    for difference in differences {
+      println!("  {}", difference);
+    }
+    Ok(1)
+  }
+}
+
+async fn lookup_cached_result(
+  store: &Store,
+  args: &Opt,
+  request: &process_execution::Process,
+  metadata: &ProcessMetadata,
+  rate_limit: RateLimitConfig,
+) -> Result<Option<FallibleProcessResultWithPlatform>, String> {
+  let cas_server = args
+    .cas_server
+    .as_ref()
+    .ok_or_else(|| "--replay-and-diff requires --cas-server.".to_owned())?;
+
+  let (action, command, _) =
+    process_execution::remote::make_execute_request(request, metadata.clone())?;
+  let (_, computed_action_digest) =
+    process_execution::remote::ensure_action_stored_locally(store, &command, &action).await?;
+  // Prefer the action digest that we were given, since the request that we reconstructed from it
+  // may not hash identically.
+  let action_digest = match (
+    args.action_digest.action_digest,
+    args.action_digest.action_digest_length,
+  ) {
+    (Some(fingerprint), Some(length)) => Digest::new(fingerprint, length),
+    _ => computed_action_digest,
+  };
+
+  let (root_ca_certs, mut headers) = cas_root_ca_certs_and_headers(args);
+  let tls_client_config = if cas_server.starts_with("https://") {
+    Some(grpc_util::tls::Config::new_without_mtls(root_ca_certs).try_into()?)
+  } else {
+    None
+  };
+  let endpoint = grpc_util::create_endpoint(cas_server, tls_client_config.as_ref(), &mut headers)?;
+  let channel = layered_service(
+    tonic::transport::Channel::balance_list(vec![endpoint].into_iter()),
+    args.cache_rpc_concurrency,
+    rate_limit,
+    headers_to_http_header_map(&headers)?,
+  );
+
+  process_execution::remote::check_action_cache(
+    action_digest,
+    &command,
+    metadata,
+    Platform::current()?,
+    &Context::default(),
+    Arc::new(ActionCacheClient::new(channel)),
+    store.clone(),
+    true,
+  )
+  .await
+}
+
 async fn make_request(
   store: &Store,
   args: &Opt,
@@ -441,6 +642,8 @@ fn make_request_from_flat_args(
     instance_name: args.remote_instance_name.clone(),
     cache_key_gen_version: args.command.cache_key_gen_version.clone(),
     platform_properties: collection_from_keyvalues(args.command.extra_platform_property.iter()),
+    output_node_properties: args.command.output_node_property.clone(),
+    output_paths: args.command.output_paths,
   };
   Ok((process, metadata))
 }
@@ -535,6 +738,8 @@ async fn extract_request_from_action_digest(
           .map(|property| (property.name.clone(), property.value.clone()))
       })
       .collect(),
+    output_node_properties: command.output_node_properties.clone(),
+    output_paths: !command.output_paths.is_empty(),
   };
 
   Ok((process, metadata))
@@ -0,0 +1,132 @@
+use std::path::Path;
+
+use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
+use hashing::Digest;
+use mock::StubActionCache;
+use store::Store;
+use structopt::StructOpt;
+use tempfile::TempDir;
+use testutil::data::{TestData, TestDirectory};
+use workunit_store::WorkunitStore;
+
+use crate::{make_request, replay, Opt};
+
+fn opt(work_dir: &Path, args: &[&str]) -> Result<Opt, structopt::clap::Error> {
+  let input_digest = TestDirectory::containing_roland().digest();
+  let mut argv = vec![
+    "process_executor".to_owned(),
+    format!("--input-digest={}", input_digest.hash),
+    format!("--input-digest-length={}", input_digest.size_bytes),
+    format!("--work-dir={}", work_dir.display()),
+    format!(
+      "--named-cache-path={}",
+      work_dir.join("named_caches").display()
+    ),
+  ];
+  argv.extend(args.iter().map(|arg| (*arg).to_owned()));
+  argv.extend(vec![
+    "--".to_owned(),
+    "/bin/cat".to_owned(),
+    "roland.ext".to_owned(),
+  ]);
+  Opt::from_iter_safe(argv)
+}
+
+async fn local_store(store_dir: &Path) -> Store {
+  let store = Store::local_only(task_executor::Executor::new(), store_dir).unwrap();
+  for data in &[TestData::roland(), TestData::catnip()] {
+    store.store_file_bytes(data.bytes(), false).await.unwrap();
+  }
+  store
+    .record_directory(&TestDirectory::containing_roland().directory(), false)
+    .await
+    .unwrap();
+  store
+}
+
+///
+/// Runs `replay` for the given args, with an action cache entry for the process whose stdout has
+/// the given Digest.
+///
+async fn run_replay(args: &[&str], cached_stdout: Option<Digest>) -> (TempDir, i32) {
+  let (_, mut workunit) = WorkunitStore::setup_for_tests();
+  let tempdir = TempDir::new().unwrap();
+  let store = local_store(&tempdir.path().join("store")).await;
+  let action_cache = StubActionCache::new().unwrap();
+  let cas_server = format!("--cas-server={}", action_cache.address());
+  let replay_to = format!("--replay-to={}", tempdir.path().join("replay").display());
+  let mut all_args = vec![replay_to.as_str(), cas_server.as_str()];
+  all_args.extend(args);
+  let args = opt(tempdir.path(), &all_args).unwrap();
+
+  let (request, metadata) = make_request(&store, &args).await.unwrap();
+  if let Some(stdout_digest) = cached_stdout {
+    let (action, command, _) =
+      process_execution::remote::make_execute_request(&request, metadata.clone()).unwrap();
+    let (_, action_digest) =
+      process_execution::remote::ensure_action_stored_locally(&store, &command, &action)
+        .await
+        .unwrap();
+    action_cache.action_map.lock().insert(
+      action_digest.hash,
+      remexec::ActionResult {
+        exit_code: 0,
+        stdout_digest: Some((&stdout_digest).into()),
+        stderr_digest: Some((&hashing::EMPTY_DIGEST).into()),
+        ..remexec::ActionResult::default()
+      },
+    );
+  }
+
+  let exit_code = replay(
+    &store,
+    task_executor::Executor::new(),
+    &args,
+    tempdir.path().join("replay"),
+    request,
+    metadata,
+    grpc_util::rate_limit::RateLimitConfig::default(),
+    &mut workunit,
+  )
+  .await
+  .unwrap();
+  (tempdir, exit_code)
+}
+
+#[test]
+fn replay_and_diff_requires_replay_to() {
+  let tempdir = TempDir::new().unwrap();
+  assert!(opt(tempdir.path(), &["--replay-and-diff"]).is_err());
+  assert!(opt(
+    tempdir.path(),
+    &["--replay-and-diff", "--replay-to=/tmp/replay"]
+  )
+  .is_ok());
+}
+
+#[tokio::test]
+async fn replay_materializes_inputs_and_script() {
+  let (tempdir, exit_code) = run_replay(&[], None).await;
+  assert_eq!(exit_code, 0);
+  let replay_dir = tempdir.path().join("replay");
+  assert_eq!(
+    std::fs::read_to_string(replay_dir.join("roland.ext")).unwrap(),
+    TestData::roland().string()
+  );
+  let run_sh = std::fs::read_to_string(replay_dir.join("__run.sh")).unwrap();
+  assert!(run_sh.contains("'/bin/cat' 'roland.ext'"), "{}", run_sh);
+}
+
+#[tokio::test]
+async fn replay_and_diff_matching_result() {
+  let (_tempdir, exit_code) =
+    run_replay(&["--replay-and-diff"], Some(TestData::roland().digest())).await;
+  assert_eq!(exit_code, 0);
+}
+
+#[tokio::test]
+async fn replay_and_diff_differing_result() {
+  let (_tempdir, exit_code) =
+    run_replay(&["--replay-and-diff"], Some(TestData::catnip().digest())).await;
+  assert_eq!(exit_code, 1);
+}
@@ -11,6 +11,8 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use crate::core::Failure;
+use crate::downloads::{self, DownloadOptions};
+use crate::graph_persistence::GraphPersistence;
 use crate::intrinsics::Intrinsics;
 use crate::nodes::{NodeKey, WrappedNode};
 use crate::session::{Session, Sessions};
@@ -21,8 +23,11 @@ use bazel_protos::gen::build::bazel::remote::execution::v2::ServerCapabilities;
 use double_checked_cell_async::DoubleCheckedCell;
 use fs::{safe_create_dir_all_ioerror, GitignoreStyleExcludes, PosixFS};
 use graph::{self, EntryId, Graph, InvalidationResult, NodeContext};
-use log::info;
+use grpc_util::rate_limit::RateLimitConfig;
+use log::{info, warn};
 use parking_lot::Mutex;
+use process_execution::circuit_breaker::CircuitBreakerOptions;
+use process_execution::verification::CacheVerifier;
 use process_execution::{
   self, BoundedCommandRunner, CommandRunner, NamedCaches, Platform, ProcessMetadata,
   RemoteCacheWarningsBehavior,
@@ -30,10 +35,12 @@ use process_execution::{
 use regex::Regex;
 use rule_graph::RuleGraph;
 use sharded_lmdb::ShardedLmdb;
-use store::{self, Store};
+use store::{
+  self, DirectoryDigestCache, FileDigestCache, MaterializeStrategy, NodePropertyOptions, Store,
+};
 use task_executor::Executor;
 use uuid::Uuid;
-use watch::{Invalidatable, InvalidationWatcher};
+use watch::{CoalescingOptions, Invalidatable, InvalidationWatcher, WatchmanOptions};
 
 // The reqwest crate has no support for ingesting multiple certificates in a single file,
 // and requires single PEM blocks. There is a crate (https://crates.io/crates/pem) that can decode
@@ -59,10 +66,21 @@ pub struct Core {
   pub intrinsics: Intrinsics,
   pub executor: Executor,
   store: Store,
+  /// The Store that downloaded files are cached in. Unlike `store`, it always uses the remote CAS
+  /// (if one is configured), because the expected digest of a download is known up front.
+  pub download_store: Store,
+  /// True if downloaded files should be uploaded to the remote CAS.
+  pub upload_downloads: bool,
+  pub file_digest_cache: FileDigestCache,
+  pub directory_digest_cache: DirectoryDigestCache,
+  /// The NodeProperties (beyond their executable bits) to capture for files in the workspace.
+  pub capture_node_properties: NodePropertyOptions,
   pub command_runner: Box<dyn process_execution::CommandRunner>,
   pub http_client: reqwest::Client,
+  pub download_options: DownloadOptions,
   pub vfs: PosixFS,
   pub watcher: Option<Arc<InvalidationWatcher>>,
+  graph_persistence: Option<Arc<GraphPersistence>>,
   pub build_root: PathBuf,
   pub local_parallelism: usize,
   pub sessions: Sessions,
@@ -85,10 +103,35 @@ pub struct RemotingOptions {
   pub cache_warnings_behavior: RemoteCacheWarningsBehavior,
   pub cache_eager_fetch: bool,
   pub cache_rpc_concurrency: usize,
+  pub cache_circuit_breaker_failures: usize,
+  pub cache_circuit_breaker_window: Duration,
+  pub cache_circuit_breaker_probe_interval: Option<Duration>,
   pub execution_extra_platform_properties: Vec<(String, String)>,
+  pub execution_output_node_properties: Vec<String>,
+  pub execution_output_paths: bool,
   pub execution_headers: BTreeMap<String, String>,
   pub execution_overall_deadline: Duration,
   pub execution_rpc_concurrency: usize,
+  pub execution_local_fallback: bool,
+  pub rpc_requests_per_second: Option<usize>,
+  pub rpc_bytes_per_second: Option<usize>,
+}
+
+impl RemotingOptions {
+  fn rpc_rate_limit(&self) -> RateLimitConfig {
+    RateLimitConfig {
+      requests_per_second: self.rpc_requests_per_second,
+      bytes_per_second: self.rpc_bytes_per_second,
+    }
+  }
+
+  fn cache_circuit_breaker(&self) -> CircuitBreakerOptions {
+    CircuitBreakerOptions {
+      failure_threshold: self.cache_circuit_breaker_failures,
+      failure_window: self.cache_circuit_breaker_window,
+      probe_interval: self.cache_circuit_breaker_probe_interval,
+    }
+  }
 }
 
 #[derive(Clone, Debug)]
@@ -100,6 +143,7 @@ pub struct ExecutionStrategyOptions {
   pub local_enable_nailgun: bool,
   pub remote_cache_read: bool,
   pub remote_cache_write: bool,
+  pub cache_verification_sample_rate: f64,
 }
 
 #[derive(Clone, Debug)]
@@ -110,6 +154,8 @@ pub struct LocalStoreOptions {
   pub directories_max_size_bytes: usize,
   pub lease_time: Duration,
   pub shard_count: u8,
+  pub materialize_strategy: MaterializeStrategy,
+  pub capture_node_properties: NodePropertyOptions,
 }
 
 impl From<&LocalStoreOptions> for store::LocalOptions {
@@ -137,6 +183,10 @@ impl Core {
       executor.clone(),
       local_store_options.store_dir.clone(),
       local_store_options.into(),
+    )?
+    .into_with_immutable_files(
+      local_store_options.store_dir.join("immutable"),
+      local_store_options.materialize_strategy,
     )?;
     if enable_remote {
       let remote_store_address = remote_store_address
@@ -151,6 +201,7 @@ impl Core {
         remoting_opts.store_chunk_upload_timeout,
         remoting_opts.store_rpc_retries,
         remoting_opts.store_rpc_concurrency,
+        remoting_opts.rpc_rate_limit(),
         capabilities_cell_opt,
         remoting_opts.store_batch_api_size_limit,
       )
@@ -218,20 +269,29 @@ impl Core {
       full_store.clone()
     };
 
-    let local_command_runner = Core::make_local_execution_runner(
+    let local_command_runner: Arc<dyn CommandRunner> = Core::make_local_execution_runner(
       &store_for_local_runner,
       executor,
       local_execution_root_dir,
       named_caches_dir,
       process_execution_metadata,
       exec_strategy_opts,
+    )
+    .into();
+
+    // Cache hits are verified by re-executing them locally, bypassing all caches.
+    let cache_verifier = CacheVerifier::new(
+      local_command_runner.clone(),
+      full_store.clone(),
+      executor.clone(),
+      exec_strategy_opts.cache_verification_sample_rate,
     );
 
     // Possibly either add the remote execution runner or the remote cache runner.
     // `global_options.py` already validates that both are not set at the same time.
     let maybe_remote_enabled_command_runner: Box<dyn CommandRunner> =
       if remoting_opts.execution_enable {
-        Box::new(BoundedCommandRunner::new(
+        let remote_command_runner: Box<dyn CommandRunner> = Box::new(BoundedCommandRunner::new(
           Box::new(process_execution::remote::CommandRunner::new(
             // We unwrap because global_options.py will have already validated these are defined.
             remoting_opts.execution_address.as_ref().unwrap(),
@@ -247,13 +307,22 @@ impl Core {
             Duration::from_millis(100),
             remoting_opts.execution_rpc_concurrency,
             remoting_opts.cache_rpc_concurrency,
+            remoting_opts.rpc_rate_limit(),
             capabilities_cell_opt,
           )?),
           exec_strategy_opts.remote_parallelism,
-        ))
+        ));
+        if remoting_opts.execution_local_fallback {
+          Box::new(process_execution::fallback::CommandRunner::new(
+            remote_command_runner,
+            Box::new(local_command_runner),
+          ))
+        } else {
+          remote_command_runner
+        }
       } else if remote_caching_used {
         Box::new(process_execution::remote_cache::CommandRunner::new(
-          local_command_runner.into(),
+          local_command_runner,
           process_execution_metadata.clone(),
           executor.clone(),
           full_store.clone(),
@@ -266,9 +335,12 @@ impl Core {
           remoting_opts.cache_warnings_behavior,
           remoting_opts.cache_eager_fetch,
           remoting_opts.cache_rpc_concurrency,
+          remoting_opts.rpc_rate_limit(),
+          remoting_opts.cache_circuit_breaker(),
+          cache_verifier.clone(),
         )?)
       } else {
-        local_command_runner
+        Box::new(local_command_runner)
       };
 
     // Possibly use the local cache runner, regardless of remote execution/caching.
@@ -286,6 +358,7 @@ impl Core {
         process_execution_store,
         full_store.clone(),
         process_execution_metadata.clone(),
+        cache_verifier,
       ))
     } else {
       maybe_remote_enabled_command_runner
@@ -339,9 +412,13 @@ impl Core {
     local_execution_root_dir: PathBuf,
     named_caches_dir: PathBuf,
     ca_certs_path: Option<PathBuf>,
+    download_options: DownloadOptions,
     local_store_options: LocalStoreOptions,
     remoting_opts: RemotingOptions,
     exec_strategy_opts: ExecutionStrategyOptions,
+    graph_persistence_path: Option<PathBuf>,
+    watchman_options: Option<WatchmanOptions>,
+    coalescing_options: CoalescingOptions,
   ) -> Result<Core, String> {
     // We re-use these certs for both the execution and store service; they're generally tied together.
     let root_ca_certs = if let Some(ref path) = remoting_opts.root_ca_certs_path {
@@ -397,10 +474,15 @@ impl Core {
       full_store.clone()
     };
 
+    let upload_downloads = need_remote_store
+      && (remoting_opts.execution_enable || exec_strategy_opts.remote_cache_write);
+
     let process_execution_metadata = ProcessMetadata {
       instance_name: remoting_opts.instance_name.clone(),
       cache_key_gen_version: remoting_opts.execution_process_cache_namespace.clone(),
       platform_properties: remoting_opts.execution_extra_platform_properties.clone(),
+      output_node_properties: remoting_opts.execution_output_node_properties.clone(),
+      output_paths: remoting_opts.execution_output_paths,
     };
 
     let command_runner = Self::make_command_runner(
@@ -424,7 +506,7 @@ impl Core {
 
     let http_client_builder = ca_certs
       .iter()
-      .fold(reqwest::Client::builder(), |builder, cert| {
+      .fold(downloads::http_client_builder(), |builder, cert| {
         builder.add_root_certificate(cert.clone())
       });
     let http_client = http_client_builder
@@ -432,28 +514,71 @@ impl Core {
       .map_err(|err| format!("Error building HTTP client: {}", err))?;
     let rule_graph = RuleGraph::new(tasks.rules().clone(), tasks.queries().clone())?;
 
-    let gitignore_file = if use_gitignore {
-      let gitignore_path = build_root.join(".gitignore");
-      if Path::is_file(&gitignore_path) {
-        Some(gitignore_path)
-      } else {
+    let graph_persistence = match graph_persistence_path {
+      Some(path) if watch_filesystem => Some(Arc::new(GraphPersistence::new(
+        path,
+        build_root.clone(),
+        &ignore_patterns,
+        use_gitignore,
+      ))),
+      Some(_) => {
+        warn!("Graph persistence requires filesystem watching to be enabled: ignoring.");
         None
       }
-    } else {
-      None
+      None => None,
     };
-    let ignorer =
-      GitignoreStyleExcludes::create_with_gitignore_file(ignore_patterns, gitignore_file)
-        .map_err(|e| format!("Could not parse build ignore patterns: {:?}", e))?;
+    let ignorer = if use_gitignore {
+      GitignoreStyleExcludes::create_with_nested_gitignores(ignore_patterns, build_root.clone())
+    } else {
+      GitignoreStyleExcludes::create(ignore_patterns)
+    }
+    .map_err(|e| format!("Could not parse build ignore patterns: {:?}", e))?;
 
     let watcher = if watch_filesystem {
-      let w = InvalidationWatcher::new(executor.clone(), build_root.clone(), ignorer.clone())?;
-      w.start(&graph);
+      let w = if let Some(watchman_options) = watchman_options {
+        InvalidationWatcher::new_with_watchman(
+          executor.clone(),
+          build_root.clone(),
+          ignorer.clone(),
+          watchman_options,
+        )?
+      } else {
+        InvalidationWatcher::new(executor.clone(), build_root.clone(), ignorer.clone())?
+      };
+      w.start(&graph, coalescing_options);
       Some(w)
     } else {
       None
     };
 
+    if let (Some(graph_persistence), Some(watcher)) = (&graph_persistence, &watcher) {
+      // Restore in the background: any Nodes which are requested before they are restored will
+      // simply run.
+      let graph_persistence = graph_persistence.clone();
+      let watcher = watcher.clone();
+      let graph = graph.clone();
+      let store = store.clone();
+      let executor2 = executor.clone();
+      let _join = executor.spawn(async move {
+        match graph_persistence
+          .restore(&graph, &watcher, &store, &executor2)
+          .await
+        {
+          Ok(count) => info!(
+            "Restored {} filesystem nodes from the persisted graph.",
+            count
+          ),
+          Err(e) => warn!("Failed to restore the persisted graph: {}", e),
+        }
+      });
+    }
+
+    let file_digest_cache = FileDigestCache::new(
+      local_store_options.store_dir.join("file_digests"),
+      executor.clone(),
+      local_store_options.shard_count,
+    )?;
+
     let sessions = Sessions::new(&executor)?;
 
     Ok(Core {
@@ -464,14 +589,21 @@ impl Core {
       intrinsics,
       executor: executor.clone(),
       store,
+      download_store: full_store,
+      upload_downloads,
+      file_digest_cache,
+      directory_digest_cache: DirectoryDigestCache::new(),
+      capture_node_properties: local_store_options.capture_node_properties,
       command_runner,
       http_client,
+      download_options,
       // TODO: Errors in initialization should definitely be exposed as python
       // exceptions, rather than as panics.
       vfs: PosixFS::new(&build_root, ignorer, executor)
         .map_err(|e| format!("Could not initialize Vfs: {:?}", e))?,
       build_root,
       watcher,
+      graph_persistence,
       local_parallelism: exec_strategy_opts.local_parallelism,
       sessions,
     })
@@ -490,6 +622,21 @@ impl Core {
     if let Err(msg) = self.sessions.shutdown(timeout).await {
       log::warn!("During shutdown: {}", msg);
     }
+    // Then persist the Graph, if enabled and if the watcher has kept it accurate.
+    if let (Some(graph_persistence), Some(watcher)) = (&self.graph_persistence, &self.watcher) {
+      if watcher.is_valid().await.is_ok() {
+        let graph_persistence = graph_persistence.clone();
+        let graph = self.graph.clone();
+        let persisted = self
+          .executor
+          .spawn_blocking(move || graph_persistence.persist(&graph))
+          .await;
+        match persisted {
+          Ok(count) => info!("Persisted {} filesystem nodes from the graph.", count),
+          Err(e) => warn!("{}", e),
+        }
+      }
+    }
     // Then clear the Graph to ensure that drop handlers run (particular for running processes).
     self.graph.clear();
   }
@@ -513,6 +660,23 @@ impl Invalidatable for InvalidatableGraph {
     cleared + dirtied
   }
 
+  fn invalidate_directories(&self, directories: &HashSet<PathBuf>, caller: &str) -> usize {
+    let InvalidationResult { cleared, dirtied } = self.invalidate_from_roots(move |node| {
+      if let Some(fs_subject) = node.fs_subject() {
+        directories
+          .iter()
+          .any(|directory| fs_subject.starts_with(directory))
+      } else {
+        false
+      }
+    });
+    info!(
+      "{} invalidation: cleared {} and dirtied {} nodes for directories: {:?}",
+      caller, cleared, dirtied, directories
+    );
+    cleared + dirtied
+  }
+
   fn invalidate_all(&self, caller: &str) -> usize {
     let InvalidationResult { cleared, dirtied } =
       self.invalidate_from_roots(|node| node.fs_subject().is_some());
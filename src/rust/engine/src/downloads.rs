@@ -0,0 +1,486 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::stream::StreamExt;
+use hashing::Digest;
+use log::{debug, warn};
+use regex::Regex;
+use reqwest::header::{CONTENT_RANGE, LOCATION, RANGE};
+use reqwest::StatusCode;
+use store::Store;
+use url::Url;
+
+///
+/// The maximum number of redirects to follow when downloading from a URL.
+///
+const MAX_REDIRECTS: usize = 10;
+
+///
+/// Creates a builder for the client which is used to download files. The client does not follow
+/// redirects itself: `fetch` follows them, so that the headers configured for a host are only
+/// sent to that host.
+///
+pub fn http_client_builder() -> reqwest::ClientBuilder {
+  reqwest::Client::builder().redirect(reqwest::redirect::Policy::none())
+}
+
+///
+/// Controls how failed attempts to download from a URL are retried.
+///
+#[derive(Clone, Debug)]
+pub(crate) struct RetryPolicy {
+  /// The maximum number of attempts to download from each URL.
+  pub attempts: u32,
+  /// The delay before the first retry, which doubles for each subsequent retry.
+  pub initial_delay: Duration,
+  pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> RetryPolicy {
+    RetryPolicy {
+      attempts: 4,
+      initial_delay: Duration::from_millis(200),
+      max_delay: Duration::from_secs(10),
+    }
+  }
+}
+
+impl RetryPolicy {
+  fn delay(&self, retry: u32) -> Duration {
+    self
+      .initial_delay
+      .checked_mul(2_u32.saturating_pow(retry - 1))
+      .unwrap_or(self.max_delay)
+      .min(self.max_delay)
+  }
+}
+
+///
+/// Options which control how files are downloaded: the authentication to use for each host, and
+/// rules to rewrite URLs before they are downloaded.
+///
+#[derive(Clone, Debug, Default)]
+pub struct DownloadOptions {
+  headers_by_host: BTreeMap<String, BTreeMap<String, String>>,
+  netrc: Netrc,
+  url_rewrites: Vec<(Regex, String)>,
+}
+
+impl DownloadOptions {
+  ///
+  /// Creates DownloadOptions.
+  ///
+  /// * `headers_by_host` - Headers to add to requests to each host (or `host:port`).
+  /// * `netrc_path` - If set, a netrc file containing credentials for hosts which do not have
+  ///   headers configured.
+  /// * `url_rewrites` - Pairs of a regex and a replacement (which may refer to capture groups as
+  ///   `$1` or `${name}`). A URL is rewritten by the first regex which matches it.
+  ///
+  pub fn new(
+    headers_by_host: BTreeMap<String, BTreeMap<String, String>>,
+    netrc_path: Option<PathBuf>,
+    url_rewrites: Vec<(String, String)>,
+  ) -> Result<DownloadOptions, String> {
+    let netrc = match netrc_path {
+      Some(path) => {
+        let content = std::fs::read_to_string(&path)
+          .map_err(|e| format!("Failed to read netrc file {}: {}", path.display(), e))?;
+        Netrc::parse(&content)
+      }
+      None => Netrc::default(),
+    };
+    let url_rewrites = url_rewrites
+      .into_iter()
+      .map(|(pattern, replacement)| {
+        let regex = Regex::new(&pattern)
+          .map_err(|e| format!("Invalid URL rewrite pattern {}: {}", pattern, e))?;
+        Ok((regex, replacement))
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+    Ok(DownloadOptions {
+      headers_by_host,
+      netrc,
+      url_rewrites,
+    })
+  }
+
+  ///
+  /// Applies the first matching URL rewrite rule to the given URL, and returns the rewritten URL if
+  /// it changed.
+  ///
+  pub fn rewrite(&self, url: &Url) -> Result<Option<Url>, String> {
+    let (regex, replacement) = match self
+      .url_rewrites
+      .iter()
+      .find(|(regex, _)| regex.is_match(url.as_str()))
+    {
+      Some(rule) => rule,
+      None => return Ok(None),
+    };
+    let rewritten = regex.replace(url.as_str(), replacement.as_str());
+    if rewritten == url.as_str() {
+      return Ok(None);
+    }
+    Url::parse(&rewritten).map(Some).map_err(|e| {
+      format!(
+        "URL {} was rewritten by {} to {}, which is not a valid URL: {}",
+        url,
+        regex.as_str(),
+        rewritten,
+        e
+      )
+    })
+  }
+
+  ///
+  /// Adds the headers configured for the host of the given URL to a request. If none are
+  /// configured, adds basic authentication from the netrc file (if any).
+  ///
+  fn authenticate(&self, request: reqwest::RequestBuilder, url: &Url) -> reqwest::RequestBuilder {
+    let host = match url.host_str() {
+      Some(host) => host,
+      None => return request,
+    };
+    let host_and_port = url.port().map(|port| format!("{}:{}", host, port));
+    let headers = host_and_port
+      .as_ref()
+      .and_then(|host_and_port| self.headers_by_host.get(host_and_port))
+      .or_else(|| self.headers_by_host.get(host));
+    if let Some(headers) = headers {
+      return headers.iter().fold(request, |request, (name, value)| {
+        request.header(name, value)
+      });
+    }
+    match self.netrc.credentials(host) {
+      Some(credentials) => request.basic_auth(&credentials.login, credentials.password.as_ref()),
+      None => request,
+    }
+  }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Credentials {
+  login: String,
+  password: Option<String>,
+}
+
+///
+/// The credentials for each host in a netrc file.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct Netrc {
+  machines: HashMap<String, Credentials>,
+  default: Option<Credentials>,
+}
+
+impl Netrc {
+  fn parse(content: &str) -> Netrc {
+    let mut netrc = Netrc::default();
+    // The machine (or None for `default`) whose credentials are being parsed.
+    let mut current: Option<(Option<String>, Credentials)> = None;
+
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+      let mut tokens = line.split_whitespace();
+      while let Some(token) = tokens.next() {
+        match token {
+          "machine" => {
+            netrc.insert(current.take());
+            current = tokens
+              .next()
+              .map(|machine| (Some(machine.to_owned()), Credentials::default()));
+          }
+          "default" => {
+            netrc.insert(current.take());
+            current = Some((None, Credentials::default()));
+          }
+          "login" | "password" | "account" => {
+            let value = tokens.next().map(str::to_owned);
+            if let (Some((_, credentials)), Some(value)) = (current.as_mut(), value) {
+              match token {
+                "login" => credentials.login = value,
+                "password" => credentials.password = Some(value),
+                _ => (),
+              }
+            }
+          }
+          "macdef" => {
+            // A macro definition continues until the next blank line.
+            netrc.insert(current.take());
+            for line in &mut lines {
+              if line.trim().is_empty() {
+                break;
+              }
+            }
+            break;
+          }
+          _ => (),
+        }
+      }
+    }
+    netrc.insert(current);
+    netrc
+  }
+
+  fn insert(&mut self, entry: Option<(Option<String>, Credentials)>) {
+    match entry {
+      // As in other netrc implementations, the first entry for a machine wins.
+      Some((Some(machine), credentials)) => {
+        self.machines.entry(machine).or_insert(credentials);
+      }
+      Some((None, credentials)) => self.default = Some(credentials),
+      None => (),
+    }
+  }
+
+  fn credentials(&self, host: &str) -> Option<&Credentials> {
+    self.machines.get(host).or_else(|| self.default.as_ref())
+  }
+}
+
+///
+/// Ensures that the Store has the file with the given Digest, downloading it from the given URLs
+/// only if neither the local store nor the remote CAS (if the Store has one) already has it.
+///
+/// If `upload` is true, a downloaded file is then uploaded to the remote CAS, so that other
+/// machines can fetch it from there rather than from the URLs. Failing to fetch from or upload to
+/// the remote CAS is not fatal.
+///
+pub(crate) async fn load_or_download(
+  store: &Store,
+  upload: bool,
+  http_client: &reqwest::Client,
+  options: &DownloadOptions,
+  urls: &[Url],
+  expected_digest: Digest,
+  retry_policy: &RetryPolicy,
+) -> Result<(), String> {
+  match store.load_file_bytes_with(expected_digest, |_| ()).await {
+    Ok(Some(())) => return Ok(()),
+    Ok(None) => (),
+    Err(e) => warn!(
+      "Failed to load {:?} from the store, so downloading it instead: {}",
+      expected_digest, e
+    ),
+  }
+
+  let bytes = download(http_client, options, urls, expected_digest, retry_policy).await?;
+  store.store_file_bytes(bytes, true).await?;
+
+  if upload {
+    if let Err(e) = store
+      .ensure_remote_has_recursive(vec![expected_digest])
+      .await
+    {
+      warn!(
+        "Failed to upload downloaded file {:?} to the remote store: {}",
+        expected_digest, e
+      );
+    }
+  }
+  Ok(())
+}
+
+enum AttemptError {
+  // An error which may succeed if retried, such as a network error or a server error.
+  Retryable(String),
+  // An error which will not succeed if retried from the same URL.
+  Permanent(String),
+}
+
+///
+/// Downloads the file with the expected Digest from the first of the given URLs (which are tried in
+/// order) which succeeds, and returns its content.
+///
+/// Each URL is retried with exponential backoff according to the RetryPolicy. When a download from
+/// an HTTP URL fails part way through, it is resumed with a `Range` request if the server supports
+/// them.
+///
+pub(crate) async fn download(
+  http_client: &reqwest::Client,
+  options: &DownloadOptions,
+  urls: &[Url],
+  expected_digest: Digest,
+  retry_policy: &RetryPolicy,
+) -> Result<bytes::Bytes, String> {
+  let mut errors = Vec::new();
+  for url in urls {
+    match download_from(http_client, options, url, expected_digest, retry_policy).await {
+      Ok(bytes) => return Ok(bytes),
+      Err(e) => {
+        debug!("Failed to download {}: {}", url, e);
+        errors.push(format!("{}: {}", url, e));
+      }
+    }
+  }
+  Err(format!(
+    "Failed to download a file with digest {:?}:\n  {}",
+    expected_digest,
+    errors.join("\n  ")
+  ))
+}
+
+async fn download_from(
+  http_client: &reqwest::Client,
+  options: &DownloadOptions,
+  url: &Url,
+  expected_digest: Digest,
+  retry_policy: &RetryPolicy,
+) -> Result<bytes::Bytes, String> {
+  let mut content = BytesMut::with_capacity(expected_digest.size_bytes);
+  let mut attempt = 0;
+  loop {
+    if attempt > 0 {
+      tokio::time::sleep(retry_policy.delay(attempt)).await;
+    }
+    attempt += 1;
+
+    let res = if url.scheme() == "file" {
+      read_file(url, &mut content).await
+    } else {
+      fetch(http_client, options, url, expected_digest, &mut content).await
+    };
+    match res {
+      Ok(()) => break,
+      Err(AttemptError::Retryable(e)) if attempt < retry_policy.attempts => {
+        debug!(
+          "Retrying download of {} after attempt {} failed: {}",
+          url, attempt, e
+        );
+      }
+      Err(AttemptError::Retryable(e)) | Err(AttemptError::Permanent(e)) => return Err(e),
+    }
+  }
+
+  let content = content.freeze();
+  let actual_digest = Digest::of_bytes(&content);
+  if expected_digest != actual_digest {
+    return Err(format!(
+      "Wrong digest for downloaded file: want {:?} got {:?}",
+      expected_digest, actual_digest
+    ));
+  }
+  Ok(content)
+}
+
+async fn read_file(url: &Url, content: &mut BytesMut) -> Result<(), AttemptError> {
+  let bytes = tokio::fs::read(url.path())
+    .await
+    .map_err(|e| AttemptError::Permanent(format!("Error ({}) opening file", e)))?;
+  content.clear();
+  content.extend_from_slice(&bytes);
+  Ok(())
+}
+
+///
+/// Sends a request for the given URL (resuming from the given byte, if non-zero), and follows any
+/// redirects. Each request is authenticated for its own host, so that headers are not leaked to
+/// the hosts which a URL redirects to.
+///
+async fn send(
+  http_client: &reqwest::Client,
+  options: &DownloadOptions,
+  url: &Url,
+  resume_from: usize,
+) -> Result<reqwest::Response, AttemptError> {
+  let mut url = url.clone();
+  for _ in 0..=MAX_REDIRECTS {
+    let mut request = options.authenticate(http_client.get(url.clone()), &url);
+    if resume_from > 0 {
+      request = request.header(RANGE, format!("bytes={}-", resume_from));
+    }
+    let response = request
+      .send()
+      .await
+      .map_err(|e| AttemptError::Retryable(format!("Error downloading file: {}", e)))?;
+    let location = match response.headers().get(LOCATION) {
+      Some(location) if response.status().is_redirection() => location,
+      _ => return Ok(response),
+    };
+    url = location
+      .to_str()
+      .ok()
+      .and_then(|location| url.join(location).ok())
+      .ok_or_else(|| {
+        AttemptError::Permanent(format!("Redirected to an invalid URL: {:?}", location))
+      })?;
+  }
+  Err(AttemptError::Permanent(format!(
+    "Exceeded the maximum of {} redirects.",
+    MAX_REDIRECTS
+  )))
+}
+
+///
+/// Fetches the given URL, appending its content to the given buffer. If the buffer already contains
+/// a prefix of the content from a previous attempt, requests only the remainder of the content.
+///
+async fn fetch(
+  http_client: &reqwest::Client,
+  options: &DownloadOptions,
+  url: &Url,
+  expected_digest: Digest,
+  content: &mut BytesMut,
+) -> Result<(), AttemptError> {
+  let resume_from = content.len();
+  let response = send(http_client, options, url, resume_from).await?;
+
+  let status = response.status();
+  if status == StatusCode::PARTIAL_CONTENT && resume_from > 0 {
+    let range_start = response
+      .headers()
+      .get(CONTENT_RANGE)
+      .and_then(|range| range.to_str().ok())
+      .and_then(|range| range.strip_prefix("bytes "))
+      .and_then(|range| range.split('-').next())
+      .and_then(|start| start.parse::<usize>().ok());
+    if range_start != Some(resume_from) {
+      content.clear();
+      return Err(AttemptError::Retryable(format!(
+        "Server responded with an unexpected range for a request to resume from byte {}.",
+        resume_from
+      )));
+    }
+  } else if status.is_success() {
+    // The server does not support (or ignored) the range request, and sent the entire content.
+    content.clear();
+  } else if status == StatusCode::RANGE_NOT_SATISFIABLE {
+    content.clear();
+    return Err(AttemptError::Retryable(format!(
+      "Server could not resume the download from byte {}.",
+      resume_from
+    )));
+  } else if status.is_server_error()
+    || status == StatusCode::TOO_MANY_REQUESTS
+    || status == StatusCode::REQUEST_TIMEOUT
+  {
+    return Err(AttemptError::Retryable(format!(
+      "Server error ({}) downloading file",
+      status.as_str()
+    )));
+  } else {
+    return Err(AttemptError::Permanent(format!(
+      "Client error ({}) downloading file",
+      status.as_str()
+    )));
+  }
+
+  let mut stream = response.bytes_stream();
+  while let Some(chunk) = stream.next().await {
+    let chunk = chunk
+      .map_err(|e| AttemptError::Retryable(format!("Error reading URL fetch response: {}", e)))?;
+    if content.len() + chunk.len() > expected_digest.size_bytes {
+      return Err(AttemptError::Permanent(
+        "Downloaded file was larger than expected digest".to_owned(),
+      ));
+    }
+    content.extend_from_slice(&chunk);
+  }
+  Ok(())
+}
@@ -0,0 +1,411 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use bytes::Bytes;
+use grpc_util::rate_limit::RateLimitConfig;
+use grpc_util::tls;
+use hashing::Digest;
+use reqwest::header::{AUTHORIZATION, RANGE};
+use store::Store;
+use tempfile::TempDir;
+use testutil::data::TestData;
+use testutil_mock::{StubCAS, StubHttpServer, StubResponse};
+use url::Url;
+
+use crate::downloads::{
+  download, http_client_builder, load_or_download, DownloadOptions, RetryPolicy,
+};
+
+fn content() -> Bytes {
+  test_data().bytes()
+}
+
+fn test_data() -> TestData {
+  TestData::new(&"European Burmese".repeat(1024))
+}
+
+fn retry_policy() -> RetryPolicy {
+  RetryPolicy {
+    attempts: 3,
+    initial_delay: Duration::from_millis(1),
+    max_delay: Duration::from_millis(10),
+  }
+}
+
+///
+/// The `Range` header (if any) of each request that the server has received.
+///
+fn ranges(server: &StubHttpServer) -> Vec<Option<String>> {
+  server
+    .requests()
+    .iter()
+    .map(|headers| {
+      headers
+        .get(RANGE)
+        .map(|range| range.to_str().unwrap().to_owned())
+    })
+    .collect()
+}
+
+async fn download_from(urls: &[String]) -> Result<Bytes, String> {
+  download_with_options(&DownloadOptions::default(), urls).await
+}
+
+async fn download_with_options(
+  options: &DownloadOptions,
+  urls: &[String],
+) -> Result<Bytes, String> {
+  let urls = urls
+    .iter()
+    .map(|url| Url::parse(url).unwrap())
+    .collect::<Vec<_>>();
+  download(
+    &http_client_builder().build().unwrap(),
+    options,
+    &urls,
+    Digest::of_bytes(&content()),
+    &retry_policy(),
+  )
+  .await
+}
+
+#[tokio::test]
+async fn downloads() {
+  let server = StubHttpServer::new(content(), vec![]);
+  assert_eq!(download_from(&[server.url("file")]).await, Ok(content()));
+  assert_eq!(ranges(&server), vec![None]);
+}
+
+#[tokio::test]
+async fn retries_server_errors() {
+  let server = StubHttpServer::new(
+    content(),
+    vec![StubResponse::Status(503), StubResponse::Status(500)],
+  );
+  assert_eq!(download_from(&[server.url("file")]).await, Ok(content()));
+  assert_eq!(server.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn gives_up_after_max_attempts() {
+  let server = StubHttpServer::new(content(), vec![StubResponse::Status(503); 3]);
+  let err = download_from(&[server.url("file")]).await.unwrap_err();
+  assert!(err.contains("Server error (503)"), "{}", err);
+  assert_eq!(server.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn does_not_retry_client_errors() {
+  let server = StubHttpServer::new(content(), vec![StubResponse::Status(404)]);
+  let err = download_from(&[server.url("file")]).await.unwrap_err();
+  assert!(err.contains("Client error (404)"), "{}", err);
+  assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn resumes_truncated_download() {
+  let server = StubHttpServer::new(content(), vec![StubResponse::Truncated(1000)]);
+  assert_eq!(download_from(&[server.url("file")]).await, Ok(content()));
+  assert_eq!(ranges(&server), vec![None, Some("bytes=1000-".to_owned())]);
+}
+
+#[tokio::test]
+async fn restarts_download_if_range_is_ignored() {
+  let server = StubHttpServer::new(
+    content(),
+    vec![StubResponse::Truncated(1000), StubResponse::IgnoreRange],
+  );
+  assert_eq!(download_from(&[server.url("file")]).await, Ok(content()));
+  assert_eq!(ranges(&server), vec![None, Some("bytes=1000-".to_owned())]);
+}
+
+#[tokio::test]
+async fn falls_back_to_mirrors() {
+  let primary = StubHttpServer::new(content(), vec![StubResponse::Status(404)]);
+  let unavailable = StubHttpServer::new(content(), vec![StubResponse::Status(503); 3]);
+  let mirror = StubHttpServer::new(content(), vec![]);
+  assert_eq!(
+    download_from(&[
+      primary.url("file"),
+      unavailable.url("file"),
+      mirror.url("file")
+    ])
+    .await,
+    Ok(content())
+  );
+  assert_eq!(primary.requests().len(), 1);
+  assert_eq!(unavailable.requests().len(), 3);
+  assert_eq!(mirror.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn rejects_wrong_digest() {
+  let server = StubHttpServer::new(Bytes::from("Wrong content"), vec![]);
+  let mirror = StubHttpServer::new(content(), vec![]);
+  let err = download_from(&[server.url("file")]).await.unwrap_err();
+  assert!(err.contains("Wrong digest for downloaded file"), "{}", err);
+
+  assert_eq!(
+    download_from(&[server.url("file"), mirror.url("file")]).await,
+    Ok(content())
+  );
+}
+
+#[tokio::test]
+async fn downloads_file_url() {
+  let dir = tempfile::TempDir::new().unwrap();
+  let path = dir.path().join("file");
+  std::fs::write(&path, content()).unwrap();
+  let url = Url::from_file_path(&path).unwrap();
+  assert_eq!(download_from(&[url.to_string()]).await, Ok(content()));
+}
+
+fn host(server: &StubHttpServer) -> String {
+  Url::parse(&server.url("")).unwrap()[url::Position::BeforeHost..url::Position::AfterPort]
+    .to_owned()
+}
+
+fn authorization(server: &StubHttpServer) -> Vec<Option<String>> {
+  server
+    .requests()
+    .iter()
+    .map(|headers| {
+      headers
+        .get(AUTHORIZATION)
+        .map(|auth| auth.to_str().unwrap().to_owned())
+    })
+    .collect()
+}
+
+#[tokio::test]
+async fn adds_headers_for_host() {
+  let server = StubHttpServer::new(content(), vec![]);
+  let other_server = StubHttpServer::new(content(), vec![]);
+  let mut headers = BTreeMap::new();
+  headers.insert("Authorization".to_owned(), "Bearer token".to_owned());
+  let mut headers_by_host = BTreeMap::new();
+  headers_by_host.insert(host(&server), headers);
+  let options = DownloadOptions::new(headers_by_host, None, vec![]).unwrap();
+
+  assert_eq!(
+    download_with_options(&options, &[server.url("file")]).await,
+    Ok(content())
+  );
+  assert_eq!(
+    download_with_options(&options, &[other_server.url("file")]).await,
+    Ok(content())
+  );
+  assert_eq!(
+    authorization(&server),
+    vec![Some("Bearer token".to_owned())]
+  );
+  assert_eq!(authorization(&other_server), vec![None]);
+}
+
+#[tokio::test]
+async fn does_not_send_headers_across_redirects() {
+  let other_server = StubHttpServer::new(content(), vec![]);
+  let server = StubHttpServer::new(
+    content(),
+    vec![StubResponse::Redirect(other_server.url("file"))],
+  );
+  let mut headers = BTreeMap::new();
+  headers.insert("X-Api-Key".to_owned(), "secret".to_owned());
+  let mut headers_by_host = BTreeMap::new();
+  headers_by_host.insert(host(&server), headers);
+  let options = DownloadOptions::new(headers_by_host, None, vec![]).unwrap();
+
+  assert_eq!(
+    download_with_options(&options, &[server.url("file")]).await,
+    Ok(content())
+  );
+  let api_keys = |server: &StubHttpServer| {
+    server
+      .requests()
+      .iter()
+      .map(|headers| headers.contains_key("X-Api-Key"))
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(api_keys(&server), vec![true]);
+  assert_eq!(api_keys(&other_server), vec![false]);
+}
+
+#[tokio::test]
+async fn adds_netrc_credentials() {
+  let server = StubHttpServer::new(content(), vec![]);
+  let dir = tempfile::TempDir::new().unwrap();
+  let netrc_path = dir.path().join(".netrc");
+  std::fs::write(
+    &netrc_path,
+    "machine example.com login other password other\n\
+     macdef init\n\
+     machine 127.0.0.1 login ignored\n\
+     \n\
+     machine 127.0.0.1\n  login user\n  password pass\n",
+  )
+  .unwrap();
+  let options = DownloadOptions::new(BTreeMap::new(), Some(netrc_path), vec![]).unwrap();
+
+  assert_eq!(
+    download_with_options(&options, &[server.url("file")]).await,
+    Ok(content())
+  );
+  // `user:pass`, base64 encoded.
+  assert_eq!(
+    authorization(&server),
+    vec![Some("Basic dXNlcjpwYXNz".to_owned())]
+  );
+}
+
+#[tokio::test]
+async fn rewrites_urls() {
+  let server = StubHttpServer::new(content(), vec![]);
+  let options = DownloadOptions::new(
+    BTreeMap::new(),
+    None,
+    vec![
+      (
+        "^https://github.com/(?P<path>.*)$".to_owned(),
+        server.url("github/${path}"),
+      ),
+      ("^https://github.com/".to_owned(), "unused".to_owned()),
+    ],
+  )
+  .unwrap();
+
+  let url = Url::parse("https://github.com/pantsbuild/pants/file").unwrap();
+  assert_eq!(
+    options.rewrite(&url),
+    Ok(Some(
+      Url::parse(&server.url("github/pantsbuild/pants/file")).unwrap()
+    ))
+  );
+  let unmatched = Url::parse("https://example.com/file").unwrap();
+  assert_eq!(options.rewrite(&unmatched), Ok(None));
+}
+
+#[test]
+fn rejects_invalid_rewrites() {
+  let err = DownloadOptions::new(
+    BTreeMap::new(),
+    None,
+    vec![("(unclosed".to_owned(), "".to_owned())],
+  )
+  .unwrap_err();
+  assert!(err.contains("Invalid URL rewrite pattern"), "{}", err);
+
+  let options = DownloadOptions::new(
+    BTreeMap::new(),
+    None,
+    vec![("^https://".to_owned(), "not a url ".to_owned())],
+  )
+  .unwrap();
+  let err = options
+    .rewrite(&Url::parse("https://example.com/file").unwrap())
+    .unwrap_err();
+  assert!(err.contains("which is not a valid URL"), "{}", err);
+}
+
+fn new_store(dir: &TempDir, cas: &StubCAS) -> Store {
+  Store::local_only(task_executor::Executor::new(), dir.path())
+    .unwrap()
+    .into_with_remote(
+      &cas.address(),
+      None,
+      tls::Config::default(),
+      BTreeMap::new(),
+      10 * 1024 * 1024,
+      Duration::from_secs(1),
+      1,
+      256,
+      RateLimitConfig::default(),
+      None,
+      4 * 1024 * 1024,
+    )
+    .unwrap()
+}
+
+async fn load_or_download_from(store: &Store, upload: bool, url: String) -> Result<(), String> {
+  load_or_download(
+    store,
+    upload,
+    &http_client_builder().build().unwrap(),
+    &DownloadOptions::default(),
+    &[Url::parse(&url).unwrap()],
+    Digest::of_bytes(&content()),
+    &retry_policy(),
+  )
+  .await
+}
+
+async fn load_local(dir: &TempDir) -> Option<Bytes> {
+  Store::local_only(task_executor::Executor::new(), dir.path())
+    .unwrap()
+    .load_file_bytes_with(Digest::of_bytes(&content()), Bytes::copy_from_slice)
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn loads_from_remote_store_before_downloading() {
+  let dir = TempDir::new().unwrap();
+  let cas = StubCAS::builder().file(&test_data()).build();
+  let server = StubHttpServer::new(content(), vec![]);
+  let store = new_store(&dir, &cas);
+
+  assert_eq!(
+    load_or_download_from(&store, true, server.url("file")).await,
+    Ok(())
+  );
+  assert_eq!(server.requests().len(), 0);
+  assert_eq!(load_local(&dir).await, Some(content()));
+}
+
+#[tokio::test]
+async fn uploads_downloaded_file_to_remote_store() {
+  let dir = TempDir::new().unwrap();
+  let cas = StubCAS::empty();
+  let server = StubHttpServer::new(content(), vec![]);
+  let store = new_store(&dir, &cas);
+
+  assert_eq!(
+    load_or_download_from(&store, true, server.url("file")).await,
+    Ok(())
+  );
+  assert_eq!(server.requests().len(), 1);
+  assert_eq!(load_local(&dir).await, Some(content()));
+  assert_eq!(
+    cas.blobs.lock().get(&test_data().fingerprint()),
+    Some(&content())
+  );
+}
+
+#[tokio::test]
+async fn does_not_upload_unless_requested() {
+  let dir = TempDir::new().unwrap();
+  let cas = StubCAS::empty();
+  let server = StubHttpServer::new(content(), vec![]);
+  let store = new_store(&dir, &cas);
+
+  assert_eq!(
+    load_or_download_from(&store, false, server.url("file")).await,
+    Ok(())
+  );
+  assert_eq!(load_local(&dir).await, Some(content()));
+  assert_eq!(cas.blobs.lock().get(&test_data().fingerprint()), None);
+}
+
+#[tokio::test]
+async fn downloads_if_remote_store_fails() {
+  let dir = TempDir::new().unwrap();
+  let cas = StubCAS::always_errors();
+  let server = StubHttpServer::new(content(), vec![]);
+  let store = new_store(&dir, &cas);
+
+  assert_eq!(
+    load_or_download_from(&store, true, server.url("file")).await,
+    Ok(())
+  );
+  assert_eq!(server.requests().len(), 1);
+  assert_eq!(load_local(&dir).await, Some(content()));
+}
@@ -135,8 +135,9 @@ py_class!(pub class PySnapshot |py| {
     }
 
     @property def files(&self) -> PyResult<PyTuple> {
+      // NB: Symlinks are reported as files, since (like files) they are leaves of the tree.
       let files = self.snapshot(py).path_stats.iter().filter_map(|ps| match ps {
-        PathStat::File { path, .. } => path.to_str(),
+        PathStat::File { path, .. } | PathStat::Link { path, .. } => path.to_str(),
         _ => None,
       }).map(|ps| PyString::new(py, ps).into_object()).collect::<Vec<_>>();
       Ok(PyTuple::new(py, &files))
@@ -171,7 +172,9 @@ py_class!(pub class PySnapshot |py| {
     def __repr__(&self) -> PyResult<String> {
       let (dirs, files): (Vec<_>, Vec<_>) = self.snapshot(py).path_stats.iter().partition_map(|ps| match ps {
         PathStat::Dir { path, .. } => Either::Left(path.to_string_lossy()),
-        PathStat::File { path, .. } => Either::Right(path.to_string_lossy()),
+        PathStat::File { path, .. } | PathStat::Link { path, .. } => {
+          Either::Right(path.to_string_lossy())
+        }
       });
 
       Ok(format!(
@@ -71,15 +71,17 @@ use petgraph::graph::{DiGraph, Graph};
 use process_execution::RemoteCacheWarningsBehavior;
 use regex::Regex;
 use rule_graph::{self, RuleGraph};
+use store::{MaterializeStrategy, NodePropertyOptions};
 use task_executor::Executor;
+use watch::{CoalescingOptions, WatchmanOptions};
 use workunit_store::{
   ArtifactOutput, ObservationMetric, UserMetadataItem, Workunit, WorkunitState,
 };
 
 use crate::{
-  externs, nodes, Core, ExecutionRequest, ExecutionStrategyOptions, ExecutionTermination, Failure,
-  Function, Intrinsics, Key, LocalStoreOptions, Params, RemotingOptions, Rule, Scheduler, Session,
-  Tasks, Types, Value,
+  externs, nodes, Core, DownloadOptions, ExecutionRequest, ExecutionStrategyOptions,
+  ExecutionTermination, Failure, Function, Intrinsics, Key, LocalStoreOptions, Params,
+  RemotingOptions, Rule, Scheduler, Session, Tasks, Types, Value,
 };
 
 py_exception!(native_engine, PollTimeout);
@@ -365,12 +367,18 @@ py_module_initializer!(native_engine, |py, m| {
         local_execution_root_dir_buf: String,
         named_caches_dir_buf: String,
         ca_certs_path: Option<String>,
+        download_options: PyDownloadOptions,
         ignore_patterns: Vec<String>,
         use_gitignore: bool,
         watch_filesystem: bool,
+        watch_quiet_window_millis: u64,
         remoting_options: PyRemotingOptions,
         local_store_options: PyLocalStoreOptions,
-        exec_strategy_opts: PyExecutionStrategyOptions
+        exec_strategy_opts: PyExecutionStrategyOptions,
+        graph_persistence_path: Option<String>,
+        use_watchman: bool,
+        watchman_socket_path: Option<String>,
+        watchman_clock_path: Option<String>
       )
     ),
   )?;
@@ -401,6 +409,7 @@ py_module_initializer!(native_engine, |py, m| {
     ),
   )?;
 
+  m.add_class::<PyDownloadOptions>(py)?;
   m.add_class::<PyExecutionRequest>(py)?;
   m.add_class::<PyExecutionStrategyOptions>(py)?;
   m.add_class::<PyExecutor>(py)?;
@@ -443,6 +452,7 @@ py_class!(class PyTypes |py| {
       paths: PyType,
       file_content: PyType,
       file_entry: PyType,
+      symlink_entry: PyType,
       directory: PyType,
       digest_contents: PyType,
       digest_entries: PyType,
@@ -453,6 +463,12 @@ py_class!(class PyTypes |py| {
       create_digest: PyType,
       digest_subset: PyType,
       download_file: PyType,
+      extract_archive: PyType,
+      archive_digest: PyType,
+      diff_digests: PyType,
+      digest_diff: PyType,
+      rename_paths: PyType,
+      substitute_contents: PyType,
       platform: PyType,
       multi_platform_process: PyType,
       process_result: PyType,
@@ -470,6 +486,7 @@ py_class!(class PyTypes |py| {
         paths: externs::type_for(paths),
         file_content: externs::type_for(file_content),
         file_entry: externs::type_for(file_entry),
+        symlink_entry: externs::type_for(symlink_entry),
         directory: externs::type_for(directory),
         digest_contents: externs::type_for(digest_contents),
         digest_entries: externs::type_for(digest_entries),
@@ -480,6 +497,12 @@ py_class!(class PyTypes |py| {
         create_digest: externs::type_for(create_digest),
         digest_subset: externs::type_for(digest_subset),
         download_file: externs::type_for(download_file),
+        extract_archive: externs::type_for(extract_archive),
+        archive_digest: externs::type_for(archive_digest),
+        diff_digests: externs::type_for(diff_digests),
+        digest_diff: externs::type_for(digest_diff),
+        rename_paths: externs::type_for(rename_paths),
+        substitute_contents: externs::type_for(substitute_contents),
         platform: externs::type_for(platform),
         multi_platform_process: externs::type_for(multi_platform_process),
         process_result: externs::type_for(process_result),
@@ -524,7 +547,8 @@ py_class!(class PyExecutionStrategyOptions |py| {
     local_cache: bool,
     local_enable_nailgun: bool,
     remote_cache_read: bool,
-    remote_cache_write: bool
+    remote_cache_write: bool,
+    cache_verification_sample_rate: f64
   ) -> CPyResult<Self> {
     Self::create_instance(py,
       ExecutionStrategyOptions {
@@ -535,6 +559,7 @@ py_class!(class PyExecutionStrategyOptions |py| {
         local_enable_nailgun,
         remote_cache_read,
         remote_cache_write,
+        cache_verification_sample_rate,
       }
     )
   }
@@ -561,10 +586,18 @@ py_class!(class PyRemotingOptions |py| {
     cache_warnings_behavior: String,
     cache_eager_fetch: bool,
     cache_rpc_concurrency: usize,
+    cache_circuit_breaker_failures: usize,
+    cache_circuit_breaker_window_secs: u64,
+    cache_circuit_breaker_probe_secs: u64,
     execution_extra_platform_properties: Vec<(String, String)>,
+    execution_output_node_properties: Vec<String>,
+    execution_output_paths: bool,
     execution_headers: Vec<(String, String)>,
     execution_overall_deadline_secs: u64,
     execution_rpc_concurrency: usize,
+    execution_local_fallback: bool,
+    rpc_requests_per_second: Option<usize>,
+    rpc_bytes_per_second: Option<usize>,
   ) -> CPyResult<Self> {
     Self::create_instance(py,
       RemotingOptions {
@@ -583,15 +616,50 @@ py_class!(class PyRemotingOptions |py| {
         cache_warnings_behavior: RemoteCacheWarningsBehavior::from_str(&cache_warnings_behavior).unwrap(),
         cache_eager_fetch,
         cache_rpc_concurrency,
+        cache_circuit_breaker_failures,
+        cache_circuit_breaker_window: Duration::from_secs(cache_circuit_breaker_window_secs),
+        cache_circuit_breaker_probe_interval: if cache_circuit_breaker_probe_secs == 0 {
+          None
+        } else {
+          Some(Duration::from_secs(cache_circuit_breaker_probe_secs))
+        },
         execution_extra_platform_properties,
+        execution_output_node_properties,
+        execution_output_paths,
         execution_headers: execution_headers.into_iter().collect(),
         execution_overall_deadline: Duration::from_secs(execution_overall_deadline_secs),
         execution_rpc_concurrency,
+        execution_local_fallback,
+        rpc_requests_per_second,
+        rpc_bytes_per_second,
       }
     )
   }
 });
 
+// Represents configuration related to downloading files: authentication, and URL rewrites.
+py_class!(class PyDownloadOptions |py| {
+  data options: DownloadOptions;
+
+  def __new__(
+    _cls,
+    headers_by_host: Vec<(String, Vec<(String, String)>)>,
+    netrc_path: Option<String>,
+    url_rewrites: Vec<(String, String)>
+  ) -> CPyResult<Self> {
+    let options = DownloadOptions::new(
+      headers_by_host
+        .into_iter()
+        .map(|(host, headers)| (host, headers.into_iter().collect()))
+        .collect(),
+      netrc_path.map(PathBuf::from),
+      url_rewrites,
+    )
+    .map_err(|e| PyErr::new::<exc::ValueError, _>(py, (e,)))?;
+    Self::create_instance(py, options)
+  }
+});
+
 py_class!(class PyLocalStoreOptions |py| {
   data options: LocalStoreOptions;
 
@@ -603,11 +671,15 @@ py_class!(class PyLocalStoreOptions |py| {
     directories_max_size_bytes: usize,
     lease_time_millis: u64,
     shard_count: u8,
+    materialize_strategy: String,
+    capture_node_properties: Vec<String>,
   ) -> CPyResult<Self> {
     if shard_count.count_ones() != 1 {
         let err_string = format!("The local store shard count must be a power of two: got {}", shard_count);
         return Err(PyErr::new::<exc::ValueError, _>(py, (err_string,)));
     }
+    let capture_node_properties = NodePropertyOptions::from_names(&capture_node_properties)
+      .map_err(|e| PyErr::new::<exc::ValueError, _>(py, (e,)))?;
     Self::create_instance(py,
       LocalStoreOptions {
         store_dir: PathBuf::from(store_dir),
@@ -616,6 +688,8 @@ py_class!(class PyLocalStoreOptions |py| {
         directories_max_size_bytes,
         lease_time: Duration::from_millis(lease_time_millis),
         shard_count,
+        materialize_strategy: MaterializeStrategy::from_str(&materialize_strategy).unwrap(),
+        capture_node_properties,
       }
     )
   }
@@ -896,12 +970,18 @@ fn scheduler_create(
   local_execution_root_dir_buf: String,
   named_caches_dir_buf: String,
   ca_certs_path_buf: Option<String>,
+  download_options: PyDownloadOptions,
   ignore_patterns: Vec<String>,
   use_gitignore: bool,
   watch_filesystem: bool,
+  watch_quiet_window_millis: u64,
   remoting_options: PyRemotingOptions,
   local_store_options: PyLocalStoreOptions,
   exec_strategy_opts: PyExecutionStrategyOptions,
+  graph_persistence_path: Option<String>,
+  use_watchman: bool,
+  watchman_socket_path: Option<String>,
+  watchman_clock_path: Option<String>,
 ) -> CPyResult<PyScheduler> {
   match fs::increase_limits() {
     Ok(msg) => debug!("{}", msg),
@@ -933,9 +1013,23 @@ fn scheduler_create(
         PathBuf::from(local_execution_root_dir_buf),
         PathBuf::from(named_caches_dir_buf),
         ca_certs_path_buf.map(PathBuf::from),
+        download_options.options(py).clone(),
         local_store_options.options(py).clone(),
         remoting_options.options(py).clone(),
         exec_strategy_opts.options(py).clone(),
+        graph_persistence_path.map(PathBuf::from),
+        if use_watchman {
+          Some(WatchmanOptions {
+            socket_path: watchman_socket_path.map(PathBuf::from),
+            clock_path: watchman_clock_path.map(PathBuf::from),
+          })
+        } else {
+          None
+        },
+        CoalescingOptions {
+          quiet_window: Duration::from_millis(watch_quiet_window_millis),
+          ..CoalescingOptions::default()
+        },
       )
     })
   });
@@ -1570,7 +1664,8 @@ fn garbage_collect_store(
       scheduler
         .core
         .store()
-        .garbage_collect(target_size_bytes, store::ShrinkBehavior::Fast)
+        .garbage_collect(target_size_bytes, store::ShrinkBehavior::Fast)?;
+      scheduler.core.file_digest_cache.garbage_collect()
     })
     .map_err(|e| PyErr::new::<exc::Exception, _>(py, (e,)))
     .map(|()| None)
@@ -0,0 +1,444 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashSet;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fs::{Dir, DirectoryListing, File, Link, Stat};
+use futures::future;
+use hashing::{Digest, Fingerprint};
+use log::debug;
+use serde_json::{json, Value as JsonValue};
+use store::{is_racy, Store, RACY_WINDOW};
+use task_executor::Executor;
+use watch::InvalidationWatcher;
+
+use crate::context::InvalidatableGraph;
+use crate::nodes::{DigestFile, LinkDest, NodeKey, NodeOutput, ReadLink, Scandir};
+
+pub(crate) const VERSION: u64 = 2;
+
+///
+/// The inode, size, mtime (seconds and nanoseconds), and ctime (seconds and nanoseconds) of a
+/// path, used to detect whether it changed while it was not being watched.
+///
+pub(crate) type StatKey = [i64; 6];
+
+///
+/// A persisted Node and its value, along with the stat of its subject path, and the stats of the
+/// ignore files which affect it (see `ignore_file_stats`).
+///
+pub(crate) type Entry = (NodeKey, NodeOutput, StatKey, Vec<Option<StatKey>>);
+
+///
+/// Persists the values of filesystem Nodes in the Graph across restarts of the process.
+///
+/// The values of `DigestFile`, `ReadLink` and `Scandir` Nodes are written to a file along with
+/// the `stat` of their subject paths. When restoring, each path is first watched by the
+/// InvalidationWatcher, then its Node is inserted into the Graph, and finally the path is
+/// re-`stat`ed to invalidate the Node if the path changed. This ordering ensures that any change
+/// is observed by either the watcher or the validation.
+///
+/// Directory listings additionally depend on the ignore files which apply to them: the global
+/// ignore configuration is fingerprinted for the whole file, while the nested `.gitignore` files
+/// of each listing are watched and validated along with it.
+///
+/// Other Nodes are not persisted: they are either derived from these Nodes (and cheap to recompute
+/// once these are restored), or depend on Python values. In particular, `ExecuteProcess` Nodes are
+/// not persisted, because their results are already persisted across restarts (keyed by the
+/// Digest of the Process) by the local process cache: once their inputs are restored, they are
+/// recomputed via cache hits, without running any processes.
+///
+pub struct GraphPersistence {
+  path: PathBuf,
+  build_root: PathBuf,
+  ignore_patterns: Vec<String>,
+  use_gitignore: bool,
+}
+
+impl GraphPersistence {
+  pub fn new(
+    path: PathBuf,
+    build_root: PathBuf,
+    ignore_patterns: &[String],
+    use_gitignore: bool,
+  ) -> GraphPersistence {
+    GraphPersistence {
+      path,
+      build_root,
+      ignore_patterns: ignore_patterns.to_vec(),
+      use_gitignore,
+    }
+  }
+
+  ///
+  /// A fingerprint of the global ignore configuration, which filesystem Nodes depend on: persisted
+  /// values are only restored if it has not changed.
+  ///
+  fn config(&self) -> String {
+    ignore_config(
+      &self.ignore_patterns,
+      self.use_gitignore.then(|| self.build_root.as_path()),
+    )
+  }
+
+  ///
+  /// Writes the values of clean filesystem Nodes in the Graph to disk, returning the number of
+  /// Nodes that were written.
+  ///
+  pub fn persist(&self, graph: &InvalidatableGraph) -> Result<usize, String> {
+    let mut nodes = Vec::new();
+    graph.visit_clean(|node, item| {
+      if is_persistable(node) {
+        nodes.push((node.clone(), item));
+      }
+    });
+
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+    let entries = nodes
+      .into_iter()
+      .filter_map(|(node, item)| {
+        let stat = stat_key(&self.build_root, node.fs_subject()?).ok()?;
+        let ignore_stats = ignore_file_stats(&self.build_root, &node, self.use_gitignore);
+        if is_racy_stat(&stat, now) || ignore_stats.iter().flatten().any(|s| is_racy_stat(s, now)) {
+          return None;
+        }
+        encode_entry(&node, &item, stat, &ignore_stats)
+      })
+      .collect::<Vec<_>>();
+    let count = entries.len();
+
+    let contents = json!({
+      "version": VERSION,
+      "config": self.config(),
+      "entries": entries,
+    });
+    let tmp_path = self.path.with_extension("tmp");
+    self
+      .path
+      .parent()
+      .map_or(Ok(()), std::fs::create_dir_all)
+      .and_then(|()| std::fs::write(&tmp_path, contents.to_string()))
+      .and_then(|()| std::fs::rename(&tmp_path, &self.path))
+      .map_err(|e| format!("Failed to persist graph to {}: {}", self.path.display(), e))?;
+    Ok(count)
+  }
+
+  ///
+  /// Restores persisted Nodes into the Graph, returning the number of Nodes that were restored
+  /// and are still valid.
+  ///
+  pub async fn restore(
+    &self,
+    graph: &InvalidatableGraph,
+    watcher: &Arc<InvalidationWatcher>,
+    store: &Store,
+    executor: &Executor,
+  ) -> Result<usize, String> {
+    let path = self.path.clone();
+    let ignore_patterns = self.ignore_patterns.clone();
+    let gitignore_root = self.use_gitignore.then(|| self.build_root.clone());
+    let entries = executor
+      .spawn_blocking(move || {
+        let config = ignore_config(&ignore_patterns, gitignore_root.as_deref());
+        read_entries(&path, &config)
+      })
+      .await?;
+
+    // Skip files whose content is no longer present in the local Store.
+    let entries = future::join_all(entries.into_iter().map(|entry| async move {
+      match entry.1 {
+        NodeOutput::Digest(digest) => match store.local_has_file(digest).await {
+          Ok(true) => Some(entry),
+          _ => None,
+        },
+        _ => Some(entry),
+      }
+    }))
+    .await;
+
+    // Watch each path (and for listings, the directories of their ignore files) before inserting
+    // its Node.
+    let entries = future::join_all(entries.into_iter().flatten().map(|entry| async move {
+      let path = entry.0.fs_subject()?;
+      let watched = if entry.3.is_empty() {
+        vec![path]
+      } else {
+        path.ancestors().collect()
+      };
+      let watches = watched
+        .into_iter()
+        .map(|path| watcher.watch(self.build_root.join(path)));
+      match future::try_join_all(watches).await {
+        Ok(_) => Some(entry),
+        Err(e) => {
+          debug!("Not restoring {}: {}", entry.0, e);
+          None
+        }
+      }
+    }))
+    .await;
+
+    let restored = entries
+      .into_iter()
+      .flatten()
+      .filter(|(node, output, _, _)| graph.insert_clean(node.clone(), output.clone()))
+      .collect::<Vec<_>>();
+    let restored_count = restored.len();
+
+    // Then validate each path, and invalidate any Nodes whose paths have changed.
+    let build_root = self.build_root.clone();
+    let use_gitignore = self.use_gitignore;
+    let changed = executor
+      .spawn_blocking(move || {
+        restored
+          .into_iter()
+          .filter(|(node, output, stat, ignore_stats)| {
+            !is_unchanged(&build_root, node, output, stat, ignore_stats, use_gitignore)
+          })
+          .map(|(node, _, _, _)| node)
+          .collect::<HashSet<_>>()
+      })
+      .await;
+    if !changed.is_empty() {
+      graph.invalidate_from_roots(|node| changed.contains(node));
+    }
+    Ok(restored_count - changed.len())
+  }
+}
+
+///
+/// A fingerprint of the given ignore patterns, and if gitignore files are used in the given build
+/// root, of its `.git/info/exclude` file (which applies to every path).
+///
+pub(crate) fn ignore_config(ignore_patterns: &[String], gitignore_root: Option<&Path>) -> String {
+  let mut config = ignore_patterns.join("\n").into_bytes();
+  if let Some(build_root) = gitignore_root {
+    config.extend(b"\0gitignore\0");
+    config.extend(std::fs::read(build_root.join(".git/info/exclude")).unwrap_or_default());
+  }
+  Digest::of_bytes(&config).hash.to_hex()
+}
+
+///
+/// The stats of the `.gitignore` files which affect the given Node (or None for those which do
+/// not exist), if gitignore files are used. A directory listing omits the paths ignored by the
+/// `.gitignore` files in the directory and all of its parents.
+///
+pub(crate) fn ignore_file_stats(
+  build_root: &Path,
+  node: &NodeKey,
+  use_gitignore: bool,
+) -> Vec<Option<StatKey>> {
+  match node {
+    NodeKey::Scandir(Scandir(Dir(path))) if use_gitignore => path
+      .ancestors()
+      .map(|directory| stat_key(build_root, &directory.join(".gitignore")).ok())
+      .collect(),
+    _ => vec![],
+  }
+}
+
+fn is_persistable(node: &NodeKey) -> bool {
+  matches!(
+    node,
+    NodeKey::DigestFile(_) | NodeKey::ReadLink(_) | NodeKey::Scandir(_)
+  )
+}
+
+///
+/// True if the subject path of the given restored Node has not changed since it was persisted.
+///
+pub(crate) fn is_unchanged(
+  build_root: &Path,
+  node: &NodeKey,
+  output: &NodeOutput,
+  stat: &StatKey,
+  ignore_stats: &[Option<StatKey>],
+  use_gitignore: bool,
+) -> bool {
+  let path = match node.fs_subject() {
+    Some(path) => path,
+    None => return false,
+  };
+  if stat_key(build_root, path).ok().as_ref() != Some(stat)
+    || ignore_file_stats(build_root, node, use_gitignore) != ignore_stats
+  {
+    return false;
+  }
+  // Changing the mode of a file does not modify its parent directory, so the executable bits
+  // recorded in a listing are validated against each file.
+  match output {
+    NodeOutput::DirectoryListing(listing) => listing.0.iter().all(|child| match child {
+      Stat::File(file) => std::fs::symlink_metadata(build_root.join(&file.path))
+        .map(|metadata| (metadata.permissions().mode() & 0o100 == 0o100) == file.is_executable)
+        .unwrap_or(false),
+      _ => true,
+    }),
+    _ => true,
+  }
+}
+
+pub(crate) fn stat_key(build_root: &Path, path: &Path) -> Result<StatKey, io::Error> {
+  let metadata = std::fs::symlink_metadata(build_root.join(path))?;
+  Ok([
+    metadata.ino() as i64,
+    metadata.size() as i64,
+    metadata.mtime(),
+    metadata.mtime_nsec(),
+    metadata.ctime(),
+    metadata.ctime_nsec(),
+  ])
+}
+
+///
+/// Paths modified within RACY_WINDOW of persisting are skipped, because a further modification
+/// within the granularity of the filesystem's timestamps would not be detectable via `stat`.
+///
+fn is_racy_stat(stat: &StatKey, now: Duration) -> bool {
+  is_racy(stat[2], stat[3], now, RACY_WINDOW) || is_racy(stat[4], stat[5], now, RACY_WINDOW)
+}
+
+pub(crate) fn read_entries(path: &Path, config: &str) -> Result<Vec<Entry>, String> {
+  let contents = match std::fs::read(path) {
+    Ok(contents) => contents,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+  };
+  let contents: JsonValue = serde_json::from_slice(&contents)
+    .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+
+  if contents.get("version").and_then(JsonValue::as_u64) != Some(VERSION)
+    || contents.get("config").and_then(JsonValue::as_str) != Some(config)
+  {
+    debug!(
+      "Not restoring graph from {}, which was persisted with a different configuration.",
+      path.display()
+    );
+    return Ok(vec![]);
+  }
+  Ok(
+    contents
+      .get("entries")
+      .and_then(JsonValue::as_array)
+      .map(|entries| entries.iter().filter_map(decode_entry).collect())
+      .unwrap_or_default(),
+  )
+}
+
+pub(crate) fn encode_entry(
+  node: &NodeKey,
+  item: &NodeOutput,
+  stat: StatKey,
+  ignore_stats: &[Option<StatKey>],
+) -> Option<JsonValue> {
+  let encoded = match (node, item) {
+    (NodeKey::DigestFile(DigestFile(file)), NodeOutput::Digest(digest)) => {
+      let path = file.path.to_str()?;
+      json!({
+        "kind": "file",
+        "path": path,
+        "is_executable": file.is_executable,
+        "fingerprint": digest.hash.to_hex(),
+        "size": digest.size_bytes,
+      })
+    }
+    (NodeKey::ReadLink(ReadLink(Link(path))), NodeOutput::LinkDest(LinkDest(dest))) => {
+      let path = path.to_str()?;
+      let dest = dest.to_str()?;
+      json!({
+        "kind": "link",
+        "path": path,
+        "dest": dest,
+      })
+    }
+    (NodeKey::Scandir(Scandir(Dir(path))), NodeOutput::DirectoryListing(listing)) => {
+      let path = path.to_str()?;
+      let listing = listing
+        .0
+        .iter()
+        .map(encode_stat)
+        .collect::<Option<Vec<_>>>()?;
+      json!({
+        "kind": "dir",
+        "path": path,
+        "listing": listing,
+      })
+    }
+    _ => return None,
+  };
+  Some(json!({
+    "stat": stat,
+    "ignore_stats": ignore_stats,
+    "node": encoded,
+  }))
+}
+
+pub(crate) fn decode_entry(entry: &JsonValue) -> Option<Entry> {
+  let stat: StatKey = serde_json::from_value(entry.get("stat")?.clone()).ok()?;
+  let ignore_stats: Vec<Option<StatKey>> =
+    serde_json::from_value(entry.get("ignore_stats")?.clone()).ok()?;
+  let encoded = entry.get("node")?;
+  let path = PathBuf::from(encoded.get("path")?.as_str()?);
+  let (node, output) = match encoded.get("kind")?.as_str()? {
+    "file" => {
+      let fingerprint = Fingerprint::from_hex_string(encoded.get("fingerprint")?.as_str()?).ok()?;
+      let size = encoded.get("size")?.as_u64()? as usize;
+      let is_executable = encoded.get("is_executable")?.as_bool()?;
+      (
+        NodeKey::DigestFile(DigestFile(File {
+          path,
+          is_executable,
+        })),
+        NodeOutput::Digest(Digest::new(fingerprint, size)),
+      )
+    }
+    "link" => {
+      let dest = PathBuf::from(encoded.get("dest")?.as_str()?);
+      (
+        NodeKey::ReadLink(ReadLink(Link(path))),
+        NodeOutput::LinkDest(LinkDest(dest)),
+      )
+    }
+    "dir" => {
+      let listing = encoded
+        .get("listing")?
+        .as_array()?
+        .iter()
+        .map(decode_stat)
+        .collect::<Option<Vec<_>>>()?;
+      (
+        NodeKey::Scandir(Scandir(Dir(path))),
+        NodeOutput::DirectoryListing(Arc::new(DirectoryListing(listing))),
+      )
+    }
+    _ => return None,
+  };
+  Some((node, output, stat, ignore_stats))
+}
+
+fn encode_stat(stat: &Stat) -> Option<JsonValue> {
+  let path = stat.path().to_str()?;
+  Some(match stat {
+    Stat::File(file) => json!(["file", path, file.is_executable]),
+    Stat::Dir(_) => json!(["dir", path]),
+    Stat::Link(_) => json!(["link", path]),
+  })
+}
+
+fn decode_stat(stat: &JsonValue) -> Option<Stat> {
+  let stat = stat.as_array()?;
+  let path = PathBuf::from(stat.get(1)?.as_str()?);
+  match stat.get(0)?.as_str()? {
+    "file" => Some(Stat::file(path, stat.get(2)?.as_bool()?)),
+    "dir" => Some(Stat::dir(path)),
+    "link" => Some(Stat::Link(Link(path))),
+    _ => None,
+  }
+}
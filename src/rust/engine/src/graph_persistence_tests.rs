@@ -0,0 +1,207 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use fs::{Dir, DirectoryListing, File, Link, Stat};
+use serde_json::json;
+use tempfile::TempDir;
+use testutil::data::TestData;
+
+use crate::graph_persistence::{
+  decode_entry, encode_entry, ignore_config, ignore_file_stats, is_unchanged, read_entries,
+  stat_key, VERSION,
+};
+use crate::nodes::{DigestFile, LinkDest, NodeKey, NodeOutput, ReadLink, Scandir};
+
+fn file_entry() -> (NodeKey, NodeOutput) {
+  (
+    NodeKey::DigestFile(DigestFile(File {
+      path: PathBuf::from("a/b.txt"),
+      is_executable: true,
+    })),
+    NodeOutput::Digest(TestData::roland().digest()),
+  )
+}
+
+fn link_entry() -> (NodeKey, NodeOutput) {
+  (
+    NodeKey::ReadLink(ReadLink(Link(PathBuf::from("a/link")))),
+    NodeOutput::LinkDest(LinkDest(PathBuf::from("b.txt"))),
+  )
+}
+
+fn dir_entry(listing: Vec<Stat>) -> (NodeKey, NodeOutput) {
+  (
+    NodeKey::Scandir(Scandir(Dir(PathBuf::from("a")))),
+    NodeOutput::DirectoryListing(Arc::new(DirectoryListing(listing))),
+  )
+}
+
+fn write_entries(path: &Path, config: &str, entries: &[(NodeKey, NodeOutput)]) {
+  let entries = entries
+    .iter()
+    .map(|(node, output)| encode_entry(node, output, [1, 2, 3, 4, 5, 6], &[]).unwrap())
+    .collect::<Vec<_>>();
+  let contents = json!({
+    "version": VERSION,
+    "config": config,
+    "entries": entries,
+  });
+  std::fs::write(path, contents.to_string()).unwrap();
+}
+
+#[test]
+fn encode_and_decode() {
+  let entries = vec![
+    file_entry(),
+    link_entry(),
+    dir_entry(vec![
+      Stat::file(PathBuf::from("a/b.txt"), true),
+      Stat::dir(PathBuf::from("a/c")),
+      Stat::Link(Link(PathBuf::from("a/link"))),
+    ]),
+  ];
+  for (node, output) in entries {
+    let stat = [1, 2, 3, 4, 5, 6];
+    let ignore_stats = vec![Some([7, 8, 9, 10, 11, 12]), None];
+    let encoded = encode_entry(&node, &output, stat, &ignore_stats).unwrap();
+    assert_eq!(
+      decode_entry(&encoded),
+      Some((node, output, stat, ignore_stats))
+    );
+  }
+}
+
+#[test]
+fn read_entries_with_matching_config() {
+  let dir = TempDir::new().unwrap();
+  let path = dir.path().join("graph.json");
+  write_entries(&path, "config", &[file_entry(), link_entry()]);
+
+  let entries = read_entries(&path, "config").unwrap();
+  assert_eq!(
+    entries
+      .into_iter()
+      .map(|(node, output, _, _)| (node, output))
+      .collect::<Vec<_>>(),
+    vec![file_entry(), link_entry()]
+  );
+}
+
+#[test]
+fn read_entries_rejects_different_config() {
+  let dir = TempDir::new().unwrap();
+  let path = dir.path().join("graph.json");
+  write_entries(&path, "config", &[file_entry()]);
+
+  assert_eq!(read_entries(&path, "other config").unwrap(), vec![]);
+  // A missing file has no entries.
+  assert_eq!(
+    read_entries(&dir.path().join("missing.json"), "config").unwrap(),
+    vec![]
+  );
+}
+
+#[test]
+fn config_includes_info_exclude_content() {
+  let dir = TempDir::new().unwrap();
+  let build_root = dir.path();
+  let patterns = vec!["/dist/".to_owned()];
+
+  let first = ignore_config(&patterns, Some(build_root));
+  assert_eq!(first, ignore_config(&patterns, Some(build_root)));
+  assert_ne!(first, ignore_config(&patterns, None));
+
+  std::fs::create_dir_all(build_root.join(".git/info")).unwrap();
+  std::fs::write(build_root.join(".git/info/exclude"), "*.log\n").unwrap();
+  assert_ne!(first, ignore_config(&patterns, Some(build_root)));
+}
+
+#[test]
+fn listing_with_changed_nested_gitignore_is_invalid() {
+  let dir = TempDir::new().unwrap();
+  let build_root = dir.path();
+  std::fs::create_dir_all(build_root.join("a/b")).unwrap();
+  std::fs::write(build_root.join("a/.gitignore"), "*.pyc\n").unwrap();
+
+  let node = NodeKey::Scandir(Scandir(Dir(PathBuf::from("a/b"))));
+  let output = NodeOutput::DirectoryListing(Arc::new(DirectoryListing(vec![])));
+  let stat = stat_key(build_root, Path::new("a/b")).unwrap();
+  let ignore_stats = ignore_file_stats(build_root, &node, true);
+  assert_eq!(ignore_stats.len(), 3);
+  assert!(is_unchanged(
+    build_root,
+    &node,
+    &output,
+    &stat,
+    &ignore_stats,
+    true
+  ));
+
+  // Editing the `.gitignore` of a parent directory invalidates the listing, as does creating one.
+  std::fs::write(build_root.join("a/.gitignore"), "*.pyc\n*.log\n").unwrap();
+  assert!(!is_unchanged(
+    build_root,
+    &node,
+    &output,
+    &stat,
+    &ignore_stats,
+    true
+  ));
+  let ignore_stats = ignore_file_stats(build_root, &node, true);
+  std::fs::write(build_root.join(".gitignore"), "*.log\n").unwrap();
+  assert!(!is_unchanged(
+    build_root,
+    &node,
+    &output,
+    &stat,
+    &ignore_stats,
+    true
+  ));
+
+  // Without gitignore files, listings do not depend on them.
+  assert_eq!(ignore_file_stats(build_root, &node, false), vec![]);
+}
+
+#[test]
+fn unchanged_paths_are_valid() {
+  let dir = TempDir::new().unwrap();
+  let build_root = dir.path();
+  std::fs::create_dir(build_root.join("a")).unwrap();
+  std::fs::write(build_root.join("a/b.txt"), TestData::roland().bytes()).unwrap();
+
+  let (node, output) = file_entry();
+  let node = match node {
+    NodeKey::DigestFile(DigestFile(file)) => NodeKey::DigestFile(DigestFile(File {
+      is_executable: false,
+      ..file
+    })),
+    _ => unreachable!(),
+  };
+  let stat = stat_key(build_root, Path::new("a/b.txt")).unwrap();
+  assert!(is_unchanged(build_root, &node, &output, &stat, &[], false));
+
+  std::fs::write(build_root.join("a/b.txt"), TestData::catnip().bytes()).unwrap();
+  assert!(!is_unchanged(build_root, &node, &output, &stat, &[], false));
+}
+
+#[test]
+fn listing_with_changed_executable_bit_is_invalid() {
+  let dir = TempDir::new().unwrap();
+  let build_root = dir.path();
+  std::fs::create_dir(build_root.join("a")).unwrap();
+  std::fs::write(build_root.join("a/b.txt"), TestData::roland().bytes()).unwrap();
+
+  let (node, output) = dir_entry(vec![Stat::file(PathBuf::from("a/b.txt"), false)]);
+  let stat = stat_key(build_root, Path::new("a")).unwrap();
+  assert!(is_unchanged(build_root, &node, &output, &stat, &[], false));
+
+  // Changing the mode of the file does not change the stat of its directory.
+  std::fs::set_permissions(
+    build_root.join("a/b.txt"),
+    std::fs::Permissions::from_mode(0o755),
+  )
+  .unwrap();
+  assert_eq!(stat_key(build_root, Path::new("a")).unwrap(), stat);
+  assert!(!is_unchanged(build_root, &node, &output, &stat, &[], false));
+}
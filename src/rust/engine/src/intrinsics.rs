@@ -9,10 +9,14 @@ use crate::tasks::Intrinsic;
 use crate::types::Types;
 use crate::Failure;
 
+use bytes::Bytes;
 use fs::RelativePath;
 use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
 use indexmap::IndexMap;
-use store::{SnapshotOps, SubsetParams};
+use regex::Regex;
+use store::{
+  ArchiveFormat, ArchiveOptions, RenameParams, SnapshotOps, SubsetParams, SubstituteParams,
+};
 
 use std::path::PathBuf;
 
@@ -54,6 +58,20 @@ impl Intrinsics {
       },
       Box::new(download_file_to_digest),
     );
+    intrinsics.insert(
+      Intrinsic {
+        product: types.directory_digest,
+        inputs: vec![types.extract_archive],
+      },
+      Box::new(extract_archive_to_digest),
+    );
+    intrinsics.insert(
+      Intrinsic {
+        product: types.directory_digest,
+        inputs: vec![types.archive_digest],
+      },
+      Box::new(archive_digest_to_digest),
+    );
     intrinsics.insert(
       Intrinsic {
         product: types.snapshot,
@@ -110,6 +128,27 @@ impl Intrinsics {
       },
       Box::new(digest_subset_to_digest),
     );
+    intrinsics.insert(
+      Intrinsic {
+        product: types.digest_diff,
+        inputs: vec![types.diff_digests],
+      },
+      Box::new(diff_digests_to_digest_diff),
+    );
+    intrinsics.insert(
+      Intrinsic {
+        product: types.directory_digest,
+        inputs: vec![types.rename_paths],
+      },
+      Box::new(rename_paths_to_digest),
+    );
+    intrinsics.insert(
+      Intrinsic {
+        product: types.directory_digest,
+        inputs: vec![types.substitute_contents],
+      },
+      Box::new(substitute_contents_to_digest),
+    );
     intrinsics.insert(
       Intrinsic {
         product: types.session_values,
@@ -327,6 +366,50 @@ fn download_file_to_digest(
   .boxed()
 }
 
+fn extract_archive_to_digest(
+  context: Context,
+  args: Vec<Value>,
+) -> BoxFuture<'static, NodeResult<Value>> {
+  let store = context.core.store();
+  async move {
+    let archive_digest = Snapshot::lift_file_digest(&externs::getattr(&args[0], "file_digest")?)?;
+    let format_obj: Value = externs::getattr(&args[0], "format")?;
+    let format = ArchiveFormat::create(&externs::getattr_as_string(&format_obj, "value"))?;
+    let digest = store.extract_archive(archive_digest, format).await?;
+    Snapshot::store_directory_digest(&digest)
+  }
+  .map_err(|e: String| throw(&e))
+  .boxed()
+}
+
+fn archive_digest_to_digest(
+  context: Context,
+  args: Vec<Value>,
+) -> BoxFuture<'static, NodeResult<Value>> {
+  let store = context.core.store();
+  async move {
+    let input_digest = lift_directory_digest(&externs::getattr(&args[0], "digest")?)?;
+    let output_path = externs::getattr_as_string(&args[0], "output_path");
+    let output_path = RelativePath::new(PathBuf::from(output_path))
+      .map_err(|e| format!("The `output_path` must be relative: {:?}", e))?;
+    let format_obj: Value = externs::getattr(&args[0], "format")?;
+    let options = ArchiveOptions {
+      format: ArchiveFormat::create(&externs::getattr_as_string(&format_obj, "value"))?,
+      compression_level: externs::getattr(&args[0], "compression_level")?,
+      mtime: externs::getattr(&args[0], "mtime")?,
+      uid: externs::getattr(&args[0], "uid")?,
+      gid: externs::getattr(&args[0], "gid")?,
+    };
+    let archive_digest = store.create_archive(input_digest, options).await?;
+    let snapshot = store
+      .snapshot_of_one_file(output_path, archive_digest, false)
+      .await?;
+    Snapshot::store_directory_digest(&snapshot.digest)
+  }
+  .map_err(|e: String| throw(&e))
+  .boxed()
+}
+
 fn path_globs_to_digest(
   context: Context,
   mut args: Vec<Value>,
@@ -433,6 +516,86 @@ fn digest_subset_to_digest(
   .boxed()
 }
 
+fn diff_digests_to_digest_diff(
+  context: Context,
+  args: Vec<Value>,
+) -> BoxFuture<'static, NodeResult<Value>> {
+  let store = context.core.store();
+  async move {
+    let before = lift_directory_digest(&externs::getattr(&args[0], "before")?)?;
+    let after = lift_directory_digest(&externs::getattr(&args[0], "after")?)?;
+    let diff = store
+      .diff(before, after)
+      .await
+      .map_err(|e| format!("{:?}", e))?;
+    Snapshot::store_digest_diff(&context.core.types, &diff)
+  }
+  .map_err(|e: String| throw(&e))
+  .boxed()
+}
+
+fn rename_paths_to_digest(
+  context: Context,
+  args: Vec<Value>,
+) -> BoxFuture<'static, NodeResult<Value>> {
+  let store = context.core.store();
+  async move {
+    let input_digest = lift_directory_digest(&externs::getattr(&args[0], "digest")?)?;
+    let params = match externs::getattr::<Option<String>>(&args[0], "regex")? {
+      Some(regex) => RenameParams::Regex {
+        regex: Regex::new(&regex).map_err(|e| format!("Invalid `regex` {}: {}", regex, e))?,
+        replacement: externs::getattr_as_string(&args[0], "replacement"),
+      },
+      None => RenameParams::Mapping(
+        externs::getattr::<Vec<(String, String)>>(&args[0], "mapping")?
+          .into_iter()
+          .map(|(from, to)| {
+            let from = RelativePath::new(&from)
+              .map_err(|e| format!("The paths to rename must be relative: {:?}", e))?;
+            let to = RelativePath::new(&to)
+              .map_err(|e| format!("The renamed paths must be relative: {:?}", e))?;
+            Ok((from, to))
+          })
+          .collect::<Result<_, String>>()?,
+      ),
+    };
+    let digest = store
+      .rename(input_digest, params)
+      .await
+      .map_err(|e| format!("{:?}", e))?;
+    Snapshot::store_directory_digest(&digest)
+  }
+  .map_err(|e: String| throw(&e))
+  .boxed()
+}
+
+fn substitute_contents_to_digest(
+  context: Context,
+  args: Vec<Value>,
+) -> BoxFuture<'static, NodeResult<Value>> {
+  let store = context.core.store();
+  async move {
+    let input_digest = lift_directory_digest(&externs::getattr(&args[0], "digest")?)?;
+    let globs = Snapshot::lift_prepared_path_globs(&externs::getattr(&args[0], "globs")?)?;
+    let replacements = externs::getattr::<Vec<(String, String)>>(&args[0], "replacements")?
+      .into_iter()
+      .map(|(from, to)| (Bytes::from(from), Bytes::from(to)))
+      .collect();
+    let params = SubstituteParams {
+      globs,
+      replacements,
+      header: Bytes::from(externs::getattr_as_string(&args[0], "header")),
+    };
+    let digest = store
+      .substitute(input_digest, params)
+      .await
+      .map_err(|e| format!("{:?}", e))?;
+    Snapshot::store_directory_digest(&digest)
+  }
+  .map_err(|e: String| throw(&e))
+  .boxed()
+}
+
 fn session_values(context: Context, _args: Vec<Value>) -> BoxFuture<'static, NodeResult<Value>> {
   async move { context.get(SessionValues).await }.boxed()
 }
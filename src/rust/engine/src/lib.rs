@@ -34,7 +34,13 @@
 
 mod context;
 mod core;
+mod downloads;
+#[cfg(test)]
+mod downloads_tests;
 mod externs;
+mod graph_persistence;
+#[cfg(test)]
+mod graph_persistence_tests;
 mod interning;
 mod intrinsics;
 mod nodes;
@@ -46,6 +52,7 @@ mod types;
 
 pub use crate::context::{Core, ExecutionStrategyOptions, LocalStoreOptions, RemotingOptions};
 pub use crate::core::{Failure, Function, Key, Params, TypeId, Value};
+pub use crate::downloads::DownloadOptions;
 pub use crate::intrinsics::Intrinsics;
 pub use crate::scheduler::{ExecutionRequest, ExecutionTermination, Scheduler};
 pub use crate::session::Session;
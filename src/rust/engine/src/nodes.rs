@@ -4,42 +4,38 @@
 use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::fmt::Display;
-use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{self, fmt};
 
 use async_trait::async_trait;
+use bazel_protos::gen::build::bazel::remote::execution::v2 as remexec;
 use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
-use futures::stream::StreamExt;
 use url::Url;
 
 use crate::context::{Context, Core};
 use crate::core::{display_sorted_in_parens, throw, Failure, Key, Params, TypeId, Value};
+use crate::downloads;
 use crate::externs;
 use crate::externs::engine_aware;
 use crate::selectors;
 use crate::tasks::{self, Rule};
 use crate::Types;
-use bytes::BufMut;
 use cpython::{PyObject, Python, PythonObject};
 use fs::{
   self, DigestEntry, Dir, DirectoryListing, File, FileContent, FileEntry, GlobExpansionConjunction,
-  GlobMatching, Link, PathGlobs, PathStat, PreparedPathGlobs, RelativePath, StrictGlobMatching,
-  Vfs,
+  GlobMatchCache, GlobMatching, Link, PathGlobs, PathMatchOptions, PathStat, PreparedPathGlobs,
+  RelativePath, StrictGlobMatching, SymlinkEntry, Vfs,
 };
 use process_execution::{
   self, CacheDest, CacheName, MultiPlatformProcess, Platform, Process, ProcessCacheScope,
   ProcessResultSource,
 };
 
-use bytes::Bytes;
 use graph::{Entry, Node, NodeError, NodeVisualizer};
 use hashing::{Digest, Fingerprint};
-use reqwest::Error;
-use std::pin::Pin;
-use store::{self, StoreFileByDigest};
+use store::{self, NodePropertyOptions, StoreFileByDigest};
 use workunit_store::{
   in_workunit, Level, Metric, ObservationMetric, RunningWorkunit, UserMetadataItem,
   UserMetadataPyValue, WorkunitMetadata,
@@ -61,6 +57,10 @@ impl Vfs<Failure> for Context {
     self.core.vfs.is_ignored(stat)
   }
 
+  fn glob_match_cache(&self) -> Option<&GlobMatchCache> {
+    Some(self.core.vfs.glob_match_cache())
+  }
+
   fn mk_error(msg: &str) -> Failure {
     throw(msg)
   }
@@ -74,6 +74,23 @@ impl StoreFileByDigest<Failure> for Context {
     let context = self.clone();
     async move { context.get(DigestFile(file)).await }.boxed()
   }
+
+  fn node_properties(
+    &self,
+    file: &File,
+  ) -> future::BoxFuture<'static, Result<Option<remexec::NodeProperties>, Failure>> {
+    let options = self.core.capture_node_properties;
+    if options == NodePropertyOptions::default() {
+      return future::ok(None).boxed();
+    }
+    let path = self.core.vfs.file_path(file);
+    self
+      .core
+      .executor
+      .spawn_blocking(move || options.node_properties(&path))
+      .map(|res| res.map_err(|e| throw(&e)))
+      .boxed()
+  }
 }
 
 ///
@@ -478,10 +495,10 @@ pub struct ProcessResult(pub process_execution::FallibleProcessResultWithPlatfor
 /// A Node that represents reading the destination of a symlink (non-recursively).
 ///
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct ReadLink(Link);
+pub struct ReadLink(pub Link);
 
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct LinkDest(PathBuf);
+pub struct LinkDest(pub PathBuf);
 
 #[async_trait]
 impl WrappedNode for ReadLink {
@@ -527,8 +544,8 @@ impl WrappedNode for DigestFile {
     let path = context.core.vfs.file_path(&self.0);
     context
       .core
-      .store()
-      .store_file(true, false, move || std::fs::File::open(&path))
+      .file_digest_cache
+      .store_file(&context.core.store(), path, false)
       .map_err(|e| throw(&e))
       .await
   }
@@ -545,7 +562,7 @@ impl From<DigestFile> for NodeKey {
 /// entry (generally in one syscall). No symlinks are expanded.
 ///
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct Scandir(Dir);
+pub struct Scandir(pub Dir);
 
 #[async_trait]
 impl WrappedNode for Scandir {
@@ -608,7 +625,7 @@ impl Paths {
     let mut dirs = Vec::new();
     for ps in item.iter() {
       match ps {
-        &PathStat::File { ref path, .. } => {
+        &PathStat::File { ref path, .. } | &PathStat::Link { ref path, .. } => {
           files.push(Snapshot::store_path(path)?);
         }
         &PathStat::Dir { ref path, .. } => {
@@ -682,13 +699,22 @@ impl Snapshot {
   async fn create(context: Context, path_globs: PreparedPathGlobs) -> NodeResult<store::Snapshot> {
     // We rely on Context::expand_globs tracking dependencies for scandirs,
     // and store::Snapshot::from_path_stats tracking dependencies for file digests.
+    let match_options = path_globs.match_options();
+    let strict_match_behavior = path_globs.strict_match_behavior().clone();
     let path_stats = context
       .expand_globs(path_globs, unmatched_globs_additional_context())
       .map_err(|e| throw(&format!("{}", e)))
       .await?;
-    store::Snapshot::from_path_stats(context.core.store(), context.clone(), path_stats)
-      .map_err(|e| throw(&format!("Snapshot failed: {}", e)))
-      .await
+    store::Snapshot::check_path_collisions(&path_stats, match_options, &strict_match_behavior)
+      .map_err(|e| throw(&e))?;
+    store::Snapshot::from_path_stats_with_cache(
+      context.core.store(),
+      context.clone(),
+      context.core.directory_digest_cache.clone(),
+      path_stats,
+    )
+    .map_err(|e| throw(&format!("Snapshot failed: {}", e)))
+    .await
   }
 
   pub fn lift_path_globs(item: &Value) -> Result<PathGlobs, String> {
@@ -710,7 +736,12 @@ impl Snapshot {
     let conjunction_obj: PyObject = externs::getattr(item, "conjunction").unwrap();
     let conjunction_string = externs::getattr_as_string(&conjunction_obj, "value");
     let conjunction = GlobExpansionConjunction::create(&conjunction_string)?;
-    Ok(PathGlobs::new(globs, strict_glob_matching, conjunction))
+
+    let match_options = PathMatchOptions::new(
+      externs::getattr(item, "case_insensitive")?,
+      externs::getattr(item, "unicode_normalization")?,
+    );
+    Ok(PathGlobs::new(globs, strict_glob_matching, conjunction).with_match_options(match_options))
   }
 
   pub fn lift_prepared_path_globs(item: &Value) -> Result<PreparedPathGlobs, String> {
@@ -781,6 +812,19 @@ impl Snapshot {
     ))
   }
 
+  fn store_symlink_entry(
+    types: &crate::types::Types,
+    item: &SymlinkEntry,
+  ) -> Result<Value, String> {
+    Ok(externs::unsafe_call(
+      types.symlink_entry,
+      &[
+        Self::store_path(&item.path)?,
+        Self::store_path(&item.target)?,
+      ],
+    ))
+  }
+
   fn store_empty_directory(types: &crate::types::Types, path: &Path) -> Result<Value, String> {
     Ok(externs::unsafe_call(
       types.directory,
@@ -799,11 +843,35 @@ impl Snapshot {
     ))
   }
 
+  pub fn store_digest_diff(
+    types: &crate::types::Types,
+    item: &store::DirectoryDiff,
+  ) -> Result<Value, String> {
+    let store_paths = |paths: &[PathBuf]| -> Result<Value, String> {
+      let paths = paths
+        .iter()
+        .map(|path| Self::store_path(path))
+        .collect::<Result<Vec<_>, _>>()?;
+      Ok(externs::store_tuple(paths))
+    };
+    Ok(externs::unsafe_call(
+      types.digest_diff,
+      &[
+        store_paths(&item.added)?,
+        store_paths(&item.removed)?,
+        store_paths(&item.modified)?,
+      ],
+    ))
+  }
+
   pub fn store_digest_entries(context: &Context, item: &[DigestEntry]) -> Result<Value, String> {
     let entries = item
       .iter()
       .map(|digest_entry| match digest_entry {
         DigestEntry::File(file_entry) => Self::store_file_entry(&context.core.types, file_entry),
+        DigestEntry::Symlink(symlink_entry) => {
+          Self::store_symlink_entry(&context.core.types, symlink_entry)
+        }
         DigestEntry::EmptyDirectory(path) => Self::store_empty_directory(&context.core.types, path),
       })
       .collect::<Result<Vec<_>, _>>()?;
@@ -835,95 +903,20 @@ impl From<Snapshot> for NodeKey {
   }
 }
 
-#[async_trait]
-trait StreamingDownload: Send {
-  async fn next(&mut self) -> Option<Result<Bytes, String>>;
-}
-
-struct NetDownload {
-  stream: futures_core::stream::BoxStream<'static, Result<Bytes, Error>>,
-}
-
-impl NetDownload {
-  async fn start(core: &Arc<Core>, url: Url, file_name: String) -> Result<NetDownload, String> {
-    // TODO: Retry failures
-    let response = core
-      .http_client
-      .get(url.clone())
-      .send()
-      .await
-      .map_err(|err| format!("Error downloading file: {}", err))?;
-
-    // Handle common HTTP errors.
-    if response.status().is_server_error() {
-      return Err(format!(
-        "Server error ({}) downloading file {} from {}",
-        response.status().as_str(),
-        file_name,
-        url,
-      ));
-    } else if response.status().is_client_error() {
-      return Err(format!(
-        "Client error ({}) downloading file {} from {}",
-        response.status().as_str(),
-        file_name,
-        url,
-      ));
-    }
-    let byte_stream = Pin::new(Box::new(response.bytes_stream()));
-    Ok(NetDownload {
-      stream: byte_stream,
-    })
-  }
-}
-
-#[async_trait]
-impl StreamingDownload for NetDownload {
-  async fn next(&mut self) -> Option<Result<Bytes, String>> {
-    self
-      .stream
-      .next()
-      .await
-      .map(|result| result.map_err(|err| err.to_string()))
-  }
-}
-
-struct FileDownload {
-  stream: tokio_util::io::ReaderStream<tokio::fs::File>,
-}
-
-impl FileDownload {
-  async fn start(path: &str, file_name: String) -> Result<FileDownload, String> {
-    let file = tokio::fs::File::open(path).await.map_err(|e| {
-      format!(
-        "Error ({}) opening file at {} for download to {}",
-        e, path, file_name
-      )
-    })?;
-    let stream = tokio_util::io::ReaderStream::new(file);
-    Ok(FileDownload { stream })
-  }
-}
-
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct DownloadedFile(pub Key);
 
-#[async_trait]
-impl StreamingDownload for FileDownload {
-  async fn next(&mut self) -> Option<Result<Bytes, String>> {
-    self
-      .stream
-      .next()
-      .await
-      .map(|result| result.map_err(|err| err.to_string()))
-  }
-}
-
 impl DownloadedFile {
+  ///
+  /// Loads the file with the given Digest from the Store (including the remote CAS), or downloads
+  /// it from the first of the given URLs which succeeds. The name of the file is taken from the
+  /// given URL, which is the URL before any rewrites were applied.
+  ///
   async fn load_or_download(
     &self,
     core: Arc<Core>,
-    url: Url,
+    url: &Url,
+    download_urls: Vec<Url>,
     digest: hashing::Digest,
   ) -> Result<store::Snapshot, String> {
     let file_name = url
@@ -934,89 +927,21 @@ impl DownloadedFile {
     let path = RelativePath::new(&file_name).map_err(|e| {
       format!(
         "The file name derived from {} was {} which is not relative: {:?}",
-        &url, &file_name, e
+        url, &file_name, e
       )
     })?;
-    let maybe_bytes = core.store().load_file_bytes_with(digest, |_| ()).await?;
-    if maybe_bytes.is_none() {
-      DownloadedFile::download(core.clone(), url, file_name, digest).await?;
-    }
+    downloads::load_or_download(
+      &core.download_store,
+      core.upload_downloads,
+      &core.http_client,
+      &core.download_options,
+      &download_urls,
+      digest,
+      &downloads::RetryPolicy::default(),
+    )
+    .await?;
     core.store().snapshot_of_one_file(path, digest, true).await
   }
-
-  async fn start_download(
-    core: &Arc<Core>,
-    url: Url,
-    file_name: String,
-  ) -> Result<Box<dyn StreamingDownload>, String> {
-    if url.scheme() == "file" {
-      return Ok(Box::new(FileDownload::start(url.path(), file_name).await?));
-    }
-    Ok(Box::new(NetDownload::start(core, url, file_name).await?))
-  }
-
-  async fn download(
-    core: Arc<Core>,
-    url: Url,
-    file_name: String,
-    expected_digest: hashing::Digest,
-  ) -> Result<(), String> {
-    let mut response_stream = DownloadedFile::start_download(&core, url, file_name).await?;
-
-    let (actual_digest, bytes) = {
-      struct SizeLimiter<W: std::io::Write> {
-        writer: W,
-        written: usize,
-        size_limit: usize,
-      }
-
-      impl<W: std::io::Write> Write for SizeLimiter<W> {
-        fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-          let new_size = self.written + buf.len();
-          if new_size > self.size_limit {
-            Err(std::io::Error::new(
-              std::io::ErrorKind::InvalidData,
-              "Downloaded file was larger than expected digest",
-            ))
-          } else {
-            self.written = new_size;
-            self.writer.write_all(buf)?;
-            Ok(buf.len())
-          }
-        }
-
-        fn flush(&mut self) -> Result<(), std::io::Error> {
-          self.writer.flush()
-        }
-      }
-
-      let mut hasher = hashing::WriterHasher::new(SizeLimiter {
-        writer: bytes::BytesMut::with_capacity(expected_digest.size_bytes).writer(),
-        written: 0,
-        size_limit: expected_digest.size_bytes,
-      });
-
-      while let Some(next_chunk) = response_stream.next().await {
-        let chunk =
-          next_chunk.map_err(|err| format!("Error reading URL fetch response: {}", err))?;
-        hasher
-          .write_all(&chunk)
-          .map_err(|err| format!("Error hashing/capturing URL fetch response: {}", err))?;
-      }
-      let (digest, bytewriter) = hasher.finish();
-      (digest, bytewriter.writer.into_inner().freeze())
-    };
-
-    if expected_digest != actual_digest {
-      return Err(format!(
-        "Wrong digest for downloaded file: want {:?} got {:?}",
-        expected_digest, actual_digest
-      ));
-    }
-
-    let _ = core.store().store_file_bytes(bytes, true).await?;
-    Ok(())
-  }
 }
 
 #[async_trait]
@@ -1026,20 +951,48 @@ impl WrappedNode for DownloadedFile {
   async fn run_wrapped_node(
     self,
     context: Context,
-    _workunit: &mut RunningWorkunit,
+    workunit: &mut RunningWorkunit,
   ) -> NodeResult<Digest> {
     let value = externs::val_for(&self.0);
     let url_str = externs::getattr_as_string(&value, "url");
+    let mirror_url_strs = externs::getattr::<Vec<String>>(&value, "mirror_urls").unwrap();
 
-    let url = Url::parse(&url_str)
-      .map_err(|err| throw(&format!("Error parsing URL {}: {}", url_str, err)))?;
+    let urls = std::iter::once(url_str)
+      .chain(mirror_url_strs)
+      .map(|url_str| {
+        Url::parse(&url_str)
+          .map_err(|err| throw(&format!("Error parsing URL {}: {}", url_str, err)))
+      })
+      .collect::<Result<Vec<_>, _>>()?;
 
     let py_digest: Value = externs::getattr(&value, "expected_digest").unwrap();
     let expected_digest =
       lift_file_digest(&context.core.types, &py_digest).map_err(|s| throw(&s))?;
 
+    let mut rewrites = Vec::new();
+    let download_urls = urls
+      .iter()
+      .map(|url| match context.core.download_options.rewrite(url) {
+        Ok(Some(rewritten)) => {
+          rewrites.push(format!("{} -> {}", url, rewritten));
+          Ok(rewritten)
+        }
+        Ok(None) => Ok(url.clone()),
+        Err(err) => Err(throw(&err)),
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    if !rewrites.is_empty() {
+      workunit.update_metadata(|mut metadata| {
+        metadata.user_metadata.push((
+          "url_rewrites".to_owned(),
+          UserMetadataItem::ImmediateString(rewrites.join("\n")),
+        ));
+        metadata
+      });
+    }
+
     let snapshot = self
-      .load_or_download(context.core, url, expected_digest)
+      .load_or_download(context.core, &urls[0], download_urls, expected_digest)
       .await
       .map_err(|err| throw(&err))?;
     Ok(snapshot.digest)
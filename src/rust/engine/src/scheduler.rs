@@ -135,6 +135,9 @@ impl Scheduler {
       session.preceding_graph_size() as i64,
     );
     m.insert("resulting_graph_size", self.core.graph.len() as i64);
+    if let Some(watcher) = &self.core.watcher {
+      m.extend(watcher.metrics());
+    }
     m
   }
 
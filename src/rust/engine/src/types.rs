@@ -10,6 +10,7 @@ pub struct Types {
   pub paths: TypeId,
   pub file_content: TypeId,
   pub file_entry: TypeId,
+  pub symlink_entry: TypeId,
   pub directory: TypeId,
   pub digest_contents: TypeId,
   pub digest_entries: TypeId,
@@ -20,6 +21,12 @@ pub struct Types {
   pub create_digest: TypeId,
   pub digest_subset: TypeId,
   pub download_file: TypeId,
+  pub extract_archive: TypeId,
+  pub archive_digest: TypeId,
+  pub diff_digests: TypeId,
+  pub digest_diff: TypeId,
+  pub rename_paths: TypeId,
+  pub substitute_contents: TypeId,
   pub platform: TypeId,
   pub multi_platform_process: TypeId,
   pub process_result: TypeId,
@@ -0,0 +1,178 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{FutureExt, StreamExt};
+use hyper::header::{HeaderMap, CONTENT_LENGTH, CONTENT_RANGE, LOCATION, RANGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use parking_lot::Mutex;
+
+///
+/// How a StubHttpServer responds to a request.
+///
+#[derive(Clone, Debug)]
+pub enum StubResponse {
+  /// Respond with the content, honoring any `Range` header of the request.
+  Content,
+  /// Respond with the full content, ignoring any `Range` header of the request.
+  IgnoreRange,
+  /// Respond as for `Content`, but close the connection after sending this many bytes of the body.
+  Truncated(usize),
+  /// Respond with the given status code and an empty body.
+  Status(u16),
+  /// Respond with a redirect to the given URL.
+  Redirect(String),
+}
+
+///
+/// An HTTP server which serves a single piece of content at every path, responding to requests
+/// with a scripted sequence of StubResponses, and then with `StubResponse::Content`.
+///
+pub struct StubHttpServer {
+  local_addr: SocketAddr,
+  requests: Arc<Mutex<Vec<HeaderMap>>>,
+  shutdown_sender: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl StubHttpServer {
+  pub fn new(content: Bytes, responses: Vec<StubResponse>) -> StubHttpServer {
+    let responses = Arc::new(Mutex::new(VecDeque::from(responses)));
+    let requests = Arc::new(Mutex::new(Vec::new()));
+
+    let requests2 = requests.clone();
+    let make_service = make_service_fn(move |_| {
+      let content = content.clone();
+      let responses = responses.clone();
+      let requests = requests2.clone();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+          let range = request
+            .headers()
+            .get(RANGE)
+            .and_then(|range| range.to_str().ok())
+            .map(str::to_owned);
+          requests.lock().push(request.headers().clone());
+          let response = responses
+            .lock()
+            .pop_front()
+            .unwrap_or(StubResponse::Content);
+          futures::future::ready(Ok::<_, Infallible>(Self::respond(
+            &content, range, response,
+          )))
+        }))
+      }
+    });
+
+    let addr = "127.0.0.1:0".parse().expect("failed to parse IP address");
+    let server = Server::bind(&addr).serve(make_service);
+    let local_addr = server.local_addr();
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel();
+    tokio::spawn(
+      server
+        .with_graceful_shutdown(shutdown_receiver.map(drop))
+        .map(|res| res.unwrap()),
+    );
+
+    StubHttpServer {
+      local_addr,
+      requests,
+      shutdown_sender: Some(shutdown_sender),
+    }
+  }
+
+  fn respond(content: &Bytes, range: Option<String>, response: StubResponse) -> Response<Body> {
+    let truncate_at = match response {
+      StubResponse::Status(status) => {
+        return Response::builder()
+          .status(status)
+          .body(Body::empty())
+          .unwrap();
+      }
+      StubResponse::Redirect(url) => {
+        return Response::builder()
+          .status(StatusCode::FOUND)
+          .header(LOCATION, url)
+          .body(Body::empty())
+          .unwrap();
+      }
+      StubResponse::IgnoreRange => return Response::new(Body::from(content.clone())),
+      StubResponse::Content => None,
+      StubResponse::Truncated(len) => Some(len),
+    };
+
+    let start = range
+      .as_ref()
+      .and_then(|range| range.strip_prefix("bytes="))
+      .and_then(|range| range.strip_suffix('-'))
+      .and_then(|start| start.parse::<usize>().ok());
+    let mut builder = Response::builder();
+    let body = match start {
+      Some(start) if start < content.len() => {
+        builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+          CONTENT_RANGE,
+          format!("bytes {}-{}/{}", start, content.len() - 1, content.len()),
+        );
+        content.slice(start..)
+      }
+      Some(_) => {
+        return Response::builder()
+          .status(StatusCode::RANGE_NOT_SATISFIABLE)
+          .body(Body::empty())
+          .unwrap();
+      }
+      None => content.clone(),
+    };
+    let builder = builder.header(CONTENT_LENGTH, body.len());
+
+    match truncate_at {
+      Some(len) if len < body.len() => {
+        // NB: The connection is aborted after a delay, so that the prefix is flushed to the client.
+        let prefix = futures::stream::once(futures::future::ok(body.slice(..len)));
+        let abort = futures::stream::once(async {
+          tokio::time::sleep(Duration::from_millis(100)).await;
+          Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "Truncated response.",
+          ))
+        });
+        builder
+          .body(Body::wrap_stream(prefix.chain(abort)))
+          .unwrap()
+      }
+      _ => builder.body(Body::from(body)).unwrap(),
+    }
+  }
+
+  ///
+  /// The URL of the given path on this server.
+  ///
+  pub fn url(&self, path: &str) -> String {
+    format!(
+      "http://{}/{}",
+      self.local_addr,
+      path.trim_start_matches('/')
+    )
+  }
+
+  ///
+  /// The headers of each request that this server has received.
+  ///
+  pub fn requests(&self) -> Vec<HeaderMap> {
+    self.requests.lock().clone()
+  }
+}
+
+impl Drop for StubHttpServer {
+  fn drop(&mut self) {
+    if let Some(s) = self.shutdown_sender.take() {
+      let _ = s.send(());
+    }
+  }
+}
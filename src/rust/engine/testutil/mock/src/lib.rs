@@ -30,7 +30,9 @@
 mod action_cache;
 mod cas;
 pub mod execution_server;
+mod http_server;
 
 pub use crate::action_cache::StubActionCache;
 pub use crate::cas::{StubCAS, StubCASBuilder};
 pub use crate::execution_server::MockExecution;
+pub use crate::http_server::{StubHttpServer, StubResponse};
@@ -0,0 +1,185 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+///
+/// Controls how filesystem events are coalesced into invalidations.
+///
+#[derive(Clone, Debug)]
+pub struct CoalescingOptions {
+  /// Changed paths are invalidated once no further events have been received for this long.
+  pub quiet_window: Duration,
+  /// Changed paths are invalidated at least this often, even if events never stop arriving.
+  pub max_delay: Duration,
+  /// If more than this many paths in a single directory change within one batch, the directory is
+  /// invalidated recursively instead.
+  pub max_paths_per_directory: usize,
+}
+
+impl Default for CoalescingOptions {
+  fn default() -> CoalescingOptions {
+    CoalescingOptions {
+      quiet_window: Duration::from_millis(50),
+      max_delay: Duration::from_secs(1),
+      max_paths_per_directory: 32,
+    }
+  }
+}
+
+///
+/// Counts of the events observed by an InvalidationWatcher, and of the invalidations that they
+/// were coalesced into.
+///
+#[derive(Default)]
+pub(crate) struct WatcherMetrics {
+  events: AtomicU64,
+  paths_changed: AtomicU64,
+  paths_invalidated: AtomicU64,
+  directories_invalidated: AtomicU64,
+  invalidations: AtomicU64,
+}
+
+impl WatcherMetrics {
+  pub(crate) fn record_event(&self, paths_changed: usize) {
+    self.events.fetch_add(1, Ordering::Relaxed);
+    self
+      .paths_changed
+      .fetch_add(paths_changed as u64, Ordering::Relaxed);
+  }
+
+  pub(crate) fn record_invalidation(&self, paths: usize, directories: usize) {
+    self.invalidations.fetch_add(1, Ordering::Relaxed);
+    self
+      .paths_invalidated
+      .fetch_add(paths as u64, Ordering::Relaxed);
+    self
+      .directories_invalidated
+      .fetch_add(directories as u64, Ordering::Relaxed);
+  }
+
+  pub(crate) fn snapshot(&self) -> HashMap<&'static str, i64> {
+    vec![
+      ("watcher_events", &self.events),
+      ("watcher_paths_changed", &self.paths_changed),
+      ("watcher_paths_invalidated", &self.paths_invalidated),
+      (
+        "watcher_directories_invalidated",
+        &self.directories_invalidated,
+      ),
+      ("watcher_invalidations", &self.invalidations),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name, value.load(Ordering::Relaxed) as i64))
+    .collect()
+  }
+}
+
+///
+/// Paths which have changed, but which have not yet been invalidated.
+///
+#[derive(Default)]
+pub(crate) struct PendingInvalidation {
+  paths: HashSet<PathBuf>,
+  // Directories to invalidate recursively.
+  directories: HashSet<PathBuf>,
+  first_change: Option<Instant>,
+  last_change: Option<Instant>,
+}
+
+impl PendingInvalidation {
+  pub(crate) fn extend(&mut self, paths: impl IntoIterator<Item = PathBuf>, now: Instant) {
+    let len = self.paths.len();
+    self.paths.extend(paths);
+    if self.paths.len() != len {
+      self.changed(now);
+    }
+  }
+
+  pub(crate) fn extend_directories(
+    &mut self,
+    directories: impl IntoIterator<Item = PathBuf>,
+    now: Instant,
+  ) {
+    let len = self.directories.len();
+    self.directories.extend(directories);
+    if self.directories.len() != len {
+      self.changed(now);
+    }
+  }
+
+  fn changed(&mut self, now: Instant) {
+    self.first_change.get_or_insert(now);
+    self.last_change = Some(now);
+  }
+
+  pub(crate) fn is_empty(&self) -> bool {
+    self.paths.is_empty() && self.directories.is_empty()
+  }
+
+  ///
+  /// True if there are pending paths, and either no change has been observed for the quiet window
+  /// or the oldest change has been pending for the maximum delay.
+  ///
+  pub(crate) fn is_ready(&self, options: &CoalescingOptions, now: Instant) -> bool {
+    match (self.first_change, self.last_change) {
+      (Some(first_change), Some(last_change)) => {
+        now.saturating_duration_since(last_change) >= options.quiet_window
+          || now.saturating_duration_since(first_change) >= options.max_delay
+      }
+      _ => false,
+    }
+  }
+
+  pub(crate) fn clear(&mut self) {
+    *self = PendingInvalidation::default();
+  }
+
+  ///
+  /// Takes the pending paths, returning the paths to invalidate individually and the directories
+  /// to invalidate recursively.
+  ///
+  /// Any directory containing more than `max_paths_per_directory` changed paths is collapsed into a
+  /// recursive invalidation of the directory, except for the build root itself: collapsing it would
+  /// invalidate everything, and so its paths are always invalidated individually.
+  ///
+  pub(crate) fn take(
+    &mut self,
+    options: &CoalescingOptions,
+  ) -> (HashSet<PathBuf>, HashSet<PathBuf>) {
+    let paths = std::mem::take(&mut self.paths);
+    let mut directories = std::mem::take(&mut self.directories);
+    self.clear();
+
+    let mut children_per_directory: HashMap<PathBuf, usize> = HashMap::new();
+    for path in &paths {
+      if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        *children_per_directory
+          .entry(parent.to_path_buf())
+          .or_default() += 1;
+      }
+    }
+    directories.extend(
+      children_per_directory
+        .into_iter()
+        .filter(|(_, children)| *children > options.max_paths_per_directory)
+        .map(|(directory, _)| directory),
+    );
+    if directories.is_empty() {
+      return (paths, directories);
+    }
+
+    let paths = paths
+      .into_iter()
+      .filter(|path| {
+        !directories
+          .iter()
+          .any(|directory| path.starts_with(directory))
+      })
+      .collect();
+    (paths, directories)
+  }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use fs::GitignoreStyleExcludes;
+use notify::event::{EventKind, ModifyKind};
+use task_executor::Executor;
+
+use crate::coalescing::{PendingInvalidation, WatcherMetrics};
+use crate::tests::TestInvalidatable;
+use crate::{CoalescingOptions, InvalidationWatcher};
+
+fn options(max_paths_per_directory: usize) -> CoalescingOptions {
+  CoalescingOptions {
+    quiet_window: Duration::from_millis(100),
+    max_delay: Duration::from_millis(1000),
+    max_paths_per_directory,
+  }
+}
+
+fn paths(paths: &[&str]) -> HashSet<PathBuf> {
+  paths.iter().map(PathBuf::from).collect()
+}
+
+#[test]
+fn ready_after_quiet_window() {
+  let options = options(10);
+  let start = Instant::now();
+  let mut pending = PendingInvalidation::default();
+  assert!(!pending.is_ready(&options, start + Duration::from_secs(10)));
+
+  pending.extend(paths(&["a"]), start);
+  pending.extend(paths(&["b"]), start + Duration::from_millis(50));
+  assert!(!pending.is_ready(&options, start + Duration::from_millis(100)));
+  assert!(pending.is_ready(&options, start + Duration::from_millis(150)));
+}
+
+#[test]
+fn ready_after_max_delay() {
+  let options = options(10);
+  let start = Instant::now();
+  let mut pending = PendingInvalidation::default();
+  // Events which never stop arriving are still invalidated once the maximum delay has elapsed.
+  for i in 0..20 {
+    let now = start + Duration::from_millis(i * 50);
+    pending.extend(vec![PathBuf::from(format!("{}", i))], now);
+    assert!(!pending.is_ready(&options, now));
+  }
+  assert!(pending.is_ready(&options, start + Duration::from_millis(1000)));
+}
+
+#[test]
+fn duplicate_paths_do_not_delay() {
+  let options = options(10);
+  let start = Instant::now();
+  let mut pending = PendingInvalidation::default();
+  pending.extend(paths(&["a"]), start);
+  pending.extend(paths(&["a"]), start + Duration::from_millis(50));
+  assert!(pending.is_ready(&options, start + Duration::from_millis(100)));
+}
+
+#[test]
+fn take_collapses_directories() {
+  let options = options(2);
+  let mut pending = PendingInvalidation::default();
+  pending.extend(
+    paths(&["a", "a/1", "a/2", "a/3", "a/b/1", "c", "c/1", "c/2"]),
+    Instant::now(),
+  );
+
+  let (paths_to_invalidate, directories) = pending.take(&options);
+  assert_eq!(paths_to_invalidate, paths(&["c", "c/1", "c/2"]));
+  assert_eq!(directories, paths(&["a"]));
+  assert!(pending.is_empty());
+}
+
+#[test]
+fn take_does_not_collapse_build_root() {
+  let options = options(2);
+  let mut pending = PendingInvalidation::default();
+  pending.extend(paths(&["a", "b", "c", "d/1"]), Instant::now());
+
+  let (paths_to_invalidate, directories) = pending.take(&options);
+  assert_eq!(paths_to_invalidate, paths(&["a", "b", "c", "d/1"]));
+  assert!(directories.is_empty());
+}
+
+#[test]
+fn metrics() {
+  let metrics = WatcherMetrics::default();
+  metrics.record_event(3);
+  metrics.record_event(2);
+  metrics.record_invalidation(4, 1);
+
+  let snapshot = metrics.snapshot();
+  assert_eq!(snapshot["watcher_events"], 2);
+  assert_eq!(snapshot["watcher_paths_changed"], 5);
+  assert_eq!(snapshot["watcher_paths_invalidated"], 4);
+  assert_eq!(snapshot["watcher_directories_invalidated"], 1);
+  assert_eq!(snapshot["watcher_invalidations"], 1);
+}
+
+#[tokio::test]
+async fn coalesces_events_into_one_invalidation() {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  let build_root = tempdir.path().canonicalize().unwrap();
+  let invalidatable = Arc::new(TestInvalidatable::default());
+  let metrics = Arc::new(WatcherMetrics::default());
+  let (liveness_sender, _liveness_receiver) = crossbeam_channel::unbounded();
+  let (event_sender, event_receiver) = crossbeam_channel::unbounded();
+  let join_handle = InvalidationWatcher::start_background_thread(
+    Arc::downgrade(&invalidatable),
+    GitignoreStyleExcludes::empty(),
+    build_root.clone(),
+    Executor::new(),
+    liveness_sender,
+    event_receiver,
+    None,
+    options(3),
+    metrics.clone(),
+  );
+
+  for path in &["gen/1", "gen/2", "gen/3", "gen/4", "gen/1", "src/a"] {
+    event_sender
+      .send(Ok(
+        notify::Event::new(EventKind::Modify(ModifyKind::Any)).add_path(build_root.join(path)),
+      ))
+      .unwrap();
+  }
+
+  let mut waited = Duration::from_millis(0);
+  while metrics.snapshot()["watcher_invalidations"] == 0 && waited < Duration::from_secs(5) {
+    std::thread::sleep(Duration::from_millis(20));
+    waited += Duration::from_millis(20);
+  }
+  assert_eq!(*invalidatable.calls.lock(), vec![paths(&["src", "src/a"])]);
+  assert_eq!(*invalidatable.directory_calls.lock(), vec![paths(&["gen"])]);
+  let snapshot = metrics.snapshot();
+  assert_eq!(snapshot["watcher_events"], 6);
+  assert_eq!(snapshot["watcher_invalidations"], 1);
+
+  std::mem::drop(invalidatable);
+  join_handle.join().unwrap();
+}
+
+#[tokio::test]
+async fn ignore_file_change_invalidates_directory() {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  let build_root = tempdir.path().canonicalize().unwrap();
+  let invalidatable = Arc::new(TestInvalidatable::default());
+  let metrics = Arc::new(WatcherMetrics::default());
+  let (liveness_sender, _liveness_receiver) = crossbeam_channel::unbounded();
+  let (event_sender, event_receiver) = crossbeam_channel::unbounded();
+  let join_handle = InvalidationWatcher::start_background_thread(
+    Arc::downgrade(&invalidatable),
+    GitignoreStyleExcludes::create_with_nested_gitignores(vec![], build_root.clone()).unwrap(),
+    build_root.clone(),
+    Executor::new(),
+    liveness_sender,
+    event_receiver,
+    None,
+    options(3),
+    metrics.clone(),
+  );
+
+  event_sender
+    .send(Ok(
+      notify::Event::new(EventKind::Modify(ModifyKind::Any))
+        .add_path(build_root.join("src/.gitignore")),
+    ))
+    .unwrap();
+
+  let mut waited = Duration::from_millis(0);
+  while metrics.snapshot()["watcher_invalidations"] == 0 && waited < Duration::from_secs(5) {
+    std::thread::sleep(Duration::from_millis(20));
+    waited += Duration::from_millis(20);
+  }
+  // The changed paths are beneath the invalidated directory, and so are not invalidated separately.
+  assert!(invalidatable.calls.lock().is_empty());
+  assert_eq!(*invalidatable.directory_calls.lock(), vec![paths(&["src"])]);
+
+  std::mem::drop(invalidatable);
+  join_handle.join().unwrap();
+}
@@ -0,0 +1,217 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, TryRecvError};
+use log::{debug, warn};
+use task_executor::Executor;
+
+///
+/// The maximum duration for which invalidation is deferred while an index lock exists. A lock
+/// which outlives this is assumed to have been left behind by a git process which crashed.
+///
+pub(crate) const MAX_OPERATION_DEFERRAL: Duration = Duration::from_secs(30);
+
+///
+/// Tracks the HEAD of a git repository rooted at the build root, in order to invalidate exactly
+/// the paths which differ between commits when HEAD moves (due to a checkout or rebase, for
+/// example), and to defer invalidation while git is operating on the working tree.
+///
+/// The git commands which inspect HEAD are run on the blocking pool of an Executor, so that the
+/// watcher thread continues to receive events while they run.
+///
+/// NB: Only repositories whose `.git` directory is located directly in the build root are
+/// supported.
+///
+pub(crate) struct GitState {
+  executor: Executor,
+  build_root: PathBuf,
+  git_dir: PathBuf,
+  head: Option<String>,
+  // The new HEAD and the paths which changed, if they are being computed.
+  head_changes: Option<Receiver<(Option<String>, HashSet<PathBuf>)>>,
+  // When the current index lock was first observed.
+  lock_observed_at: Option<Instant>,
+  stale_lock_reported: bool,
+}
+
+impl GitState {
+  ///
+  /// Creates a GitState for the given build root, if it is a git repository. The initial HEAD is
+  /// computed in the background, as if by `start_head_changes`.
+  ///
+  pub(crate) fn new(canonical_build_root: &Path, executor: Executor) -> Option<GitState> {
+    let git_dir = canonical_build_root.join(".git");
+    if !git_dir.is_dir() {
+      return None;
+    }
+    let mut state = GitState {
+      executor,
+      build_root: canonical_build_root.to_owned(),
+      git_dir,
+      head: None,
+      head_changes: None,
+      lock_observed_at: None,
+      stale_lock_reported: false,
+    };
+    state.start_head_changes();
+    Some(state)
+  }
+
+  ///
+  /// True if the given (absolute) path is one which git modifies when HEAD or the index change.
+  ///
+  pub(crate) fn is_git_path(&self, path: &Path) -> bool {
+    path
+      .strip_prefix(&self.git_dir)
+      .map(|relative| {
+        relative == Path::new("HEAD")
+          || relative == Path::new("index")
+          || relative == Path::new("index.lock")
+      })
+      .unwrap_or(false)
+  }
+
+  ///
+  /// True if git is currently modifying the index and working tree, in which case invalidation
+  /// should be deferred until it has finished.
+  ///
+  /// Invalidation is only deferred for up to `MAX_OPERATION_DEFERRAL` after a lock is first
+  /// observed, so that a stale lock cannot prevent invalidation indefinitely.
+  ///
+  pub(crate) fn operation_in_progress(&mut self, now: Instant) -> bool {
+    if !self.git_dir.join("index.lock").exists() {
+      self.lock_observed_at = None;
+      self.stale_lock_reported = false;
+      return false;
+    }
+    let observed_at = *self.lock_observed_at.get_or_insert(now);
+    if now.saturating_duration_since(observed_at) <= MAX_OPERATION_DEFERRAL {
+      return true;
+    }
+    if !self.stale_lock_reported {
+      self.stale_lock_reported = true;
+      warn!(
+        "{} has existed for more than {:?}: assuming that it is stale, and no longer deferring \
+        invalidation.",
+        self.git_dir.join("index.lock").display(),
+        MAX_OPERATION_DEFERRAL
+      );
+    }
+    false
+  }
+
+  ///
+  /// True if HEAD is being inspected in the background (see `poll_head_changes`).
+  ///
+  pub(crate) fn head_changes_in_progress(&self) -> bool {
+    self.head_changes.is_some()
+  }
+
+  ///
+  /// Starts computing whether HEAD has moved since it was last observed in the background. Must
+  /// not be called while a previous computation is in progress.
+  ///
+  pub(crate) fn start_head_changes(&mut self) {
+    assert!(
+      self.head_changes.is_none(),
+      "HEAD changes are already being computed."
+    );
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let build_root = self.build_root.clone();
+    let old_head = self.head.clone();
+    // NB: The Future is dropped, but the task runs to completion regardless.
+    std::mem::drop(self.executor.spawn_blocking(move || {
+      let new_head = rev_parse_head(&build_root);
+      let changes = match (&old_head, &new_head) {
+        (Some(old_head), Some(new_head)) if old_head != new_head => {
+          diff(&build_root, old_head, new_head)
+        }
+        _ => HashSet::new(),
+      };
+      let _ = sender.send((new_head, changes));
+    }));
+    self.head_changes = Some(receiver);
+  }
+
+  ///
+  /// If HEAD was being inspected and has finished, returns the paths (relative to the build root)
+  /// which differ between the previous and current HEAD commits, which are empty if HEAD has not
+  /// moved. Returns `Pending` while HEAD is still being inspected.
+  ///
+  pub(crate) fn poll_head_changes(&mut self) -> Poll<HashSet<PathBuf>> {
+    let (new_head, changes) = match self.head_changes.as_ref().map(Receiver::try_recv) {
+      None => return Poll::Ready(HashSet::new()),
+      Some(Err(TryRecvError::Empty)) => return Poll::Pending,
+      Some(Ok(head_changes)) => head_changes,
+      Some(Err(TryRecvError::Disconnected)) => (self.head.clone(), HashSet::new()),
+    };
+    self.head_changes = None;
+    if new_head != self.head {
+      debug!(
+        "git HEAD moved from {:?} to {:?}, changing {} paths",
+        self.head,
+        new_head,
+        changes.len()
+      );
+      self.head = new_head;
+    }
+    Poll::Ready(changes)
+  }
+}
+
+fn rev_parse_head(build_root: &Path) -> Option<String> {
+  git(build_root, &["rev-parse", "--verify", "--quiet", "HEAD"])
+    .map(|output| output.trim().to_owned())
+    .filter(|head| !head.is_empty())
+}
+
+fn diff(build_root: &Path, old_head: &str, new_head: &str) -> HashSet<PathBuf> {
+  git(
+    build_root,
+    &[
+      "diff",
+      "--name-only",
+      "--no-renames",
+      "-z",
+      old_head,
+      new_head,
+    ],
+  )
+  .map(|output| {
+    output
+      .split('\0')
+      .filter(|path| !path.is_empty())
+      .map(PathBuf::from)
+      .collect()
+  })
+  .unwrap_or_default()
+}
+
+fn git(build_root: &Path, args: &[&str]) -> Option<String> {
+  let output = Command::new("git")
+    .arg("-C")
+    .arg(build_root)
+    .args(args)
+    .output();
+  match output {
+    Ok(output) if output.status.success() => String::from_utf8(output.stdout).ok(),
+    Ok(output) => {
+      debug!(
+        "`git {}` failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+      );
+      None
+    }
+    Err(e) => {
+      debug!("Failed to run `git {}`: {}", args.join(" "), e);
+      None
+    }
+  }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use fs::GitignoreStyleExcludes;
+use notify::event::{EventKind, ModifyKind};
+use task_executor::Executor;
+
+use crate::git::{GitState, MAX_OPERATION_DEFERRAL};
+use crate::tests::TestInvalidatable;
+use crate::{CoalescingOptions, InvalidationWatcher};
+
+fn git(build_root: &Path, args: &[&str]) {
+  let status = Command::new("git")
+    .arg("-C")
+    .arg(build_root)
+    .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+    .args(args)
+    .status()
+    .unwrap();
+  assert!(status.success(), "`git {}` failed", args.join(" "));
+}
+
+///
+/// Creates a repository with a `main` branch containing `a.txt` and `b/c.txt`, and a `feature`
+/// branch which additionally modifies `b/c.txt` and adds `d.txt`.
+///
+fn setup_repo() -> (tempfile::TempDir, PathBuf) {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  let build_root = tempdir.path().canonicalize().unwrap();
+  git(&build_root, &["init", "--quiet"]);
+  git(&build_root, &["checkout", "--quiet", "-b", "main"]);
+  std::fs::write(build_root.join("a.txt"), "a").unwrap();
+  std::fs::create_dir(build_root.join("b")).unwrap();
+  std::fs::write(build_root.join("b/c.txt"), "c").unwrap();
+  git(&build_root, &["add", "."]);
+  git(&build_root, &["commit", "--quiet", "-m", "main"]);
+
+  git(&build_root, &["checkout", "--quiet", "-b", "feature"]);
+  std::fs::write(build_root.join("b/c.txt"), "c2").unwrap();
+  std::fs::write(build_root.join("d.txt"), "d").unwrap();
+  git(&build_root, &["add", "."]);
+  git(&build_root, &["commit", "--quiet", "-m", "feature"]);
+  git(&build_root, &["checkout", "--quiet", "main"]);
+  (tempdir, build_root)
+}
+
+fn paths(paths: &[&str]) -> HashSet<PathBuf> {
+  paths.iter().map(PathBuf::from).collect()
+}
+
+///
+/// Waits for the HEAD changes which the given GitState is computing in the background.
+///
+fn wait_for_head_changes(git_state: &mut GitState) -> HashSet<PathBuf> {
+  loop {
+    if let Poll::Ready(changes) = git_state.poll_head_changes() {
+      return changes;
+    }
+    std::thread::sleep(Duration::from_millis(10));
+  }
+}
+
+#[tokio::test]
+async fn head_changes_after_checkout() {
+  let (_tempdir, build_root) = setup_repo();
+  let mut git_state = GitState::new(&build_root, Executor::new()).unwrap();
+  // The initial HEAD is inspected in the background.
+  assert!(git_state.head_changes_in_progress());
+  assert_eq!(wait_for_head_changes(&mut git_state), HashSet::new());
+  assert!(!git_state.head_changes_in_progress());
+
+  git(&build_root, &["checkout", "--quiet", "feature"]);
+  git_state.start_head_changes();
+  assert_eq!(
+    wait_for_head_changes(&mut git_state),
+    paths(&["b/c.txt", "d.txt"])
+  );
+  // HEAD has not moved again.
+  git_state.start_head_changes();
+  assert_eq!(wait_for_head_changes(&mut git_state), HashSet::new());
+}
+
+#[tokio::test]
+async fn is_git_path() {
+  let (_tempdir, build_root) = setup_repo();
+  let git_state = GitState::new(&build_root, Executor::new()).unwrap();
+  assert!(git_state.is_git_path(&build_root.join(".git/HEAD")));
+  assert!(git_state.is_git_path(&build_root.join(".git/index")));
+  assert!(!git_state.is_git_path(&build_root.join(".git/config")));
+  assert!(!git_state.is_git_path(&build_root.join("HEAD")));
+}
+
+#[tokio::test]
+async fn operation_in_progress_while_locked() {
+  let (_tempdir, build_root) = setup_repo();
+  let mut git_state = GitState::new(&build_root, Executor::new()).unwrap();
+  let start = Instant::now();
+  assert!(!git_state.operation_in_progress(start));
+
+  std::fs::write(build_root.join(".git/index.lock"), "").unwrap();
+  assert!(git_state.operation_in_progress(start));
+  assert!(git_state.operation_in_progress(start + MAX_OPERATION_DEFERRAL));
+
+  std::fs::remove_file(build_root.join(".git/index.lock")).unwrap();
+  assert!(!git_state.operation_in_progress(start + MAX_OPERATION_DEFERRAL));
+}
+
+#[tokio::test]
+async fn operation_in_progress_ignores_stale_lock() {
+  let (_tempdir, build_root) = setup_repo();
+  let mut git_state = GitState::new(&build_root, Executor::new()).unwrap();
+  let start = Instant::now();
+  std::fs::write(build_root.join(".git/index.lock"), "").unwrap();
+  assert!(git_state.operation_in_progress(start));
+
+  // Once the lock has outlived the maximum deferral, it is assumed to be stale.
+  let stale = start + MAX_OPERATION_DEFERRAL + Duration::from_secs(1);
+  assert!(!git_state.operation_in_progress(stale));
+  assert!(!git_state.operation_in_progress(stale + Duration::from_secs(1)));
+
+  // A lock which is observed again after having been removed is deferred for afresh.
+  std::fs::remove_file(build_root.join(".git/index.lock")).unwrap();
+  assert!(!git_state.operation_in_progress(stale));
+  std::fs::write(build_root.join(".git/index.lock"), "").unwrap();
+  assert!(git_state.operation_in_progress(stale));
+}
+
+#[tokio::test]
+async fn not_a_repository() {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  assert!(GitState::new(tempdir.path(), Executor::new()).is_none());
+}
+
+#[tokio::test]
+async fn invalidates_changed_paths_in_one_batch() {
+  let (_tempdir, build_root) = setup_repo();
+  let invalidatable = Arc::new(TestInvalidatable::default());
+  let ignorer = GitignoreStyleExcludes::create(vec!["/.git".to_owned()]).unwrap();
+  let (liveness_sender, _liveness_receiver) = crossbeam_channel::unbounded();
+  let (event_sender, event_receiver) = crossbeam_channel::unbounded();
+  let join_handle = InvalidationWatcher::start_background_thread(
+    Arc::downgrade(&invalidatable),
+    ignorer,
+    build_root.clone(),
+    Executor::new(),
+    liveness_sender,
+    event_receiver,
+    None,
+    CoalescingOptions::default(),
+    Arc::default(),
+  );
+
+  git(&build_root, &["checkout", "--quiet", "feature"]);
+  event_sender
+    .send(Ok(
+      notify::Event::new(EventKind::Modify(ModifyKind::Any))
+        .add_path(build_root.join(".git/HEAD"))
+        .add_path(build_root.join("d.txt")),
+    ))
+    .unwrap();
+
+  let mut calls = vec![];
+  for _ in 0..50 {
+    calls = invalidatable.calls.lock().clone();
+    if !calls.is_empty() {
+      break;
+    }
+    std::thread::sleep(Duration::from_millis(20));
+  }
+  assert_eq!(
+    calls,
+    vec![paths(&["", "b", "b/c.txt", "d.txt"])],
+    "Expected exactly one invalidation of the paths changed by the checkout."
+  );
+
+  std::mem::drop(invalidatable);
+  join_handle.join().unwrap();
+}
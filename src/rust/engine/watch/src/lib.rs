@@ -27,14 +27,27 @@
 // Arc<Mutex> can be more clear than needing to grok Orderings:
 #![allow(clippy::mutex_atomic)]
 
+mod coalescing;
+#[cfg(test)]
+mod coalescing_tests;
+mod git;
+#[cfg(test)]
+mod git_tests;
 #[cfg(test)]
 mod tests;
+mod watchman;
+#[cfg(test)]
+mod watchman_tests;
+
+pub use crate::coalescing::CoalescingOptions;
+pub use crate::watchman::WatchmanOptions;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Weak};
+use std::task::Poll;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crossbeam_channel::{self, Receiver, RecvTimeoutError, TryRecvError};
 use fs::GitignoreStyleExcludes;
@@ -44,17 +57,23 @@ use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use parking_lot::Mutex;
 use task_executor::Executor;
 
+use crate::coalescing::{PendingInvalidation, WatcherMetrics};
+use crate::git::GitState;
+use crate::watchman::ClockRecorder;
+
 ///
-/// An InvalidationWatcher maintains a Thread that receives events from a notify Watcher.
+/// An InvalidationWatcher maintains a Thread that receives events from a notify Watcher (or from
+/// a Watchman subscription).
 ///
 /// If the spawned Thread exits for any reason, InvalidationWatcher::running() will return False,
 /// and the caller should create a new InvalidationWatcher (or shut down, in some cases). Generally
 /// this will mean polling.
 ///
 struct Inner {
-  watcher: RecommendedWatcher,
+  backend: Backend,
   executor: Executor,
   liveness: Receiver<String>,
+  metrics: Arc<WatcherMetrics>,
   // Until the background task has started, contains the relevant inputs to launch it via
   // start_background_thread. The decoupling of creating the `InvalidationWatcher` and starting it
   // is to allow for testing of the background thread.
@@ -66,8 +85,20 @@ type WatcherTaskInputs = (
   PathBuf,
   crossbeam_channel::Sender<String>,
   Receiver<notify::Result<notify::Event>>,
+  Option<ClockRecorder>,
 );
 
+///
+/// The source of filesystem events for an InvalidationWatcher.
+///
+enum Backend {
+  Notify(RecommendedWatcher),
+  // Watchman watches the build root recursively, so the Subscription only needs to be held open.
+  Watchman {
+    _subscription: watchman::Subscription,
+  },
+}
+
 pub struct InvalidationWatcher(Mutex<Inner>);
 
 impl InvalidationWatcher {
@@ -107,27 +138,82 @@ impl InvalidationWatcher {
             e
           )
         })?
+    } else {
+      // The git directory is generally ignored, and so will never be watched by a Node. But we
+      // watch it in order to observe changes to HEAD: see `git::GitState`.
+      // And we watch `.git/info` to observe changes to `.git/info/exclude`.
+      for git_dir in &[".git", ".git/info"] {
+        let git_dir = canonical_build_root.join(git_dir);
+        if git_dir.is_dir() {
+          if let Err(e) = watcher.watch(&git_dir, RecursiveMode::NonRecursive) {
+            debug!("Failed to watch {}: {}", git_dir.display(), e);
+          }
+        }
+      }
     }
 
     Ok(Arc::new(InvalidationWatcher(Mutex::new(Inner {
-      watcher,
+      backend: Backend::Notify(watcher),
       executor,
       liveness: liveness_receiver,
+      metrics: Arc::default(),
       background_task_inputs: Some((
         ignorer,
         canonical_build_root,
         liveness_sender,
         watch_receiver,
+        None,
       )),
     }))))
   }
 
   ///
-  /// Starts the background task that monitors watch events. Panics if called more than once.
+  /// Creates an InvalidationWatcher which receives events from a Watchman daemon, rather than
+  /// from the notify crate. Watchman watches the entire build root, so `watch` is a noop.
+  ///
+  /// NB: Watchman does not report changes beneath version control directories (see its
+  /// `ignore_vcs` setting, which includes `.git` by default). Changes to `.git/HEAD`, `.git/index`
+  /// and `.git/info/exclude` are thus not observed: the working tree changes made by git
+  /// operations are invalidated as they arrive (without waiting for the operation to complete),
+  /// and changes to `.git/info/exclude` are not applied until restart.
   ///
-  pub fn start<I: Invalidatable>(&self, invalidatable: &Arc<I>) {
+  pub fn new_with_watchman(
+    executor: Executor,
+    build_root: PathBuf,
+    ignorer: Arc<GitignoreStyleExcludes>,
+    options: WatchmanOptions,
+  ) -> Result<Arc<InvalidationWatcher>, String> {
+    let canonical_build_root =
+      std::fs::canonicalize(build_root.as_path()).map_err(|e| format!("{:?}", e))?;
+    let (watch_sender, watch_receiver) = crossbeam_channel::unbounded();
+    let clock_recorder = options.clock_path.clone().map(ClockRecorder::new);
+    let subscription = watchman::subscribe(options, canonical_build_root.clone(), watch_sender)?;
+
+    let (liveness_sender, liveness_receiver) = crossbeam_channel::unbounded();
+    Ok(Arc::new(InvalidationWatcher(Mutex::new(Inner {
+      backend: Backend::Watchman {
+        _subscription: subscription,
+      },
+      executor,
+      liveness: liveness_receiver,
+      metrics: Arc::default(),
+      background_task_inputs: Some((
+        ignorer,
+        canonical_build_root,
+        liveness_sender,
+        watch_receiver,
+        clock_recorder,
+      )),
+    }))))
+  }
+
+  ///
+  /// Starts the background task that monitors watch events, and coalesces them into
+  /// invalidations according to the given options. Panics if called more than once.
+  ///
+  pub fn start<I: Invalidatable>(&self, invalidatable: &Arc<I>, coalescing: CoalescingOptions) {
     let mut inner = self.0.lock();
-    let (ignorer, canonical_build_root, liveness_sender, watch_receiver) = inner
+    let (ignorer, canonical_build_root, liveness_sender, watch_receiver, clock_recorder) = inner
       .background_task_inputs
       .take()
       .expect("An InvalidationWatcher can only be started once.");
@@ -136,20 +222,43 @@ impl InvalidationWatcher {
       Arc::downgrade(invalidatable),
       ignorer,
       canonical_build_root,
+      inner.executor.clone(),
       liveness_sender,
       watch_receiver,
+      clock_recorder,
+      coalescing,
+      inner.metrics.clone(),
     );
   }
 
+  ///
+  /// Returns counts of the events that this watcher has received, and of the invalidations that
+  /// they were coalesced into.
+  ///
+  pub fn metrics(&self) -> HashMap<&'static str, i64> {
+    self.0.lock().metrics.snapshot()
+  }
+
   // Public for testing purposes.
   pub(crate) fn start_background_thread<I: Invalidatable>(
     invalidatable: Weak<I>,
     ignorer: Arc<GitignoreStyleExcludes>,
     canonical_build_root: PathBuf,
+    executor: Executor,
     liveness_sender: crossbeam_channel::Sender<String>,
     watch_receiver: Receiver<notify::Result<notify::Event>>,
+    clock_recorder: Option<ClockRecorder>,
+    coalescing: CoalescingOptions,
+    metrics: Arc<WatcherMetrics>,
   ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
+      let mut git = GitState::new(&canonical_build_root, executor);
+      let mut git_changed = false;
+      // Paths which have changed, but which have not yet been invalidated because events are
+      // still arriving, or because git is operating on the working tree.
+      let mut pending = PendingInvalidation::default();
+      // The Watchman clock of the latest event, which is recorded once the event is invalidated.
+      let mut unrecorded_clock: Option<String> = None;
       let exit_msg = loop {
         let event_res = watch_receiver.recv_timeout(Duration::from_millis(10));
         let invalidatable = if let Some(g) = invalidatable.upgrade() {
@@ -161,51 +270,38 @@ impl InvalidationWatcher {
         match event_res {
           Ok(Ok(ev)) => {
             let flag = ev.flag();
-            let paths: HashSet<_> = ev
-              .paths
-              .into_iter()
-              .filter_map(|path| {
-                // relativize paths to build root.
-                let path_relative_to_build_root = if path.starts_with(&canonical_build_root) {
-                  // Unwrapping is fine because we check that the path starts with
-                  // the build root above.
-                  path.strip_prefix(&canonical_build_root).unwrap().into()
-                } else {
-                  path
-                };
-                // To avoid having to stat paths for events we will eventually ignore we "lie" to the ignorer
-                // to say that no path is a directory, they could be if someone chmod's or creates a dir.
-                // This maintains correctness by ensuring that at worst we have false negative events, where a directory
-                // only glob (one that ends in `/` ) was supposed to ignore a directory path, but didn't because we claimed it was a file. That
-                // directory path will be used to invalidate nodes, but won't invalidate anything because its path is somewhere
-                // out of our purview.
-                if ignorer.is_ignored_or_child_of_ignored_path(
-                  &path_relative_to_build_root,
-                  /* is_dir */ false,
-                ) {
-                  trace!("notify ignoring {:?}", path_relative_to_build_root);
-                  None
-                } else {
-                  Some(path_relative_to_build_root)
-                }
-              })
-              .flat_map(|path_relative_to_build_root| {
-                let mut paths_to_invalidate: Vec<PathBuf> = vec![];
-                if let Some(parent_dir) = path_relative_to_build_root.parent() {
-                  paths_to_invalidate.push(parent_dir.to_path_buf());
-                }
-                paths_to_invalidate.push(path_relative_to_build_root);
-                paths_to_invalidate
-              })
-              .collect();
+            if let Some(clock) = ev.info() {
+              unrecorded_clock = Some(clock.to_owned());
+            }
+            if let Some(git) = &git {
+              git_changed |= ev.paths.iter().any(|path| git.is_git_path(path));
+            }
+            // If an ignore file changed, then the set of ignored paths beneath its directory may
+            // have changed.
+            pending.extend_directories(
+              ev.paths.iter().filter_map(|path| {
+                let path = path.strip_prefix(&canonical_build_root).ok()?;
+                ignorer.invalidate_ignore_file(path)
+              }),
+              Instant::now(),
+            );
+            let paths = Self::paths_to_invalidate(ev.paths, &ignorer, &canonical_build_root);
+            metrics.record_event(paths.len());
 
             // Only invalidate stuff if we have paths that weren't filtered out by gitignore.
             if flag == Some(Flag::Rescan) {
               debug!("notify queue overflowed: invalidating all paths");
               invalidatable.invalidate_all("notify");
+              pending.clear();
+              if let (Some(clock_recorder), Some(clock)) =
+                (&clock_recorder, unrecorded_clock.take())
+              {
+                clock_recorder.record(&clock);
+              }
+              continue;
             } else if !paths.is_empty() {
-              debug!("notify invalidating {:?} because of {:?}", paths, ev.kind);
-              invalidatable.invalidate(&paths, "notify");
+              trace!("notify observed {:?} because of {:?}", paths, ev.kind);
+              pending.extend(paths, Instant::now());
             }
           }
           Ok(Err(err)) => {
@@ -216,11 +312,61 @@ impl InvalidationWatcher {
               break format!("Watch error: {}", err);
             }
           }
-          Err(RecvTimeoutError::Timeout) => continue,
+          Err(RecvTimeoutError::Timeout) => (),
           Err(RecvTimeoutError::Disconnected) => {
             break "The watch provider exited.".to_owned();
           }
         };
+
+        // If git is modifying the working tree, wait for it to finish, and then invalidate all of
+        // the paths that it changed in one batch.
+        let caller = match &mut git {
+          Some(git) => {
+            // NB: The index lock is only checked while there is something to invalidate. Its
+            // creation and removal are themselves events, which keep its tracking up to date.
+            if (git_changed || !pending.is_empty()) && git.operation_in_progress(Instant::now()) {
+              continue;
+            }
+            if git_changed && !git.head_changes_in_progress() {
+              git_changed = false;
+              git.start_head_changes();
+            }
+            match git.poll_head_changes() {
+              // Wait for HEAD to be inspected, in case the pending changes were made by git.
+              Poll::Pending => continue,
+              Poll::Ready(head_changes) if !head_changes.is_empty() => {
+                pending.extend(
+                  Self::paths_to_invalidate(head_changes, &ignorer, &canonical_build_root),
+                  Instant::now(),
+                );
+                "git"
+              }
+              Poll::Ready(_) => "notify",
+            }
+          }
+          None => "notify",
+        };
+        // Changes made by git are complete, so there is no need to wait for the quiet window.
+        if !pending.is_empty() && (caller == "git" || pending.is_ready(&coalescing, Instant::now()))
+        {
+          let (paths, directories) = pending.take(&coalescing);
+          debug!(
+            "{} invalidating {:?} and directories {:?}",
+            caller, paths, directories
+          );
+          if !paths.is_empty() {
+            invalidatable.invalidate(&paths, caller);
+          }
+          if !directories.is_empty() {
+            invalidatable.invalidate_directories(&directories, caller);
+          }
+          metrics.record_invalidation(paths.len(), directories.len());
+        }
+        if pending.is_empty() && !git_changed {
+          if let (Some(clock_recorder), Some(clock)) = (&clock_recorder, unrecorded_clock.take()) {
+            clock_recorder.record(&clock);
+          }
+        }
       };
 
       // Log and send the exit code.
@@ -229,6 +375,53 @@ impl InvalidationWatcher {
     })
   }
 
+  ///
+  /// Relativizes the given paths to the build root, filters out ignored paths, and adds the parent
+  /// directory of each path (since the directory's listing may have changed).
+  ///
+  fn paths_to_invalidate(
+    paths: impl IntoIterator<Item = PathBuf>,
+    ignorer: &GitignoreStyleExcludes,
+    canonical_build_root: &Path,
+  ) -> HashSet<PathBuf> {
+    paths
+      .into_iter()
+      .filter_map(|path| {
+        // relativize paths to build root.
+        let path_relative_to_build_root = if path.starts_with(canonical_build_root) {
+          // Unwrapping is fine because we check that the path starts with
+          // the build root above.
+          path.strip_prefix(canonical_build_root).unwrap().into()
+        } else {
+          path
+        };
+        // To avoid having to stat paths for events we will eventually ignore we "lie" to the ignorer
+        // to say that no path is a directory, they could be if someone chmod's or creates a dir.
+        // This maintains correctness by ensuring that at worst we have false negative events, where a directory
+        // only glob (one that ends in `/` ) was supposed to ignore a directory path, but didn't because we claimed it was a file. That
+        // directory path will be used to invalidate nodes, but won't invalidate anything because its path is somewhere
+        // out of our purview.
+        if ignorer.is_ignored_or_child_of_ignored_path(
+          &path_relative_to_build_root,
+          /* is_dir */ false,
+        ) {
+          trace!("notify ignoring {:?}", path_relative_to_build_root);
+          None
+        } else {
+          Some(path_relative_to_build_root)
+        }
+      })
+      .flat_map(|path_relative_to_build_root| {
+        let mut paths_to_invalidate: Vec<PathBuf> = vec![];
+        if let Some(parent_dir) = path_relative_to_build_root.parent() {
+          paths_to_invalidate.push(parent_dir.to_path_buf());
+        }
+        paths_to_invalidate.push(path_relative_to_build_root);
+        paths_to_invalidate
+      })
+      .collect()
+  }
+
   ///
   /// An InvalidationWatcher will never restart on its own: a consumer should re-initialize if this
   /// method returns an error.
@@ -269,6 +462,9 @@ impl InvalidationWatcher {
 
     let executor = {
       let inner = self.0.lock();
+      if let Backend::Watchman { .. } = inner.backend {
+        return Ok(());
+      }
       inner.executor.clone()
     };
 
@@ -276,10 +472,12 @@ impl InvalidationWatcher {
     executor
       .spawn_blocking(move || {
         let mut inner = watcher.0.lock();
-        inner
-          .watcher
-          .watch(&path, RecursiveMode::NonRecursive)
-          .map_err(|e| maybe_enrich_notify_error(&path, e))
+        match inner.backend {
+          Backend::Notify(ref mut watcher) => watcher
+            .watch(&path, RecursiveMode::NonRecursive)
+            .map_err(|e| maybe_enrich_notify_error(&path, e)),
+          Backend::Watchman { .. } => Ok(()),
+        }
       })
       .await
   }
@@ -287,6 +485,8 @@ impl InvalidationWatcher {
 
 pub trait Invalidatable: Send + Sync + 'static {
   fn invalidate(&self, paths: &HashSet<PathBuf>, caller: &str) -> usize;
+  /// Invalidates the given directories, and all paths beneath them.
+  fn invalidate_directories(&self, directories: &HashSet<PathBuf>, caller: &str) -> usize;
   fn invalidate_all(&self, caller: &str) -> usize;
 }
 
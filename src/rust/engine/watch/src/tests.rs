@@ -1,4 +1,4 @@
-use crate::{Invalidatable, InvalidationWatcher};
+use crate::{CoalescingOptions, Invalidatable, InvalidationWatcher};
 
 use std::collections::HashSet;
 use std::fs::create_dir;
@@ -52,7 +52,7 @@ async fn receive_watch_event_on_file_change() {
   let invalidatable = Arc::new(TestInvalidatable::default());
   let ignorer = GitignoreStyleExcludes::empty();
   let watcher = setup_watch(ignorer, build_root.clone(), file_path.clone()).await;
-  watcher.start(&invalidatable);
+  watcher.start(&invalidatable, CoalescingOptions::default());
 
   // Update the content of the file being watched.
   let new_content = "stnetnoc".as_bytes().to_vec();
@@ -84,7 +84,7 @@ async fn ignore_file_events_matching_patterns_in_pants_ignore() {
   let invalidatable = Arc::new(TestInvalidatable::default());
   let ignorer = GitignoreStyleExcludes::create(vec!["/foo".to_string()]).unwrap();
   let watcher = setup_watch(ignorer, build_root, file_path.clone()).await;
-  watcher.start(&invalidatable);
+  watcher.start(&invalidatable, CoalescingOptions::default());
 
   // Update the content of the file being watched.
   let new_content = "stnetnoc".as_bytes().to_vec();
@@ -116,8 +116,12 @@ async fn liveness_watch_error() {
     Arc::downgrade(&invalidatable),
     ignorer,
     build_root,
+    Executor::new(),
     liveness_sender,
     event_receiver,
+    None,
+    CoalescingOptions::default(),
+    Arc::default(),
   );
 
   // Should not exit.
@@ -139,12 +143,14 @@ async fn liveness_watch_error() {
 }
 
 #[derive(Default)]
-struct TestInvalidatable {
+pub(crate) struct TestInvalidatable {
   pub calls: Mutex<Vec<HashSet<PathBuf>>>,
+  pub directory_calls: Mutex<Vec<HashSet<PathBuf>>>,
+  pub invalidate_all_calls: Mutex<usize>,
 }
 
 impl TestInvalidatable {
-  fn was_invalidated(&self, path: &Path) -> bool {
+  pub(crate) fn was_invalidated(&self, path: &Path) -> bool {
     let calls = self.calls.lock();
     calls.iter().any(|call| call.contains(path))
   }
@@ -158,7 +164,14 @@ impl Invalidatable for TestInvalidatable {
     invalidated
   }
 
+  fn invalidate_directories(&self, directories: &HashSet<PathBuf>, _caller: &str) -> usize {
+    let mut directory_calls = self.directory_calls.lock();
+    directory_calls.push(directories.clone());
+    directories.len()
+  }
+
   fn invalidate_all(&self, _caller: &str) -> usize {
-    unimplemented!();
+    *self.invalidate_all_calls.lock() += 1;
+    0
   }
 }
@@ -0,0 +1,251 @@
+// Copyright 2021 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+use crossbeam_channel::Sender;
+use log::{debug, warn};
+use notify::event::{EventKind, Flag};
+use serde_json::{json, Value as JsonValue};
+
+/// The name of the subscription that Pants registers with Watchman.
+const SUBSCRIPTION_NAME: &str = "pants";
+
+#[derive(Clone, Debug)]
+pub struct WatchmanOptions {
+  /// The path of the unix socket of a running Watchman daemon. If None, the socket is located via
+  /// `$WATCHMAN_SOCK` or `watchman get-sockname`.
+  pub socket_path: Option<PathBuf>,
+  /// If set, a file in which to record the Watchman clock of the latest invalidated changes. When
+  /// a watcher starts, it will report any changes since the recorded clock.
+  pub clock_path: Option<PathBuf>,
+}
+
+///
+/// Records the Watchman clock of changes once they have been invalidated, so that a watcher which
+/// starts later will report any changes since then.
+///
+pub(crate) struct ClockRecorder {
+  clock_path: PathBuf,
+}
+
+impl ClockRecorder {
+  pub(crate) fn new(clock_path: PathBuf) -> ClockRecorder {
+    ClockRecorder { clock_path }
+  }
+
+  pub(crate) fn record(&self, clock: &str) {
+    if let Err(e) = std::fs::write(&self.clock_path, clock) {
+      warn!(
+        "Failed to record the Watchman clock in {}: {}",
+        self.clock_path.display(),
+        e
+      );
+    }
+  }
+}
+
+///
+/// Locates the socket of the Watchman daemon for the current user, starting it if necessary.
+///
+fn discover_socket_path() -> Result<PathBuf, String> {
+  if let Some(socket_path) = std::env::var_os("WATCHMAN_SOCK") {
+    return Ok(PathBuf::from(socket_path));
+  }
+  let output = Command::new("watchman")
+    .args(["--output-encoding=json", "--no-pretty", "get-sockname"])
+    .output()
+    .map_err(|e| format!("Failed to run `watchman get-sockname`: {}", e))?;
+  if !output.status.success() {
+    return Err(format!(
+      "Failed to run `watchman get-sockname`: {}",
+      String::from_utf8_lossy(&output.stderr)
+    ));
+  }
+  let response: JsonValue = serde_json::from_slice(&output.stdout)
+    .map_err(|e| format!("Failed to parse `watchman get-sockname` output: {}", e))?;
+  response
+    .get("sockname")
+    .and_then(JsonValue::as_str)
+    .map(PathBuf::from)
+    .ok_or_else(|| format!("Unexpected `watchman get-sockname` output: {}", response))
+}
+
+///
+/// A connection to a Watchman daemon using its JSON protocol, in which each request and response
+/// PDU is a single line of JSON.
+///
+struct Connection {
+  reader: BufReader<UnixStream>,
+  writer: UnixStream,
+}
+
+impl Connection {
+  fn connect(socket_path: &Path) -> Result<Connection, String> {
+    let writer = UnixStream::connect(socket_path).map_err(|e| {
+      format!(
+        "Failed to connect to Watchman at {}: {}",
+        socket_path.display(),
+        e
+      )
+    })?;
+    let reader = writer
+      .try_clone()
+      .map_err(|e| format!("Failed to connect to Watchman: {}", e))?;
+    Ok(Connection {
+      reader: BufReader::new(reader),
+      writer,
+    })
+  }
+
+  fn send(&mut self, command: &JsonValue) -> Result<(), String> {
+    let mut line = command.to_string();
+    line.push('\n');
+    self
+      .writer
+      .write_all(line.as_bytes())
+      .map_err(|e| format!("Failed to send command to Watchman: {}", e))
+  }
+
+  fn recv(&mut self) -> Result<JsonValue, String> {
+    let mut line = String::new();
+    match self.reader.read_line(&mut line) {
+      Ok(0) => return Err("Watchman closed the connection.".to_owned()),
+      Ok(_) => (),
+      Err(e) => return Err(format!("Failed to read from Watchman: {}", e)),
+    }
+    let pdu: JsonValue = serde_json::from_str(&line)
+      .map_err(|e| format!("Failed to parse Watchman response: {}", e))?;
+    if let Some(error) = pdu.get("error") {
+      return Err(format!("Watchman error: {}", error));
+    }
+    Ok(pdu)
+  }
+
+  ///
+  /// Sends a command and returns its response, skipping any unilateral PDUs (such as subscription
+  /// notifications) which arrive first.
+  ///
+  fn command(&mut self, command: JsonValue) -> Result<JsonValue, String> {
+    self.send(&command)?;
+    loop {
+      let pdu = self.recv()?;
+      if pdu.get("unilateral").and_then(JsonValue::as_bool) != Some(true) {
+        return Ok(pdu);
+      }
+    }
+  }
+}
+
+///
+/// A subscription to changes in a Watchman-watched build root. Dropping the Subscription closes
+/// its connection, which stops its background thread.
+///
+pub(crate) struct Subscription {
+  stream: UnixStream,
+}
+
+impl Drop for Subscription {
+  fn drop(&mut self) {
+    let _ = self.stream.shutdown(Shutdown::Both);
+  }
+}
+
+///
+/// Subscribes to changes under the given build root, and spawns a thread which converts Watchman
+/// subscription PDUs into notify Events for the given Sender. The clock of each PDU is attached to
+/// its Event as its `info`, to be recorded via a ClockRecorder once the Event has been invalidated.
+///
+/// If a clock was recorded by a previous subscription, changes since that clock are reported in
+/// the first PDU. If Watchman cannot report changes since the clock (because it restarted, for
+/// example) or if there is no recorded clock, a `Rescan` event is sent, which invalidates
+/// everything.
+///
+pub(crate) fn subscribe(
+  options: WatchmanOptions,
+  canonical_build_root: PathBuf,
+  sender: Sender<notify::Result<notify::Event>>,
+) -> Result<Subscription, String> {
+  let build_root = canonical_build_root.to_str().ok_or_else(|| {
+    format!(
+      "Cannot watch non-UTF8 build root {} with Watchman.",
+      canonical_build_root.display()
+    )
+  })?;
+  let socket_path = match options.socket_path {
+    Some(ref socket_path) => socket_path.clone(),
+    None => discover_socket_path()?,
+  };
+  let mut connection = Connection::connect(&socket_path)?;
+
+  let response = connection.command(json!(["watch-project", build_root]))?;
+  let watch_root = response
+    .get("watch")
+    .and_then(JsonValue::as_str)
+    .ok_or_else(|| format!("Unexpected Watchman watch-project response: {}", response))?
+    .to_owned();
+
+  let mut query = json!({
+    "fields": ["name"],
+    "empty_on_fresh_instance": true,
+  });
+  if let Some(relative_path) = response.get("relative_path").and_then(JsonValue::as_str) {
+    query["relative_root"] = json!(relative_path);
+  }
+  let since = options
+    .clock_path
+    .as_ref()
+    .and_then(|clock_path| std::fs::read_to_string(clock_path).ok())
+    .map(|clock| clock.trim().to_owned())
+    .filter(|clock| !clock.is_empty());
+  if let Some(since) = since {
+    debug!("Subscribing to Watchman changes since {}", since);
+    query["since"] = json!(since);
+  }
+  connection.command(json!(["subscribe", watch_root, SUBSCRIPTION_NAME, query]))?;
+
+  let stream = connection
+    .writer
+    .try_clone()
+    .map_err(|e| format!("Failed to subscribe to Watchman: {}", e))?;
+  thread::spawn(move || loop {
+    let pdu = match connection.recv() {
+      Ok(pdu) => pdu,
+      Err(e) => {
+        // If the Subscription was dropped, the receiver has likely gone away too.
+        let _ = sender.send(Err(notify::Error::generic(&e)));
+        break;
+      }
+    };
+    if pdu.get("subscription").and_then(JsonValue::as_str) != Some(SUBSCRIPTION_NAME) {
+      continue;
+    }
+
+    let mut event = notify::Event::new(EventKind::Any);
+    if let Some(clock) = pdu.get("clock").and_then(JsonValue::as_str) {
+      event = event.set_info(clock);
+    }
+    let event = if pdu.get("is_fresh_instance").and_then(JsonValue::as_bool) == Some(true) {
+      event.set_flag(Flag::Rescan)
+    } else {
+      let files = pdu.get("files").and_then(JsonValue::as_array);
+      files
+        .into_iter()
+        .flatten()
+        .filter_map(JsonValue::as_str)
+        .fold(event, |event, name| {
+          event.add_path(canonical_build_root.join(name))
+        })
+    };
+    if sender.send(Ok(event)).is_err() {
+      break;
+    }
+  });
+
+  Ok(Subscription { stream })
+}
@@ -0,0 +1,238 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use fs::GitignoreStyleExcludes;
+use serde_json::{json, Value as JsonValue};
+use task_executor::Executor;
+
+use crate::tests::TestInvalidatable;
+use crate::{CoalescingOptions, InvalidationWatcher, WatchmanOptions};
+
+///
+/// Starts a fake Watchman server which responds to `watch-project` and `subscribe` commands, and
+/// then sends the given subscription PDUs. Returns the commands that it received once the client
+/// disconnects.
+///
+fn fake_watchman(
+  socket_path: &Path,
+  build_root: &Path,
+  pdus: Vec<JsonValue>,
+) -> thread::JoinHandle<Vec<JsonValue>> {
+  let listener = UnixListener::bind(socket_path).unwrap();
+  let build_root = build_root.to_str().unwrap().to_owned();
+  thread::spawn(move || {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut commands = vec![];
+    for _ in 0..2 {
+      let mut line = String::new();
+      reader.read_line(&mut line).unwrap();
+      let command: JsonValue = serde_json::from_str(&line).unwrap();
+      let response = match command[0].as_str().unwrap() {
+        "watch-project" => json!({"version": "fake", "watch": build_root}),
+        "subscribe" => json!({"version": "fake", "subscribe": command[2], "clock": "c:0:1"}),
+        _ => json!({"version": "fake", "error": "unknown command"}),
+      };
+      writeln!(stream, "{}", response).unwrap();
+      commands.push(command);
+    }
+    for pdu in pdus {
+      writeln!(stream, "{}", pdu).unwrap();
+    }
+    // Wait for the client to disconnect.
+    let mut line = String::new();
+    let _ = reader.read_line(&mut line);
+    commands
+  })
+}
+
+fn setup() -> (tempfile::TempDir, PathBuf, WatchmanOptions) {
+  let tempdir = tempfile::TempDir::new().unwrap();
+  let build_root = tempdir.path().canonicalize().unwrap().join("buildroot");
+  std::fs::create_dir(&build_root).unwrap();
+  let options = WatchmanOptions {
+    socket_path: Some(tempdir.path().join("watchman.sock")),
+    clock_path: Some(tempdir.path().join("watchman.clock")),
+  };
+  (tempdir, build_root, options)
+}
+
+fn wait_for(condition: impl Fn() -> bool) -> bool {
+  for _ in 0..50 {
+    if condition() {
+      return true;
+    }
+    thread::sleep(Duration::from_millis(20));
+  }
+  false
+}
+
+#[tokio::test]
+async fn invalidates_changed_files() {
+  let (_tempdir, build_root, options) = setup();
+  let server = fake_watchman(
+    options.socket_path.as_ref().unwrap(),
+    &build_root,
+    vec![json!({
+      "unilateral": true,
+      "subscription": "pants",
+      "clock": "c:0:2",
+      "is_fresh_instance": false,
+      "files": ["foo/watch_me.txt"],
+    })],
+  );
+
+  let invalidatable = Arc::new(TestInvalidatable::default());
+  let watcher = InvalidationWatcher::new_with_watchman(
+    Executor::new(),
+    build_root.clone(),
+    GitignoreStyleExcludes::empty(),
+    options.clone(),
+  )
+  .unwrap();
+  watcher.start(&invalidatable, CoalescingOptions::default());
+  // Watching is a noop, because Watchman watches the entire build root.
+  watcher
+    .watch(build_root.join("foo/watch_me.txt"))
+    .await
+    .unwrap();
+
+  assert!(wait_for(|| invalidatable
+    .was_invalidated(Path::new("foo/watch_me.txt"))
+    && invalidatable.was_invalidated(Path::new("foo"))));
+  let clock_path = options.clock_path.unwrap();
+  assert!(wait_for(
+    || std::fs::read_to_string(&clock_path).ok() == Some("c:0:2".to_owned())
+  ));
+  assert_eq!(*invalidatable.invalidate_all_calls.lock(), 0);
+
+  std::mem::drop(watcher);
+  let commands = server.join().unwrap();
+  assert_eq!(commands[0], json!(["watch-project", build_root]));
+  assert_eq!(commands[1][0], json!("subscribe"));
+  assert_eq!(commands[1][3].get("since"), None);
+}
+
+#[tokio::test]
+async fn records_clock_after_invalidating() {
+  let (_tempdir, build_root, options) = setup();
+  let server = fake_watchman(
+    options.socket_path.as_ref().unwrap(),
+    &build_root,
+    vec![json!({
+      "unilateral": true,
+      "subscription": "pants",
+      "clock": "c:0:2",
+      "is_fresh_instance": false,
+      "files": ["foo/watch_me.txt"],
+    })],
+  );
+
+  let invalidatable = Arc::new(TestInvalidatable::default());
+  let watcher = InvalidationWatcher::new_with_watchman(
+    Executor::new(),
+    build_root.clone(),
+    GitignoreStyleExcludes::empty(),
+    options.clone(),
+  )
+  .unwrap();
+  watcher.start(
+    &invalidatable,
+    CoalescingOptions {
+      quiet_window: Duration::from_millis(400),
+      ..CoalescingOptions::default()
+    },
+  );
+
+  // While the change is waiting to be coalesced, its clock is not recorded: otherwise, a restart
+  // during the quiet window would lose the change.
+  let clock_path = options.clock_path.unwrap();
+  thread::sleep(Duration::from_millis(200));
+  assert!(!invalidatable.was_invalidated(Path::new("foo/watch_me.txt")));
+  assert!(!clock_path.exists());
+
+  assert!(wait_for(
+    || std::fs::read_to_string(&clock_path).ok() == Some("c:0:2".to_owned())
+  ));
+  assert!(invalidatable.was_invalidated(Path::new("foo/watch_me.txt")));
+
+  std::mem::drop(watcher);
+  server.join().unwrap();
+}
+
+#[tokio::test]
+async fn subscribes_since_recorded_clock() {
+  let (_tempdir, build_root, options) = setup();
+  std::fs::write(options.clock_path.as_ref().unwrap(), "c:0:1\n").unwrap();
+  let server = fake_watchman(
+    options.socket_path.as_ref().unwrap(),
+    &build_root,
+    vec![json!({
+      "unilateral": true,
+      "subscription": "pants",
+      "clock": "c:1:1",
+      "is_fresh_instance": true,
+      "files": [],
+    })],
+  );
+
+  let invalidatable = Arc::new(TestInvalidatable::default());
+  let watcher = InvalidationWatcher::new_with_watchman(
+    Executor::new(),
+    build_root,
+    GitignoreStyleExcludes::empty(),
+    options,
+  )
+  .unwrap();
+  watcher.start(&invalidatable, CoalescingOptions::default());
+
+  // Watchman could not report changes since the clock, so everything is invalidated.
+  assert!(wait_for(|| *invalidatable.invalidate_all_calls.lock() == 1));
+
+  std::mem::drop(watcher);
+  let commands = server.join().unwrap();
+  assert_eq!(commands[1][3]["since"], json!("c:0:1"));
+}
+
+#[tokio::test]
+async fn exits_when_watchman_disconnects() {
+  let (_tempdir, build_root, options) = setup();
+  let listener = UnixListener::bind(options.socket_path.as_ref().unwrap()).unwrap();
+  let server_build_root = build_root.clone();
+  let server = thread::spawn(move || {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    writeln!(stream, "{}", json!({ "watch": server_build_root })).unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    writeln!(stream, "{}", json!({"subscribe": "pants"})).unwrap();
+    // Then hang up.
+  });
+
+  let invalidatable = Arc::new(TestInvalidatable::default());
+  let watcher = InvalidationWatcher::new_with_watchman(
+    Executor::new(),
+    build_root,
+    GitignoreStyleExcludes::empty(),
+    options,
+  )
+  .unwrap();
+  watcher.start(&invalidatable, CoalescingOptions::default());
+  server.join().unwrap();
+
+  let mut is_valid = Ok(());
+  for _ in 0..50 {
+    is_valid = watcher.is_valid().await;
+    if is_valid.is_err() {
+      break;
+    }
+    thread::sleep(Duration::from_millis(20));
+  }
+  assert!(is_valid.is_err());
+}
@@ -43,6 +43,12 @@ use strum::IntoEnumIterator;
 )]
 #[strum(serialize_all = "snake_case")]
 pub enum Metric {
+  /// The number of cache hits which were re-executed to verify that they were reproducible.
+  CacheVerificationRuns,
+  /// The number of verified cache hits whose re-executed result differed from the cached result.
+  CacheVerificationMismatches,
+  /// The number of cache hits which could not be verified because re-execution failed.
+  CacheVerificationErrors,
   LocalProcessTotalTimeRunMs,
   LocalCacheRequests,
   LocalCacheRequestsCached,
@@ -58,6 +64,9 @@ pub enum Metric {
   RemoteCacheRequestsCached,
   RemoteCacheRequestsUncached,
   RemoteCacheReadErrors,
+  /// The number of remote cache reads or writes which were skipped because the remote cache had
+  /// failed repeatedly and was temporarily disabled.
+  RemoteCacheRequestsSkipped,
   RemoteCacheWriteAttempts,
   RemoteCacheWriteSuccesses,
   RemoteCacheWriteErrors,
@@ -67,6 +76,10 @@ pub enum Metric {
   /// processes directly.
   RemoteCacheTotalTimeSavedMs,
   RemoteExecutionErrors,
+  /// The number of processes which were run locally because remote execution failed.
+  RemoteExecutionFallbacks,
+  /// The number of processes which failed to run locally after remote execution failed.
+  RemoteExecutionFallbackErrors,
   RemoteExecutionRequests,
   RemoteExecutionRPCErrors,
   RemoteExecutionRPCExecute,
@@ -98,6 +111,11 @@ pub enum ObservationMetric {
   RemoteExecutionRPCFirstResponseTime,
   RemoteStoreTimeToFirstByte,
   RemoteStoreReadBlobTimeMicros,
+  /// The time (in microseconds) that a remote RPC was delayed by client-side rate limiting or
+  /// overload backoff before being sent.
+  RemoteRpcThrottledTimeMicros,
+  /// The backoff (in milliseconds) applied after a remote server signaled that it was overloaded.
+  RemoteRpcOverloadBackoffMs,
   /// The time saved (in milliseconds) thanks to a local cache hit instead of running the process
   /// directly.
   LocalCacheTimeSavedMs,